@@ -0,0 +1,62 @@
+#![no_main]
+
+//! Feeds arbitrary payloads through the ingest path of `handle_event` and
+//! `DatabaseService::insert_value_at`: validation, message id extraction, the unit
+//! conversion of a topic's `UnitRule` and delta encoding.
+
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+
+#[path = "../../src/delta.rs"]
+mod delta;
+#[path = "../../src/models.rs"]
+mod models;
+#[path = "../../src/payload.rs"]
+mod payload;
+
+use models::UnitRule;
+use payload::{PayloadError, PayloadLimits};
+
+const LIMITS: PayloadLimits = PayloadLimits {
+    max_bytes: 64 * 1024,
+    max_json_depth: 32,
+};
+
+fuzz_target!(|input: (f64, f64, &[u8])| {
+    let (scale, offset, data) = input;
+    let text = match payload::parse(data, &LIMITS) {
+        Ok(text) => text,
+        Err(PayloadError::TooLarge { .. }) => {
+            assert!(data.len() > LIMITS.max_bytes);
+            return;
+        }
+        Err(PayloadError::InvalidUtf8(_)) => {
+            assert!(std::str::from_utf8(data).is_err());
+            return;
+        }
+        Err(PayloadError::TooDeep(_)) => return,
+    };
+
+    let _ = payload::message_id(text, "id");
+
+    // Converted values are stored as numbers, so they must parse back as finite ones
+    let rule = UnitRule {
+        scale,
+        offset,
+        keep_raw: false,
+    };
+    if let Some(converted) = rule.apply(text) {
+        assert!(converted.parse::<f64>().is_ok_and(f64::is_finite));
+    }
+
+    let Ok(json) = serde_json::from_str::<Value>(text) else {
+        return;
+    };
+    if let Value::Object(new) = json {
+        // Delta storage must reproduce the payload from an empty previous value
+        let mut rebuilt = serde_json::Map::new();
+        let delta = delta::diff(&rebuilt, &new);
+        assert!(delta::apply(&mut rebuilt, &delta));
+        assert_eq!(rebuilt, new);
+    }
+});
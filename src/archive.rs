@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::bufread::GzipDecoder;
+use async_compression::tokio::write::GzipEncoder;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::info;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::config::ArchiveFormat;
+use crate::db::DatabaseService;
+use crate::encryption::{EncryptionError, ValueCipher};
+use crate::models::ValueRow;
+
+/// Rows moved from the database to the archive per step of a run
+const ARCHIVE_BATCH_ROWS: usize = 5_000;
+
+/// Day of values whose timestamp doesn't start with a date
+const UNDATED: &str = "undated";
+
+const SQLITE_TIMESTAMP_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error("Archive I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Archive database failed: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("Malformed archive record: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Archived value is encrypted, but no encryption key is configured")]
+    MissingKey,
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+}
+
+/// A value as written to an archive file, `value` encrypted when `nonce` is set
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedRecord {
+    id: i64,
+    topic: String,
+    value: String,
+    timestamp: String,
+    /// Base64 encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    /// `value` is a binary payload, base64 encoded
+    #[serde(default)]
+    is_binary: bool,
+}
+
+/// An archive file, holding the values of one day
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveFile {
+    /// `YYYY-MM-DD` of the value timestamps, or `undated`
+    pub day: String,
+    pub file: String,
+    pub size_bytes: u64,
+}
+
+/// Cold storage for values moved out of the database, keeping it small while the history
+/// stays queryable. Values go to one file per day of their timestamp, in ARCHIVE_FORMAT.
+/// Archived values keep their id, topic, value and timestamp; labels, raw values and
+/// message ids are not archived. With value encryption, archived values are encrypted
+/// with the same key.
+pub struct Archive {
+    dir: PathBuf,
+    format: ArchiveFormat,
+    cipher: Option<ValueCipher>,
+}
+
+impl Archive {
+    pub fn new(dir: impl Into<PathBuf>, format: ArchiveFormat, cipher: Option<ValueCipher>) -> Self {
+        Self {
+            dir: dir.into(),
+            format,
+            cipher,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            ArchiveFormat::Sqlite => "db",
+            ArchiveFormat::NdjsonGz => "ndjson.gz",
+        }
+    }
+
+    /// Moves every value received more than `after_secs` ago from `db` to the archive,
+    /// in batches. Rows are only deleted once they are written, a failed run leaves the
+    /// rest in the database for the next one. Returns the number of archived values.
+    pub async fn archive_values(&self, db: &DatabaseService, after_secs: u64) -> Result<usize, ArchiveError> {
+        let cutoff = (OffsetDateTime::now_utc() - time::Duration::seconds(after_secs as i64))
+            .format(SQLITE_TIMESTAMP_FORMAT)
+            .unwrap_or_default();
+        let mut archived = 0;
+        loop {
+            let values = db.get_archivable_values(&cutoff, ARCHIVE_BATCH_ROWS)?;
+            let Some(last_id) = values.last().map(|value| value.id) else {
+                break;
+            };
+            self.store(&values).await?;
+            archived += db.delete_archived_values(&cutoff, last_id)?;
+            if values.len() < ARCHIVE_BATCH_ROWS {
+                break;
+            }
+        }
+        if archived > 0 {
+            info!("Archived {} values received before {}.", archived, cutoff);
+        }
+        Ok(archived)
+    }
+
+    /// Appends `values` to the files of their days. A value stored twice, e.g. after a
+    /// crash between archiving and deleting it, is read back once.
+    pub async fn store(&self, values: &[ValueRow]) -> Result<(), ArchiveError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut days: BTreeMap<&str, Vec<ArchivedRecord>> = BTreeMap::new();
+        for value in values {
+            days.entry(day_of(&value.timestamp)).or_default().push(self.seal(value)?);
+        }
+        for (day, records) in days {
+            let path = self.dir.join(format!("{}.{}", day, self.extension()));
+            match self.format {
+                ArchiveFormat::Sqlite => store_sqlite(&path, &records)?,
+                ArchiveFormat::NdjsonGz => store_ndjson_gz(&path, &records).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Archive files in ARCHIVE_FORMAT, oldest day first
+    pub async fn files(&self) -> Result<Vec<ArchiveFile>, ArchiveError> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let suffix = format!(".{}", self.extension());
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let file = entry.file_name().to_string_lossy().into_owned();
+            if let Some(day) = file.strip_suffix(&suffix) {
+                files.push(ArchiveFile {
+                    day: day.to_string(),
+                    size_bytes: entry.metadata().await?.len(),
+                    file,
+                });
+            }
+        }
+        files.sort_by(|a, b| a.day.cmp(&b.day));
+        Ok(files)
+    }
+
+    /// Up to `limit` archived values of `topic` with a timestamp in `[from, to]`, oldest
+    /// first. Every file of the days in range is opened, ndjson-gz files are read in
+    /// full, so this is a lot slower than querying the database.
+    pub async fn values_in_range(
+        &self,
+        topic: &str,
+        from: &str,
+        to: &str,
+        limit: usize,
+    ) -> Result<Vec<ValueRow>, ArchiveError> {
+        let (first_day, last_day) = (day_of(from), day_of(to));
+        let mut seen = HashSet::new();
+        let mut values = Vec::new();
+        for file in self.files().await? {
+            if file.day.as_str() < first_day || file.day.as_str() > last_day {
+                continue;
+            }
+            let path = self.dir.join(&file.file);
+            let records = match self.format {
+                ArchiveFormat::Sqlite => read_sqlite(&path, topic, from, to, limit)?,
+                ArchiveFormat::NdjsonGz => read_ndjson_gz(&path, topic, from, to).await?,
+            };
+            for record in records {
+                if seen.insert(record.id) {
+                    values.push(self.open(record)?);
+                }
+            }
+            // Later days only hold later values
+            if values.len() >= limit {
+                break;
+            }
+        }
+        values.sort_by(|a, b| (a.timestamp.as_str(), a.id).cmp(&(b.timestamp.as_str(), b.id)));
+        values.truncate(limit);
+        Ok(values)
+    }
+
+    fn seal(&self, value: &ValueRow) -> Result<ArchivedRecord, ArchiveError> {
+        let (stored, nonce) = match &self.cipher {
+            Some(cipher) => {
+                let (ciphertext, nonce) = cipher.encrypt(&value.value)?;
+                (ciphertext, Some(BASE64.encode(nonce)))
+            }
+            None => (value.value.clone(), None),
+        };
+        Ok(ArchivedRecord {
+            id: value.id,
+            topic: value.topic.clone(),
+            value: stored,
+            timestamp: value.timestamp.clone(),
+            nonce,
+            is_binary: value.is_binary,
+        })
+    }
+
+    fn open(&self, record: ArchivedRecord) -> Result<ValueRow, ArchiveError> {
+        let value = match (&record.nonce, &self.cipher) {
+            (None, _) => record.value,
+            (Some(nonce), Some(cipher)) => {
+                let nonce = BASE64.decode(nonce).map_err(|_| EncryptionError::Decrypt)?;
+                cipher.decrypt(&record.value, &nonce)?
+            }
+            (Some(_), None) => return Err(ArchiveError::MissingKey),
+        };
+        Ok(ValueRow {
+            id: record.id,
+            topic: record.topic,
+            value,
+            timestamp: record.timestamp,
+            is_binary: record.is_binary,
+            raw_value: None,
+        })
+    }
+}
+
+/// `YYYY-MM-DD` prefix of a stored timestamp
+fn day_of(timestamp: &str) -> &str {
+    match timestamp.get(..10) {
+        Some(day) if day.as_bytes()[4] == b'-' && day.as_bytes()[7] == b'-' => day,
+        _ => UNDATED,
+    }
+}
+
+fn store_sqlite(path: &Path, records: &[ArchivedRecord]) -> Result<(), ArchiveError> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS archived_values (
+            id INTEGER PRIMARY KEY,
+            topic TEXT NOT NULL,
+            value TEXT NOT NULL,
+            timestamp TEXT NOT NULL,
+            nonce TEXT,
+            is_binary BOOLEAN NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS idx_archived_values_topic_timestamp ON archived_values (topic, timestamp);",
+    )?;
+    // Files written before binary values were archived lack the column
+    if !has_binary_column(&conn)? {
+        conn.execute_batch("ALTER TABLE archived_values ADD COLUMN is_binary BOOLEAN NOT NULL DEFAULT 0")?;
+    }
+
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR IGNORE INTO archived_values (id, topic, value, timestamp, nonce, is_binary)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for record in records {
+            stmt.execute(params![record.id, record.topic, record.value, record.timestamp, record.nonce, record.is_binary])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn read_sqlite(path: &Path, topic: &str, from: &str, to: &str, limit: usize) -> Result<Vec<ArchivedRecord>, ArchiveError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let is_binary = if has_binary_column(&conn)? { "is_binary" } else { "0" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, topic, value, timestamp, nonce, {} FROM archived_values
+         WHERE topic = ?1 AND timestamp BETWEEN ?2 AND ?3
+         ORDER BY timestamp, id
+         LIMIT ?4",
+        is_binary
+    ))?;
+    let rows = stmt.query_map(params![topic, from, to, limit], |row| {
+        Ok(ArchivedRecord {
+            id: row.get(0)?,
+            topic: row.get(1)?,
+            value: row.get(2)?,
+            timestamp: row.get(3)?,
+            nonce: row.get(4)?,
+            is_binary: row.get(5)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn has_binary_column(conn: &Connection) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare("PRAGMA table_info(archived_values)")?;
+    let mut names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    Ok(names.any(|name| name.is_ok_and(|name| name == "is_binary")))
+}
+
+async fn store_ndjson_gz(path: &Path, records: &[ArchivedRecord]) -> Result<(), ArchiveError> {
+    let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    // Every run appends a gzip member of its own, readers decode them one after another
+    let mut encoder = GzipEncoder::new(file);
+    for record in records {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        encoder.write_all(&line).await?;
+    }
+    encoder.shutdown().await?;
+    Ok(())
+}
+
+async fn read_ndjson_gz(path: &Path, topic: &str, from: &str, to: &str) -> Result<Vec<ArchivedRecord>, ArchiveError> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut decoder = GzipDecoder::new(BufReader::new(file));
+    decoder.multiple_members(true);
+
+    let mut lines = BufReader::new(decoder).lines();
+    let mut records = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        let record: ArchivedRecord = serde_json::from_str(&line)?;
+        if record.topic == topic && (from..=to).contains(&record.timestamp.as_str()) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
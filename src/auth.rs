@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::info;
+use thiserror::Error;
+
+use crate::db::DatabaseService;
+
+/// Username and password presented by a client
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// A verified API user
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub username: String,
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Authentication backend error: {0}")]
+    Backend(String),
+}
+
+/// Source of truth for REST API credentials
+pub trait AuthBackend: Send + Sync {
+    fn verify(&self, credentials: &Credentials<'_>) -> Result<Identity, AuthError>;
+}
+
+/// The single user configured via `REST_API_USERNAME` / `REST_API_PASSWORD`
+pub struct StaticCredentials {
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl StaticCredentials {
+    pub fn new(username: Option<String>, password: Option<String>) -> Self {
+        Self { username, password }
+    }
+}
+
+impl AuthBackend for StaticCredentials {
+    fn verify(&self, credentials: &Credentials<'_>) -> Result<Identity, AuthError> {
+        let valid = self.username.as_deref() == Some(credentials.username)
+            && self.password.as_deref() == Some(credentials.password);
+        if valid {
+            Ok(Identity {
+                username: credentials.username.to_string(),
+            })
+        } else {
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+}
+
+/// Users stored in the `users` table with Argon2 password hashes
+pub struct DatabaseUsers {
+    db: Arc<DatabaseService>,
+}
+
+impl DatabaseUsers {
+    pub fn new(db: Arc<DatabaseService>) -> Self {
+        Self { db }
+    }
+
+    /// Create the configured static user when the table is still empty, so a fresh
+    /// installation can log in and manage users.
+    pub fn bootstrap(&self, username: Option<&str>, password: Option<&str>) -> Result<(), AuthError> {
+        let (Some(username), Some(password)) = (username, password) else {
+            return Ok(());
+        };
+        let existing = self.db.list_users().map_err(|e| AuthError::Backend(e.to_string()))?;
+        if existing.is_empty() {
+            self.create_user(username, password)?;
+            info!("Created initial API user '{}'.", username);
+        }
+        Ok(())
+    }
+
+    pub fn create_user(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let password_hash = hash_password(password)?;
+        self.db
+            .create_user(username, &password_hash)
+            .map_err(|e| AuthError::Backend(e.to_string()))
+    }
+}
+
+impl AuthBackend for DatabaseUsers {
+    fn verify(&self, credentials: &Credentials<'_>) -> Result<Identity, AuthError> {
+        let password_hash = self
+            .db
+            .get_password_hash(credentials.username)
+            .map_err(|e| AuthError::Backend(e.to_string()))?
+            .ok_or(AuthError::InvalidCredentials)?;
+        let parsed = PasswordHash::new(&password_hash).map_err(|e| AuthError::Backend(e.to_string()))?;
+
+        Argon2::default()
+            .verify_password(credentials.password.as_bytes(), &parsed)
+            .map_err(|_| AuthError::InvalidCredentials)?;
+        Ok(Identity {
+            username: credentials.username.to_string(),
+        })
+    }
+}
+
+/// Argon2id hash of `password` with a random salt, in PHC string format
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AuthError::Backend(e.to_string()))
+}
@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+
+use clap::{Arg, Command};
+
+use crate::config;
+
+/// Parse the command line into configuration overrides, keyed by variable name. Every
+/// variable in `config::VARIABLES` is accepted as `--<name in kebab case>`. Exits on
+/// `--help` or invalid arguments.
+pub fn parse_overrides() -> HashMap<String, String> {
+    let flags: Vec<(String, &str)> = config::VARIABLES
+        .iter()
+        .map(|(name, _)| (name.to_ascii_lowercase().replace('_', "-"), *name))
+        .collect();
+
+    let command = Command::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Stores MQTT messages and serves them via a REST API")
+        .after_help("Options take precedence over environment variables and .env entries of the same name.")
+        .args(config::VARIABLES.iter().zip(&flags).map(|((name, help), (flag, _))| {
+            Arg::new(*name)
+                .long(flag.clone())
+                .value_name("VALUE")
+                .help(*help)
+        }));
+
+    let matches = command.get_matches();
+    flags
+        .into_iter()
+        .filter_map(|(_, name)| {
+            matches
+                .get_one::<String>(name)
+                .map(|value| (name.to_string(), value.clone()))
+        })
+        .collect()
+}
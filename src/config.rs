@@ -1,7 +1,189 @@
 use dotenvy::dotenv;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
 use thiserror::Error;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::serialization::PublishFormat;
+use crate::srv;
+use crate::tls;
+use crate::topic_filter;
+
+/// Transport used to reach an MQTT broker.
+///
+/// For `ws`/`wss` the client connects to `ws[s]://<host>:<port><path>`, where the path
+/// comes from `*_MQTT_WS_PATH` (default `/mqtt`). `wss` requires the `*_MQTT_SSL_*`
+/// settings, while `tcp` with SSL enabled connects via plain MQTT over TLS.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    Tcp,
+    Ws,
+    Wss,
+}
+
+impl FromStr for MqttTransport {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(MqttTransport::Tcp),
+            "ws" => Ok(MqttTransport::Ws),
+            "wss" => Ok(MqttTransport::Wss),
+            other => Err(ConfigError::ParsingError(format!(
+                "Unknown MQTT transport '{}', expected tcp, ws or wss",
+                other
+            ))),
+        }
+    }
+}
+
+/// A broker address, `host:port` in the config.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct BrokerEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::fmt::Display for BrokerEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Connection settings of one broker, see `Config::internal_broker` and
+/// `Config::monitored_brokers`
+#[derive(Debug, Deserialize, Clone)]
+pub struct BrokerConfig {
+    /// Client id prefix of the broker's service and its name in logs
+    pub name: String,
+    /// Name in the `brokers` table that topics and subscriptions refer to: the host for
+    /// the internal and the primary monitored broker, as registered before several
+    /// brokers could be monitored, `name` for the others, so brokers on one host stay apart
+    pub registered_name: String,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub ssl_enabled: bool,
+    pub ssl_cert_path: Option<String>,
+    pub ssl_alpn: Vec<String>,
+    pub transport: MqttTransport,
+    pub ws_path: String,
+    pub failover_brokers: Vec<BrokerEndpoint>,
+    pub srv_name: Option<String>,
+    /// Client id used verbatim, `<name>_<uuid>` on every start when unset
+    pub client_id: Option<String>,
+}
+
+/// What to do when a broker is registered under a name that already exists with
+/// different connection details.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerConflictMode {
+    /// Keep the stored details (previous behavior)
+    Ignore,
+    /// Overwrite host, port, credentials and TLS flag with the requested ones
+    Update,
+}
+
+impl FromStr for BrokerConflictMode {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ignore" => Ok(BrokerConflictMode::Ignore),
+            "update" => Ok(BrokerConflictMode::Update),
+            other => Err(ConfigError::ParsingError(format!(
+                "Unknown broker conflict mode '{}', expected ignore or update",
+                other
+            ))),
+        }
+    }
+}
+
+/// How values moved out of the database are archived, one file per day.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// `YYYY-MM-DD.db`, a SQLite file indexed by topic and timestamp
+    Sqlite,
+    /// `YYYY-MM-DD.ndjson.gz`, gzipped NDJSON, smaller but scanned in full on queries
+    NdjsonGz,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sqlite" => Ok(ArchiveFormat::Sqlite),
+            "ndjson-gz" => Ok(ArchiveFormat::NdjsonGz),
+            other => Err(ConfigError::ParsingError(format!(
+                "Unknown archive format '{}', expected sqlite or ndjson-gz",
+                other
+            ))),
+        }
+    }
+}
+
+/// Where REST API credentials are verified.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackendKind {
+    /// The single user from REST_API_USERNAME / REST_API_PASSWORD
+    Static,
+    /// The `users` table, seeded with the static user while it is empty
+    Database,
+}
+
+impl FromStr for AuthBackendKind {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "static" => Ok(AuthBackendKind::Static),
+            "database" => Ok(AuthBackendKind::Database),
+            other => Err(ConfigError::ParsingError(format!(
+                "Unknown auth backend '{}', expected static or database",
+                other
+            ))),
+        }
+    }
+}
+
+/// Detail shown by the REST API root handler.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RootSummaryField {
+    Version,
+    /// Number of currently connected brokers
+    Brokers,
+    /// Number of known topics
+    Topics,
+    Uptime,
+}
+
+impl FromStr for RootSummaryField {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "version" => Ok(RootSummaryField::Version),
+            "brokers" => Ok(RootSummaryField::Brokers),
+            "topics" => Ok(RootSummaryField::Topics),
+            "uptime" => Ok(RootSummaryField::Uptime),
+            other => Err(ConfigError::ParsingError(format!(
+                "Unknown root summary field '{}', expected version, brokers, topics or uptime",
+                other
+            ))),
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -12,6 +194,16 @@ pub struct Config {
     pub monitored_mqtt_password: String,
     pub monitored_mqtt_ssl_enabled: bool,
     pub monitored_mqtt_ssl_cert_path: Option<String>,
+    pub monitored_mqtt_ssl_alpn: Vec<String>,
+    pub monitored_mqtt_transport: MqttTransport,
+    pub monitored_mqtt_ws_path: String,
+    /// Tried in order when the monitored broker keeps failing
+    pub monitored_mqtt_failover_brokers: Vec<BrokerEndpoint>,
+    /// SRV record the monitored broker is looked up by, set when MONITORED_MQTT_USE_SRV is
+    pub monitored_mqtt_srv_name: Option<String>,
+    pub monitored_mqtt_client_id: Option<String>,
+    /// Brokers monitored next to the one above, from MONITORED_BROKER_<n>_*
+    pub additional_monitored_brokers: Vec<BrokerConfig>,
 
     // Internal MQTT Configuration
     pub internal_mqtt_host: String,
@@ -20,30 +212,136 @@ pub struct Config {
     pub internal_mqtt_password: String,
     pub internal_mqtt_ssl_enabled: bool,
     pub internal_mqtt_ssl_cert_path: Option<String>,
+    pub internal_mqtt_ssl_alpn: Vec<String>,
+    pub internal_mqtt_transport: MqttTransport,
+    pub internal_mqtt_ws_path: String,
+    pub internal_mqtt_failover_brokers: Vec<BrokerEndpoint>,
+    pub internal_mqtt_srv_name: Option<String>,
+    pub internal_mqtt_client_id: Option<String>,
 
     // Shared MQTT Settings
     pub mqtt_max_retries: i32,
     pub mqtt_retry_interval_ms: u64,
+    pub mqtt_stable_connection_secs: u64,
+    /// Start every connection with a fresh session. Without, a broker resumes the session
+    /// of a stable client id (see `BrokerConfig::client_id`) with its subscriptions and
+    /// the messages queued while disconnected.
+    pub mqtt_clean_session: bool,
+    /// Interval of the keep-alive pings, a connection silent for 1.5 times as long is dropped
+    pub mqtt_keep_alive_secs: u16,
+    /// Consecutive failed connection attempts before switching to the next broker
+    pub mqtt_failover_after_attempts: u32,
+    /// How often the primary broker is checked while connected to a failover broker
+    pub mqtt_failback_check_secs: u64,
+    /// Nameserver for SRV lookups, the first one of /etc/resolv.conf when unset
+    pub mqtt_srv_nameserver: Option<SocketAddr>,
+    /// Subscription QoS of topics without their own in `topics.qos`
+    pub mqtt_default_qos: u8,
+    pub mqtt_subscribe_batch_size: usize,
+    /// Incoming payloads above this size are skipped
+    pub mqtt_max_payload_bytes: usize,
+    /// JSON payloads nesting objects and arrays deeper than this are skipped
+    pub mqtt_max_json_depth: usize,
+    pub mqtt_exclude_system_topics: bool,
+    /// Don't store the topics this service publishes to, see `own_topic_filters`
+    pub mqtt_exclude_own_topics: bool,
+    pub mqtt_exclude_topics: Vec<String>,
+    /// Filters the storing client subscribes to, the whole broker (`#`) when empty
+    pub mqtt_subscribe_topics: Vec<String>,
+    /// Subscribe to the active `subscriptions` rows of each broker instead
+    pub mqtt_subscriptions_from_db: bool,
+    /// Register unknown topics on their first message with the defaults below
+    pub mqtt_auto_register_topics: bool,
+    pub default_max_values: usize,
+    pub default_query_frequency_ms: u64,
+    pub broker_conflict_mode: BrokerConflictMode,
 
     // MQTT Topics
+    pub publish_serialization_format: PublishFormat,
+    pub status_message_expiry_secs: u32,
+    pub progress_message_expiry_secs: u32,
     pub log_topic: String,
     pub status_topic: String,
     pub command_topic: String,
     pub progress_topic: String,
     pub analytics_topic: String,
+    /// CONNECTION_STATE_TOPIC, connection state changes aren't published when unset
+    pub connection_topic: Option<String>,
+    /// MQTT_LWT_TOPIC as given, see `last_will_topic`
+    pub mqtt_lwt_topic: Option<String>,
+    /// Will message as is, an offline status message in the publish format when unset
+    pub mqtt_lwt_payload: Option<String>,
+    pub mqtt_lwt_qos: u8,
+    pub mqtt_lwt_retain: bool,
+    pub heartbeat_topic: String,
+    /// Seconds between two heartbeats with the resource usage, 0 = disabled
+    pub heartbeat_interval_secs: u64,
+    /// HTTP endpoints receiving status, progress and analytics messages as JSON POSTs
+    pub status_http_sink_url: Option<String>,
+    pub progress_http_sink_url: Option<String>,
+    pub analytics_http_sink_url: Option<String>,
+    pub http_sink_timeout_ms: u64,
+    /// Failed requests in a row after which a sink is paused for `http_sink_cooldown_secs`
+    pub http_sink_failure_threshold: u32,
+    pub http_sink_cooldown_secs: u64,
+
+    // Progress Tracking
+    pub progress_tracker_ttl_secs: u64,
+    pub progress_tracker_max_entries: usize,
+    pub progress_publish_interval_ms: u64,
+    pub progress_publish_step_percent: f64,
+
+    // Shutdown
+    pub shutdown_drain_secs: u64,
+
+    // Storage
+    pub trim_slack_percent: u32,
+    /// SQLite connections shared by the services, at least 1
+    pub db_pool_size: u32,
+    /// How long SQLite waits for a lock held by another connection
+    pub db_busy_timeout_ms: u64,
+    /// Seconds between two purges of values past their topic's retention
+    pub retention_interval_secs: u64,
+    /// Maximum number of registered topics, 0 for no limit
+    pub max_topics: usize,
+    /// At the limit, delete the least recently active topic instead of refusing new ones
+    pub max_topics_evict: bool,
+    /// Base64 encoded AES-256 key encrypting stored values, takes precedence over the file
+    pub value_encryption_key: Option<String>,
+    pub value_encryption_key_file: Option<String>,
+    /// Values received longer ago are moved to the archive, 0 = archiving disabled
+    pub archive_after_secs: u64,
+    pub archive_interval_secs: u64,
+    pub archive_dir: String,
+    pub archive_format: ArchiveFormat,
 
     // REST API Configuration
     pub rest_api_host: String,
     pub rest_api_port: u16,
+    pub rest_api_uds_path: Option<String>,
+    /// Path all routes are mounted under, `/` or a prefix without trailing slash
+    pub rest_api_base_path: String,
+    pub rest_api_tls_cert_path: Option<String>,
+    pub rest_api_tls_key_path: Option<String>,
     pub max_api_requests_per_minute: u32,
+    pub rest_api_max_response_rows: usize,
+    /// How long `POST /topics/<topic>/watch` waits for the first value
+    pub watch_timeout_ms: u64,
+    /// Concurrent `GET /debug/tail` sessions at most
+    pub debug_tail_max_sessions: usize,
+    pub rest_api_streaming_threshold_rows: usize,
     pub rest_api_auth_enabled: bool,
     pub rest_api_username: Option<String>,
     pub rest_api_password: Option<String>,
+    pub rest_api_auth_backend: AuthBackendKind,
+    /// Require a valid HS256 Bearer token on the routes taking `AuthToken`
     pub jwt_auth_enabled: bool,
     pub jwt_secret_key: Option<String>,
     pub jwt_expiration_minutes: u32,
     pub cors_enabled: bool,
     pub cors_allowed_origins: Vec<String>,
+    /// Details of the root handler, none for a bare status
+    pub root_summary_fields: Vec<RootSummaryField>,
 }
 
 #[derive(Debug, Error)]
@@ -70,96 +368,938 @@ impl Config {
         Ok(())
     }
 
+    /// Validate that no two services connect to the same broker with the same client id,
+    /// the broker would disconnect one whenever the other connects.
+    fn validate_client_ids(&self) -> Result<(), ConfigError> {
+        let mut brokers = vec![self.internal_broker()];
+        brokers.extend(self.monitored_brokers());
+
+        for (i, broker) in brokers.iter().enumerate() {
+            let Some(client_id) = &broker.client_id else {
+                continue;
+            };
+            if let Some(other) = brokers[..i]
+                .iter()
+                .find(|other| other.client_id.as_ref() == Some(client_id) && other.host == broker.host && other.port == broker.port)
+            {
+                return Err(ConfigError::ParsingError(format!(
+                    "Brokers '{}' and '{}' use the same client id '{}' on {}:{}",
+                    other.name, broker.name, client_id, broker.host, broker.port
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate that each broker's transport matches its TLS settings.
+    fn validate_transports(&self) -> Result<(), ConfigError> {
+        let mut brokers = vec![
+            ("MONITORED_MQTT".to_string(), self.monitored_broker()),
+            ("INTERNAL_MQTT".to_string(), self.internal_broker()),
+        ];
+        for (index, broker) in self.additional_monitored_brokers.iter().enumerate() {
+            brokers.push((format!("MONITORED_BROKER_{}", index + 1), broker.clone()));
+        }
+
+        for (prefix, broker) in brokers {
+            match broker.transport {
+                MqttTransport::Wss if !broker.ssl_enabled => {
+                    return Err(ConfigError::ParsingError(format!(
+                        "{}_TRANSPORT=wss requires {}_SSL_ENABLED=true",
+                        prefix, prefix
+                    )));
+                }
+                MqttTransport::Ws if broker.ssl_enabled => {
+                    return Err(ConfigError::ParsingError(format!(
+                        "{}_TRANSPORT=ws does not use TLS, use wss with {}_SSL_ENABLED=true",
+                        prefix, prefix
+                    )));
+                }
+                MqttTransport::Ws | MqttTransport::Wss if !broker.ws_path.starts_with('/') => {
+                    return Err(ConfigError::ParsingError(format!(
+                        "{}_WS_PATH must start with '/'",
+                        prefix
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the REST API TLS files: both or neither must be set, and they must load.
+    fn validate_rest_tls(&self) -> Result<(), ConfigError> {
+        match (&self.rest_api_tls_cert_path, &self.rest_api_tls_key_path) {
+            (Some(cert_path), Some(key_path)) => tls::load_identity(cert_path, key_path)
+                .map(|_| ())
+                .map_err(|e| ConfigError::ParsingError(e.to_string())),
+            (None, None) => Ok(()),
+            _ => Err(ConfigError::ParsingError(
+                "REST_API_TLS_CERT_PATH and REST_API_TLS_KEY_PATH must be set together".to_string(),
+            )),
+        }
+    }
+
+    /// The internal broker, from INTERNAL_MQTT_*
+    pub fn internal_broker(&self) -> BrokerConfig {
+        BrokerConfig {
+            name: "internal".to_string(),
+            registered_name: self.internal_mqtt_host.clone(),
+            host: self.internal_mqtt_host.clone(),
+            port: self.internal_mqtt_port,
+            username: self.internal_mqtt_username.clone(),
+            password: self.internal_mqtt_password.clone(),
+            ssl_enabled: self.internal_mqtt_ssl_enabled,
+            ssl_cert_path: self.internal_mqtt_ssl_cert_path.clone(),
+            ssl_alpn: self.internal_mqtt_ssl_alpn.clone(),
+            transport: self.internal_mqtt_transport,
+            ws_path: self.internal_mqtt_ws_path.clone(),
+            failover_brokers: self.internal_mqtt_failover_brokers.clone(),
+            srv_name: self.internal_mqtt_srv_name.clone(),
+            client_id: self.internal_mqtt_client_id.clone(),
+        }
+    }
+
+    /// The primary monitored broker, from MONITORED_MQTT_*
+    pub fn monitored_broker(&self) -> BrokerConfig {
+        BrokerConfig {
+            name: "monitored".to_string(),
+            registered_name: self.monitored_mqtt_host.clone(),
+            host: self.monitored_mqtt_host.clone(),
+            port: self.monitored_mqtt_port,
+            username: self.monitored_mqtt_username.clone(),
+            password: self.monitored_mqtt_password.clone(),
+            ssl_enabled: self.monitored_mqtt_ssl_enabled,
+            ssl_cert_path: self.monitored_mqtt_ssl_cert_path.clone(),
+            ssl_alpn: self.monitored_mqtt_ssl_alpn.clone(),
+            transport: self.monitored_mqtt_transport,
+            ws_path: self.monitored_mqtt_ws_path.clone(),
+            failover_brokers: self.monitored_mqtt_failover_brokers.clone(),
+            srv_name: self.monitored_mqtt_srv_name.clone(),
+            client_id: self.monitored_mqtt_client_id.clone(),
+        }
+    }
+
+    /// Topic under which the brokers publish the last will of a service when its connection
+    /// drops, and its online message on every connect, each service to `<topic>/<broker
+    /// name>`. The status topic in the publish format unless MQTT_LWT_TOPIC is set, `None`
+    /// when it is set empty.
+    pub fn last_will_topic(&self) -> Option<String> {
+        match &self.mqtt_lwt_topic {
+            Some(topic) if topic.is_empty() => None,
+            Some(topic) => Some(topic.clone()),
+            None => Some(self.publish_serialization_format.topic(&self.status_topic)),
+        }
+    }
+
+    /// Filters matching the topics this service publishes to: status, logs, progress,
+    /// analytics, heartbeat and connection state in every publish format, and the last
+    /// will topics
+    pub fn own_topic_filters(&self) -> Vec<String> {
+        let mut filters = Vec::new();
+        for topic in [
+            Some(&self.status_topic),
+            Some(&self.log_topic),
+            Some(&self.progress_topic),
+            Some(&self.analytics_topic),
+            Some(&self.heartbeat_topic),
+            self.connection_topic.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            // Formats other than JSON publish to a subtopic
+            filters.push(topic.clone());
+            filters.push(format!("{}/#", topic));
+        }
+        if let Some(topic) = self.last_will_topic() {
+            filters.push(format!("{}/#", topic));
+        }
+        filters
+    }
+
+    /// Every monitored broker, the primary one first
+    pub fn monitored_brokers(&self) -> Vec<BrokerConfig> {
+        let mut brokers = vec![self.monitored_broker()];
+        brokers.extend(self.additional_monitored_brokers.iter().cloned());
+        brokers
+    }
+
+    /// Validate that JWT authentication has a secret to verify tokens with.
+    fn validate_jwt(&self) -> Result<(), ConfigError> {
+        match &self.jwt_secret_key {
+            Some(secret) if !secret.is_empty() => Ok(()),
+            _ if self.jwt_auth_enabled => Err(ConfigError::ParsingError(
+                "JWT_AUTH_ENABLED=true requires JWT_SECRET_KEY".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
     pub fn from_env() -> Result<Self, ConfigError> {
         dotenv().ok();
 
-        let mqtt_root_topic = env::var("MQTT_ROOT_TOPIC").unwrap_or_else(|_| "MonitorFlux".to_string());
+        let mqtt_root_topic = interpolate_env(
+            "MQTT_ROOT_TOPIC",
+            &lookup("MQTT_ROOT_TOPIC").unwrap_or_else(|_| "MonitorFlux".to_string()),
+        )?;
 
         let config = Self {
             // Monitored MQTT Configuration
-            monitored_mqtt_host: env::var("MONITORED_MQTT_HOST")
+            monitored_mqtt_host: lookup("MONITORED_MQTT_HOST")
                 .map_err(|_| ConfigError::MissingOrInvalid("MONITORED_MQTT_HOST".to_string()))?,
-            monitored_mqtt_port: env::var("MONITORED_MQTT_PORT")
+            monitored_mqtt_port: lookup("MONITORED_MQTT_PORT")
                 .map_err(|_| ConfigError::MissingOrInvalid("MONITORED_MQTT_PORT".to_string()))?
                 .parse::<u16>()
                 .map_err(|_| ConfigError::ParsingError("MONITORED_MQTT_PORT must be a valid number".to_string()))?,
-            monitored_mqtt_username: env::var("MONITORED_MQTT_USERNAME").unwrap_or_default(),
-            monitored_mqtt_password: env::var("MONITORED_MQTT_PASSWORD").unwrap_or_default(),
-            monitored_mqtt_ssl_enabled: env::var("MONITORED_MQTT_SSL_ENABLED")
+            monitored_mqtt_username: lookup("MONITORED_MQTT_USERNAME").unwrap_or_default(),
+            monitored_mqtt_password: lookup("MONITORED_MQTT_PASSWORD").unwrap_or_default(),
+            monitored_mqtt_ssl_enabled: lookup("MONITORED_MQTT_SSL_ENABLED")
                 .unwrap_or_else(|_| "false".to_string())
                 .parse::<bool>()
                 .map_err(|_| ConfigError::ParsingError("MONITORED_MQTT_SSL_ENABLED must be a boolean".to_string()))?,
-            monitored_mqtt_ssl_cert_path: env::var("MONITORED_MQTT_SSL_CERT_PATH").ok(),
+            monitored_mqtt_ssl_cert_path: lookup("MONITORED_MQTT_SSL_CERT_PATH").ok(),
+            monitored_mqtt_ssl_alpn: parse_alpn("MONITORED_MQTT_SSL_ALPN")?,
+            monitored_mqtt_transport: lookup("MONITORED_MQTT_TRANSPORT")
+                .unwrap_or_else(|_| "tcp".to_string())
+                .parse::<MqttTransport>()?,
+            monitored_mqtt_ws_path: lookup("MONITORED_MQTT_WS_PATH").unwrap_or_else(|_| "/mqtt".to_string()),
+            monitored_mqtt_failover_brokers: parse_broker_endpoints("MONITORED_MQTT_FAILOVER_BROKERS")?,
+            monitored_mqtt_srv_name: parse_srv_name("MONITORED_MQTT_USE_SRV", "MONITORED_MQTT_SRV_NAME")?,
+            monitored_mqtt_client_id: parse_client_id("MONITORED_MQTT_CLIENT_ID"),
+            additional_monitored_brokers: parse_monitored_brokers()?,
 
             // Internal MQTT Configuration
-            internal_mqtt_host: env::var("INTERNAL_MQTT_HOST")
+            internal_mqtt_host: lookup("INTERNAL_MQTT_HOST")
                 .map_err(|_| ConfigError::MissingOrInvalid("INTERNAL_MQTT_HOST".to_string()))?,
-            internal_mqtt_port: env::var("INTERNAL_MQTT_PORT")
+            internal_mqtt_port: lookup("INTERNAL_MQTT_PORT")
                 .map_err(|_| ConfigError::MissingOrInvalid("INTERNAL_MQTT_PORT".to_string()))?
                 .parse::<u16>()
                 .map_err(|_| ConfigError::ParsingError("INTERNAL_MQTT_PORT must be a valid number".to_string()))?,
-            internal_mqtt_username: env::var("INTERNAL_MQTT_USERNAME").unwrap_or_default(),
-            internal_mqtt_password: env::var("INTERNAL_MQTT_PASSWORD").unwrap_or_default(),
-            internal_mqtt_ssl_enabled: env::var("INTERNAL_MQTT_SSL_ENABLED")
+            internal_mqtt_username: lookup("INTERNAL_MQTT_USERNAME").unwrap_or_default(),
+            internal_mqtt_password: lookup("INTERNAL_MQTT_PASSWORD").unwrap_or_default(),
+            internal_mqtt_ssl_enabled: lookup("INTERNAL_MQTT_SSL_ENABLED")
                 .unwrap_or_else(|_| "false".to_string())
                 .parse::<bool>()
                 .map_err(|_| ConfigError::ParsingError("INTERNAL_MQTT_SSL_ENABLED must be a boolean".to_string()))?,
-            internal_mqtt_ssl_cert_path: env::var("INTERNAL_MQTT_SSL_CERT_PATH").ok(),
+            internal_mqtt_ssl_cert_path: lookup("INTERNAL_MQTT_SSL_CERT_PATH").ok(),
+            internal_mqtt_ssl_alpn: parse_alpn("INTERNAL_MQTT_SSL_ALPN")?,
+            internal_mqtt_transport: lookup("INTERNAL_MQTT_TRANSPORT")
+                .unwrap_or_else(|_| "tcp".to_string())
+                .parse::<MqttTransport>()?,
+            internal_mqtt_ws_path: lookup("INTERNAL_MQTT_WS_PATH").unwrap_or_else(|_| "/mqtt".to_string()),
+            internal_mqtt_failover_brokers: parse_broker_endpoints("INTERNAL_MQTT_FAILOVER_BROKERS")?,
+            internal_mqtt_srv_name: parse_srv_name("INTERNAL_MQTT_USE_SRV", "INTERNAL_MQTT_SRV_NAME")?,
+            internal_mqtt_client_id: parse_client_id("INTERNAL_MQTT_CLIENT_ID"),
 
             // Shared MQTT Settings
-            mqtt_max_retries: env::var("MQTT_MAX_RETRIES")
+            mqtt_max_retries: lookup("MQTT_MAX_RETRIES")
                 .unwrap_or_else(|_| "-1".to_string())
                 .parse::<i32>()
                 .map_err(|_| ConfigError::ParsingError("MQTT_MAX_RETRIES must be an integer".to_string()))?,
-            mqtt_retry_interval_ms: env::var("MQTT_RETRY_INTERVAL_MS")
+            mqtt_retry_interval_ms: lookup("MQTT_RETRY_INTERVAL_MS")
                 .unwrap_or_else(|_| "5000".to_string())
                 .parse::<u64>()
                 .map_err(|_| ConfigError::ParsingError("MQTT_RETRY_INTERVAL_MS must be a valid number".to_string()))?,
+            mqtt_stable_connection_secs: lookup("MQTT_STABLE_CONNECTION_SECS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_STABLE_CONNECTION_SECS must be a valid number".to_string()))?,
+            mqtt_clean_session: lookup("MQTT_CLEAN_SESSION")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_CLEAN_SESSION must be a boolean".to_string()))?,
+            mqtt_keep_alive_secs: lookup("MQTT_KEEP_ALIVE_SECS")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse::<u16>()
+                .ok()
+                .filter(|secs| *secs >= 1)
+                .ok_or_else(|| ConfigError::ParsingError("MQTT_KEEP_ALIVE_SECS must be between 1 and 65535".to_string()))?,
+            mqtt_failover_after_attempts: lookup("MQTT_FAILOVER_AFTER_ATTEMPTS")
+                .unwrap_or_else(|_| "3".to_string())
+                .parse::<u32>()
+                .ok()
+                .filter(|attempts| *attempts > 0)
+                .ok_or_else(|| ConfigError::ParsingError("MQTT_FAILOVER_AFTER_ATTEMPTS must be a positive number".to_string()))?,
+            mqtt_failback_check_secs: lookup("MQTT_FAILBACK_CHECK_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse::<u64>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| ConfigError::ParsingError("MQTT_FAILBACK_CHECK_SECS must be a positive number".to_string()))?,
+            mqtt_srv_nameserver: match lookup("MQTT_SRV_NAMESERVER") {
+                Ok(address) if !address.is_empty() => Some(parse_nameserver(&address)?),
+                _ => None,
+            },
+            mqtt_default_qos: lookup("MQTT_DEFAULT_QOS")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse::<u8>()
+                .ok()
+                .filter(|qos| *qos <= 2)
+                .ok_or_else(|| ConfigError::ParsingError("MQTT_DEFAULT_QOS must be 0, 1 or 2".to_string()))?,
+            mqtt_subscribe_batch_size: lookup("MQTT_SUBSCRIBE_BATCH_SIZE")
+                .unwrap_or_else(|_| "100".to_string())
+                .parse::<usize>()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| ConfigError::ParsingError("MQTT_SUBSCRIBE_BATCH_SIZE must be a positive number".to_string()))?,
+            mqtt_max_payload_bytes: lookup("MQTT_MAX_PAYLOAD_BYTES")
+                .unwrap_or_else(|_| "262144".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_MAX_PAYLOAD_BYTES must be a valid number".to_string()))?,
+            mqtt_max_json_depth: lookup("MQTT_MAX_JSON_DEPTH")
+                .unwrap_or_else(|_| "32".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_MAX_JSON_DEPTH must be a valid number".to_string()))?,
+            mqtt_exclude_system_topics: lookup("MQTT_EXCLUDE_SYSTEM_TOPICS")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_EXCLUDE_SYSTEM_TOPICS must be a boolean".to_string()))?,
+            mqtt_exclude_own_topics: lookup("MQTT_EXCLUDE_OWN_TOPICS")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_EXCLUDE_OWN_TOPICS must be a boolean".to_string()))?,
+            mqtt_exclude_topics: parse_topic_filters("MQTT_EXCLUDE_TOPICS")?,
+            mqtt_subscribe_topics: parse_topic_filters("MQTT_SUBSCRIBE_TOPICS")?,
+            mqtt_subscriptions_from_db: lookup("MQTT_SUBSCRIPTIONS_FROM_DB")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_SUBSCRIPTIONS_FROM_DB must be a boolean".to_string()))?,
+            mqtt_auto_register_topics: lookup("MQTT_AUTO_REGISTER_TOPICS")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_AUTO_REGISTER_TOPICS must be a boolean".to_string()))?,
+            default_max_values: lookup("DEFAULT_MAX_VALUES")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse::<usize>()
+                .ok()
+                .filter(|max_values| *max_values > 0)
+                .ok_or_else(|| ConfigError::ParsingError("DEFAULT_MAX_VALUES must be a positive number".to_string()))?,
+            default_query_frequency_ms: lookup("DEFAULT_QUERY_FREQUENCY_MS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("DEFAULT_QUERY_FREQUENCY_MS must be a valid number".to_string()))?,
+            broker_conflict_mode: lookup("BROKER_CONFLICT_MODE")
+                .unwrap_or_else(|_| "ignore".to_string())
+                .parse::<BrokerConflictMode>()?,
 
             // MQTT Topics
+            publish_serialization_format: lookup("PUBLISH_SERIALIZATION_FORMAT")
+                .unwrap_or_else(|_| "json".to_string())
+                .parse::<PublishFormat>()?,
+            status_message_expiry_secs: lookup("STATUS_MESSAGE_EXPIRY_SECS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse::<u32>()
+                .map_err(|_| ConfigError::ParsingError("STATUS_MESSAGE_EXPIRY_SECS must be a valid number".to_string()))?,
+            progress_message_expiry_secs: lookup("PROGRESS_MESSAGE_EXPIRY_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse::<u32>()
+                .map_err(|_| ConfigError::ParsingError("PROGRESS_MESSAGE_EXPIRY_SECS must be a valid number".to_string()))?,
             log_topic: format!("{}/logs", mqtt_root_topic),
             status_topic: format!("{}/status", mqtt_root_topic),
             command_topic: format!("{}/commands", mqtt_root_topic),
             progress_topic: format!("{}/progress", mqtt_root_topic),
             analytics_topic: format!("{}/analytics", mqtt_root_topic),
+            connection_topic: match lookup("CONNECTION_STATE_TOPIC") {
+                Ok(topic) if !topic.is_empty() && !topic_filter::is_valid_topic(&topic) => {
+                    return Err(ConfigError::ParsingError(format!("CONNECTION_STATE_TOPIC is not a valid topic: '{}'", topic)));
+                }
+                Ok(topic) => Some(topic).filter(|topic| !topic.is_empty()),
+                Err(_) => None,
+            },
+            mqtt_lwt_topic: match lookup("MQTT_LWT_TOPIC") {
+                Ok(topic) if !topic.is_empty() && !topic_filter::is_valid_topic(&topic) => {
+                    return Err(ConfigError::ParsingError(format!("MQTT_LWT_TOPIC is not a valid topic: '{}'", topic)));
+                }
+                Ok(topic) => Some(topic),
+                Err(_) => None,
+            },
+            mqtt_lwt_payload: lookup("MQTT_LWT_PAYLOAD").ok(),
+            mqtt_lwt_qos: lookup("MQTT_LWT_QOS")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse::<u8>()
+                .ok()
+                .filter(|qos| *qos <= 2)
+                .ok_or_else(|| ConfigError::ParsingError("MQTT_LWT_QOS must be 0, 1 or 2".to_string()))?,
+            mqtt_lwt_retain: lookup("MQTT_LWT_RETAIN")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MQTT_LWT_RETAIN must be a boolean".to_string()))?,
+            heartbeat_topic: format!("{}/heartbeat", mqtt_root_topic),
+            heartbeat_interval_secs: lookup("HEARTBEAT_INTERVAL_SECS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("HEARTBEAT_INTERVAL_SECS must be a valid number".to_string()))?,
+            status_http_sink_url: lookup("STATUS_HTTP_SINK_URL").ok().filter(|url| !url.is_empty()),
+            progress_http_sink_url: lookup("PROGRESS_HTTP_SINK_URL").ok().filter(|url| !url.is_empty()),
+            analytics_http_sink_url: lookup("ANALYTICS_HTTP_SINK_URL").ok().filter(|url| !url.is_empty()),
+            http_sink_timeout_ms: lookup("HTTP_SINK_TIMEOUT_MS")
+                .unwrap_or_else(|_| "5000".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("HTTP_SINK_TIMEOUT_MS must be a valid number".to_string()))?,
+            http_sink_failure_threshold: lookup("HTTP_SINK_FAILURE_THRESHOLD")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse::<u32>()
+                .ok()
+                .filter(|threshold| *threshold > 0)
+                .ok_or_else(|| ConfigError::ParsingError("HTTP_SINK_FAILURE_THRESHOLD must be a positive number".to_string()))?,
+            http_sink_cooldown_secs: lookup("HTTP_SINK_COOLDOWN_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("HTTP_SINK_COOLDOWN_SECS must be a valid number".to_string()))?,
+
+            // Progress Tracking
+            progress_tracker_ttl_secs: lookup("PROGRESS_TRACKER_TTL_SECS")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("PROGRESS_TRACKER_TTL_SECS must be a valid number".to_string()))?,
+            progress_tracker_max_entries: lookup("PROGRESS_TRACKER_MAX_ENTRIES")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("PROGRESS_TRACKER_MAX_ENTRIES must be a valid number".to_string()))?,
+            progress_publish_interval_ms: lookup("PROGRESS_PUBLISH_INTERVAL_MS")
+                .unwrap_or_else(|_| "500".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("PROGRESS_PUBLISH_INTERVAL_MS must be a valid number".to_string()))?,
+            progress_publish_step_percent: lookup("PROGRESS_PUBLISH_STEP_PERCENT")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse::<f64>()
+                .ok()
+                .filter(|step| (0.0..=100.0).contains(step))
+                .ok_or_else(|| ConfigError::ParsingError("PROGRESS_PUBLISH_STEP_PERCENT must be between 0 and 100".to_string()))?,
+
+            // Shutdown
+            shutdown_drain_secs: lookup("SHUTDOWN_DRAIN_SECS")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("SHUTDOWN_DRAIN_SECS must be a valid number".to_string()))?,
+
+            // Storage
+            trim_slack_percent: lookup("TRIM_SLACK_PERCENT")
+                .unwrap_or_else(|_| "20".to_string())
+                .parse::<u32>()
+                .map_err(|_| ConfigError::ParsingError("TRIM_SLACK_PERCENT must be a valid number".to_string()))?,
+            db_pool_size: lookup("DB_POOL_SIZE")
+                .unwrap_or_else(|_| "8".to_string())
+                .parse::<u32>()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| ConfigError::ParsingError("DB_POOL_SIZE must be a positive number".to_string()))?,
+            db_busy_timeout_ms: lookup("DB_BUSY_TIMEOUT_MS")
+                .unwrap_or_else(|_| "5000".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("DB_BUSY_TIMEOUT_MS must be a valid number".to_string()))?,
+            retention_interval_secs: lookup("RETENTION_INTERVAL_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse::<u64>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| ConfigError::ParsingError("RETENTION_INTERVAL_SECS must be a positive number".to_string()))?,
+            max_topics: lookup("MAX_TOPICS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("MAX_TOPICS must be a valid number".to_string()))?,
+            max_topics_evict: lookup("MAX_TOPICS_EVICT")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError("MAX_TOPICS_EVICT must be a boolean".to_string()))?,
+            value_encryption_key: lookup("VALUE_ENCRYPTION_KEY").ok().filter(|key| !key.is_empty()),
+            value_encryption_key_file: lookup("VALUE_ENCRYPTION_KEY_FILE").ok().filter(|path| !path.is_empty()),
+            archive_after_secs: lookup("ARCHIVE_AFTER_SECS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("ARCHIVE_AFTER_SECS must be a valid number".to_string()))?,
+            archive_interval_secs: lookup("ARCHIVE_INTERVAL_SECS")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse::<u64>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| ConfigError::ParsingError("ARCHIVE_INTERVAL_SECS must be a positive number".to_string()))?,
+            archive_dir: lookup("ARCHIVE_DIR").unwrap_or_else(|_| "archive".to_string()),
+            archive_format: lookup("ARCHIVE_FORMAT")
+                .unwrap_or_else(|_| "sqlite".to_string())
+                .parse::<ArchiveFormat>()?,
 
             // REST API Configuration
-            rest_api_host: env::var("REST_API_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
-            rest_api_port: env::var("REST_API_PORT")
+            rest_api_host: lookup("REST_API_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
+            rest_api_port: lookup("REST_API_PORT")
                 .unwrap_or_else(|_| "8080".to_string())
                 .parse::<u16>()
                 .map_err(|_| ConfigError::ParsingError("REST_API_PORT must be a valid number".to_string()))?,
-            max_api_requests_per_minute: env::var("MAX_API_REQUESTS_PER_MINUTE")
+            rest_api_uds_path: lookup("REST_API_UDS_PATH").ok().filter(|path| !path.is_empty()),
+            rest_api_base_path: parse_base_path("REST_API_BASE_PATH")?,
+            rest_api_tls_cert_path: lookup("REST_API_TLS_CERT_PATH").ok().filter(|path| !path.is_empty()),
+            rest_api_tls_key_path: lookup("REST_API_TLS_KEY_PATH").ok().filter(|path| !path.is_empty()),
+            max_api_requests_per_minute: lookup("MAX_API_REQUESTS_PER_MINUTE")
                 .unwrap_or_else(|_| "100".to_string())
                 .parse::<u32>()
                 .map_err(|_| ConfigError::ParsingError("MAX_API_REQUESTS_PER_MINUTE must be a valid number".to_string()))?,
-            rest_api_auth_enabled: env::var("REST_API_AUTH_ENABLED")
+            rest_api_max_response_rows: lookup("REST_API_MAX_RESPONSE_ROWS")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("REST_API_MAX_RESPONSE_ROWS must be a valid number".to_string()))?,
+            watch_timeout_ms: lookup("WATCH_TIMEOUT_MS")
+                .unwrap_or_else(|_| "5000".to_string())
+                .parse::<u64>()
+                .map_err(|_| ConfigError::ParsingError("WATCH_TIMEOUT_MS must be a valid number".to_string()))?,
+            debug_tail_max_sessions: lookup("DEBUG_TAIL_MAX_SESSIONS")
+                .unwrap_or_else(|_| "4".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("DEBUG_TAIL_MAX_SESSIONS must be a valid number".to_string()))?,
+            rest_api_streaming_threshold_rows: lookup("REST_API_STREAMING_THRESHOLD_ROWS")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse::<usize>()
+                .map_err(|_| ConfigError::ParsingError("REST_API_STREAMING_THRESHOLD_ROWS must be a valid number".to_string()))?,
+            rest_api_auth_enabled: lookup("REST_API_AUTH_ENABLED")
                 .unwrap_or_else(|_| "true".to_string())
                 .parse::<bool>()
                 .map_err(|_| ConfigError::ParsingError("REST_API_AUTH_ENABLED must be a boolean".to_string()))?,
-            rest_api_username: env::var("REST_API_USERNAME").ok(),
-            rest_api_password: env::var("REST_API_PASSWORD").ok(),
-            jwt_auth_enabled: env::var("JWT_AUTH_ENABLED")
-                .unwrap_or_else(|_| "true".to_string())
+            rest_api_username: lookup("REST_API_USERNAME").ok(),
+            rest_api_password: lookup("REST_API_PASSWORD").ok(),
+            rest_api_auth_backend: lookup("REST_API_AUTH_BACKEND")
+                .unwrap_or_else(|_| "static".to_string())
+                .parse::<AuthBackendKind>()?,
+            jwt_auth_enabled: lookup("JWT_AUTH_ENABLED")
+                .unwrap_or_else(|_| "false".to_string())
                 .parse::<bool>()
                 .map_err(|_| ConfigError::ParsingError("JWT_AUTH_ENABLED must be a boolean".to_string()))?,
-            jwt_secret_key: env::var("JWT_SECRET_KEY").ok(),
-            jwt_expiration_minutes: env::var("JWT_EXPIRATION_MINUTES")
+            jwt_secret_key: lookup("JWT_SECRET_KEY").ok(),
+            jwt_expiration_minutes: lookup("JWT_EXPIRATION_MINUTES")
                 .unwrap_or_else(|_| "60".to_string())
                 .parse::<u32>()
                 .map_err(|_| ConfigError::ParsingError("JWT_EXPIRATION_MINUTES must be a valid number".to_string()))?,
-            cors_enabled: env::var("CORS_ENABLED")
+            cors_enabled: lookup("CORS_ENABLED")
                 .unwrap_or_else(|_| "true".to_string())
                 .parse::<bool>()
                 .map_err(|_| ConfigError::ParsingError("CORS_ENABLED must be a boolean".to_string()))?,
-            cors_allowed_origins: env::var("CORS_ALLOWED_ORIGINS")
+            cors_allowed_origins: lookup("CORS_ALLOWED_ORIGINS")
                 .unwrap_or_else(|_| "http://localhost".to_string())
                 .split(',')
                 .map(|s| s.trim().to_string())
                 .collect(),
+            root_summary_fields: lookup("ROOT_SUMMARY_FIELDS")
+                .unwrap_or_else(|_| "version,brokers,topics,uptime".to_string())
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<RootSummaryField>())
+                .collect::<Result<_, _>>()?,
         };
 
         config.validate_timeouts()?;
+        config.validate_transports()?;
+        config.validate_client_ids()?;
+        config.validate_rest_tls()?;
+        config.validate_jwt()?;
         Ok(config)
     }
 }
+
+/// Configuration variables with a short description, each of which can also be given on
+/// the command line as `--<name in kebab case>` (e.g. `--rest-api-port`).
+pub const VARIABLES: &[(&str, &str)] = &[
+    ("LOG_LEVEL", "Minimum log level: error, warn, info, debug or trace"),
+    ("MONITORED_MQTT_HOST", "Host of the monitored broker"),
+    ("MONITORED_MQTT_PORT", "Port of the monitored broker"),
+    ("MONITORED_MQTT_USERNAME", "Username for the monitored broker"),
+    ("MONITORED_MQTT_PASSWORD", "Password for the monitored broker"),
+    ("MONITORED_MQTT_SSL_ENABLED", "Connect to the monitored broker via TLS"),
+    ("MONITORED_MQTT_SSL_CERT_PATH", "CA certificate for the monitored broker"),
+    ("MONITORED_MQTT_SSL_ALPN", "Comma-separated ALPN protocols for the monitored broker"),
+    ("MONITORED_MQTT_TRANSPORT", "Transport to the monitored broker: tcp, ws or wss"),
+    ("MONITORED_MQTT_WS_PATH", "WebSocket path of the monitored broker"),
+    ("MONITORED_MQTT_FAILOVER_BROKERS", "Comma-separated host:port brokers to fail over to, in order"),
+    ("MONITORED_MQTT_USE_SRV", "Look the broker up by MONITORED_MQTT_SRV_NAME, falling back to host and port"),
+    ("MONITORED_MQTT_SRV_NAME", "SRV record of the broker, e.g. _mqtt._tcp.example.com"),
+    ("MONITORED_MQTT_CLIENT_ID", "Client id used verbatim, monitored_<uuid> on every start when unset"),
+    ("INTERNAL_MQTT_HOST", "Host of the internal broker"),
+    ("INTERNAL_MQTT_PORT", "Port of the internal broker"),
+    ("INTERNAL_MQTT_USERNAME", "Username for the internal broker"),
+    ("INTERNAL_MQTT_PASSWORD", "Password for the internal broker"),
+    ("INTERNAL_MQTT_SSL_ENABLED", "Connect to the internal broker via TLS"),
+    ("INTERNAL_MQTT_SSL_CERT_PATH", "CA certificate for the internal broker"),
+    ("INTERNAL_MQTT_SSL_ALPN", "Comma-separated ALPN protocols for the internal broker"),
+    ("INTERNAL_MQTT_TRANSPORT", "Transport to the internal broker: tcp, ws or wss"),
+    ("INTERNAL_MQTT_WS_PATH", "WebSocket path of the internal broker"),
+    ("INTERNAL_MQTT_FAILOVER_BROKERS", "Comma-separated host:port brokers to fail over to, in order"),
+    ("INTERNAL_MQTT_USE_SRV", "Look the broker up by INTERNAL_MQTT_SRV_NAME, falling back to host and port"),
+    ("INTERNAL_MQTT_SRV_NAME", "SRV record of the broker, e.g. _mqtt._tcp.example.com"),
+    ("INTERNAL_MQTT_CLIENT_ID", "Client id used verbatim, internal_<uuid> on every start when unset"),
+    ("MQTT_MAX_RETRIES", "Reconnect attempts before giving up, -1 for unlimited"),
+    ("MQTT_RETRY_INTERVAL_MS", "Initial reconnect interval in milliseconds"),
+    ("MQTT_STABLE_CONNECTION_SECS", "Connected time after which the reconnect backoff is reset"),
+    ("MQTT_CLEAN_SESSION", "Start fresh sessions; false resumes the session of a stable client id"),
+    ("MQTT_KEEP_ALIVE_SECS", "Keep-alive interval in seconds, at least 1"),
+    ("MQTT_FAILOVER_AFTER_ATTEMPTS", "Failed connection attempts before switching to the next broker"),
+    ("MQTT_FAILBACK_CHECK_SECS", "Interval for checking the primary broker while failed over"),
+    ("MQTT_SRV_NAMESERVER", "Nameserver ip[:port] for SRV lookups, defaults to /etc/resolv.conf"),
+    ("MQTT_DEFAULT_QOS", "Subscription QoS of topics without their own: 0, 1 or 2"),
+    ("MQTT_SUBSCRIBE_BATCH_SIZE", "Maximum number of topic filters per subscribe request"),
+    ("MQTT_MAX_PAYLOAD_BYTES", "Incoming payloads above this size in bytes are skipped"),
+    ("MQTT_MAX_JSON_DEPTH", "JSON payloads nesting deeper than this are skipped"),
+    ("MQTT_EXCLUDE_SYSTEM_TOPICS", "Don't store $-prefixed topics such as $SYS/#"),
+    ("MQTT_EXCLUDE_OWN_TOPICS", "Don't store the status, log, progress, analytics, heartbeat, connection and last will topics of this service"),
+    ("MQTT_EXCLUDE_TOPICS", "Comma-separated MQTT filters whose messages are not stored"),
+    ("MQTT_SUBSCRIBE_TOPICS", "Comma-separated MQTT filters to subscribe to and store, # when empty"),
+    ("MQTT_SUBSCRIPTIONS_FROM_DB", "Subscribe to the active subscriptions of each broker in the database instead, none when it has none"),
+    ("MQTT_AUTO_REGISTER_TOPICS", "Register unknown topics on their first message instead of dropping it"),
+    ("DEFAULT_MAX_VALUES", "max_values of automatically registered topics"),
+    ("DEFAULT_QUERY_FREQUENCY_MS", "query_frequency_ms of automatically registered topics"),
+    ("BROKER_CONFLICT_MODE", "Handling of a known broker name with other settings: ignore or update"),
+    ("MQTT_ROOT_TOPIC", "Root of the published log, status, command and progress topics"),
+    ("PUBLISH_SERIALIZATION_FORMAT", "Format of published messages: json, msgpack or cbor"),
+    ("STATUS_MESSAGE_EXPIRY_SECS", "MQTT v5 expiry of status messages, 0 for none; ignored on MQTT 3.1.1"),
+    ("PROGRESS_MESSAGE_EXPIRY_SECS", "MQTT v5 expiry of progress messages, 0 for none; ignored on MQTT 3.1.1"),
+    ("CONNECTION_STATE_TOPIC", "Topic connection state changes are published to (retained), none when unset or empty"),
+    ("MQTT_LWT_TOPIC", "Topic of the last will and the online message, one subtopic per broker; the status topic when unset, none when empty"),
+    ("MQTT_LWT_PAYLOAD", "Last will payload, an offline status message when unset"),
+    ("MQTT_LWT_QOS", "QoS of the last will and the online message: 0, 1 or 2"),
+    ("MQTT_LWT_RETAIN", "Retain the last will and the online message"),
+    ("HEARTBEAT_INTERVAL_SECS", "Publish resource usage to <root>/heartbeat every n seconds, 0 to disable"),
+    ("STATUS_HTTP_SINK_URL", "Also POST status messages as JSON to this URL"),
+    ("PROGRESS_HTTP_SINK_URL", "Also POST progress messages as JSON to this URL"),
+    ("ANALYTICS_HTTP_SINK_URL", "Also POST analytics events as JSON to this URL"),
+    ("HTTP_SINK_TIMEOUT_MS", "Timeout of a request to an HTTP sink"),
+    ("HTTP_SINK_FAILURE_THRESHOLD", "Failed requests in a row after which an HTTP sink is paused"),
+    ("HTTP_SINK_COOLDOWN_SECS", "How long a failing HTTP sink is paused"),
+    ("PROGRESS_TRACKER_TTL_SECS", "How long finished progress trackers are kept"),
+    ("PROGRESS_TRACKER_MAX_ENTRIES", "Maximum number of progress trackers"),
+    ("PROGRESS_PUBLISH_INTERVAL_MS", "Minimum time between published progress updates of a task"),
+    ("PROGRESS_PUBLISH_STEP_PERCENT", "Progress change published before the interval is up, 0 to disable"),
+    ("SHUTDOWN_DRAIN_SECS", "Time to store in-flight messages on shutdown"),
+    ("TRIM_SLACK_PERCENT", "Rows a topic may exceed max_values by before trimming, in percent"),
+    ("DB_POOL_SIZE", "Maximum number of open SQLite connections"),
+    ("DB_BUSY_TIMEOUT_MS", "How long SQLite waits for a lock held by another connection"),
+    ("RETENTION_INTERVAL_SECS", "Seconds between two purges of values past their topic's retention"),
+    ("MAX_TOPICS", "Maximum number of registered topics, 0 for no limit"),
+    ("MAX_TOPICS_EVICT", "At the topic limit, delete the least recently active topic instead of refusing new ones"),
+    ("VALUE_ENCRYPTION_KEY", "Base64 encoded 32 byte key encrypting stored values"),
+    ("VALUE_ENCRYPTION_KEY_FILE", "File holding the base64 encoded value encryption key"),
+    ("ARCHIVE_AFTER_SECS", "Move values received longer ago to the archive, 0 disables archiving"),
+    ("ARCHIVE_INTERVAL_SECS", "Seconds between two archiving runs"),
+    ("ARCHIVE_DIR", "Directory of the archive files"),
+    ("ARCHIVE_FORMAT", "Archive file format: sqlite or ndjson-gz"),
+    ("REST_API_HOST", "Address the REST API listens on"),
+    ("REST_API_PORT", "Port the REST API listens on"),
+    ("REST_API_UDS_PATH", "Serve the REST API on this Unix socket instead"),
+    ("REST_API_BASE_PATH", "Path prefix all REST routes are mounted under"),
+    ("REST_API_TLS_CERT_PATH", "Certificate chain for HTTPS"),
+    ("REST_API_TLS_KEY_PATH", "Private key for HTTPS"),
+    ("MAX_API_REQUESTS_PER_MINUTE", "Requests per client IP and minute the REST API answers, 0 for no limit"),
+    ("REST_API_MAX_RESPONSE_ROWS", "Maximum rows per response"),
+    ("WATCH_TIMEOUT_MS", "How long watching a topic waits for its first value"),
+    ("DEBUG_TAIL_MAX_SESSIONS", "Concurrent raw traffic tails at most"),
+    ("REST_API_STREAMING_THRESHOLD_ROWS", "Responses with more rows are streamed"),
+    ("REST_API_AUTH_ENABLED", "Require authentication for protected routes"),
+    ("REST_API_USERNAME", "Username of the static API user"),
+    ("REST_API_PASSWORD", "Password of the static API user"),
+    ("REST_API_AUTH_BACKEND", "Source of API users: static or database"),
+    ("JWT_AUTH_ENABLED", "Require a Bearer JWT for reading values and /action"),
+    ("JWT_SECRET_KEY", "HMAC secret the Bearer JWTs are verified with"),
+    ("JWT_EXPIRATION_MINUTES", "Lifetime of issued JWTs"),
+    ("CORS_ENABLED", "Send CORS headers"),
+    ("CORS_ALLOWED_ORIGINS", "Comma-separated origins allowed by CORS"),
+    ("ROOT_SUMMARY_FIELDS", "Details shown at /: version, brokers, topics, uptime, empty for none"),
+];
+
+static OVERRIDES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Set values taking precedence over the environment and `.env` (e.g. from the command
+/// line). Only the first call has an effect, it must happen before loading the config.
+pub fn set_overrides(overrides: HashMap<String, String>) {
+    let _ = OVERRIDES.set(overrides);
+}
+
+/// Value of a configuration variable: an override, else the environment, where `.env`
+/// entries only fill in variables that aren't set already.
+fn lookup(name: &str) -> Result<String, env::VarError> {
+    #[cfg(test)]
+    if let Some(value) = tests::VARS.with(|vars| vars.borrow().get(name).cloned()) {
+        return value.ok_or(env::VarError::NotPresent);
+    }
+    match OVERRIDES.get().and_then(|overrides| overrides.get(name)) {
+        Some(value) => Ok(value.clone()),
+        None => env::var(name),
+    }
+}
+
+/// Minimum log level from LOG_LEVEL, `info` by default. Read separately from `Config`
+/// because logging is set up before the configuration is loaded.
+pub fn log_level() -> Result<LevelFilter, ConfigError> {
+    dotenv().ok();
+    lookup("LOG_LEVEL")
+        .unwrap_or_else(|_| "info".to_string())
+        .parse::<LevelFilter>()
+        .map_err(|_| ConfigError::ParsingError("LOG_LEVEL must be error, warn, info, debug or trace".to_string()))
+}
+
+/// Parse a URL path prefix such as `/monitorflux/`, normalized to `/monitorflux`.
+fn parse_base_path(var: &str) -> Result<String, ConfigError> {
+    let value = lookup(var).unwrap_or_else(|_| "/".to_string());
+    if !value.starts_with('/') || value.contains(['?', '#', ' ']) || value.contains("//") {
+        return Err(ConfigError::ParsingError(format!(
+            "{} must be a path starting with '/', e.g. /monitorflux",
+            var
+        )));
+    }
+
+    let trimmed = value.trim_end_matches('/');
+    Ok(if trimmed.is_empty() { "/".to_string() } else { trimmed.to_string() })
+}
+
+/// Parse a comma-separated list of MQTT topic filters, rejecting malformed filters.
+fn parse_topic_filters(var: &str) -> Result<Vec<String>, ConfigError> {
+    let filters: Vec<String> = lookup(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| interpolate_env(var, s))
+        .collect::<Result<_, _>>()?;
+
+    match filters.iter().find(|filter| !topic_filter::is_valid(filter)) {
+        Some(invalid) => Err(ConfigError::ParsingError(format!(
+            "{} contains an invalid topic filter: '{}'",
+            var, invalid
+        ))),
+        None => Ok(filters),
+    }
+}
+
+/// Parse a comma-separated list of `host:port` broker endpoints, empty when unset.
+/// IPv6 hosts are written in brackets, e.g. `[::1]:1883`.
+fn parse_broker_endpoints(var: &str) -> Result<Vec<BrokerEndpoint>, ConfigError> {
+    lookup(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|endpoint| {
+            endpoint
+                .rsplit_once(':')
+                .and_then(|(host, port)| {
+                    Some(BrokerEndpoint {
+                        host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+                        port: port.parse().ok()?,
+                    })
+                })
+                .filter(|endpoint| !endpoint.host.is_empty())
+                .ok_or_else(|| {
+                    ConfigError::ParsingError(format!(
+                        "{} must be a comma-separated list of host:port, got '{}'",
+                        var, endpoint
+                    ))
+                })
+        })
+        .collect()
+}
+
+/// Brokers monitored next to the MONITORED_MQTT_* one, from MONITORED_BROKER_<n>_HOST and
+/// the like with `n` counting up from 1 until a HOST is missing. Settings other than the
+/// host default as for the primary broker, the port to 1883.
+fn parse_monitored_brokers() -> Result<Vec<BrokerConfig>, ConfigError> {
+    let mut brokers = Vec::new();
+    for index in 1.. {
+        let var = |setting: &str| format!("MONITORED_BROKER_{}_{}", index, setting);
+        let Ok(host) = lookup(&var("HOST")) else {
+            break;
+        };
+        brokers.push(BrokerConfig {
+            name: format!("monitored_{}", index),
+            registered_name: format!("monitored_{}", index),
+            host,
+            port: lookup(&var("PORT"))
+                .unwrap_or_else(|_| "1883".to_string())
+                .parse::<u16>()
+                .map_err(|_| ConfigError::ParsingError(format!("{} must be a valid number", var("PORT"))))?,
+            username: lookup(&var("USERNAME")).unwrap_or_default(),
+            password: lookup(&var("PASSWORD")).unwrap_or_default(),
+            ssl_enabled: lookup(&var("SSL_ENABLED"))
+                .unwrap_or_else(|_| "false".to_string())
+                .parse::<bool>()
+                .map_err(|_| ConfigError::ParsingError(format!("{} must be a boolean", var("SSL_ENABLED"))))?,
+            ssl_cert_path: lookup(&var("SSL_CERT_PATH")).ok(),
+            ssl_alpn: parse_alpn(&var("SSL_ALPN"))?,
+            transport: lookup(&var("TRANSPORT"))
+                .unwrap_or_else(|_| "tcp".to_string())
+                .parse::<MqttTransport>()?,
+            ws_path: lookup(&var("WS_PATH")).unwrap_or_else(|_| "/mqtt".to_string()),
+            failover_brokers: parse_broker_endpoints(&var("FAILOVER_BROKERS"))?,
+            srv_name: parse_srv_name(&var("USE_SRV"), &var("SRV_NAME"))?,
+            client_id: parse_client_id(&var("CLIENT_ID")),
+        });
+    }
+    Ok(brokers)
+}
+
+/// The client id in `var`, unset when empty
+fn parse_client_id(var: &str) -> Option<String> {
+    lookup(var).ok().filter(|client_id| !client_id.is_empty())
+}
+
+/// The SRV name in `name_var` when `use_var` is true, checked to be `_service._proto.domain`
+fn parse_srv_name(use_var: &str, name_var: &str) -> Result<Option<String>, ConfigError> {
+    let use_srv = lookup(use_var)
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .map_err(|_| ConfigError::ParsingError(format!("{} must be a boolean", use_var)))?;
+    if !use_srv {
+        return Ok(None);
+    }
+    match lookup(name_var) {
+        Ok(name) if srv::is_valid_srv_name(&name) => Ok(Some(name)),
+        Ok(name) => Err(ConfigError::ParsingError(format!(
+            "{} must be an SRV name like _mqtt._tcp.example.com, got '{}'",
+            name_var, name
+        ))),
+        Err(_) => Err(ConfigError::MissingOrInvalid(name_var.to_string())),
+    }
+}
+
+/// A nameserver address, port 53 unless given
+fn parse_nameserver(address: &str) -> Result<SocketAddr, ConfigError> {
+    address
+        .parse::<SocketAddr>()
+        .or_else(|_| address.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| {
+            ConfigError::ParsingError(format!(
+                "MQTT_SRV_NAMESERVER must be an IP address with optional port, got '{}'",
+                address
+            ))
+        })
+}
+
+/// Parse a comma-separated list of TLS ALPN protocols. Unset means no ALPN; a set value
+/// must name at least one protocol and no empty entries.
+fn parse_alpn(var: &str) -> Result<Vec<String>, ConfigError> {
+    let value = lookup(var).unwrap_or_default();
+    if value.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let protocols: Vec<String> = value.split(',').map(|s| s.trim().to_string()).collect();
+    if protocols.iter().any(|protocol| protocol.is_empty()) {
+        return Err(ConfigError::ParsingError(format!(
+            "{} must be a comma-separated list of non-empty protocols",
+            var
+        )));
+    }
+    Ok(protocols)
+}
+
+/// Replace `${NAME}` placeholders in the value of `var` with the environment variable
+/// `NAME`. A `$` that does not start a placeholder (e.g. `$SYS/#`) is kept literally.
+fn interpolate_env(var: &str, value: &str) -> Result<String, ConfigError> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder.find('}').ok_or_else(|| {
+            ConfigError::ParsingError(format!("{} contains an unterminated '${{' placeholder", var))
+        })?;
+        let name = &placeholder[..end];
+        let resolved = lookup(name).map_err(|_| {
+            ConfigError::ParsingError(format!(
+                "{} references undefined environment variable '{}'",
+                var, name
+            ))
+        })?;
+        result.push_str(&resolved);
+        rest = &placeholder[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// Values `lookup` returns on the current thread before anything else, `None`
+        /// for an unset variable
+        pub(super) static VARS: RefCell<HashMap<String, Option<String>>> = RefCell::new(HashMap::new());
+    }
+
+    /// The config loaded with `vars` set on top of the environment and `.env`, without
+    /// touching the process environment. A `None` value unsets the variable.
+    pub fn config_with(vars: &[(&str, Option<&str>)]) -> Result<Config, ConfigError> {
+        VARS.with(|cell| {
+            *cell.borrow_mut() = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+                .collect();
+        });
+        let config = Config::from_env();
+        VARS.with(|cell| cell.borrow_mut().clear());
+        config
+    }
+
+    /// The config loaded with `vars` set, see `config_with`
+    pub fn config(vars: &[(&str, &str)]) -> Config {
+        let vars: Vec<_> = vars.iter().map(|(name, value)| (*name, Some(*value))).collect();
+        config_with(&vars).expect("test configuration is valid")
+    }
+
+    #[test]
+    fn additional_monitored_brokers_are_numbered_from_one() {
+        let config = config(&[
+            ("MONITORED_BROKER_1_HOST", "broker-a"),
+            ("MONITORED_BROKER_1_PORT", "1884"),
+            ("MONITORED_BROKER_1_CLIENT_ID", "store-a"),
+            ("MONITORED_BROKER_2_HOST", "broker-b"),
+            ("MONITORED_BROKER_2_TRANSPORT", "ws"),
+        ]);
+        let brokers = &config.additional_monitored_brokers;
+        assert_eq!(brokers.len(), 2);
+        assert_eq!((brokers[0].name.as_str(), brokers[0].host.as_str(), brokers[0].port), ("monitored_1", "broker-a", 1884));
+        assert_eq!(brokers[0].client_id.as_deref(), Some("store-a"));
+        assert_eq!((brokers[1].name.as_str(), brokers[1].host.as_str(), brokers[1].port), ("monitored_2", "broker-b", 1883));
+        assert_eq!(brokers[1].transport, MqttTransport::Ws);
+        assert_eq!(brokers[1].client_id, None);
+        assert_eq!(config.monitored_brokers()[0].name, "monitored");
+
+        let error = config_with(&[("MONITORED_BROKER_1_HOST", Some("broker-a")), ("MONITORED_BROKER_1_PORT", Some("x"))]);
+        assert!(matches!(error, Err(ConfigError::ParsingError(message)) if message.contains("MONITORED_BROKER_1_PORT")));
+    }
+
+    #[test]
+    fn connection_state_topic_is_optional_and_validated() {
+        let config = config_with(&[("CONNECTION_STATE_TOPIC", None)]).unwrap();
+        assert_eq!(config.connection_topic, None);
+        let config = config_with(&[("CONNECTION_STATE_TOPIC", Some(""))]).unwrap();
+        assert_eq!(config.connection_topic, None);
+        let config = config_with(&[("CONNECTION_STATE_TOPIC", Some("plant/monitor/connection"))]).unwrap();
+        assert_eq!(config.connection_topic.as_deref(), Some("plant/monitor/connection"));
+
+        let error = config_with(&[("CONNECTION_STATE_TOPIC", Some("plant/+/connection"))]);
+        assert!(matches!(error, Err(ConfigError::ParsingError(message)) if message.contains("CONNECTION_STATE_TOPIC")));
+    }
+
+    #[test]
+    fn placeholders_resolve_against_the_environment() {
+        let config = config(&[
+            ("HOSTNAME", "edge-7"),
+            ("MQTT_ROOT_TOPIC", "image_uploader/${HOSTNAME}"),
+            ("MQTT_EXCLUDE_TOPICS", "lab/${HOSTNAME}/#, $SYS/#"),
+        ]);
+        assert_eq!(config.status_topic, "image_uploader/edge-7/status");
+        // A `$` that doesn't open a placeholder is kept
+        assert_eq!(config.mqtt_exclude_topics, ["lab/edge-7/#", "$SYS/#"]);
+    }
+
+    #[test]
+    fn undefined_and_unterminated_placeholders_are_rejected() {
+        let error = config_with(&[("MQTT_ROOT_TOPIC", Some("uploader/${MISSING_HOST}")), ("MISSING_HOST", None)]);
+        assert!(matches!(error, Err(ConfigError::ParsingError(message))
+            if message.contains("MQTT_ROOT_TOPIC") && message.contains("MISSING_HOST")));
+
+        let error = config_with(&[("MQTT_EXCLUDE_TOPICS", Some("lab/${HOSTNAME"))]);
+        assert!(matches!(error, Err(ConfigError::ParsingError(message)) if message.contains("unterminated")));
+    }
+}
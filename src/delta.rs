@@ -0,0 +1,41 @@
+use serde_json::{Map, Value};
+
+/// The delta turning the JSON object `old` into `new`, e.g.
+/// `{"set": {"temperature": 21.5}, "unset": ["error"]}`.
+///
+/// `set` holds the top-level fields that were added or changed, `unset` the ones that
+/// were removed. Nested objects are compared as a whole, so a change anywhere inside
+/// one stores the whole field again.
+pub fn diff(old: &Map<String, Value>, new: &Map<String, Value>) -> Value {
+    let set: Map<String, Value> = new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let unset: Vec<Value> = old
+        .keys()
+        .filter(|key| !new.contains_key(*key))
+        .map(|key| Value::String(key.clone()))
+        .collect();
+
+    serde_json::json!({ "set": set, "unset": unset })
+}
+
+/// Apply a delta produced by `diff` to `base`. Returns `false` if `delta` is malformed,
+/// leaving `base` untouched.
+pub fn apply(base: &mut Map<String, Value>, delta: &Value) -> bool {
+    let (Some(set), Some(unset)) = (
+        delta.get("set").and_then(Value::as_object),
+        delta.get("unset").and_then(Value::as_array),
+    ) else {
+        return false;
+    };
+
+    for key in unset.iter().filter_map(Value::as_str) {
+        base.remove(key);
+    }
+    for (key, value) in set {
+        base.insert(key.clone(), value.clone());
+    }
+    true
+}
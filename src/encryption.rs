@@ -0,0 +1,124 @@
+use std::fmt;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use thiserror::Error;
+
+const KEY_LEN: usize = 32;
+/// The GCM standard nonce size, a fresh random one per encryption
+pub const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Errors while loading the value encryption key or encrypting and decrypting values.
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("Failed to read encryption key file '{path}': {source}")]
+    ReadKey {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Encryption key must be {KEY_LEN} bytes, base64 encoded")]
+    InvalidKey,
+    #[error("Failed to encrypt value: {0}")]
+    Encrypt(openssl::error::ErrorStack),
+    #[error("Failed to decrypt value, the encryption key is wrong or the row is corrupted")]
+    Decrypt,
+}
+
+/// AES-256-GCM encryption of stored values. Each value gets its own random nonce, which
+/// is stored next to it; the authentication tag is appended to the ciphertext.
+#[derive(Clone)]
+pub struct ValueCipher {
+    key: [u8; KEY_LEN],
+}
+
+impl ValueCipher {
+    /// A cipher from a base64 encoded 32 byte key, e.g. from `openssl rand -base64 32`
+    pub fn from_base64(encoded: &str) -> Result<Self, EncryptionError> {
+        let key = BASE64.decode(encoded.trim()).map_err(|_| EncryptionError::InvalidKey)?;
+        Ok(Self {
+            key: key.try_into().map_err(|_| EncryptionError::InvalidKey)?,
+        })
+    }
+
+    /// A cipher from a file holding the base64 encoded key
+    pub fn from_key_file(path: &str) -> Result<Self, EncryptionError> {
+        let encoded = std::fs::read_to_string(path).map_err(|source| EncryptionError::ReadKey {
+            path: path.to_string(),
+            source,
+        })?;
+        Self::from_base64(&encoded)
+    }
+
+    /// Encrypts `plaintext`, returning the base64 encoded ciphertext and its nonce
+    pub fn encrypt(&self, plaintext: &str) -> Result<(String, Vec<u8>), EncryptionError> {
+        let mut nonce = vec![0; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(EncryptionError::Encrypt)?;
+        let mut tag = [0; TAG_LEN];
+        let mut ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(&nonce), &[], plaintext.as_bytes(), &mut tag)
+            .map_err(EncryptionError::Encrypt)?;
+        ciphertext.extend_from_slice(&tag);
+        Ok((BASE64.encode(ciphertext), nonce))
+    }
+
+    /// Decrypts a value produced by `encrypt`
+    pub fn decrypt(&self, ciphertext: &str, nonce: &[u8]) -> Result<String, EncryptionError> {
+        let data = BASE64.decode(ciphertext).map_err(|_| EncryptionError::Decrypt)?;
+        if data.len() < TAG_LEN || nonce.len() != NONCE_LEN {
+            return Err(EncryptionError::Decrypt);
+        }
+        let (data, tag) = data.split_at(data.len() - TAG_LEN);
+        let plaintext = decrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(nonce), &[], data, tag)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| EncryptionError::Decrypt)
+    }
+}
+
+// Keeps the key out of logs
+impl fmt::Debug for ValueCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueCipher").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(byte: u8) -> ValueCipher {
+        ValueCipher::from_base64(&BASE64.encode([byte; KEY_LEN])).unwrap()
+    }
+
+    #[test]
+    fn values_round_trip_with_a_fresh_nonce_each() {
+        let cipher = cipher(7);
+        let (first, first_nonce) = cipher.encrypt("21.5").unwrap();
+        let (second, second_nonce) = cipher.encrypt("21.5").unwrap();
+
+        assert_eq!(first_nonce.len(), NONCE_LEN);
+        assert_ne!(first_nonce, second_nonce);
+        assert_ne!(first, second);
+        assert_eq!(cipher.decrypt(&first, &first_nonce).unwrap(), "21.5");
+        assert_eq!(cipher.decrypt(&second, &second_nonce).unwrap(), "21.5");
+    }
+
+    #[test]
+    fn wrong_keys_and_tampered_rows_fail_to_decrypt() {
+        let (ciphertext, nonce) = cipher(7).encrypt("21.5").unwrap();
+
+        assert!(matches!(cipher(8).decrypt(&ciphertext, &nonce), Err(EncryptionError::Decrypt)));
+        let mut tampered = BASE64.decode(&ciphertext).unwrap();
+        tampered[0] ^= 1;
+        assert!(matches!(cipher(7).decrypt(&BASE64.encode(tampered), &nonce), Err(EncryptionError::Decrypt)));
+        assert!(matches!(cipher(7).decrypt(&ciphertext, &nonce[1..]), Err(EncryptionError::Decrypt)));
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        assert!(matches!(ValueCipher::from_base64("not base64!"), Err(EncryptionError::InvalidKey)));
+        assert!(matches!(ValueCipher::from_base64(&BASE64.encode([0; 16])), Err(EncryptionError::InvalidKey)));
+        assert!(ValueCipher::from_base64(&format!("{}\n", BASE64.encode([0; KEY_LEN]))).is_ok());
+    }
+}
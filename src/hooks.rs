@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::warn;
+use tokio::sync::mpsc;
+
+/// Events queued for the hooks at most, further ones are dropped until they catch up
+const HOOK_QUEUE_CAPACITY: usize = 1_024;
+
+/// Custom logic run on connection and message events of an `MqttService`, registered
+/// with `MqttService::with_hooks`. Every method defaults to doing nothing, so a hook only
+/// implements the events it cares about.
+///
+/// Hooks never run on the MQTT event loop: events are queued and handed to the hooks in
+/// order on a separate task. A slow hook delays the events after it, not message
+/// handling; events arriving while the queue is full are dropped. Hooks should still
+/// return quickly and spawn their own tasks for anything long running.
+pub trait EventHook: Send + Sync {
+    /// Connected to `broker` (`host:port`)
+    fn on_connect(&self, _broker: &str) {}
+
+    /// Lost the connection to `broker`, `cause` being the `DisconnectCause` name
+    fn on_disconnect(&self, _broker: &str, _cause: &str) {}
+
+    /// Received a message that passed the exclusion rules and payload validation
+    fn on_message(&self, _topic: &str, _payload: &str) {}
+
+    /// Gave up publishing to `topic` after all retries
+    fn on_publish_failure(&self, _topic: &str) {}
+}
+
+#[derive(Debug)]
+enum HookEvent {
+    Connect { broker: String },
+    Disconnect { broker: String, cause: &'static str },
+    Message { topic: String, payload: String },
+    PublishFailure { topic: String },
+}
+
+/// Queues events for the registered hooks and runs them on their own task
+pub struct HookDispatcher {
+    /// None without hooks, so no event is built or queued at all
+    sender: Option<mpsc::Sender<HookEvent>>,
+    dropped: AtomicU64,
+}
+
+impl HookDispatcher {
+    /// Spawns the task running `hooks`, nothing without any. Needs a Tokio runtime.
+    pub fn new(hooks: Vec<Arc<dyn EventHook>>) -> Self {
+        if hooks.is_empty() {
+            return Self {
+                sender: None,
+                dropped: AtomicU64::new(0),
+            };
+        }
+
+        let (sender, mut receiver) = mpsc::channel(HOOK_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                for hook in &hooks {
+                    match &event {
+                        HookEvent::Connect { broker } => hook.on_connect(broker),
+                        HookEvent::Disconnect { broker, cause } => hook.on_disconnect(broker, cause),
+                        HookEvent::Message { topic, payload } => hook.on_message(topic, payload),
+                        HookEvent::PublishFailure { topic } => hook.on_publish_failure(topic),
+                    }
+                }
+            }
+        });
+        Self {
+            sender: Some(sender),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of events dropped because the hooks fell behind
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn connect(&self, broker: &str) {
+        self.emit(|| HookEvent::Connect {
+            broker: broker.to_string(),
+        });
+    }
+
+    pub fn disconnect(&self, broker: &str, cause: &'static str) {
+        self.emit(|| HookEvent::Disconnect {
+            broker: broker.to_string(),
+            cause,
+        });
+    }
+
+    pub fn message(&self, topic: &str, payload: &str) {
+        self.emit(|| HookEvent::Message {
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+        });
+    }
+
+    pub fn publish_failure(&self, topic: &str) {
+        self.emit(|| HookEvent::PublishFailure {
+            topic: topic.to_string(),
+        });
+    }
+
+    fn emit(&self, event: impl FnOnce() -> HookEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(event()) {
+            // Warn once per thousand drops, a stuck hook would flood the log otherwise
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % 1_000 == 1 {
+                warn!("Event hooks are falling behind, {} events dropped so far.", dropped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct CountingHook(Arc<AtomicUsize>);
+
+    impl EventHook for CountingHook {
+        fn on_message(&self, _topic: &str, _payload: &str) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn events_beyond_the_queue_are_dropped_and_counted() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let dispatcher = HookDispatcher::new(vec![Arc::new(CountingHook(handled.clone()))]);
+
+        // The hook task can't run before this test yields, so the queue fills up
+        for _ in 0..HOOK_QUEUE_CAPACITY + 5 {
+            dispatcher.message("sensors/a", "1");
+        }
+        assert_eq!(dispatcher.dropped_count(), 5);
+
+        while handled.load(Ordering::Relaxed) < HOOK_QUEUE_CAPACITY {
+            tokio::task::yield_now().await;
+        }
+        dispatcher.message("sensors/a", "1");
+        assert_eq!(dispatcher.dropped_count(), 5);
+    }
+
+    #[test]
+    fn nothing_is_dropped_without_hooks() {
+        let dispatcher = HookDispatcher::new(Vec::new());
+        for _ in 0..HOOK_QUEUE_CAPACITY + 5 {
+            dispatcher.message("sensors/a", "1");
+        }
+        assert_eq!(dispatcher.dropped_count(), 0);
+    }
+}
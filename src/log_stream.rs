@@ -0,0 +1,96 @@
+use serde::Serialize;
+use std::fmt;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+/// A single application log line as sent to live log subscribers
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    #[serde(skip)]
+    pub severity: Level,
+}
+
+/// Fan-out of application log records to any number of live subscribers.
+///
+/// The underlying broadcast channel is bounded: subscribers that fall behind lose the
+/// oldest records instead of slowing down logging.
+#[derive(Clone)]
+pub struct LogStream {
+    sender: broadcast::Sender<LogRecord>,
+}
+
+impl LogStream {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LogRecord> {
+        self.sender.subscribe()
+    }
+
+    /// `tracing` layer publishing every event to this stream
+    pub fn layer(&self) -> LogStreamLayer {
+        LogStreamLayer {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+pub struct LogStreamLayer {
+    sender: broadcast::Sender<LogRecord>,
+}
+
+impl<S: Subscriber> Layer<S> for LogStreamLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // Nobody is listening, skip formatting entirely
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        // Events bridged from the `log` crate report their real origin via normalization
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let record = LogRecord {
+            timestamp: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            severity: *metadata.level(),
+        };
+        let _ = self.sender.send(record);
+    }
+}
+
+/// Collects the `message` field and appends any other fields as `key=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // Events bridged from the `log` crate carry their metadata as extra fields
+        if field.name().starts_with("log.") {
+            return;
+        }
+        if field.name() == "message" {
+            self.message.insert_str(0, &format!("{:?}", value));
+        } else {
+            self.message.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+}
@@ -1,30 +1,81 @@
+// Without the REST API, the queries, models and auth backends only it uses are unused
+#![cfg_attr(not(feature = "rest-api"), allow(dead_code))]
+
+mod archive;
+mod auth;
+mod cli;
 mod config;
 mod mqtt_service;
 mod progress_tracker;
 mod service_utils;
+mod replay;
+mod resource_usage;
+#[cfg(feature = "rest-api")]
 mod rest_server;
+mod serialization;
+mod sinks;
+mod srv;
 mod db;
+mod delta;
+mod encryption;
+mod hooks;
 mod models;
+mod payload;
+#[cfg(feature = "rest-api")]
+mod schema;
+mod log_stream;
+mod materialize;
+mod metrics;
+#[cfg(feature = "rest-api")]
+mod time_range;
+mod tls;
+mod topic_filter;
+#[cfg(feature = "rest-api")]
+mod unix_socket;
 
-use crate::config::Config;
+use crate::archive::Archive;
+use crate::config::{BrokerConfig, Config};
 use crate::db::DatabaseService;
-use crate::mqtt_service::{MqttConfig, MqttService};
+use crate::encryption::ValueCipher;
+use crate::log_stream::LogStream;
+use crate::models::TopicDefaults;
+use crate::mqtt_service::{LastWillConfig, MqttConfig, MqttService};
+use crate::payload::PayloadLimits;
 use crate::progress_tracker::SharedState;
-use crate::rest_server::run_rest_server;
+use crate::sinks::{HttpSinkSettings, HttpSinks};
+use crate::srv::SrvResolver;
+#[cfg(feature = "rest-api")]
+use crate::rest_server::{run_rest_server, Brokers};
 use crate::service_utils::{
-    handle_shutdown, periodic_status_update, publish_status, start_logging, start_mqtt_service,
+    periodic_status_update, publish_status, shutdown_signal, StatusPayload, start_aggregate_flush, start_archiver, start_heartbeat,
+    start_logging, start_mqtt_service, start_multiple_mqtt_services, start_progress_eviction, start_retention,
 };
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::RwLock;
 use tracing::{error, info};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+    // Command line options override the environment and .env
+    config::set_overrides(cli::parse_overrides());
+    let log_level = config::log_level();
+
+    // Initialize logging, mirrored into the live log stream of the REST API
+    let log_stream = LogStream::new(1024);
+    tracing_subscriber::registry()
+        .with(*log_level.as_ref().unwrap_or(&LevelFilter::INFO))
+        .with(tracing_subscriber::fmt::layer())
+        .with(log_stream.layer())
         .init();
+    if let Err(e) = log_level {
+        error!("Error loading configuration: {:?}", e);
+        return;
+    }
 
     // Load configuration
     let config = match Config::from_env() {
@@ -35,7 +86,37 @@ async fn main() {
         }
     };
 
-    let db_service = match DatabaseService::new("mqtt_storage.db") {
+    let value_cipher = match (&config.value_encryption_key, &config.value_encryption_key_file) {
+        (Some(key), _) => ValueCipher::from_base64(key).map(Some),
+        (None, Some(path)) => ValueCipher::from_key_file(path).map(Some),
+        (None, None) => Ok(None),
+    };
+    let value_cipher = match value_cipher {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            error!("Failed to load the value encryption key: {}", e);
+            return;
+        }
+    };
+
+    let archive = Arc::new(Archive::new(
+        &config.archive_dir,
+        config.archive_format,
+        value_cipher.clone(),
+    ));
+
+    let db_service = DatabaseService::new("mqtt_storage.db").and_then(|service| {
+        let service = service
+            .with_trim_slack_percent(config.trim_slack_percent)
+            .with_topic_limit(config.max_topics, config.max_topics_evict)
+            .with_pool_size(config.db_pool_size)?
+            .with_busy_timeout(Duration::from_millis(config.db_busy_timeout_ms))?;
+        match value_cipher {
+            Some(cipher) => service.with_value_cipher(cipher),
+            None => Ok(service),
+        }
+    });
+    let db_service = match db_service {
         Ok(service) => Arc::new(service),
         Err(e) => {
             error!("Failed to create database service: {:?}", e);
@@ -49,6 +130,11 @@ async fn main() {
     }
     info!("Database initialized successfully.");
 
+    if let Err(e) = db_service.check_value_encryption() {
+        error!("Cannot read encrypted values, check VALUE_ENCRYPTION_KEY: {}", e);
+        return;
+    }
+
     // Broker für internen MQTT-Service überprüfen
     if let Err(e) = db_service.validate_or_add_broker(
         &config.internal_mqtt_host,
@@ -57,64 +143,105 @@ async fn main() {
         Some(&config.internal_mqtt_username),
         Some(&config.internal_mqtt_password),
         config.internal_mqtt_ssl_enabled,
+        config.broker_conflict_mode,
     ) {
         error!("Failed to validate internal broker: {:?}", e);
         return;
     }
 
-    // Brok
+    // Topics are bound to the broker they are received from, so every monitored one is registered too
+    for broker in config.monitored_brokers() {
+        if let Err(e) = db_service.validate_or_add_broker(
+            &broker.registered_name,
+            &broker.host,
+            broker.port,
+            Some(&broker.username),
+            Some(&broker.password),
+            broker.ssl_enabled,
+            config.broker_conflict_mode,
+        ) {
+            error!("Failed to validate monitored broker '{}': {:?}", broker.name, e);
+            return;
+        }
+    }
 
     // Shared state for progress tracking
-    let state: SharedState = Arc::new(Mutex::new(HashMap::new()));
-
-    let mqtt_service_internal = MqttService::new(
+    let state: SharedState = Arc::new(RwLock::new(HashMap::new()));
+    start_progress_eviction(
         state.clone(),
-        MqttConfig {
-            mqtt_host: config.internal_mqtt_host.clone(),
-            mqtt_port: config.internal_mqtt_port,
-            mqtt_username: config.internal_mqtt_username.clone(),
-            mqtt_password: config.internal_mqtt_password.clone(),
-            mqtt_ssl_enabled: config.internal_mqtt_ssl_enabled,
-            mqtt_ssl_cert_path: config.internal_mqtt_ssl_cert_path.clone(),
-            log_topic: config.log_topic.clone(),
-            status_topic: config.status_topic.clone(),
-            command_topic: config.command_topic.clone(),
-            progress_topic: config.progress_topic.clone(),
-            analytics_topic: config.analytics_topic.clone(),
-            mqtt_max_retries: config.mqtt_max_retries,
-            mqtt_retry_interval_ms: config.mqtt_retry_interval_ms,
-        },
-        None, // Keine Datenbankoperationen für `mqtt_service_internal`
+        config.progress_tracker_ttl_secs,
+        config.progress_tracker_max_entries,
     );
 
-    let mqtt_service_monitored = MqttService::new(
-        state.clone(),
-        MqttConfig {
-            mqtt_host: config.monitored_mqtt_host.clone(),
-            mqtt_port: config.monitored_mqtt_port,
-            mqtt_username: config.monitored_mqtt_username.clone(),
-            mqtt_password: config.monitored_mqtt_password.clone(),
-            mqtt_ssl_enabled: config.monitored_mqtt_ssl_enabled,
-            mqtt_ssl_cert_path: config.monitored_mqtt_ssl_cert_path.clone(),
-            log_topic: config.log_topic.clone(),
-            status_topic: config.status_topic.clone(),
-            command_topic: config.command_topic.clone(),
-            progress_topic: config.progress_topic.clone(),
-            analytics_topic: config.analytics_topic.clone(),
-            mqtt_max_retries: config.mqtt_max_retries,
-            mqtt_retry_interval_ms: config.mqtt_retry_interval_ms,
+    // MQTT_DEFAULT_QOS is validated when loading the config
+    let default_qos = rumqttc::qos(config.mqtt_default_qos).unwrap_or(rumqttc::QoS::AtLeastOnce);
+    let payload_limits = PayloadLimits {
+        max_bytes: config.mqtt_max_payload_bytes,
+        max_json_depth: config.mqtt_max_json_depth,
+    };
+    let auto_register = config.mqtt_auto_register_topics.then_some(TopicDefaults {
+        max_values: config.default_max_values,
+        query_frequency_ms: config.default_query_frequency_ms,
+    });
+
+    let http_sinks = Arc::new(HttpSinks::new(
+        config.status_http_sink_url.clone(),
+        config.progress_http_sink_url.clone(),
+        config.analytics_http_sink_url.clone(),
+        HttpSinkSettings {
+            timeout: Duration::from_millis(config.http_sink_timeout_ms),
+            failure_threshold: config.http_sink_failure_threshold,
+            cooldown: Duration::from_secs(config.http_sink_cooldown_secs),
         },
-        Some(db_service.clone()), // Datenbankoperationen für `mqtt_service_monitored`
+    ));
+
+    let mqtt_service_internal = MqttService::new(
+        mqtt_config(&config, &config.internal_broker(), default_qos, payload_limits, auto_register, &http_sinks),
+        None, // Keine Datenbankoperationen für `mqtt_service_internal`
     );
 
+    // Ein Service pro überwachtem Broker, alle mit derselben Datenbank
+    let monitored_services: Vec<(Arc<MqttService>, String)> = config
+        .monitored_brokers()
+        .iter()
+        .map(|broker| {
+            let mqtt_service = MqttService::new(
+                mqtt_config(&config, broker, default_qos, payload_limits, auto_register, &http_sinks),
+                Some(db_service.clone()),
+            );
+            (mqtt_service, broker.name.clone())
+        })
+        .collect();
+    // The primary monitored broker is the one the REST API and heartbeat report on
+    let mqtt_service_monitored = monitored_services[0].0.clone();
 
-    // Start both MQTT services
+    // Start the MQTT services
     start_mqtt_service(mqtt_service_internal.clone(), "internal");
-    start_mqtt_service(mqtt_service_monitored.clone(), "monitored");
+    start_multiple_mqtt_services(
+        monitored_services
+            .iter()
+            .map(|(mqtt_service, name)| (mqtt_service.clone(), name.as_str()))
+            .collect(),
+    );
 
-    // Start periodic status updates for both services
+    // Monitored services get their periodic status updates from `start_multiple_mqtt_services`
     start_logging(mqtt_service_internal.clone(), "Service is starting...".to_string());
     periodic_status_update(mqtt_service_internal.clone(), "internal");
+    start_aggregate_flush(db_service.clone());
+    start_retention(db_service.clone(), config.retention_interval_secs);
+    start_archiver(
+        db_service.clone(),
+        archive.clone(),
+        config.archive_after_secs,
+        config.archive_interval_secs,
+    );
+    start_heartbeat(
+        mqtt_service_internal.clone(),
+        mqtt_service_monitored.clone(),
+        db_service.clone(),
+        config.heartbeat_topic.clone(),
+        config.heartbeat_interval_secs,
+    );
 
     // Publish startup status for both services
     publish_status(
@@ -123,36 +250,178 @@ async fn main() {
         Some("Internal MQTT service started successfully.".to_string()),
     );
 
-    publish_status(
-        mqtt_service_monitored.clone(),
-        "running".to_string(),
-        Some("Monitored MQTT service started successfully.".to_string()),
-    );
+    for (mqtt_service, name) in &monitored_services {
+        publish_status(
+            mqtt_service.clone(),
+            "running".to_string(),
+            Some(format!("Monitored MQTT service '{}' started successfully.", name)),
+        );
+    }
+
+    let shutdown_db = db_service.clone();
 
     // Start REST API server
-    let config_for_rest_api = (*config).clone();
-    let rest_api_task = tokio::spawn(async move {
-        run_rest_server(db_service, config_for_rest_api).await;
-    });
+    #[cfg(feature = "rest-api")]
+    let rest_api_task = {
+        let config_for_rest_api = (*config).clone();
+        let rest_api_state = state.clone();
+        let rest_api_mqtt_service = mqtt_service_internal.clone();
+        let brokers = Brokers {
+            internal: mqtt_service_internal.clone(),
+            monitored: mqtt_service_monitored.clone(),
+            additional_monitored: monitored_services[1..]
+                .iter()
+                .map(|(mqtt_service, _)| mqtt_service.clone())
+                .collect(),
+        };
+        tokio::spawn(async move {
+            run_rest_server(
+                db_service,
+                config_for_rest_api,
+                log_stream,
+                rest_api_state,
+                rest_api_mqtt_service,
+                brokers,
+                archive,
+            )
+            .await;
+        })
+    };
 
-    // Handle shutdown for both MQTT services
-    handle_shutdown(mqtt_service_internal.clone(), "internal").await;
-    handle_shutdown(mqtt_service_monitored.clone(), "monitored").await;
+    // Shut every MQTT service down on the first Ctrl+C or SIGTERM
+    match shutdown_signal().await {
+        Ok(()) => info!("Shutting down..."),
+        Err(e) => error!("Failed to handle termination signal, shutting down: {:?}", e),
+    }
 
-    // Publish shutdown status for both services
+    // Publish shutdown status for all services
     publish_status(
         mqtt_service_internal.clone(),
         "shutdown".to_string(),
         Some("Internal MQTT service is shutting down.".to_string()),
     );
 
-    publish_status(
-        mqtt_service_monitored.clone(),
-        "shutdown".to_string(),
-        Some("Monitored MQTT service is shutting down.".to_string()),
-    );
+    for (mqtt_service, name) in &monitored_services {
+        publish_status(
+            mqtt_service.clone(),
+            "shutdown".to_string(),
+            Some(format!("Monitored MQTT service '{}' is shutting down.", name)),
+        );
+    }
+
+    // Let in-flight messages finish storing before disconnecting
+    let drain_timeout = Duration::from_secs(config.shutdown_drain_secs);
+    let all_services = std::iter::once((&mqtt_service_internal, "internal"))
+        .chain(monitored_services.iter().map(|(mqtt_service, name)| (mqtt_service, name.as_str())));
+    for (mqtt_service, client_name) in all_services {
+        let (drained, dropped) = mqtt_service.drain(drain_timeout).await;
+        info!("[{}] Drained {} messages, dropped {}.", client_name, drained, dropped);
+    }
+    // Values of the open pre-aggregation windows would be lost otherwise
+    match shutdown_db.flush_aggregate_windows(true) {
+        Ok(flushed) => info!("Stored {} open pre-aggregation windows.", flushed),
+        Err(e) => error!("Failed to store open pre-aggregation windows: {:?}", e),
+    }
 
     // Wait for tasks to complete
+    #[cfg(feature = "rest-api")]
     let _ = tokio::join!(rest_api_task);
     info!("All services shut down successfully.");
 }
+
+/// MQTT_EXCLUDE_TOPICS, with the topics this service publishes to unless
+/// MQTT_EXCLUDE_OWN_TOPICS is disabled, so a `#` subscription doesn't store them
+fn exclude_topics(config: &Config) -> Vec<String> {
+    let mut filters = config.mqtt_exclude_topics.clone();
+    if config.mqtt_exclude_own_topics {
+        filters.extend(config.own_topic_filters());
+    }
+    filters
+}
+
+/// Last will from MQTT_LWT_*, by default an offline status message followed by an online
+/// one on every connect. Published to a subtopic named after `broker`, so the services
+/// don't overwrite each other's state.
+fn last_will(config: &Config, broker: &BrokerConfig) -> Option<LastWillConfig> {
+    let topic = format!("{}/{}", config.last_will_topic()?, broker.name);
+    let status = |status: &str| {
+        config.publish_serialization_format.encode(&StatusPayload {
+            status: status.to_string(),
+            details: None,
+            message: None,
+        })
+    };
+    let (payload, birth_payload) = match (&config.mqtt_lwt_payload, status("offline"), status("online")) {
+        (Some(payload), _, Ok(birth_payload)) => (payload.clone().into_bytes(), birth_payload),
+        (None, Ok(payload), Ok(birth_payload)) => (payload, birth_payload),
+        (_, Err(e), _) | (_, _, Err(e)) => {
+            error!("Failed to serialize the last will, connecting without: {}", e);
+            return None;
+        }
+    };
+    Some(LastWillConfig {
+        topic,
+        payload,
+        birth_payload,
+        qos: rumqttc::qos(config.mqtt_lwt_qos).unwrap_or(rumqttc::QoS::AtLeastOnce),
+        retain: config.mqtt_lwt_retain,
+    })
+}
+
+/// Settings of the MQTT service connecting to `broker`, everything but the connection
+/// shared by all services
+fn mqtt_config(
+    config: &Config,
+    broker: &BrokerConfig,
+    default_qos: rumqttc::QoS,
+    payload_limits: PayloadLimits,
+    auto_register: Option<TopicDefaults>,
+    http_sinks: &Arc<HttpSinks>,
+) -> MqttConfig {
+    MqttConfig {
+        broker_name: broker.registered_name.clone(),
+        mqtt_host: broker.host.clone(),
+        mqtt_port: broker.port,
+        mqtt_username: broker.username.clone(),
+        mqtt_password: broker.password.clone(),
+        mqtt_ssl_enabled: broker.ssl_enabled,
+        mqtt_ssl_cert_path: broker.ssl_cert_path.clone(),
+        mqtt_ssl_alpn: broker.ssl_alpn.clone(),
+        mqtt_transport: broker.transport,
+        mqtt_ws_path: broker.ws_path.clone(),
+        client_id: broker.client_id.clone(),
+        clean_session: config.mqtt_clean_session,
+        keep_alive_secs: config.mqtt_keep_alive_secs,
+        last_will: last_will(config, broker),
+        failover_brokers: broker.failover_brokers.clone(),
+        log_topic: config.log_topic.clone(),
+        status_topic: config.status_topic.clone(),
+        command_topic: config.command_topic.clone(),
+        progress_topic: config.progress_topic.clone(),
+        analytics_topic: config.analytics_topic.clone(),
+        connection_topic: config.connection_topic.clone(),
+        mqtt_max_retries: config.mqtt_max_retries,
+        mqtt_retry_interval_ms: config.mqtt_retry_interval_ms,
+        stable_connection_secs: config.mqtt_stable_connection_secs,
+        failover_after_attempts: config.mqtt_failover_after_attempts,
+        failback_check_secs: config.mqtt_failback_check_secs,
+        srv: broker
+            .srv_name
+            .clone()
+            .map(|name| SrvResolver::new(name, config.mqtt_srv_nameserver)),
+        publish_format: config.publish_serialization_format,
+        subscribe_batch_size: config.mqtt_subscribe_batch_size,
+        default_qos,
+        status_expiry_secs: config.status_message_expiry_secs,
+        progress_expiry_secs: config.progress_message_expiry_secs,
+        progress_publish_interval_ms: config.progress_publish_interval_ms,
+        progress_publish_step_percent: config.progress_publish_step_percent,
+        exclude_system_topics: config.mqtt_exclude_system_topics,
+        exclude_topics: exclude_topics(config),
+        subscribe_topics: config.mqtt_subscribe_topics.clone(),
+        subscriptions_from_db: config.mqtt_subscriptions_from_db,
+        auto_register,
+        payload_limits,
+        http_sinks: http_sinks.clone(),
+    }
+}
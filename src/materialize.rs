@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+
+use log::{debug, info};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Columns every materialized table has, not available for fields
+const RESERVED_COLUMNS: &[&str] = &["value_id", "timestamp"];
+
+/// Type of a materialized column, inferred from the first non-null value of its field
+/// and kept from then on. Stored as the declared type of the SQLite column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    /// Any JSON number, integers included so a field sending `21` before `21.5` fits
+    Real,
+    /// JSON `true`/`false`, stored as 1/0
+    Boolean,
+    /// Strings as they are, objects and arrays as JSON
+    Text,
+}
+
+impl ColumnType {
+    fn infer(value: &Value) -> ColumnType {
+        match value {
+            Value::Number(_) => ColumnType::Real,
+            Value::Bool(_) => ColumnType::Boolean,
+            _ => ColumnType::Text,
+        }
+    }
+
+    fn as_sql(self) -> &'static str {
+        match self {
+            ColumnType::Real => "REAL",
+            ColumnType::Boolean => "BOOLEAN",
+            ColumnType::Text => "TEXT",
+        }
+    }
+
+    fn from_sql(declared: &str) -> ColumnType {
+        match declared {
+            "REAL" => ColumnType::Real,
+            "BOOLEAN" => ColumnType::Boolean,
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// `value` as stored in a column of this type, `None` if it doesn't fit
+    fn convert(self, value: &Value) -> Option<SqlValue> {
+        match (self, value) {
+            (ColumnType::Real, Value::Number(number)) => number.as_f64().map(SqlValue::Real),
+            (ColumnType::Boolean, Value::Bool(flag)) => Some(SqlValue::Integer(*flag as i64)),
+            (ColumnType::Text, Value::String(text)) => Some(SqlValue::Text(text.clone())),
+            (ColumnType::Text, value) => Some(SqlValue::Text(value.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// A row of a materialized table, column name to typed value
+pub type MaterializedRow = Map<String, Value>;
+
+/// A column of a materialized table
+#[derive(Debug, Clone, Serialize)]
+pub struct MaterializedColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+/// Whether `name` can be used as the column of a field: an SQL identifier of letters,
+/// digits and underscores, not starting with a digit and not one of RESERVED_COLUMNS
+pub fn is_valid_column(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && !RESERVED_COLUMNS.iter().any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+fn table_name(topic_id: i64) -> String {
+    format!("materialized_{}", topic_id)
+}
+
+/// Columns of the materialized table of a topic after `value_id` and `timestamp`, in the
+/// order they were added. `None` if the topic has no table yet.
+pub fn columns(conn: &Connection, topic_id: i64) -> Result<Option<Vec<MaterializedColumn>>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table_name(topic_id)))?;
+    let columns: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_>>()?;
+    if columns.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        columns
+            .into_iter()
+            .filter(|(name, _)| !RESERVED_COLUMNS.contains(&name.as_str()))
+            .map(|(name, declared)| MaterializedColumn {
+                name,
+                column_type: ColumnType::from_sql(&declared),
+            })
+            .collect(),
+    ))
+}
+
+/// Copies the fields of `payload` mapped in `mapping` (field to column) into the
+/// materialized table of the topic, as the row of the stored value `value_id`.
+///
+/// The table is created with the first value, a column with the first non-null value of
+/// its field, typed after it (see `ColumnType`). A value that doesn't fit the type of its
+/// column, a missing field and `null` are stored as NULL. Payloads that are not JSON
+/// objects store no row.
+pub fn store(
+    conn: &Connection,
+    topic_id: i64,
+    value_id: i64,
+    timestamp: &str,
+    mapping: &BTreeMap<String, String>,
+    payload: &str,
+) -> Result<()> {
+    let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(payload) else {
+        return Ok(());
+    };
+    let table = table_name(topic_id);
+    let mut existing = match columns(conn, topic_id)? {
+        Some(existing) => existing,
+        None => {
+            create_table(conn, topic_id)?;
+            Vec::new()
+        }
+    };
+
+    let mut names = vec!["value_id".to_string(), "timestamp".to_string()];
+    let mut values = vec![SqlValue::Integer(value_id), SqlValue::Text(timestamp.to_string())];
+    for (field, column) in mapping {
+        let Some(value) = fields.get(field).filter(|value| !value.is_null()) else {
+            continue;
+        };
+        let column_type = match existing.iter().find(|existing| existing.name.eq_ignore_ascii_case(column)) {
+            Some(existing) => existing.column_type,
+            None => {
+                let column_type = ColumnType::infer(value);
+                info!("Adding {} column '{}' to materialized table '{}'.", column_type.as_sql(), column, table);
+                conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN \"{}\" {}", table, column, column_type.as_sql()))?;
+                existing.push(MaterializedColumn {
+                    name: column.clone(),
+                    column_type,
+                });
+                column_type
+            }
+        };
+        match column_type.convert(value) {
+            Some(value) => {
+                names.push(format!("\"{}\"", column));
+                values.push(value);
+            }
+            None => debug!("Field '{}' doesn't fit {} column '{}', storing NULL.", field, column_type.as_sql(), column),
+        }
+    }
+
+    let placeholders = vec!["?"; values.len()].join(", ");
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO {} ({}) VALUES ({})", table, names.join(", "), placeholders),
+        params_from_iter(values),
+    )?;
+    Ok(())
+}
+
+/// Creates the materialized table of a topic. Its rows follow the stored values: a
+/// trigger deletes them together with their `topic_values` row, whether trimmed,
+/// archived or evicted.
+fn create_table(conn: &Connection, topic_id: i64) -> Result<()> {
+    let table = table_name(topic_id);
+    info!("Creating materialized table '{}'.", table);
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            value_id INTEGER PRIMARY KEY,
+            timestamp DATETIME NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_{table}_timestamp ON {table} (timestamp);
+        CREATE TRIGGER IF NOT EXISTS trg_{table}_delete
+        AFTER DELETE ON topic_values
+        WHEN OLD.topic_id = {topic_id}
+        BEGIN
+            DELETE FROM {table} WHERE value_id = OLD.id;
+        END;",
+    ))
+}
+
+/// Drops the materialized table of a topic and its trigger, if any
+pub fn drop_table(conn: &Connection, topic_id: i64) -> Result<()> {
+    let table = table_name(topic_id);
+    conn.execute_batch(&format!(
+        "DROP TRIGGER IF EXISTS trg_{table}_delete;
+        DROP TABLE IF EXISTS {table};",
+    ))
+}
+
+/// Up to `limit` rows of the materialized table of a topic with a timestamp in
+/// `[from, to]`, oldest first, as JSON objects with `value_id`, `timestamp` and every
+/// column typed after its `ColumnType`. `None` if the topic has no table.
+pub fn rows(
+    conn: &Connection,
+    topic_id: i64,
+    from: &str,
+    to: &str,
+    limit: usize,
+) -> Result<Option<(Vec<MaterializedColumn>, Vec<MaterializedRow>)>> {
+    let Some(columns) = columns(conn, topic_id)? else {
+        return Ok(None);
+    };
+    let selected: Vec<String> = columns.iter().map(|column| format!(", \"{}\"", column.name)).collect();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT value_id, timestamp{} FROM {}
+         WHERE timestamp BETWEEN ?1 AND ?2
+         ORDER BY timestamp, value_id
+         LIMIT ?3",
+        selected.concat(),
+        table_name(topic_id)
+    ))?;
+    let rows = stmt.query_map(params![from, to, limit], |row| {
+        let mut object = MaterializedRow::new();
+        object.insert("value_id".to_string(), Value::from(row.get::<_, i64>(0)?));
+        object.insert("timestamp".to_string(), Value::from(row.get::<_, String>(1)?));
+        for (index, column) in columns.iter().enumerate() {
+            let value = match column.column_type {
+                ColumnType::Real => row.get::<_, Option<f64>>(index + 2)?.map(Value::from),
+                ColumnType::Boolean => row.get::<_, Option<bool>>(index + 2)?.map(Value::from),
+                ColumnType::Text => row.get::<_, Option<String>>(index + 2)?.map(Value::from),
+            };
+            object.insert(column.name.clone(), value.unwrap_or(Value::Null));
+        }
+        Ok(object)
+    })?;
+    let rows = rows.collect::<Result<_>>()?;
+    Ok(Some((columns, rows)))
+}
@@ -0,0 +1,227 @@
+use rumqttc::QoS;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the publish enqueue time buckets in milliseconds. Failed attempts are
+/// retried after a second, so the upper buckets separate publishes that needed retries.
+const PUBLISH_ENQUEUE_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Process-wide metrics, updated with relaxed atomics so recording never blocks
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    /// Time from the first attempt of a `publish_message` until the client queued the
+    /// publish or it finally failed. The broker's acknowledgement is not awaited.
+    pub publish_enqueue: Histogram<{ PUBLISH_ENQUEUE_BUCKETS_MS.len() }>,
+    /// MQTT messages received by either service
+    pub messages_received: AtomicU64,
+    /// Values written to the database
+    pub messages_stored: AtomicU64,
+    /// Reconnect attempts after a lost or failed broker connection
+    pub mqtt_reconnects: AtomicU64,
+    /// Publish attempts beyond the first
+    pub publish_retries: AtomicU64,
+    /// Publishes that failed after all attempts
+    pub publish_failures: AtomicU64,
+    /// New topics refused because `MAX_TOPICS` was reached
+    pub topics_rejected: AtomicU64,
+    /// Topics deleted to make room for new ones under `MAX_TOPICS`
+    pub topics_evicted: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            publish_enqueue: Histogram::new(PUBLISH_ENQUEUE_BUCKETS_MS),
+            messages_received: AtomicU64::new(0),
+            messages_stored: AtomicU64::new(0),
+            mqtt_reconnects: AtomicU64::new(0),
+            publish_retries: AtomicU64::new(0),
+            publish_failures: AtomicU64::new(0),
+            topics_rejected: AtomicU64::new(0),
+            topics_evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_counter(
+            &mut out,
+            "messages_received_total",
+            "MQTT messages received",
+            self.messages_received.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "messages_stored_total",
+            "Values written to the database",
+            self.messages_stored.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "mqtt_reconnects_total",
+            "Reconnect attempts after a lost or failed broker connection",
+            self.mqtt_reconnects.load(Ordering::Relaxed),
+        );
+        self.publish_enqueue.render(
+            &mut out,
+            "mqtt_publish_enqueue_duration_milliseconds",
+            "Time until a publish was queued for the broker or finally failed, including retries, without awaiting its acknowledgement",
+        );
+        render_counter(
+            &mut out,
+            "mqtt_publish_retries_total",
+            "Publish attempts beyond the first",
+            self.publish_retries.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "mqtt_publish_failures_total",
+            "Publishes that failed after all retries",
+            self.publish_failures.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "topics_rejected_total",
+            "New topics refused because the topic limit was reached",
+            self.topics_rejected.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "topics_evicted_total",
+            "Least recently active topics deleted to make room for new ones",
+            self.topics_evicted.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// Fixed-bucket histogram of millisecond durations
+pub struct Histogram<const N: usize> {
+    bounds_ms: [u64; N],
+    /// Observations per bucket
+    buckets: [AtomicU64; N],
+    /// Observations above all bounds
+    overflow: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds_ms: [u64; N]) -> Self {
+        Self {
+            bounds_ms,
+            buckets: [const { AtomicU64::new(0) }; N],
+            overflow: AtomicU64::new(0),
+            sum_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        match self.bounds_ms.iter().position(|bound| ms <= *bound) {
+            Some(bucket) => self.buckets[bucket].fetch_add(1, Ordering::Relaxed),
+            None => self.overflow.fetch_add(1, Ordering::Relaxed),
+        };
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        // Prometheus buckets are cumulative
+        let mut count = 0;
+        for (bound, bucket) in self.bounds_ms.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        count += self.overflow.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_ms.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// `mqtt_connected` gauge with a sample per `(service, connected)`
+pub fn render_connection_gauge(out: &mut String, services: &[(&str, bool)]) {
+    let _ = writeln!(out, "# HELP mqtt_connected Whether the service is connected to its broker");
+    let _ = writeln!(out, "# TYPE mqtt_connected gauge");
+    for (service, connected) in services {
+        let _ = writeln!(out, "mqtt_connected{{service=\"{}\"}} {}", service, u8::from(*connected));
+    }
+}
+
+/// Counter `name` with a sample per `(service, value)`
+pub fn render_service_counter(out: &mut String, name: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (service, value) in samples {
+        let _ = writeln!(out, "{}{{service=\"{}\"}} {}", name, service, value);
+    }
+}
+
+/// Subscribed filters of a service with the QoS granted for each, None if refused
+pub type GrantedQos = Vec<(String, Option<QoS>)>;
+
+/// `mqtt_subscription_granted_qos` gauge with a sample per subscribed filter of each
+/// service, -1 where the broker refused the subscription
+pub fn render_granted_qos(out: &mut String, services: &[(&str, GrantedQos)]) {
+    let _ = writeln!(out, "# HELP mqtt_subscription_granted_qos QoS the broker granted per subscribed filter, -1 if refused");
+    let _ = writeln!(out, "# TYPE mqtt_subscription_granted_qos gauge");
+    for (service, filters) in services {
+        for (filter, qos) in filters {
+            let _ = writeln!(
+                out,
+                "mqtt_subscription_granted_qos{{service=\"{}\",filter=\"{}\"}} {}",
+                service,
+                escape_label(filter),
+                qos.map_or(-1, |qos| qos as i8)
+            );
+        }
+    }
+}
+
+/// Escape a label value for the text exposition format
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_enqueue_histogram_is_cumulative() {
+        let histogram = Histogram::new(PUBLISH_ENQUEUE_BUCKETS_MS);
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "mqtt_publish_enqueue_duration_milliseconds", "help");
+        assert!(out.contains("mqtt_publish_enqueue_duration_milliseconds_bucket{le=\"2\"} 0\n"));
+        assert!(out.contains("mqtt_publish_enqueue_duration_milliseconds_bucket{le=\"5\"} 1\n"));
+        assert!(out.contains("mqtt_publish_enqueue_duration_milliseconds_bucket{le=\"5000\"} 2\n"));
+        assert!(out.contains("mqtt_publish_enqueue_duration_milliseconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("mqtt_publish_enqueue_duration_milliseconds_sum 10043\n"));
+        assert!(METRICS.render().contains("# TYPE mqtt_publish_enqueue_duration_milliseconds histogram\n"));
+    }
+
+    #[test]
+    fn granted_qos_renders_refused_filters_and_escapes_labels() {
+        let mut out = String::new();
+        let filters = vec![("#".to_string(), Some(QoS::AtLeastOnce)), ("a\"b".to_string(), None)];
+        render_granted_qos(&mut out, &[("monitored", filters)]);
+
+        assert!(out.contains("mqtt_subscription_granted_qos{service=\"monitored\",filter=\"#\"} 1\n"));
+        assert!(out.contains("mqtt_subscription_granted_qos{service=\"monitored\",filter=\"a\\\"b\"} -1\n"));
+    }
+}
@@ -1,3 +1,7 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+
 #[derive(Debug)]
 pub struct Broker {
     pub id: i64,
@@ -20,8 +24,199 @@ pub struct Topic {
 
 #[derive(Debug)]
 pub struct Subscription {
-    pub id: i64,
-    pub broker_id: i64,
-    pub topic_id: i64,
+    /// Topic filter subscribed to
+    pub topic: String,
     pub is_active: bool,
 }
+
+/// One bucket of a downsampled time range.
+#[derive(Debug)]
+pub struct DownsampledValue {
+    pub bucket_start: String,
+    pub value: String,
+    pub count: usize,
+}
+
+/// Aggregation applied to the values of a time bucket.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+}
+
+impl Aggregation {
+    /// SQL aggregate function applied to the numeric value of each row.
+    pub fn sql_function(&self) -> &'static str {
+        match self {
+            Aggregation::Avg => "AVG",
+            Aggregation::Min => "MIN",
+            Aggregation::Max => "MAX",
+            Aggregation::Sum => "SUM",
+            Aggregation::Count => "COUNT",
+        }
+    }
+}
+
+/// Declared type of the values of a topic, deciding how they are aggregated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Number,
+    Boolean,
+    String,
+    Enum,
+}
+
+impl ValueType {
+    /// Name stored in the `topics.value_type` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValueType::Number => "number",
+            ValueType::Boolean => "boolean",
+            ValueType::String => "string",
+            ValueType::Enum => "enum",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "number" => Some(ValueType::Number),
+            "boolean" => Some(ValueType::Boolean),
+            "string" => Some(ValueType::String),
+            "enum" => Some(ValueType::Enum),
+            _ => None,
+        }
+    }
+}
+
+/// A single stored value, addressed by its row id.
+#[derive(Debug)]
+pub struct ValueRow {
+    pub id: i64,
+    pub topic: String,
+    pub value: String,
+    pub timestamp: String,
+    /// `value` is a payload that wasn't UTF-8, base64 encoded (see
+    /// `DatabaseService::insert_value_bytes`)
+    pub is_binary: bool,
+    /// The payload as received when a unit rule with `keep_raw` converted `value`
+    pub raw_value: Option<String>,
+}
+
+impl ValueRow {
+    /// The value as the bytes it was received as
+    pub fn bytes(&self) -> Vec<u8> {
+        match self.is_binary {
+            true => BASE64.decode(&self.value).unwrap_or_else(|_| self.value.clone().into_bytes()),
+            false => self.value.clone().into_bytes(),
+        }
+    }
+}
+
+/// A numeric value of a topic at a point in time.
+#[derive(Debug)]
+pub struct NumericPoint {
+    pub value: f64,
+    pub timestamp: String,
+}
+
+/// Aggregated result of a single time bucket.
+#[derive(Debug)]
+pub struct AggregateBucket {
+    pub bucket_start: String,
+    pub value: Option<f64>,
+    pub count: usize,
+    /// Number of values per category, only filled for `enum` topics
+    pub categories: Vec<(String, usize)>,
+}
+
+/// One recorded administrative API action.
+#[derive(Debug)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub outcome: String,
+    pub timestamp: String,
+}
+
+/// A REST API user of the database auth backend.
+#[derive(Debug)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub created_at: String,
+}
+
+/// Outcome of registering a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicRegistration {
+    Added,
+    /// The topic was registered already
+    Existing,
+    /// The topic is new, but the topic limit is reached and eviction is disabled
+    LimitReached,
+}
+
+/// Settings of topics registered on their first message
+#[derive(Debug, Clone, Copy)]
+pub struct TopicDefaults {
+    pub max_values: usize,
+    pub query_frequency_ms: u64,
+}
+
+/// When a topic last received a value and how regularly it did so.
+#[derive(Debug)]
+pub struct TopicHealth {
+    pub topic: String,
+    pub query_frequency_ms: u64,
+    pub last_received_at: Option<String>,
+    pub seconds_since_last: Option<i64>,
+    /// Median time between the most recent values, `None` with fewer than two
+    pub median_interval_secs: Option<i64>,
+}
+
+/// Summary of the numeric values stored for a topic, other values are left out.
+#[derive(Debug)]
+pub struct TopicStats {
+    pub count: usize,
+    /// `None` without numeric values, as are `max` and `avg`
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    /// Most recent numeric value by timestamp
+    pub last: Option<f64>,
+}
+
+/// Number of values received across all topics within one time bucket.
+#[derive(Debug)]
+pub struct IngestRateBucket {
+    pub bucket_start: String,
+    pub count: usize,
+}
+
+/// Linear unit conversion applied to the numeric values of a topic on ingest, e.g.
+/// Fahrenheit to Celsius with `scale = 5/9` and `offset = -160/9`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnitRule {
+    pub scale: f64,
+    pub offset: f64,
+    /// Also store the received value next to the converted one
+    pub keep_raw: bool,
+}
+
+impl UnitRule {
+    /// `value * scale + offset`, or `None` if `value` is not a number or the result
+    /// overflows.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        let number = value.trim().parse::<f64>().ok().filter(|number| number.is_finite())?;
+        Some(number * self.scale + self.offset)
+            .filter(|converted| converted.is_finite())
+            .map(|converted| converted.to_string())
+    }
+}
@@ -1,64 +1,543 @@
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport, TlsConfiguration};
-use std::fs::read;
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, ConnectionError, Event, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS, StateError, SubAck,
+    SubscribeFilter, SubscribeReasonCode, Transport,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_util::task::TaskTracker;
 use log::{debug, error, info, warn};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
+use crate::config::{BrokerEndpoint, MqttTransport};
 use crate::db::DatabaseService;
-use crate::progress_tracker::SharedState;
+use crate::hooks::{EventHook, HookDispatcher};
+use crate::metrics::METRICS;
+use crate::models::{TopicDefaults, TopicRegistration};
+use crate::payload::{self, PayloadError, PayloadLimits};
+use crate::serialization::PublishFormat;
+use crate::sinks::HttpSinks;
+use crate::srv::SrvResolver;
+use crate::service_utils::{publish_analytics, ConnectionStatePayload};
+use crate::tls;
+use crate::topic_filter;
 
-#[derive(Debug)]
-enum ClientState {
+#[derive(Debug, Clone)]
+pub enum ClientState {
     Disconnected,
     Connecting,
     Connected,
     Error(String),
 }
+
+impl ClientState {
+    /// `disconnected`, `connecting`, `connected` or `error`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientState::Disconnected => "disconnected",
+            ClientState::Connecting => "connecting",
+            ClientState::Connected => "connected",
+            ClientState::Error(_) => "error",
+        }
+    }
+}
+
+/// Why the connection to the broker ended, classified from the event loop error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectCause {
+    /// The broker closed the connection, e.g. on shutdown or a client id takeover
+    BrokerDisconnect,
+    /// The broker rejected the credentials or the client is not authorized
+    AuthFailure,
+    Network,
+    Tls,
+    /// The broker refused the connection or sent unexpected packets
+    Protocol,
+}
+
+impl DisconnectCause {
+    pub fn classify(error: &ConnectionError) -> Self {
+        match error {
+            ConnectionError::ConnectionRefused(
+                ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized,
+            ) => DisconnectCause::AuthFailure,
+            ConnectionError::ConnectionRefused(_) | ConnectionError::NotConnAck(_) => DisconnectCause::Protocol,
+            ConnectionError::Tls(_) => DisconnectCause::Tls,
+            ConnectionError::Io(e) | ConnectionError::MqttState(StateError::Io(e)) => Self::classify_io(e),
+            ConnectionError::MqttState(StateError::AwaitPingResp)
+            | ConnectionError::NetworkTimeout
+            | ConnectionError::FlushTimeout
+            | ConnectionError::Websocket(_)
+            | ConnectionError::WsConnect(_) => DisconnectCause::Network,
+            _ => DisconnectCause::Protocol,
+        }
+    }
+
+    fn classify_io(error: &io::Error) -> Self {
+        match error.kind() {
+            // rumqttc reports a connection the peer closed between packets as aborted
+            io::ErrorKind::ConnectionAborted => DisconnectCause::BrokerDisconnect,
+            io::ErrorKind::InvalidData => DisconnectCause::Protocol,
+            _ => DisconnectCause::Network,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisconnectCause::BrokerDisconnect => "broker disconnect",
+            DisconnectCause::AuthFailure => "auth failure",
+            DisconnectCause::Network => "network error",
+            DisconnectCause::Tls => "TLS error",
+            DisconnectCause::Protocol => "protocol error",
+        }
+    }
+
+    /// The wait before the next reconnect attempt after waiting `current` last time, or
+    /// None if reconnecting can't succeed without changing the configuration. A broker
+    /// that closed the connection is up, so the interval doesn't grow; an unreachable
+    /// broker is backed off from fastest.
+    fn next_retry_interval(self, current: Duration) -> Option<Duration> {
+        let next = match self {
+            DisconnectCause::AuthFailure => return None,
+            DisconnectCause::BrokerDisconnect => current,
+            DisconnectCause::Network => current * 4,
+            DisconnectCause::Tls | DisconnectCause::Protocol => current * 2,
+        };
+        Some(next.min(MAX_RETRY_INTERVAL))
+    }
+}
+
+/// Upper limit of the reconnect backoff
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait for the SubAck of a subscribe request before retrying its filters
+const SUBACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts to subscribe a filter on a fresh session before giving up on it
+const SUBSCRIBE_ATTEMPTS: u32 = 3;
+
+/// A sent subscribe request awaiting its SubAck
+struct PendingSubscription {
+    id: u64,
+    filters: Vec<(String, QoS)>,
+    /// Receives the filters the broker refused, None if nobody waits for the SubAck
+    acked: Option<oneshot::Sender<Vec<(String, QoS)>>>,
+}
+
+/// How long a failback check waits for the primary broker to accept the connection
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check every `interval` whether `broker` accepts TCP connections, reporting once
+/// through the returned receiver when it does. Abort the task to stop checking.
+fn probe_broker(broker: BrokerEndpoint, interval: Duration) -> (JoinHandle<()>, oneshot::Receiver<()>) {
+    let (reachable, receiver) = oneshot::channel();
+    let probe = tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+            let connect = tokio::net::TcpStream::connect((broker.host.as_str(), broker.port));
+            if let Ok(Ok(_)) = timeout(PROBE_TIMEOUT, connect).await {
+                let _ = reachable.send(());
+                return;
+            }
+            debug!("Primary broker {} is still unreachable.", broker);
+        }
+    });
+    (probe, receiver)
+}
+
+/// Resolves when the failback probe reported the primary broker, never without a probe
+async fn recovered(receiver: &mut Option<oneshot::Receiver<()>>) -> Result<(), oneshot::error::RecvError> {
+    match receiver {
+        Some(receiver) => receiver.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Stores a received message if its topic is registered for `broker` and persisted.
+/// With `auto_register`, unknown topics are registered with it first. Returns whether
+/// the topic was registered.
+fn store_message(db: &DatabaseService, topic: &str, broker: &str, payload: &[u8], auto_register: Option<TopicDefaults>) -> bool {
+    let mut registered = false;
+    let mut persist = db.validate_topic_persist(topic, broker);
+    if let (Ok(None), Some(defaults)) = (&persist, auto_register) {
+        persist = match db.ensure_topic(topic, broker, defaults) {
+            Ok(TopicRegistration::Added) => {
+                registered = true;
+                Ok(Some(true))
+            }
+            // Registered by a concurrent message in the meantime, or for another broker
+            Ok(TopicRegistration::Existing) => db.validate_topic_persist(topic, broker),
+            Ok(TopicRegistration::LimitReached) => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
+
+    match persist {
+        Ok(Some(true)) => {
+            if let Err(e) = db.insert_value_bytes(topic, payload) {
+                error!("Failed to insert value for topic '{}': {:?}", topic, e);
+            }
+        }
+        // Live consumers were served before
+        Ok(Some(false)) => debug!("Not storing message for live-only topic '{}'.", topic),
+        // Expected for every unregistered topic under `#` without auto-registration, so not a warning
+        Ok(None) => debug!("Topic '{}' is not registered for the current broker.", topic),
+        Err(e) => error!("Failed to validate topic '{}': {:?}", topic, e),
+    }
+    registered
+}
+
 #[derive(Debug, Clone)]
 pub struct MqttConfig {
+    /// Name of the broker in the `brokers` table, see `BrokerConfig::registered_name`
+    pub broker_name: String,
     pub mqtt_host: String,
     pub mqtt_port: u16,
     pub mqtt_username: String,
     pub mqtt_password: String,
     pub mqtt_ssl_enabled: bool,
     pub mqtt_ssl_cert_path: Option<String>,
+    /// ALPN protocols offered during the TLS handshake, none when empty
+    pub mqtt_ssl_alpn: Vec<String>,
+    pub mqtt_transport: MqttTransport,
+    pub mqtt_ws_path: String,
+    /// Client id used verbatim instead of a random one per start
+    pub client_id: Option<String>,
+    pub clean_session: bool,
+    pub keep_alive_secs: u16,
+    /// Last will set on every connection, none when `None`
+    pub last_will: Option<LastWillConfig>,
     pub log_topic: String,
     pub status_topic: String,
     pub command_topic: String,
     pub progress_topic: String,
+    /// Receives a `topic_registered` event per topic registered on its first message
     pub analytics_topic: String,
+    /// Topic receiving a retained message on every connection state change, none when
+    /// notifications are disabled
+    pub connection_topic: Option<String>,
     pub mqtt_max_retries: i32,
     pub mqtt_retry_interval_ms: u64,
+    /// Minimum connected time before the reconnect backoff is reset
+    pub stable_connection_secs: u64,
+    /// Brokers tried in order after the primary one kept failing
+    pub failover_brokers: Vec<BrokerEndpoint>,
+    /// Consecutive failed connection attempts before switching to the next broker
+    pub failover_after_attempts: u32,
+    /// How often the primary broker is checked while connected to a failover broker
+    pub failback_check_secs: u64,
+    /// Looks up the primary broker before every connection attempt to it, `mqtt_host`
+    /// and `mqtt_port` being the fallback when the lookup fails
+    pub srv: Option<SrvResolver>,
+    pub publish_format: PublishFormat,
+    /// Maximum number of filters per subscribe request
+    pub subscribe_batch_size: usize,
+    /// Subscription QoS of filters without their own in `topics.qos`
+    pub default_qos: QoS,
+    /// MQTT v5 message expiry of status messages, 0 = never. Kept for MQTT v5 clients,
+    /// the MQTT 3.1.1 client ignores it (see `PublishProperties`).
+    pub status_expiry_secs: u32,
+    /// MQTT v5 message expiry of progress messages, 0 = never, ignored like
+    /// `status_expiry_secs`
+    pub progress_expiry_secs: u32,
+    /// Minimum time between two published progress updates of a task, 0 = no limit
+    pub progress_publish_interval_ms: u64,
+    /// Progress change that is published before the interval is up, 0 = disabled
+    pub progress_publish_step_percent: f64,
+    /// Drop `$`-prefixed broker topics such as `$SYS/#` before storing
+    pub exclude_system_topics: bool,
+    /// MQTT filters whose messages are never stored
+    pub exclude_topics: Vec<String>,
+    /// Filters a service with a database subscribes to, `#` when empty
+    pub subscribe_topics: Vec<String>,
+    /// Subscribe a service with a database to the active rows of the `subscriptions`
+    /// table for its broker instead of `subscribe_topics`
+    pub subscriptions_from_db: bool,
+    /// Settings of unknown topics registered on their first message, `None` drops
+    /// messages of unknown topics
+    pub auto_register: Option<TopicDefaults>,
+    /// Incoming payloads outside these limits are skipped
+    pub payload_limits: PayloadLimits,
+    /// HTTP endpoints receiving status, progress and analytics messages as well
+    pub http_sinks: Arc<HttpSinks>,
+}
+
+/// Message the broker publishes on behalf of a service whose connection drops without a
+/// disconnect, and the birth message the service publishes in its place on every ConnAck
+#[derive(Debug, Clone)]
+pub struct LastWillConfig {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub birth_payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// MQTT v5 publish properties of a message.
+///
+/// The client speaks MQTT 3.1.1, which has no publish properties, so they are not sent
+/// on the wire: messages are published without content type and never expire.
+#[derive(Debug, Clone, Default)]
+pub struct PublishProperties {
+    pub content_type: Option<String>,
+    /// Seconds until the broker discards an undelivered message
+    pub message_expiry_interval: Option<u32>,
+}
+
+impl PublishProperties {
+    /// Properties with a message expiry of `expiry_secs`, none when 0
+    pub fn expiring_after(expiry_secs: u32) -> Self {
+        Self {
+            content_type: None,
+            message_expiry_interval: (expiry_secs > 0).then_some(expiry_secs),
+        }
+    }
+}
+
+/// Received messages buffered per traffic tail before it loses the oldest ones
+const TRAFFIC_TAIL_CAPACITY: usize = 1_024;
+
+/// What became of a received message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageDisposition {
+    /// Passed the exclusion rules and payload validation
+    Accepted,
+    /// Matched an exclusion rule
+    Excluded,
+    /// Malformed or oversized payload
+    Rejected,
+    /// Arrived while draining for shutdown
+    Dropped,
+}
+
+/// A received message as it came off the wire, reported to traffic tails
+#[derive(Debug, Clone, Serialize)]
+pub struct RawMessage {
+    pub topic: String,
+    /// Payload decoded as UTF-8, invalid sequences replaced
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub disposition: MessageDisposition,
 }
 
 pub struct MqttService {
     client_state: Mutex<ClientState>,
     client: Mutex<Option<AsyncClient>>,
-    state: SharedState,
     pub config: MqttConfig,
     db_service: Option<Arc<DatabaseService>>,
+    received_messages: AtomicU64,
+    excluded_messages: AtomicU64,
+    rejected_payloads: AtomicU64,
+    sessions_resumed: AtomicU64,
+    sessions_fresh: AtomicU64,
+    /// Reconnect attempts of the current connection loop
+    reconnect_attempts: AtomicI32,
+    last_disconnect_cause: Mutex<Option<DisconnectCause>>,
+    /// Broker currently connected to or being tried
+    active_endpoint: Mutex<BrokerEndpoint>,
+    /// Topics subscribed at runtime through `watch` while it waits, renewed on fresh sessions
+    watched_topics: Mutex<Vec<String>>,
+    /// Filters subscribed at runtime through `subscribe` with their QoS, renewed on every
+    /// fresh session
+    runtime_filters: Mutex<HashMap<String, QoS>>,
+    /// Callers of `watch` waiting for the next message per topic
+    watchers: Mutex<HashMap<String, Vec<oneshot::Sender<String>>>>,
+    /// Subscribe requests queued on the client but not sent yet, in the order the event
+    /// loop sends them
+    unsent_subscriptions: Mutex<VecDeque<PendingSubscription>>,
+    /// Held while queuing a subscribe request, so `unsent_subscriptions` keeps the order
+    /// of the client's requests
+    subscribe_order: Mutex<()>,
+    /// Sent subscribe requests awaiting their SubAck by packet id
+    pending_subscriptions: Mutex<HashMap<u16, PendingSubscription>>,
+    next_subscription_id: AtomicU64,
+    /// QoS granted per subscribed filter in the current session, None if refused
+    granted_qos: Mutex<HashMap<String, Option<QoS>>>,
+    /// In-flight `handle_event` tasks, awaited on shutdown
+    tasks: TaskTracker,
+    draining: AtomicBool,
+    dropped_while_draining: AtomicU64,
+    /// Whether a message arrived without a database to store it, warned about once
+    warned_without_db: AtomicBool,
+    hooks: HookDispatcher,
+    /// Every received message, for `tail_traffic`
+    traffic: broadcast::Sender<RawMessage>,
 }
 
 impl MqttService {
-    pub fn new(
-        state: SharedState,
+    pub fn new(config: MqttConfig, db_service: Option<Arc<DatabaseService>>) -> Arc<Self> {
+        Self::with_hooks(config, db_service, Vec::new())
+    }
+
+    /// Like `new`, additionally running `hooks` on connection and message events (see
+    /// `EventHook`)
+    pub fn with_hooks(
         config: MqttConfig,
         db_service: Option<Arc<DatabaseService>>,
+        hooks: Vec<Arc<dyn EventHook>>,
     ) -> Arc<Self> {
+        let primary = BrokerEndpoint {
+            host: config.mqtt_host.clone(),
+            port: config.mqtt_port,
+        };
         Arc::new(Self {
             client_state: Mutex::new(ClientState::Disconnected),
             client: Mutex::new(None),
-            state,
             config,
             db_service, // Speichern der Referenz
+            received_messages: AtomicU64::new(0),
+            excluded_messages: AtomicU64::new(0),
+            rejected_payloads: AtomicU64::new(0),
+            sessions_resumed: AtomicU64::new(0),
+            sessions_fresh: AtomicU64::new(0),
+            reconnect_attempts: AtomicI32::new(0),
+            last_disconnect_cause: Mutex::new(None),
+            active_endpoint: Mutex::new(primary),
+            watched_topics: Mutex::new(Vec::new()),
+            runtime_filters: Mutex::new(HashMap::new()),
+            watchers: Mutex::new(HashMap::new()),
+            unsent_subscriptions: Mutex::new(VecDeque::new()),
+            subscribe_order: Mutex::new(()),
+            pending_subscriptions: Mutex::new(HashMap::new()),
+            next_subscription_id: AtomicU64::new(0),
+            granted_qos: Mutex::new(HashMap::new()),
+            tasks: TaskTracker::new(),
+            draining: AtomicBool::new(false),
+            dropped_while_draining: AtomicU64::new(0),
+            warned_without_db: AtomicBool::new(false),
+            hooks: HookDispatcher::new(hooks),
+            traffic: broadcast::channel(TRAFFIC_TAIL_CAPACITY).0,
         })
     }
 
+    /// Number of messages received from the broker, including excluded and rejected ones
+    pub fn received_message_count(&self) -> u64 {
+        self.received_messages.load(Ordering::Relaxed)
+    }
+
+    /// Number of received messages dropped by the topic exclusion rules
+    pub fn excluded_message_count(&self) -> u64 {
+        self.excluded_messages.load(Ordering::Relaxed)
+    }
+
+    /// Number of received messages skipped for a malformed or oversized payload
+    pub fn rejected_payload_count(&self) -> u64 {
+        self.rejected_payloads.load(Ordering::Relaxed)
+    }
+
+    /// Number of events the hooks of `with_hooks` missed because they fell behind
+    pub fn dropped_hook_event_count(&self) -> u64 {
+        self.hooks.dropped_count()
+    }
+
+    /// Every message received from now on, whatever becomes of it. Subscribers that fall
+    /// behind lose the oldest messages.
+    pub fn tail_traffic(&self) -> broadcast::Receiver<RawMessage> {
+        self.traffic.subscribe()
+    }
+
+    /// Report `publish` to the traffic tails, if any are listening
+    fn tap(&self, publish: &Publish, disposition: MessageDisposition) {
+        if self.traffic.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.traffic.send(RawMessage {
+            topic: publish.topic.clone(),
+            payload: String::from_utf8_lossy(&publish.payload).into_owned(),
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            disposition,
+        });
+    }
+
+    /// Whether messages on `topic` must not be stored
+    fn is_excluded(&self, topic: &str) -> bool {
+        (self.config.exclude_system_topics && topic.starts_with('$'))
+            || self
+                .config
+                .exclude_topics
+                .iter()
+                .any(|filter| topic_filter::matches(filter, topic))
+    }
+
+    /// The broker the SRV record points at, `configured` when the lookup fails. Each
+    /// failed attempt moves on to the next target in RFC 2782 order.
+    async fn resolve_primary(&self, srv: &SrvResolver, configured: &BrokerEndpoint, failed_attempts: u32) -> BrokerEndpoint {
+        match srv.lookup().await {
+            Ok(targets) => {
+                let target = targets[failed_attempts as usize % targets.len()].clone();
+                let all: Vec<String> = targets.iter().map(ToString::to_string).collect();
+                debug!("SRV record {} points at {}, using {}.", srv.name, all.join(", "), target);
+                target
+            }
+            Err(e) => {
+                warn!("SRV lookup of {} failed ({}), using {}.", srv.name, e, configured);
+                configured.clone()
+            }
+        }
+    }
+
+    /// Options for connecting to the broker at `mqtt_host:mqtt_port`, `Err` with the reason
+    /// when the TLS settings can't be used.
+    fn mqtt_options(&self, mqtt_client_id: &str, mqtt_host: &str, mqtt_port: u16) -> Result<MqttOptions, String> {
+        // Bei WebSockets erwartet rumqttc die vollständige URL als Broker-Adresse
+        let broker_addr = match self.config.mqtt_transport {
+            MqttTransport::Tcp => mqtt_host.to_string(),
+            MqttTransport::Ws => format!("ws://{}:{}{}", mqtt_host, mqtt_port, self.config.mqtt_ws_path),
+            MqttTransport::Wss => format!("wss://{}:{}{}", mqtt_host, mqtt_port, self.config.mqtt_ws_path),
+        };
+        let mut mqtt_options = MqttOptions::new(mqtt_client_id, broker_addr, mqtt_port);
+        if self.config.mqtt_transport == MqttTransport::Ws {
+            mqtt_options.set_transport(Transport::Ws);
+        }
+        mqtt_options.set_keep_alive(Duration::from_secs(self.config.keep_alive_secs.into()));
+        mqtt_options.set_clean_session(self.config.clean_session);
+        if let Some(will) = &self.config.last_will {
+            mqtt_options.set_last_will(LastWill::new(&will.topic, will.payload.clone(), will.qos, will.retain));
+        }
+        // Oversized payloads are skipped in `handle_event`, larger packets would end the connection
+        let max_packet_size = self.config.payload_limits.max_bytes.saturating_add(payload::PUBLISH_OVERHEAD_BYTES);
+        mqtt_options.set_max_packet_size(max_packet_size, max_packet_size);
+
+        // Optional: Benutzername/Passwort setzen
+        if !self.config.mqtt_username.is_empty() && !self.config.mqtt_password.is_empty() {
+            mqtt_options.set_credentials(&self.config.mqtt_username, &self.config.mqtt_password);
+        }
+
+        // TLS aktivieren
+        if self.config.mqtt_ssl_enabled {
+            let Some(cert_path) = &self.config.mqtt_ssl_cert_path else {
+                error!("MQTT_SSL_ENABLED is true, but no MQTT_SSL_CERT_PATH is provided.");
+                return Err("No CA certificate configured".to_string());
+            };
+            match tls::mqtt_tls_config(cert_path, &self.config.mqtt_ssl_alpn) {
+                Ok(tls_config) => {
+                    mqtt_options.set_transport(match self.config.mqtt_transport {
+                        MqttTransport::Wss => Transport::wss_with_config(tls_config),
+                        _ => Transport::tls_with_config(tls_config),
+                    });
+                    info!("Using TLS with CA certificate from: {}", cert_path);
+                }
+                Err(e) => {
+                    error!("Failed to load CA certificate: {}. Stopping service.", e);
+                    return Err(e.to_string());
+                }
+            }
+        }
+        Ok(mqtt_options)
+    }
+
     pub async fn start(self: Arc<Self>, mqtt_host: &str, mqtt_port: u16, mqtt_client_id: &str) {
         info!("Starting MQTT service...");
 
         let initial_retry_interval = Duration::from_millis(self.config.mqtt_retry_interval_ms);
+        let stable_connection = Duration::from_secs(self.config.stable_connection_secs);
         let max_retries = if self.config.mqtt_max_retries > 0 {
             self.config.mqtt_max_retries
         } else {
@@ -66,57 +545,51 @@ impl MqttService {
         };
         let mut retry_interval = initial_retry_interval;
         let mut retries = 0;
+        // The disconnect notification can only be sent through the next client
+        let mut pending_notification = None;
+
+        let configured_primary = BrokerEndpoint {
+            host: mqtt_host.to_string(),
+            port: mqtt_port,
+        };
+        let mut endpoints: Vec<BrokerEndpoint> = std::iter::once(configured_primary.clone())
+            .chain(self.config.failover_brokers.iter().cloned())
+            .collect();
+        let mut endpoint = 0;
+        // Failed connection attempts in a row on the current endpoint
+        let mut failed_attempts = 0;
 
         loop {
+            if self.draining.load(Ordering::Relaxed) {
+                info!("Service is draining, not reconnecting.");
+                break;
+            }
             if max_retries != -1 && retries >= max_retries {
                 error!(
                     "Maximum number of retries ({}) reached. Stopping the service.",
                     max_retries
                 );
+                self.stop_with_error("Maximum number of retries reached".to_string(), retries).await;
                 break;
             }
 
-            debug!("Configuring MQTT broker at {}:{}...", mqtt_host, mqtt_port);
-            let mut mqtt_options = MqttOptions::new(mqtt_client_id, mqtt_host, mqtt_port);
-            mqtt_options.set_keep_alive(Duration::from_secs(10));
-            mqtt_options.set_clean_session(true);
-
-            // Optional: Benutzername/Passwort setzen
-            if !self.config.mqtt_username.is_empty() && !self.config.mqtt_password.is_empty() {
-                mqtt_options.set_credentials(
-                    &self.config.mqtt_username,
-                    &self.config.mqtt_password,
-                );
+            // Looked up on every attempt, so the primary follows the record when it moves
+            if let (0, Some(srv)) = (endpoint, &self.config.srv) {
+                endpoints[0] = self.resolve_primary(srv, &configured_primary, failed_attempts).await;
             }
 
-            // TLS aktivieren
-            if self.config.mqtt_ssl_enabled {
-                if let Some(cert_path) = &self.config.mqtt_ssl_cert_path {
-                    match read(cert_path) {
-                        Ok(ca) => {
-                            // Wichtig: TlsConfiguration::Simple verlangt ein `Vec<u8>` in `ca`,
-                            // KEIN `Option<Vec<u8>>`. `alpn` und `client_auth` sind optional.
-                            let tls_config = TlsConfiguration::Simple {
-                                ca,                 // hier der geladene CA-Bytestring
-                                alpn: None,         // z. B. Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()])
-                                client_auth: None,  // hier könntest du Client-Zertifikat + Key eintragen
-                            };
-                            mqtt_options.set_transport(Transport::tls_with_config(tls_config));
-                            info!("Using TLS with CA certificate from: {}", cert_path);
-                        }
-                        Err(e) => {
-                            error!(
-                                "Failed to load CA certificate from '{}': {}. Stopping service.",
-                                cert_path, e
-                            );
-                            break;
-                        }
-                    }
-                } else {
-                    error!("MQTT_SSL_ENABLED is true, but no MQTT_SSL_CERT_PATH is provided.");
+            let mqtt_host = endpoints[endpoint].host.as_str();
+            let mqtt_port = endpoints[endpoint].port;
+            *self.active_endpoint.lock().await = endpoints[endpoint].clone();
+
+            debug!("Configuring MQTT broker at {}:{}...", mqtt_host, mqtt_port);
+            let mqtt_options = match self.mqtt_options(mqtt_client_id, mqtt_host, mqtt_port) {
+                Ok(mqtt_options) => mqtt_options,
+                Err(e) => {
+                    self.stop_with_error(e, retries).await;
                     break;
                 }
-            }
+            };
 
             // AsyncClient + EventLoop erzeugen
             let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
@@ -127,110 +600,790 @@ impl MqttService {
                 *client_lock = Some(client.clone());
             }
 
-            {
-                let mut client_state = self.client_state.lock().await;
-                *client_state = ClientState::Connecting;
-            }
-
-            // Subscriben
-            let control_topic = self.config.command_topic.clone();
-            match client.subscribe(&control_topic, QoS::AtLeastOnce).await {
-                Ok(_) => {
-                    info!("Successfully subscribed to topic '{}'.", control_topic);
-                    {
-                        let mut client_state = self.client_state.lock().await;
-                        *client_state = ClientState::Connected;
-                    }
-                    retry_interval = initial_retry_interval;
-                }
-                Err(e) => {
-                    error!("Failed to subscribe to topic '{}': {}", control_topic, e);
-                    {
-                        let mut client_state = self.client_state.lock().await;
-                        *client_state = ClientState::Error(e.to_string());
-                    }
-                    retries += 1;
-                    sleep(retry_interval).await;
-                    retry_interval = (retry_interval * 2).min(Duration::from_secs(60));
-                    continue;
-                }
-            }
+            self.notify_connection_state(&client, pending_notification.take());
+            let notification = self.set_client_state(ClientState::Connecting, retries).await;
+            self.notify_connection_state(&client, notification);
+
+            // While on a failover broker, switch back as soon as the primary is reachable
+            let (failback_probe, mut primary_recovered) = if endpoint > 0 {
+                let interval = Duration::from_secs(self.config.failback_check_secs);
+                let (probe, recovered) = probe_broker(endpoints[0].clone(), interval);
+                (Some(probe), Some(recovered))
+            } else {
+                (None, None)
+            };
 
             // MQTT-Event-Loop
-            loop {
-                match eventloop.poll().await {
-                    Ok(event) => {
-                        let self_clone = self.clone();
-                        tokio::spawn(async move {
-                            self_clone.handle_event(event).await;
-                        });
+            let mut connected_since = None;
+            let cause = loop {
+                let polled = tokio::select! {
+                    polled = eventloop.poll() => polled,
+                    Ok(()) = recovered(&mut primary_recovered) => break None,
+                };
+                match polled {
+                    Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                        self.on_connected(&client, connack.session_present, retries).await;
+                        connected_since = Some(Instant::now());
                     }
+                    // Handled in order, as each SubAck must find its request sent before
+                    Ok(Event::Outgoing(Outgoing::Subscribe(pkid))) => self.subscription_sent(pkid).await,
+                    Ok(Event::Incoming(Packet::SubAck(suback))) => self.record_granted_qos(&suback).await,
+                    Ok(event) => self.dispatch(event),
                     Err(e) => {
-                        error!("Error in MQTT event loop: {:?}", e);
-                        {
-                            let mut client_state = self.client_state.lock().await;
-                            *client_state = ClientState::Disconnected;
-                        }
-                        break; // Verlasse die innere Schleife => Reconnect
+                        let cause = DisconnectCause::classify(&e);
+                        error!("Error in MQTT event loop ({}): {:?}", cause.name(), e);
+                        *self.last_disconnect_cause.lock().await = Some(cause);
+                        pending_notification = self.set_client_state(ClientState::Disconnected, retries).await;
+                        break Some(cause); // Verlasse die innere Schleife => Reconnect
                     }
                 }
+            };
+            if let Some(probe) = failback_probe {
+                probe.abort();
+            }
+
+            let Some(cause) = cause else {
+                info!("Primary broker {} is reachable again, failing back from {}.", endpoints[0], endpoints[endpoint]);
+                endpoint = 0;
+                failed_attempts = 0;
+                retry_interval = initial_retry_interval;
+                pending_notification = self.set_client_state(ClientState::Disconnected, retries).await;
+                continue;
+            };
+
+            if connected_since.is_some() {
+                self.hooks.disconnect(&endpoints[endpoint].to_string(), cause.name());
+            }
+
+            // Flapping connections keep growing the backoff and count as failed attempts,
+            // only stable ones reset both
+            if connected_since.is_some_and(|since| since.elapsed() >= stable_connection) {
+                retry_interval = initial_retry_interval;
+                failed_attempts = 0;
+            }
+            let Some(next_retry_interval) = cause.next_retry_interval(retry_interval) else {
+                error!("Broker rejected the credentials. Stopping the service.");
+                self.stop_with_error("Broker rejected the credentials".to_string(), retries).await;
+                break;
+            };
+            let mut delay = retry_interval;
+            retry_interval = next_retry_interval;
+
+            failed_attempts += 1;
+            if endpoints.len() > 1 && failed_attempts >= self.config.failover_after_attempts {
+                let next = (endpoint + 1) % endpoints.len();
+                warn!(
+                    "Broker {} failed {} times in a row, failing over to {}.",
+                    endpoints[endpoint], failed_attempts, endpoints[next]
+                );
+                endpoint = next;
+                failed_attempts = 0;
+                delay = initial_retry_interval;
+                retry_interval = initial_retry_interval;
             }
 
             warn!(
-                "Lost connection to MQTT broker. Retrying in {:?}...",
-                retry_interval
+                "Lost connection to MQTT broker ({}). Retrying in {:?}...",
+                cause.name(),
+                delay
             );
             retries += 1;
-            sleep(retry_interval).await;
-            retry_interval = (retry_interval * 2).min(Duration::from_secs(60));
+            METRICS.mqtt_reconnects.fetch_add(1, Ordering::Relaxed);
+            sleep(delay).await;
         }
     }
 
-    async fn handle_event(self: Arc<Self>, event: Event) {
-        match event {
-            Event::Incoming(Packet::Publish(publish)) => {
-                let topic = publish.topic.clone();
-                let payload = String::from_utf8(publish.payload.to_vec()).unwrap_or_default();
-
-                // Überprüfen, ob ein db_service vorhanden ist
-                if let Some(db_service) = &self.db_service {
-                    if let Ok(valid) = db_service.validate_topic(&topic, &self.config.mqtt_host) {
-                        if valid {
-                            if let Err(e) = db_service.insert_value(&topic, &payload) {
-                                error!("Failed to insert value for topic '{}': {:?}", topic, e);
+    /// Switch the client state. With connection notifications enabled, returns the
+    /// message describing the new state for `notify_connection_state`.
+    async fn set_client_state(&self, state: ClientState, retries: i32) -> Option<ConnectionStatePayload> {
+        let broker = self.active_endpoint.lock().await.to_string();
+        let notification = self.config.connection_topic.as_ref().map(|_| ConnectionStatePayload {
+            state: state.name().to_string(),
+            broker,
+            timestamp: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            retries,
+            error: match &state {
+                ClientState::Error(reason) => Some(reason.clone()),
+                _ => None,
+            },
+        });
+        *self.client_state.lock().await = state;
+        self.reconnect_attempts.store(retries, Ordering::Relaxed);
+        notification
+    }
+
+    /// Current state of the broker connection
+    pub async fn client_state(&self) -> ClientState {
+        self.client_state.lock().await.clone()
+    }
+
+    /// Name of the current state of the broker connection, see `ClientState::name`
+    pub async fn connection_state(&self) -> &'static str {
+        self.client_state.lock().await.name()
+    }
+
+    /// Broker currently connected to or being tried, the primary one unless failed over
+    pub async fn active_endpoint(&self) -> BrokerEndpoint {
+        self.active_endpoint.lock().await.clone()
+    }
+
+    /// The failover broker in use, none while on the primary one
+    pub async fn failed_over_to(&self) -> Option<BrokerEndpoint> {
+        let active = self.active_endpoint().await;
+        (active.host != self.config.mqtt_host || active.port != self.config.mqtt_port).then_some(active)
+    }
+
+    /// Cause of the most recent connection loss, none while the first connection lasts
+    pub async fn last_disconnect_cause(&self) -> Option<DisconnectCause> {
+        *self.last_disconnect_cause.lock().await
+    }
+
+    /// Reconnect attempts made so far, as of the last state change
+    pub fn reconnect_attempts(&self) -> i32 {
+        self.reconnect_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Queue a connection state message on `client` without waiting for room in its
+    /// request queue, so notifying can't block the connection loop.
+    fn notify_connection_state(&self, client: &AsyncClient, notification: Option<ConnectionStatePayload>) {
+        let (Some(topic), Some(notification)) = (&self.config.connection_topic, notification) else {
+            return;
+        };
+        let format = self.config.publish_format;
+        match format.encode(&notification) {
+            Ok(message) => {
+                if let Err(e) = client.try_publish(format.topic(topic), QoS::AtLeastOnce, true, message) {
+                    warn!("Failed to queue connection state notification: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to serialize connection state notification: {}", e),
+        }
+    }
+
+    /// Enter the error state when the service stops for good. The connection is gone by
+    /// then, so the notification is only queued on the last client, best effort.
+    async fn stop_with_error(&self, reason: String, retries: i32) {
+        let notification = self.set_client_state(ClientState::Error(reason), retries).await;
+        if let Some(client) = self.client.lock().await.as_ref() {
+            self.notify_connection_state(client, notification);
+        }
+    }
+
+    /// Topic filters this service subscribes to on a fresh session, with their QoS. Only a
+    /// service with a database stores messages, so only it subscribes to `subscribe_topics`,
+    /// or the whole broker (which includes its command topic) without any; the others just
+    /// listen for commands. With `subscriptions_from_db`, the active subscriptions of the
+    /// broker are used instead, and none means no subscription rather than `#`.
+    ///
+    /// Brokers may deliver a message once per matching subscription, so nothing is added
+    /// that an existing filter covers, which would store its messages twice. A topic with
+    /// its own QoS in `topics.qos` replaces the QoS of a filter naming it exactly, but
+    /// under a wildcard filter it arrives at the wildcard's QoS. To store most topics at
+    /// QoS 0 and a few at a higher one, subscribe to filters not overlapping those topics
+    /// (MQTT_SUBSCRIBE_TOPICS or subscriptions from the database) rather than `#`. With
+    /// subscriptions from the database, topics with their own QoS are only subscribed
+    /// where one matches them. Watched topics and filters added through `subscribe`
+    /// follow the same rule with their own QoS.
+    async fn subscription_filters(&self) -> Vec<(String, QoS)> {
+        let default_qos = self.config.default_qos;
+        let from_db = self.db_service.is_some() && self.config.subscriptions_from_db;
+        let mut filters = if self.db_service.is_none() {
+            vec![(self.config.command_topic.clone(), default_qos)]
+        } else if from_db {
+            self.subscriptions_from_db()
+                .into_iter()
+                .map(|filter| (filter, default_qos))
+                .collect()
+        } else if self.config.subscribe_topics.is_empty() {
+            vec![("#".to_string(), default_qos)]
+        } else {
+            self.config
+                .subscribe_topics
+                .iter()
+                .map(|filter| (filter.clone(), default_qos))
+                .collect()
+        };
+
+        if let Some(db_service) = &self.db_service {
+            match db_service.get_topic_qos() {
+                Ok(topic_qos) => {
+                    let mut ignored = Vec::new();
+                    for (topic, level) in topic_qos {
+                        if from_db && !filters.iter().any(|(filter, _)| topic_filter::matches(filter, &topic)) {
+                            continue;
+                        }
+                        let Some(qos) = u8::try_from(level).ok().and_then(|level| rumqttc::qos(level).ok()) else {
+                            warn!("Topic '{}' has invalid QoS {}, subscribing with the default {:?}.", topic, level, default_qos);
+                            continue;
+                        };
+                        if let Some(exact) = filters.iter_mut().find(|(filter, _)| *filter == topic) {
+                            exact.1 = qos;
+                        } else if let Some((filter, filter_qos)) =
+                            filters.iter().find(|(filter, _)| topic_filter::matches(filter, &topic))
+                        {
+                            if *filter_qos != qos {
+                                ignored.push(format!("'{}' ({:?} under '{}')", topic, qos, filter));
                             }
                         } else {
-                            warn!("Topic '{}' is not valid for the current broker.", topic);
+                            filters.push((topic, qos));
                         }
                     }
-                } else {
-                    // Falls keine Datenbank: Nur Logging
-                    info!("Received message for topic '{}', but no database is configured.", topic);
+                    if !ignored.is_empty() {
+                        warn!(
+                            "{} topic(s) with their own QoS are covered by a wildcard subscription and arrive at its QoS: {}",
+                            ignored.len(),
+                            ignored.join(", ")
+                        );
+                    }
                 }
+                Err(e) => error!("Failed to read topic QoS, using the default for all topics: {:?}", e),
             }
-            _ => {}
         }
+
+        let mut extra: Vec<(String, QoS)> =
+            self.watched_topics.lock().await.iter().map(|topic| (topic.clone(), default_qos)).collect();
+        extra.extend(self.runtime_filters.lock().await.iter().map(|(filter, qos)| (filter.clone(), *qos)));
+        for (topic, qos) in extra {
+            if !filters.iter().any(|(filter, _)| topic_filter::matches(filter, &topic)) {
+                filters.push((topic, qos));
+            }
+        }
+        filters
     }
 
+    /// Active subscriptions of this service's broker in the database. An empty list and a
+    /// failed read both leave the service without stored topics, never on `#`.
+    fn subscriptions_from_db(&self) -> Vec<String> {
+        let Some(db_service) = &self.db_service else {
+            return Vec::new();
+        };
+        match db_service.get_active_subscriptions(&self.config.broker_name) {
+            Ok(filters) if filters.is_empty() => {
+                warn!("Broker '{}' has no active subscriptions, not subscribing to any topic.", self.config.broker_name);
+                filters
+            }
+            Ok(filters) => {
+                info!("Subscribing to {} topic(s) of broker '{}' from the database.", filters.len(), self.config.broker_name);
+                filters
+            }
+            Err(e) => {
+                error!("Failed to read the subscriptions of broker '{}', not subscribing to any topic: {:?}", self.config.broker_name, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// QoS granted per subscribed filter in the current session, None where the broker
+    /// refused the subscription
+    pub async fn granted_qos(&self) -> Vec<(String, Option<QoS>)> {
+        let mut granted: Vec<_> = self
+            .granted_qos
+            .lock()
+            .await
+            .iter()
+            .map(|(filter, qos)| (filter.clone(), *qos))
+            .collect();
+        granted.sort_by(|a, b| a.0.cmp(&b.0));
+        granted
+    }
+
+    /// The event loop sent the oldest queued subscribe request as packet `pkid`, its
+    /// SubAck carries the same id
+    async fn subscription_sent(&self, pkid: u16) {
+        let Some(pending) = self.unsent_subscriptions.lock().await.pop_front() else {
+            warn!("Sent a subscribe request that wasn't queued.");
+            return;
+        };
+        self.pending_subscriptions.lock().await.insert(pkid, pending);
+    }
+
+    /// Match a SubAck to the subscribe request with its packet id. Its return codes are
+    /// in the order of the request's filters.
+    async fn record_granted_qos(&self, suback: &SubAck) {
+        let Some(pending) = self.pending_subscriptions.lock().await.remove(&suback.pkid) else {
+            warn!("Received a SubAck without a pending subscription.");
+            return;
+        };
+        let return_codes = &suback.return_codes;
+
+        let mut refused = Vec::new();
+        let mut granted_qos = self.granted_qos.lock().await;
+        for (i, (filter, requested)) in pending.filters.into_iter().enumerate() {
+            let granted = match return_codes.get(i) {
+                Some(SubscribeReasonCode::Success(qos)) => {
+                    if *qos != requested {
+                        warn!("Broker granted {:?} instead of {:?} for '{}'.", qos, requested, filter);
+                    }
+                    Some(*qos)
+                }
+                _ => {
+                    error!("Broker refused the subscription to '{}'.", filter);
+                    refused.push((filter.clone(), requested));
+                    None
+                }
+            };
+            granted_qos.insert(filter, granted);
+        }
+        if let Some(acked) = pending.acked {
+            let _ = acked.send(refused);
+        }
+    }
+
+    /// Queue a subscribe request for `filters`, registering it for its SubAck first.
+    /// Returns its id for `forget_subscription`.
+    async fn send_subscribe(
+        &self,
+        client: &AsyncClient,
+        filters: Vec<(String, QoS)>,
+        acked: Option<oneshot::Sender<Vec<(String, QoS)>>>,
+    ) -> Result<u64, rumqttc::ClientError> {
+        let id = self.next_subscription_id.fetch_add(1, Ordering::Relaxed);
+        let request: Vec<SubscribeFilter> = filters
+            .iter()
+            .map(|(filter, qos)| SubscribeFilter::new(filter.clone(), *qos))
+            .collect();
+
+        let order = self.subscribe_order.lock().await;
+        self.unsent_subscriptions
+            .lock()
+            .await
+            .push_back(PendingSubscription { id, filters, acked });
+        let result = client.subscribe_many(request).await;
+        drop(order);
+        if result.is_err() {
+            // Never sent, so no SubAck will come for it
+            self.forget_subscription(id).await;
+        }
+        result.map(|()| id)
+    }
+
+    /// Stop waiting for the SubAck of subscribe request `id`
+    async fn forget_subscription(&self, id: u64) {
+        self.unsent_subscriptions.lock().await.retain(|pending| pending.id != id);
+        self.pending_subscriptions.lock().await.retain(|_, pending| pending.id != id);
+    }
+
+    /// Subscribe to `filters` in batches of `subscribe_batch_size`, each awaiting its
+    /// SubAck before the next goes out, so large topic sets don't exceed the broker's
+    /// packet size limit. Filters refused or not acknowledged in time are retried in
+    /// later rounds, up to SUBSCRIBE_ATTEMPTS times. Stops when the connection is gone,
+    /// the next fresh session subscribes again.
+    async fn subscribe_all(self: Arc<Self>, client: AsyncClient, mut filters: Vec<(String, QoS)>) {
+        let total = filters.len();
+        let mut subscribed = 0;
+
+        for attempt in 1..=SUBSCRIBE_ATTEMPTS {
+            let mut failed = Vec::new();
+            for batch in filters.chunks(self.config.subscribe_batch_size.max(1)) {
+                let (acked, refused) = oneshot::channel();
+                let id = match self.send_subscribe(&client, batch.to_vec(), Some(acked)).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("Failed to subscribe, connection is gone: {}", e);
+                        return;
+                    }
+                };
+                let refused = match timeout(SUBACK_TIMEOUT, refused).await {
+                    Ok(Ok(refused)) => refused,
+                    _ => {
+                        // A late SubAck must not be taken for a later request's
+                        self.forget_subscription(id).await;
+                        warn!("No SubAck for {} topic filter(s) within {:?}.", batch.len(), SUBACK_TIMEOUT);
+                        batch.to_vec()
+                    }
+                };
+                subscribed += batch.len() - refused.len();
+                info!("Subscribed to {}/{} topic filters.", subscribed, total);
+                failed.extend(refused);
+            }
+
+            if failed.is_empty() {
+                return;
+            }
+            if attempt < SUBSCRIBE_ATTEMPTS {
+                warn!(
+                    "{} topic filter(s) failed to subscribe (attempt {}/{}), retrying...",
+                    failed.len(),
+                    attempt,
+                    SUBSCRIBE_ATTEMPTS
+                );
+                sleep(Duration::from_millis(self.config.mqtt_retry_interval_ms)).await;
+            }
+            filters = failed;
+        }
+
+        let names: Vec<&str> = filters.iter().map(|(filter, _)| filter.as_str()).collect();
+        error!("Giving up on subscribing to {:?}.", names);
+    }
+
+    /// Subscribe to `topic` and wait up to `wait` for its next message, usually the
+    /// retained value the broker sends for a new subscription. Returns None if nothing
+    /// arrives in time. The subscription ends with the last `watch` call waiting for the
+    /// topic.
+    ///
+    /// The waiter is registered before the subscribe goes out, so a retained value
+    /// delivered right after the SubAck is not missed. A topic another filter of the
+    /// session already covers is not subscribed again, which would deliver its messages
+    /// twice on some brokers; the call then waits for its next message, as the retained
+    /// value came with that filter.
+    pub async fn watch(&self, topic: &str, wait: Duration) -> Result<Option<String>, String> {
+        let Some(client) = self.client.lock().await.clone() else {
+            return Err("MQTT client is not running".to_string());
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.watchers.lock().await.entry(topic.to_string()).or_default().push(sender);
+        if self.is_covered(topic).await {
+            let value = timeout(wait, receiver).await.ok().and_then(Result::ok);
+            if value.is_none() {
+                self.forget_closed_watchers(topic).await;
+            }
+            return Ok(value);
+        }
+        {
+            let mut watched_topics = self.watched_topics.lock().await;
+            if !watched_topics.iter().any(|watched| watched == topic) {
+                watched_topics.push(topic.to_string());
+            }
+        }
+
+        let filters = vec![(topic.to_string(), self.config.default_qos)];
+        let result = match self.send_subscribe(&client, filters, None).await {
+            Ok(_) => Ok(timeout(wait, receiver).await.ok().and_then(Result::ok)),
+            Err(e) => Err(format!("Failed to subscribe to topic '{}': {}", topic, e)),
+        };
+        self.unwatch_if_idle(&client, topic).await;
+        result
+    }
+
+    /// Whether a filter subscribed in this session, other than a watched topic, covers
+    /// `topic`
+    async fn is_covered(&self, topic: &str) -> bool {
+        let watched_topics = self.watched_topics.lock().await.clone();
+        self.granted_qos.lock().await.iter().any(|(filter, granted)| {
+            granted.is_some() && !watched_topics.contains(filter) && topic_filter::matches(filter, topic)
+        })
+    }
+
+    /// Stop watching `topic` once no `watch` call waits for it any more: it is no longer
+    /// subscribed on fresh sessions and unsubscribed from right away. The watchers stay
+    /// locked meanwhile, so a `watch` starting concurrently subscribes after this.
+    async fn unwatch_if_idle(&self, client: &AsyncClient, topic: &str) {
+        let mut watchers = self.watchers.lock().await;
+        if let Some(senders) = watchers.get_mut(topic) {
+            senders.retain(|sender| !sender.is_closed());
+            if !senders.is_empty() {
+                return;
+            }
+            watchers.remove(topic);
+        }
+
+        self.watched_topics.lock().await.retain(|watched| watched != topic);
+        if self.runtime_filters.lock().await.contains_key(topic) {
+            return;
+        }
+        self.granted_qos.lock().await.remove(topic);
+        if let Err(e) = client.unsubscribe(topic).await {
+            warn!("Failed to unsubscribe from watched topic '{}': {}", topic, e);
+        }
+    }
+
+    /// Publish an empty retained message to `topic`, which makes the broker drop the
+    /// retained value it holds for it. Only the broker's retained store is affected,
+    /// values stored in the database stay.
+    pub async fn clear_retained(&self, topic: &str) {
+        self.publish_message(topic, &[], QoS::AtLeastOnce, true).await
+    }
+
+    /// Subscribe to `filter` at runtime. The filter is kept and subscribed again on every
+    /// fresh session until `unsubscribe` removes it. Fails if the client isn't running.
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), String> {
+        if !topic_filter::is_valid(filter) {
+            return Err(format!("Invalid topic filter '{}'", filter));
+        }
+        let Some(client) = self.client.lock().await.clone() else {
+            return Err("MQTT client is not running".to_string());
+        };
+
+        self.send_subscribe(&client, vec![(filter.to_string(), qos)], None)
+            .await
+            .map_err(|e| format!("Failed to subscribe to '{}': {}", filter, e))?;
+        self.runtime_filters.lock().await.insert(filter.to_string(), qos);
+        Ok(())
+    }
+
+    /// Unsubscribe from a filter added through `subscribe`, for this session and the
+    /// following ones. Fails for any other filter and if the client isn't running.
+    pub async fn unsubscribe(&self, filter: &str) -> Result<(), String> {
+        let Some(client) = self.client.lock().await.clone() else {
+            return Err("MQTT client is not running".to_string());
+        };
+
+        if self.runtime_filters.lock().await.remove(filter).is_none() {
+            return Err(format!("'{}' was not subscribed at runtime", filter));
+        }
+        self.granted_qos.lock().await.remove(filter);
+        client
+            .unsubscribe(filter)
+            .await
+            .map_err(|e| format!("Failed to unsubscribe from '{}': {}", filter, e))
+    }
+
+    /// Name of the broker this service connects to, as stored in the `brokers` table
+    pub fn broker_name(&self) -> &str {
+        &self.config.broker_name
+    }
+
+    /// Subscribe to or unsubscribe from a changed subscription of the database right away,
+    /// if this service takes its subscriptions from there (see `subscriptions_from_db`)
+    /// and is connected. Otherwise the change applies with the next fresh session.
+    /// Watched topics and filters added through `subscribe` stay subscribed.
+    pub async fn sync_subscription(&self, filter: &str, active: bool) {
+        if self.db_service.is_none() || !self.config.subscriptions_from_db {
+            return;
+        }
+        if !matches!(self.client_state().await, ClientState::Connected) {
+            return;
+        }
+        let Some(client) = self.client.lock().await.clone() else {
+            return;
+        };
+
+        let result = if active {
+            self.send_subscribe(&client, vec![(filter.to_string(), self.config.default_qos)], None)
+                .await
+                .map(|_| ())
+        } else if self.watched_topics.lock().await.iter().any(|watched| watched == filter)
+            || self.runtime_filters.lock().await.contains_key(filter)
+        {
+            return;
+        } else {
+            self.granted_qos.lock().await.remove(filter);
+            client.unsubscribe(filter).await
+        };
+        match result {
+            Ok(()) if active => info!("Subscribed to '{}' of broker '{}'.", filter, self.broker_name()),
+            Ok(()) => info!("Unsubscribed from '{}' of broker '{}'.", filter, self.broker_name()),
+            Err(e) => warn!("Failed to update the subscription to '{}', applying it on reconnect: {}", filter, e),
+        }
+    }
+
+    /// Drop the waiters for `topic` whose `watch` call gave up
+    async fn forget_closed_watchers(&self, topic: &str) {
+        let mut watchers = self.watchers.lock().await;
+        if let Some(senders) = watchers.get_mut(topic) {
+            senders.retain(|sender| !sender.is_closed());
+            if senders.is_empty() {
+                watchers.remove(topic);
+            }
+        }
+    }
+
+    /// Hand a received message to everyone waiting in `watch` for its topic
+    async fn notify_watchers(&self, topic: &str, payload: &str) {
+        if let Some(senders) = self.watchers.lock().await.remove(topic) {
+            for sender in senders {
+                let _ = sender.send(payload.to_string());
+            }
+        }
+    }
+
+    /// Handle a ConnAck: mark the client connected and subscribe unless the broker resumed
+    /// a persistent session, in which case it still holds our subscriptions.
+    async fn on_connected(self: &Arc<Self>, client: &AsyncClient, session_present: bool, retries: i32) {
+        let notification = self.set_client_state(ClientState::Connected, retries).await;
+        self.notify_connection_state(client, notification);
+        self.hooks.connect(&self.active_endpoint.lock().await.to_string());
+        // Replaces a will the broker published for the previous connection
+        if let Some(will) = &self.config.last_will {
+            if let Err(e) = client.try_publish(&will.topic, will.qos, will.retain, will.birth_payload.clone()) {
+                warn!("Failed to queue online message: {}", e);
+            }
+        }
+
+        if session_present {
+            self.sessions_resumed.fetch_add(1, Ordering::Relaxed);
+            info!("Broker resumed the previous session, keeping existing subscriptions.");
+            return;
+        }
+        self.sessions_fresh.fetch_add(1, Ordering::Relaxed);
+
+        // Subscribe from a separate task: requests are only sent and acknowledged while
+        // the event loop is polled, so awaiting them here would block it.
+        let filters = self.subscription_filters().await;
+        self.granted_qos.lock().await.clear();
+        self.unsent_subscriptions.lock().await.clear();
+        self.pending_subscriptions.lock().await.clear();
+        tokio::spawn(self.clone().subscribe_all(client.clone(), filters));
+    }
+
+    /// Number of connections on which the broker resumed (`session_present`) or started a
+    /// fresh session
+    pub fn session_counts(&self) -> (u64, u64) {
+        (
+            self.sessions_resumed.load(Ordering::Relaxed),
+            self.sessions_fresh.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Stop accepting new messages and wait up to `drain_timeout` for in-flight ones to be
+    /// stored, then disconnect from the broker. Returns the number of drained and dropped
+    /// messages.
+    pub async fn drain(&self, drain_timeout: Duration) -> (usize, usize) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.tasks.close();
+
+        let pending = self.tasks.len();
+        if timeout(drain_timeout, self.tasks.wait()).await.is_err() {
+            warn!("Drain period of {:?} elapsed with messages still in flight.", drain_timeout);
+        }
+        let unfinished = self.tasks.len();
+        let dropped = unfinished + self.dropped_while_draining.load(Ordering::Relaxed) as usize;
+
+        if let Some(client) = self.client.lock().await.as_ref() {
+            // Brokers only publish the will when the connection drops without a disconnect
+            if let Some(will) = &self.config.last_will {
+                if let Err(e) = client.publish(&will.topic, will.qos, will.retain, will.payload.clone()).await {
+                    warn!("Failed to publish the last will before disconnecting: {}", e);
+                }
+            }
+            if let Err(e) = client.disconnect().await {
+                warn!("Failed to disconnect from MQTT broker: {}", e);
+            }
+        }
+
+        (pending.saturating_sub(unfinished), dropped)
+    }
+
+    /// Handle an event of the event loop in a task of its own. Once draining, no task is
+    /// spawned any more and received messages are counted as dropped.
+    fn dispatch(self: &Arc<Self>, event: Event) {
+        if self.draining.load(Ordering::Relaxed) {
+            if let Event::Incoming(Packet::Publish(publish)) = &event {
+                self.dropped_while_draining.fetch_add(1, Ordering::Relaxed);
+                self.tap(publish, MessageDisposition::Dropped);
+            }
+            return;
+        }
+        let self_clone = self.clone();
+        self.tasks.spawn(async move {
+            self_clone.handle_event(event).await;
+        });
+    }
+
+    async fn handle_event(self: Arc<Self>, event: Event) {
+        if let Event::Incoming(Packet::Publish(publish)) = event {
+            self.received_messages.fetch_add(1, Ordering::Relaxed);
+            METRICS.messages_received.fetch_add(1, Ordering::Relaxed);
+            let topic = publish.topic.clone();
+            if self.is_excluded(&topic) {
+                self.tap(&publish, MessageDisposition::Excluded);
+                self.excluded_messages.fetch_add(1, Ordering::Relaxed);
+                debug!("Skipping message for excluded topic '{}'.", topic);
+                return;
+            }
+            let payload = match payload::parse(&publish.payload, &self.config.payload_limits) {
+                Ok(payload) => Some(payload),
+                // Binary payloads are stored as they are, watchers and hooks only get text
+                Err(PayloadError::InvalidUtf8(_)) => None,
+                Err(e) => {
+                    self.tap(&publish, MessageDisposition::Rejected);
+                    self.rejected_payloads.fetch_add(1, Ordering::Relaxed);
+                    warn!("Skipping message for topic '{}': {}", topic, e);
+                    return;
+                }
+            };
+            self.tap(&publish, MessageDisposition::Accepted);
+            if let Some(payload) = payload {
+                self.notify_watchers(&topic, payload).await;
+                self.hooks.message(&topic, payload);
+            }
+
+            // Überprüfen, ob ein db_service vorhanden ist
+            if let Some(db_service) = &self.db_service {
+                let payload = publish.payload.clone();
+                let broker = self.config.broker_name.clone();
+                let auto_register = self.config.auto_register;
+                let registered = db_service
+                    .clone()
+                    .blocking({
+                        let topic = topic.clone();
+                        move |db_service| store_message(db_service, &topic, &broker, &payload, auto_register)
+                    })
+                    .await;
+                if registered {
+                    publish_analytics(self.clone(), "topic_registered".to_string(), topic);
+                }
+            } else if !self.warned_without_db.swap(true, Ordering::Relaxed) {
+                // Ohne Datenbank kommen nur Kommandos an, gespeichert wird nichts
+                warn!("Received message for topic '{}' without a database, messages of this client are not stored.", topic);
+            } else {
+                debug!("Received message for topic '{}', not storing without a database.", topic);
+            }
+        }
+    }
+
+
+    /// Serialize `payload` in the configured publish format and publish it. The content
+    /// type is set from the format.
+    pub async fn publish_payload<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        qos: QoS,
+        retain: bool,
+        mut properties: PublishProperties,
+    ) {
+        let format = self.config.publish_format;
+        properties.content_type = Some(format.content_type().to_string());
+        match format.encode(payload) {
+            Ok(message) => {
+                self.publish_message_with_properties(&format.topic(topic), &message, qos, retain, &properties)
+                    .await
+            }
+            Err(e) => error!("Failed to serialize payload for topic '{}': {}", topic, e),
+        }
+    }
+
+    /// Publish with MQTT v5 properties. Under MQTT 3.1.1 the properties are dropped.
+    pub async fn publish_message_with_properties(
+        &self,
+        topic: &str,
+        message: &[u8],
+        qos: QoS,
+        retain: bool,
+        properties: &PublishProperties,
+    ) {
+        debug!(
+            "MQTT 3.1.1 has no publish properties, sending '{}' without content type {:?} and expiry {:?}.",
+            topic, properties.content_type, properties.message_expiry_interval
+        );
+        self.publish_message(topic, message, qos, retain).await
+    }
 
     pub async fn publish_message(
         &self,
         topic: &str,
-        message: &str,
+        message: &[u8],
         qos: QoS,
         retain: bool,
     ) {
+        let started = Instant::now();
         // Mehrfache Publish-Versuche (simple Retry-Logik)
-        for _ in 0..5 {
+        for attempt in 0..5 {
+            if attempt > 0 {
+                METRICS.publish_retries.fetch_add(1, Ordering::Relaxed);
+            }
             let client = self.client.lock().await;
             if let Some(client) = client.as_ref() {
                 // Falls das Topic bereits in der Config zusammengebaut wird,
                 // hier nur noch `topic.to_string()` verwenden
                 let full_topic = topic.to_string();
 
-                match client.publish(full_topic.clone(), qos, retain, message).await {
+                match client.publish(full_topic.clone(), qos, retain, message.to_vec()).await {
                     Ok(_) => {
-                        info!("Message published to '{}': {}", full_topic, message);
+                        METRICS.publish_enqueue.observe(started.elapsed());
+                        info!("Message published to '{}': {}", full_topic, String::from_utf8_lossy(message));
                         return;
                     }
                     Err(e) => {
@@ -244,9 +1397,680 @@ impl MqttService {
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
 
+        METRICS.publish_enqueue.observe(started.elapsed());
+        METRICS.publish_failures.fetch_add(1, Ordering::Relaxed);
+        self.hooks.publish_failure(topic);
         error!(
             "Failed to publish message to topic '{}' after multiple retries: {}",
-            topic, message
+            topic, String::from_utf8_lossy(message)
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::config::{BrokerConfig, BrokerConflictMode, Config};
+    use crate::sinks::HttpSinkSettings;
+    use rumqttc::Request;
+
+    /// Settings of the service for the monitored broker of `config`, as `main` builds them
+    pub fn test_mqtt_config(config: &Config) -> MqttConfig {
+        test_broker_mqtt_config(config, &config.monitored_broker())
+    }
+
+    /// Settings of a service for `broker` of `config`
+    pub fn test_broker_mqtt_config(config: &Config, broker: &BrokerConfig) -> MqttConfig {
+        let http_sinks = Arc::new(HttpSinks::new(
+            None,
+            None,
+            None,
+            HttpSinkSettings {
+                timeout: Duration::from_secs(1),
+                failure_threshold: 1,
+                cooldown: Duration::from_secs(1),
+            },
+        ));
+        let payload_limits = PayloadLimits {
+            max_bytes: config.mqtt_max_payload_bytes,
+            max_json_depth: config.mqtt_max_json_depth,
+        };
+        crate::mqtt_config(config, broker, QoS::AtLeastOnce, payload_limits, None, &http_sinks)
+    }
+
+    /// A service for the monitored broker of `config` that is never started
+    pub fn test_service(config: &Config, db_service: Option<Arc<DatabaseService>>) -> Arc<MqttService> {
+        test_service_for(config, &config.monitored_broker(), db_service)
+    }
+
+    /// A service for `broker` of `config` that is never started
+    pub fn test_service_for(
+        config: &Config,
+        broker: &BrokerConfig,
+        db_service: Option<Arc<DatabaseService>>,
+    ) -> Arc<MqttService> {
+        MqttService::new(test_broker_mqtt_config(config, broker), db_service)
+    }
+
+    /// Handle `publish` as if `service` received it from its broker
+    pub async fn receive(service: &Arc<MqttService>, publish: Publish) {
+        service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+    }
+
+    /// Start a session on the client of `attach_client`, as a ConnAck does
+    pub async fn start_session(service: &Arc<MqttService>, session_present: bool) {
+        let client = service.client.lock().await.clone().expect("a client is attached");
+        service.on_connected(&client, session_present, 0).await;
+    }
+
+    /// Mark `service` connected, as a ConnAck does
+    pub async fn mark_connected(service: &MqttService) {
+        service.set_client_state(ClientState::Connected, 0).await;
+    }
+
+    /// Send the oldest queued subscribe request as packet `pkid` and answer it with
+    /// `return_codes`, as the event loop does
+    async fn acknowledge_subscribe(service: &MqttService, pkid: u16, return_codes: Vec<SubscribeReasonCode>) {
+        service.subscription_sent(pkid).await;
+        service.record_granted_qos(&SubAck::new(pkid, return_codes)).await;
+    }
+
+    /// Mark `service` disconnected, as an event loop error of `cause` does
+    pub async fn mark_disconnected(service: &MqttService, cause: DisconnectCause) {
+        *service.last_disconnect_cause.lock().await = Some(cause);
+        service.set_client_state(ClientState::Disconnected, 0).await;
+    }
+
+    /// Give `service` a client whose requests end up in the returned receiver instead of
+    /// going to a broker
+    pub async fn attach_client(service: &MqttService) -> flume::Receiver<Request> {
+        let (sender, receiver) = flume::bounded(64);
+        *service.client.lock().await = Some(AsyncClient::from_senders(sender));
+        receiver
+    }
+
+    #[tokio::test]
+    async fn connection_state_follows_the_client_state() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        assert_eq!(service.connection_state().await, "disconnected");
+
+        for (state, name) in [
+            (ClientState::Connecting, "connecting"),
+            (ClientState::Connected, "connected"),
+            (ClientState::Error("refused".to_string()), "error"),
+            (ClientState::Disconnected, "disconnected"),
+        ] {
+            service.set_client_state(state, 0).await;
+            assert_eq!(service.connection_state().await, name);
+        }
+    }
+
+    #[tokio::test]
+    async fn runtime_filters_are_renewed_until_unsubscribed() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        let requests = attach_client(&service).await;
+
+        service.subscribe("alarms/+", QoS::ExactlyOnce).await.unwrap();
+        assert!(matches!(requests.try_recv(), Ok(Request::Subscribe(_))));
+        // A fresh session after a reconnect subscribes to it again
+        let filters = service.subscription_filters().await;
+        assert!(filters.contains(&("alarms/+".to_string(), QoS::ExactlyOnce)));
+
+        assert!(service.unsubscribe("sensors/#").await.is_err());
+        assert!(service.subscribe("alarms/#/x", QoS::AtMostOnce).await.is_err());
+        assert!(requests.is_empty());
+
+        service.unsubscribe("alarms/+").await.unwrap();
+        assert!(matches!(requests.try_recv(), Ok(Request::Unsubscribe(_))));
+        assert!(!service.subscription_filters().await.iter().any(|(filter, _)| filter == "alarms/+"));
+    }
+
+    #[tokio::test]
+    async fn watch_subscribes_until_the_last_watcher_finishes() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        let requests = attach_client(&service).await;
+
+        let first = tokio::spawn({
+            let service = service.clone();
+            async move { service.watch("sensors/a", Duration::from_secs(5)).await }
+        });
+        let second = tokio::spawn({
+            let service = service.clone();
+            async move { service.watch("sensors/a", Duration::from_millis(10)).await }
+        });
+        assert_eq!(second.await.unwrap(), Ok(None));
+        while !service.watchers.lock().await.contains_key("sensors/a") {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(service.watched_topics.lock().await.clone(), vec!["sensors/a".to_string()]);
+
+        service.notify_watchers("sensors/a", "21.5").await;
+        assert_eq!(first.await.unwrap(), Ok(Some("21.5".to_string())));
+        assert!(service.watched_topics.lock().await.is_empty());
+        let requests: Vec<Request> = requests.drain().collect();
+        assert!(matches!(requests.first(), Some(Request::Subscribe(_))));
+        assert!(matches!(requests.last(), Some(Request::Unsubscribe(unsubscribe)) if unsubscribe.topics == ["sensors/a"]));
+    }
+
+    #[tokio::test]
+    async fn watch_under_a_covering_filter_does_not_subscribe() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        let requests = attach_client(&service).await;
+        service.granted_qos.lock().await.insert("sensors/#".to_string(), Some(QoS::AtLeastOnce));
+
+        let watch = tokio::spawn({
+            let service = service.clone();
+            async move { service.watch("sensors/a", Duration::from_secs(5)).await }
+        });
+        while !service.watchers.lock().await.contains_key("sensors/a") {
+            tokio::task::yield_now().await;
+        }
+        service.notify_watchers("sensors/a", "21.5").await;
+
+        assert_eq!(watch.await.unwrap(), Ok(Some("21.5".to_string())));
+        assert!(requests.is_empty());
+        assert!(service.watched_topics.lock().await.is_empty());
+    }
+
+    fn service_with_topics(vars: &[(&str, &str)], topic_qos: &[(&str, Option<u8>)]) -> Arc<MqttService> {
+        let db_service = Arc::new(DatabaseService::in_memory());
+        for (topic, qos) in topic_qos {
+            db_service.register_topic(topic, 100).unwrap();
+            db_service.set_topic_qos(topic, *qos).unwrap();
+        }
+        test_service(&crate::config::tests::config(vars), Some(db_service))
+    }
+
+    #[tokio::test]
+    async fn subscriptions_from_the_database_replace_the_configured_filters() {
+        let config = crate::config::tests::config(&[("MQTT_SUBSCRIPTIONS_FROM_DB", "true"), ("MQTT_SUBSCRIBE_TOPICS", "lab/#")]);
+        let db = Arc::new(DatabaseService::in_memory());
+        let service = test_service(&config, Some(db.clone()));
+        // None at all subscribes to nothing rather than the whole broker
+        assert!(service.subscription_filters().await.is_empty());
+
+        let broker = &service.config.broker_name;
+        db.validate_or_add_broker(broker, broker, 1883, None, None, false, BrokerConflictMode::Ignore).unwrap();
+        let defaults = TopicDefaults { max_values: 100, query_frequency_ms: 0 };
+        for topic in ["sensors/#", "alarms/+"] {
+            db.add_subscription(broker, topic, defaults).unwrap().unwrap();
+        }
+        let filters: Vec<String> = service.subscription_filters().await.into_iter().map(|(filter, _)| filter).collect();
+        assert_eq!(filters, ["alarms/+", "sensors/#"]);
+    }
+
+    #[test]
+    fn session_settings_propagate_into_the_mqtt_options() {
+        let config = crate::config::tests::config(&[
+            ("MQTT_KEEP_ALIVE_SECS", "30"),
+            ("MQTT_CLEAN_SESSION", "false"),
+            ("MONITORED_MQTT_CLIENT_ID", "store-a"),
+        ]);
+        let service = test_service(&config, None);
+        let options = service.mqtt_options("store-a", "broker.local", 1883).unwrap();
+        assert_eq!(options.keep_alive(), Duration::from_secs(30));
+        assert!(!options.clean_session());
+        assert_eq!(options.client_id(), "store-a");
+        assert_eq!(options.broker_address(), ("broker.local".to_string(), 1883));
+
+        let options = test_service(&crate::config::tests::config(&[]), None).mqtt_options("id", "broker.local", 1883).unwrap();
+        assert_eq!(options.keep_alive(), Duration::from_secs(10));
+        assert!(options.clean_session());
+        assert!(crate::config::tests::config_with(&[("MQTT_KEEP_ALIVE_SECS", Some("0"))]).is_err());
+    }
+
+    #[test]
+    fn own_topics_are_excluded_by_default() {
+        let config = crate::config::tests::config(&[("MQTT_ROOT_TOPIC", "mf"), ("MQTT_LWT_TOPIC", "devices/mf/state")]);
+        let service = test_service(&config, None);
+        for topic in ["mf/status", "mf/logs", "mf/heartbeat/cbor", "mf/progress/msgpack", "devices/mf/state"] {
+            assert!(service.is_excluded(topic), "{}", topic);
+        }
+        assert!(!service.is_excluded("mf/commands"));
+        assert!(!service.is_excluded("sensors/a"));
+
+        let config = crate::config::tests::config(&[("MQTT_ROOT_TOPIC", "mf"), ("MQTT_EXCLUDE_OWN_TOPICS", "false")]);
+        assert!(!test_service(&config, None).is_excluded("mf/status"));
+    }
+
+    #[tokio::test]
+    async fn excluded_messages_are_counted() {
+        let config = crate::config::tests::config(&[("MQTT_ROOT_TOPIC", "mf"), ("MQTT_EXCLUDE_TOPICS", "lab/#")]);
+        let service = test_service(&config, None);
+
+        for topic in ["mf/status", "lab/scale", "sensors/a"] {
+            let publish = Publish::new(topic, QoS::AtLeastOnce, "1");
+            service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        }
+        assert_eq!(service.received_message_count(), 3);
+        assert_eq!(service.excluded_message_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn messages_are_stored_once_in_the_injected_database() {
+        let db = Arc::new(DatabaseService::in_memory());
+        db.register_topic("sensors/a", 100).unwrap();
+        let service = test_service(&crate::config::tests::config(&[]), Some(db.clone()));
+
+        let publish = Publish::new("sensors/a", QoS::AtLeastOnce, "21.5");
+        service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        assert_eq!(db.count_values("sensors/a").unwrap(), 1);
+        assert!(!service.warned_without_db.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn unknown_topics_are_registered_with_the_defaults_on_their_first_message() {
+        let config = crate::config::tests::config(&[]);
+        let db = Arc::new(DatabaseService::in_memory());
+        let mut mqtt_config = test_broker_mqtt_config(&config, &config.monitored_broker());
+        mqtt_config.auto_register = Some(TopicDefaults { max_values: 42, query_frequency_ms: 500 });
+        let service = MqttService::new(mqtt_config, Some(db.clone()));
+        let requests = attach_client(&service).await;
+
+        for payload in ["21.5", "22.0"] {
+            let publish = Publish::new("sensors/new", QoS::AtLeastOnce, payload);
+            service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        }
+        // The registration is announced once as an analytics event
+        let Ok(Request::Publish(event)) = tokio::time::timeout(Duration::from_secs(5), requests.recv_async()).await.unwrap() else {
+            panic!("an analytics event is published");
+        };
+        assert_eq!(event.topic, service.config.analytics_topic);
+        let event: serde_json::Value = serde_json::from_slice(&event.payload).unwrap();
+        assert_eq!(event, serde_json::json!({"event": "topic_registered", "details": "sensors/new"}));
+
+        let topics = db.list_topics(None).unwrap();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].topic, "sensors/new");
+        assert_eq!(topics[0].max_values, 42);
+        assert_eq!(topics[0].query_frequency_ms, 500);
+        assert_eq!(db.count_values("sensors/new").unwrap(), 2);
+
+        // Without auto-registration messages of unknown topics are dropped
+        let service = test_service(&config, Some(db.clone()));
+        let publish = Publish::new("sensors/other", QoS::AtLeastOnce, "21.5");
+        service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        assert_eq!(db.list_topics(None).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn received_and_stored_messages_show_up_in_the_rendered_metrics() {
+        // Other tests record into the same process-wide metrics, so only increases are asserted
+        let counter = |name: &str| -> u64 {
+            let rendered = METRICS.render();
+            assert!(rendered.contains(&format!("# TYPE {} counter\n", name)));
+            let sample = rendered.lines().find_map(|line| line.strip_prefix(&format!("{} ", name))).unwrap();
+            sample.parse().unwrap()
+        };
+        let db = Arc::new(DatabaseService::in_memory());
+        db.register_topic("sensors/a", 100).unwrap();
+        let service = test_service(&crate::config::tests::config(&[]), Some(db.clone()));
+        let (received, stored) = (counter("messages_received_total"), counter("messages_stored_total"));
+
+        for topic in ["sensors/a", "sensors/unregistered"] {
+            let publish = Publish::new(topic, QoS::AtLeastOnce, "21.5");
+            service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        }
+        assert!(counter("messages_received_total") >= received + 2);
+        assert!(counter("messages_stored_total") > stored);
+
+        let mut out = String::new();
+        crate::metrics::render_connection_gauge(&mut out, &[("internal", true), ("monitored", false)]);
+        assert!(out.contains("# TYPE mqtt_connected gauge\n"));
+        assert!(out.contains("mqtt_connected{service=\"internal\"} 1\n"));
+        assert!(out.contains("mqtt_connected{service=\"monitored\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn live_only_topics_reach_watchers_without_being_stored() {
+        let db = Arc::new(DatabaseService::in_memory());
+        db.register_topic("sensors/stored", 100).unwrap();
+        db.register_topic("sensors/live", 100).unwrap();
+        db.set_persist("sensors/live", false).unwrap();
+        let service = test_service(&crate::config::tests::config(&[]), Some(db.clone()));
+        let _requests = attach_client(&service).await;
+
+        let watching = tokio::spawn({
+            let service = service.clone();
+            async move { service.watch("sensors/live", Duration::from_secs(5)).await }
+        });
+        while !service.watchers.lock().await.contains_key("sensors/live") {
+            tokio::task::yield_now().await;
+        }
+        for topic in ["sensors/stored", "sensors/live"] {
+            let publish = Publish::new(topic, QoS::AtLeastOnce, "21.5");
+            service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        }
+
+        assert_eq!(watching.await.unwrap(), Ok(Some("21.5".to_string())));
+        assert_eq!(db.count_values("sensors/stored").unwrap(), 1);
+        assert_eq!(db.count_values("sensors/live").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_database_is_warned_about_once() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+
+        let publish = Publish::new("sensors/a", QoS::AtLeastOnce, "21.5");
+        service.clone().handle_event(Event::Incoming(Packet::Publish(publish.clone()))).await;
+        assert!(service.warned_without_db.load(Ordering::Relaxed));
+        // Later messages only log at debug level
+        service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        assert!(service.warned_without_db.load(Ordering::Relaxed));
+        assert_eq!(service.received_message_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn payloads_are_published_in_the_configured_format() {
+        let config = crate::config::tests::config(&[("PUBLISH_SERIALIZATION_FORMAT", "msgpack")]);
+        let service = test_service(&config, None);
+        let requests = attach_client(&service).await;
+
+        let status = crate::service_utils::StatusPayload {
+            status: "online".to_string(),
+            details: None,
+            message: None,
+        };
+        service.publish_payload("mf/status", &status, QoS::AtLeastOnce, false, PublishProperties::default()).await;
+        let Ok(Request::Publish(publish)) = requests.try_recv() else {
+            panic!("payload is published");
+        };
+        assert_eq!(publish.topic, "mf/status/msgpack");
+        let decoded: serde_json::Value = rmp_serde::from_slice(&publish.payload).unwrap();
+        assert_eq!(decoded, serde_json::json!({"status": "online"}));
+    }
+
+    #[tokio::test]
+    async fn large_topic_sets_are_subscribed_in_batches_retrying_refused_filters() {
+        let config = crate::config::tests::config(&[("MQTT_SUBSCRIBE_BATCH_SIZE", "100"), ("MQTT_RETRY_INTERVAL_MS", "100")]);
+        let service = test_service(&config, None);
+        let requests = attach_client(&service).await;
+        let client = service.client.lock().await.clone().unwrap();
+        let filters: Vec<_> = (0..250).map(|i| (format!("sensors/{}", i), QoS::AtLeastOnce)).collect();
+        let mut subscribing = tokio::spawn(service.clone().subscribe_all(client, filters));
+
+        let mut batches = Vec::new();
+        loop {
+            let request = tokio::select! {
+                finished = &mut subscribing => {
+                    finished.unwrap();
+                    break;
+                }
+                request = requests.recv_async() => request.unwrap(),
+            };
+            let Request::Subscribe(subscribe) = request else {
+                continue;
+            };
+            // The broker refuses one filter the first time
+            let codes = subscribe
+                .filters
+                .iter()
+                .map(|filter| match (filter.path.as_str(), batches.len()) {
+                    ("sensors/150", 1) => SubscribeReasonCode::Failure,
+                    _ => SubscribeReasonCode::Success(QoS::AtLeastOnce),
+                })
+                .collect();
+            batches.push(subscribe.filters.len());
+            acknowledge_subscribe(&service, batches.len() as u16, codes).await;
+        }
+
+        assert_eq!(batches, [100, 100, 50, 1]);
+        let granted_qos = service.granted_qos.lock().await;
+        assert_eq!(granted_qos.len(), 250);
+        assert!(granted_qos.values().all(|granted| *granted == Some(QoS::AtLeastOnce)));
+    }
+
+    #[tokio::test]
+    async fn subacks_are_matched_to_their_requests_by_packet_id() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        let _requests = attach_client(&service).await;
+        service.subscribe("alarms/+", QoS::AtLeastOnce).await.unwrap();
+        service.subscribe("plant/+", QoS::ExactlyOnce).await.unwrap();
+        service.subscription_sent(7).await;
+        service.subscription_sent(8).await;
+
+        // The broker answers the second request first
+        service.record_granted_qos(&SubAck::new(8, vec![SubscribeReasonCode::Success(QoS::ExactlyOnce)])).await;
+        service.record_granted_qos(&SubAck::new(7, vec![SubscribeReasonCode::Failure])).await;
+        let granted_qos = service.granted_qos.lock().await;
+        assert_eq!(granted_qos["plant/+"], Some(QoS::ExactlyOnce));
+        assert_eq!(granted_qos["alarms/+"], None);
+    }
+
+    #[tokio::test]
+    async fn failed_subscribe_requests_are_not_left_pending() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        drop(attach_client(&service).await);
+        let client = service.client.lock().await.clone().unwrap();
+
+        assert!(service.send_subscribe(&client, vec![("alarms/+".to_string(), QoS::AtLeastOnce)], None).await.is_err());
+        assert!(service.unsent_subscriptions.lock().await.is_empty());
+        assert!(service.pending_subscriptions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_are_counted_as_resumed_or_fresh() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        let requests = attach_client(&service).await;
+        let client = service.client.lock().await.clone().unwrap();
+
+        service.on_connected(&client, true, 0).await;
+        assert_eq!(service.session_counts(), (1, 0));
+        // A resumed session keeps its subscriptions
+        assert!(!requests.drain().any(|request| matches!(request, Request::Subscribe(_))));
+
+        service.on_connected(&client, false, 0).await;
+        service.on_connected(&client, false, 0).await;
+        assert_eq!(service.session_counts(), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn oversized_and_too_deep_payloads_are_rejected() {
+        let config = crate::config::tests::config(&[("MQTT_MAX_PAYLOAD_BYTES", "16"), ("MQTT_MAX_JSON_DEPTH", "2")]);
+        let service = test_service(&config, None);
+
+        for payload in ["0123456789abcdefg", "[[[1]]]", "[[1]]", "21.5"] {
+            let publish = Publish::new("sensors/a", QoS::AtLeastOnce, payload);
+            service.clone().handle_event(Event::Incoming(Packet::Publish(publish))).await;
+        }
+        assert_eq!(service.received_message_count(), 4);
+        assert_eq!(service.rejected_payload_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn drain_counts_unfinished_and_stops_dispatching() {
+        let service = test_service(&crate::config::tests::config(&[]), None);
+        service.tasks.spawn(tokio::time::sleep(Duration::from_secs(60)));
+
+        assert_eq!(service.drain(Duration::from_millis(10)).await, (0, 1));
+
+        let publish = Publish::new("sensors/a", QoS::AtLeastOnce, "21.5");
+        service.dispatch(Event::Incoming(Packet::Publish(publish)));
+        service.dispatch(Event::Incoming(Packet::PingResp));
+        assert_eq!(service.tasks.len(), 1);
+        assert_eq!(service.dropped_while_draining.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn configured_topics_are_subscribed_on_every_fresh_session() {
+        let service = service_with_topics(&[("MQTT_SUBSCRIBE_TOPICS", "sensors/#, plant/+/status")], &[]);
+        let requests = attach_client(&service).await;
+        let client = service.client.lock().await.clone().unwrap();
+
+        // Connected, then reconnected after the connection dropped
+        for pkid in 1..=2 {
+            service.on_connected(&client, false, 0).await;
+            // Skipping the connection state and birth messages published on connect
+            let subscribe = loop {
+                match timeout(Duration::from_secs(5), requests.recv_async()).await {
+                    Ok(Ok(Request::Subscribe(subscribe))) => break subscribe,
+                    Ok(Ok(_)) => continue,
+                    _ => panic!("configured topics are subscribed"),
+                }
+            };
+            let filters: Vec<_> = subscribe.filters.iter().map(|filter| filter.path.as_str()).collect();
+            assert_eq!(filters, ["sensors/#", "plant/+/status"]);
+            acknowledge_subscribe(&service, pkid, vec![SubscribeReasonCode::Success(QoS::AtLeastOnce); 2]).await;
+        }
+        assert!(!requests.drain().any(|request| matches!(request, Request::Subscribe(_))));
+    }
+
+    #[tokio::test]
+    async fn topic_qos_under_wildcard_is_not_subscribed_again() {
+        let service = service_with_topics(&[], &[("sensors/a", Some(0)), ("sensors/b", Some(2))]);
+
+        let filters = service.subscription_filters().await;
+        assert_eq!(filters, vec![("#".to_string(), QoS::AtLeastOnce)]);
+    }
+
+    #[tokio::test]
+    async fn topic_qos_replaces_exact_filter_and_adds_uncovered_topics() {
+        let service = service_with_topics(
+            &[("MQTT_SUBSCRIBE_TOPICS", "bulk/#,alarms/door")],
+            &[("alarms/door", Some(2)), ("bulk/x", Some(0)), ("other/y", Some(0))],
         );
+
+        let filters = service.subscription_filters().await;
+        assert_eq!(
+            filters,
+            vec![
+                ("bulk/#".to_string(), QoS::AtLeastOnce),
+                ("alarms/door".to_string(), QoS::ExactlyOnce),
+                ("other/y".to_string(), QoS::AtMostOnce),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn runtime_filters_covered_by_a_filter_are_skipped() {
+        let service = service_with_topics(&[("MQTT_SUBSCRIBE_TOPICS", "sensors/#")], &[]);
+        service.runtime_filters.lock().await.insert("sensors/a".to_string(), QoS::ExactlyOnce);
+        service.runtime_filters.lock().await.insert("alarms/+".to_string(), QoS::AtMostOnce);
+
+        let filters = service.subscription_filters().await;
+        assert_eq!(
+            filters,
+            vec![("sensors/#".to_string(), QoS::AtLeastOnce), ("alarms/+".to_string(), QoS::AtMostOnce)]
+        );
+    }
+
+    #[test]
+    fn last_will_is_set_per_broker_when_configured() {
+        let config = crate::config::tests::config(&[
+            ("MQTT_LWT_TOPIC", "services/state"),
+            ("MQTT_LWT_PAYLOAD", "gone"),
+            ("MQTT_LWT_QOS", "2"),
+            ("MQTT_LWT_RETAIN", "false"),
+        ]);
+        let service = test_service(&config, None);
+        let will = service.mqtt_options("client", "localhost", 1883).unwrap().last_will().unwrap();
+        assert_eq!(will.topic, "services/state/monitored");
+        assert_eq!(&will.message[..], b"gone");
+        assert_eq!(will.qos, QoS::ExactlyOnce);
+        assert!(!will.retain);
+
+        let monitored = test_mqtt_config(&config);
+        let internal = crate::mqtt_config(
+            &config,
+            &config.internal_broker(),
+            QoS::AtLeastOnce,
+            monitored.payload_limits,
+            None,
+            &monitored.http_sinks,
+        );
+        assert_eq!(internal.last_will.unwrap().topic, "services/state/internal");
+    }
+
+    #[test]
+    fn last_will_is_omitted_when_disabled() {
+        let service = test_service(&crate::config::tests::config(&[("MQTT_LWT_TOPIC", "")]), None);
+        assert!(service.mqtt_options("client", "localhost", 1883).unwrap().last_will().is_none());
+    }
+
+    #[test]
+    fn disconnect_causes_are_classified() {
+        let io = |kind| ConnectionError::Io(io::Error::new(kind, "test"));
+        let cases = [
+            (ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword), DisconnectCause::AuthFailure),
+            (ConnectionError::ConnectionRefused(ConnectReturnCode::NotAuthorized), DisconnectCause::AuthFailure),
+            (ConnectionError::ConnectionRefused(ConnectReturnCode::ServiceUnavailable), DisconnectCause::Protocol),
+            (io(io::ErrorKind::ConnectionAborted), DisconnectCause::BrokerDisconnect),
+            (io(io::ErrorKind::ConnectionRefused), DisconnectCause::Network),
+            (io(io::ErrorKind::InvalidData), DisconnectCause::Protocol),
+            (
+                ConnectionError::MqttState(StateError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "test"))),
+                DisconnectCause::BrokerDisconnect,
+            ),
+            (ConnectionError::MqttState(StateError::AwaitPingResp), DisconnectCause::Network),
+            (ConnectionError::NetworkTimeout, DisconnectCause::Network),
+            (ConnectionError::RequestsDone, DisconnectCause::Protocol),
+        ];
+        for (error, cause) in cases {
+            assert_eq!(DisconnectCause::classify(&error), cause, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn retry_interval_follows_the_disconnect_cause() {
+        let current = Duration::from_secs(1);
+        assert_eq!(DisconnectCause::AuthFailure.next_retry_interval(current), None);
+        assert_eq!(DisconnectCause::BrokerDisconnect.next_retry_interval(current), Some(current));
+        assert_eq!(DisconnectCause::Network.next_retry_interval(current), Some(current * 4));
+        assert_eq!(DisconnectCause::Tls.next_retry_interval(current), Some(current * 2));
+        assert_eq!(DisconnectCause::Network.next_retry_interval(MAX_RETRY_INTERVAL), Some(MAX_RETRY_INTERVAL));
+    }
+
+    /// Waits until `service` tries `endpoint`, failing after five seconds
+    async fn wait_for_endpoint(service: &MqttService, endpoint: &BrokerEndpoint) {
+        timeout(Duration::from_secs(5), async {
+            while service.active_endpoint().await != *endpoint {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("service never switched to {}", endpoint));
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_broker_and_back_once_the_primary_answers() {
+        // The primary refuses connections until it listens again
+        let primary = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_endpoint = BrokerEndpoint {
+            host: "127.0.0.1".to_string(),
+            port: primary.local_addr().unwrap().port(),
+        };
+        drop(primary);
+        // Accepts connections but never answers the CONNECT
+        let failover = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failover_endpoint = BrokerEndpoint {
+            host: "127.0.0.1".to_string(),
+            port: failover.local_addr().unwrap().port(),
+        };
+
+        let mut mqtt_config = test_mqtt_config(&crate::config::tests::config(&[]));
+        mqtt_config.mqtt_host = primary_endpoint.host.clone();
+        mqtt_config.mqtt_port = primary_endpoint.port;
+        mqtt_config.failover_brokers = vec![failover_endpoint.clone()];
+        mqtt_config.failover_after_attempts = 1;
+        mqtt_config.failback_check_secs = 1;
+        mqtt_config.mqtt_retry_interval_ms = 10;
+        mqtt_config.mqtt_max_retries = 0;
+        mqtt_config.srv = None;
+        let service = MqttService::new(mqtt_config, None);
+        let task = tokio::spawn({
+            let service = service.clone();
+            let primary_endpoint = primary_endpoint.clone();
+            async move { service.start(&primary_endpoint.host, primary_endpoint.port, "failover-test").await }
+        });
+
+        wait_for_endpoint(&service, &failover_endpoint).await;
+        assert_eq!(service.failed_over_to().await, Some(failover_endpoint.clone()));
+
+        let _primary = tokio::net::TcpListener::bind(("127.0.0.1", primary_endpoint.port)).await.unwrap();
+        wait_for_endpoint(&service, &primary_endpoint).await;
+        assert_eq!(service.failed_over_to().await, None);
+
+        task.abort();
+        drop(failover);
     }
 }
@@ -0,0 +1,85 @@
+use thiserror::Error;
+
+/// Bytes a PUBLISH packet needs besides its payload: fixed header, topic (at most 65535
+/// bytes plus its length prefix) and packet id
+pub const PUBLISH_OVERHEAD_BYTES: usize = 5 + 2 + 65_535 + 2;
+
+/// Bounds an incoming payload must stay within before it reaches watchers and storage
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadLimits {
+    pub max_bytes: usize,
+    /// Maximum nesting of objects and arrays in a JSON payload
+    pub max_json_depth: usize,
+}
+
+/// Why an incoming payload was skipped.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PayloadError {
+    #[error("payload of {size} bytes exceeds the limit of {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+    #[error("payload is not valid UTF-8 (at byte {0})")]
+    InvalidUtf8(usize),
+    #[error("JSON payload nests deeper than {0} levels")]
+    TooDeep(usize),
+}
+
+/// The payload as text, or why it must be skipped. Payloads looking like JSON (starting
+/// with `{` or `[`) are checked for their nesting depth without being parsed, so later
+/// parsing on the ingest path stays bounded; anything else is accepted as plain text.
+pub fn parse<'a>(payload: &'a [u8], limits: &PayloadLimits) -> Result<&'a str, PayloadError> {
+    if payload.len() > limits.max_bytes {
+        return Err(PayloadError::TooLarge {
+            size: payload.len(),
+            limit: limits.max_bytes,
+        });
+    }
+    let text = std::str::from_utf8(payload).map_err(|e| PayloadError::InvalidUtf8(e.valid_up_to()))?;
+
+    if text.trim_start().starts_with(['{', '[']) && exceeds_depth(text.as_bytes(), limits.max_json_depth) {
+        return Err(PayloadError::TooDeep(limits.max_json_depth));
+    }
+    Ok(text)
+}
+
+/// Reads the message id from a JSON payload. Strings and numbers are accepted;
+/// payloads that are not JSON objects or lack the field yield `None`.
+pub fn message_id(payload: &str, field: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(payload).ok()?;
+    match json.get(field)? {
+        serde_json::Value::String(id) => Some(id.clone()),
+        serde_json::Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Whether brackets outside of JSON strings nest deeper than `max_depth`. Unbalanced
+/// input is fine here, it's rejected by the JSON parser later on.
+fn exceeds_depth(json: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
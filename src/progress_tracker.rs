@@ -5,9 +5,12 @@ use std::fmt;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering}; // Import AtomicBool and Ordering
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{Duration, Instant};
 
-pub type SharedState = Arc<Mutex<HashMap<String, Arc<ProgressTracker>>>>;
+/// Progress trackers by task id. Lookups only take the read lock, so concurrent handlers
+/// don't serialize on it; registration and eviction take the write lock.
+pub type SharedState = Arc<RwLock<HashMap<String, Arc<ProgressTracker>>>>;
 
 pub struct ProgressTracker {
     pub(crate) total_size: Mutex<u64>,
@@ -15,6 +18,10 @@ pub struct ProgressTracker {
     mqtt_service: Arc<MqttService>,
     pub task_id: String,  // Make task_id public if needed elsewhere
     pub cancelled: AtomicBool, // Add the cancelled field
+    last_activity: Mutex<Instant>,
+    finished_at: Mutex<Option<Instant>>,
+    /// When and at which percentage progress was last published
+    last_published: Mutex<Option<(Instant, f64)>>,
 }
 
 impl ProgressTracker {
@@ -29,6 +36,9 @@ impl ProgressTracker {
             mqtt_service,
             task_id,
             cancelled: AtomicBool::new(false), // Initialize as not cancelled
+            last_activity: Mutex::new(Instant::now()),
+            finished_at: Mutex::new(None),
+            last_published: Mutex::new(None),
         }
     }
 
@@ -40,15 +50,30 @@ impl ProgressTracker {
     /// Stop the progress tracker
     pub async fn stop(&self) {
         self.cancelled.store(true, Ordering::SeqCst);
+        self.mark_finished().await;
         info!("Progress tracker for task {} marked as stopped.", self.task_id);
 
         // Optionally publish a progress update or a cancellation event
         publish_progress(self.mqtt_service.clone(), 0, 0); // Example reset progress
     }
 
+    /// Remember when the task was cancelled or completed, for TTL-based eviction
+    pub(crate) async fn mark_finished(&self) {
+        let mut finished_at = self.finished_at.lock().await;
+        if finished_at.is_none() {
+            *finished_at = Some(Instant::now());
+        }
+    }
+
+    /// Whether the task was cancelled or completed
+    pub async fn is_finished(&self) -> bool {
+        self.finished_at.lock().await.is_some()
+    }
+
     pub async fn set_total_size(&self, size: u64) {
         let mut total_size = self.total_size.lock().await;
         *total_size = size;
+        *self.last_activity.lock().await = Instant::now();
         info!(
             "Set total size for task {}: {} bytes",
             self.task_id, size
@@ -64,6 +89,11 @@ impl ProgressTracker {
         let mut uploaded_size = self.uploaded_size.lock().await;
         let total_size = *self.total_size.lock().await;
         *uploaded_size += bytes_uploaded;
+        *self.last_activity.lock().await = Instant::now();
+        let completed = total_size > 0 && *uploaded_size >= total_size;
+        if completed {
+            self.mark_finished().await;
+        }
 
         let progress_percentage = if total_size > 0 {
             (*uploaded_size as f64 / total_size as f64) * 100.0
@@ -71,6 +101,11 @@ impl ProgressTracker {
             0.0
         };
 
+        // The final update always goes out, intermediate ones are throttled
+        if !self.publish_due(progress_percentage).await && !completed {
+            return;
+        }
+
         info!(
             "Progress update for task {}: {:.2}% uploaded",
             self.task_id, progress_percentage
@@ -83,6 +118,87 @@ impl ProgressTracker {
             total_size,
         );
     }
+
+    /// Whether an update at `percentage` should be published: the first one, or once
+    /// `progress_publish_interval_ms` passed or the percentage moved by at least
+    /// `progress_publish_step_percent` since the last published update. Records the
+    /// update as published when it is due.
+    async fn publish_due(&self, percentage: f64) -> bool {
+        let config = &self.mqtt_service.config;
+        let interval = Duration::from_millis(config.progress_publish_interval_ms);
+        let step = config.progress_publish_step_percent;
+
+        let mut last_published = self.last_published.lock().await;
+        let due = match *last_published {
+            None => true,
+            Some((published_at, published_percentage)) => {
+                published_at.elapsed() >= interval
+                    || (step > 0.0 && (percentage - published_percentage).abs() >= step)
+            }
+        };
+        if due {
+            *last_published = Some((Instant::now(), percentage));
+        }
+        due
+    }
+}
+
+/// Register a tracker in the shared state. When the map already holds `max_entries`
+/// trackers, the least recently active ones are evicted to make room.
+pub async fn register_tracker(state: &SharedState, tracker: Arc<ProgressTracker>, max_entries: usize) {
+    let mut trackers = state.write().await;
+    if !trackers.contains_key(&tracker.task_id) {
+        evict_least_recently_active(&mut trackers, max_entries.saturating_sub(1)).await;
+    }
+    trackers.insert(tracker.task_id.clone(), tracker);
+}
+
+/// Remove trackers that were cancelled or completed more than `ttl` ago and enforce the
+/// `max_entries` cap. Returns the number of evicted trackers.
+pub async fn evict_trackers(state: &SharedState, ttl: Duration, max_entries: usize) -> usize {
+    let mut trackers = state.write().await;
+    let before = trackers.len();
+
+    let mut expired = Vec::new();
+    for (task_id, tracker) in trackers.iter() {
+        if let Some(finished_at) = *tracker.finished_at.lock().await {
+            if finished_at.elapsed() >= ttl {
+                expired.push(task_id.clone());
+            }
+        }
+    }
+    for task_id in expired {
+        trackers.remove(&task_id);
+    }
+
+    evict_least_recently_active(&mut trackers, max_entries).await;
+
+    let evicted = before - trackers.len();
+    if evicted > 0 {
+        info!("Evicted {} progress tracker(s), {} remaining.", evicted, trackers.len());
+    }
+    evicted
+}
+
+async fn evict_least_recently_active(
+    trackers: &mut HashMap<String, Arc<ProgressTracker>>,
+    max_entries: usize,
+) {
+    if trackers.len() <= max_entries {
+        return;
+    }
+
+    let mut by_activity = Vec::with_capacity(trackers.len());
+    for (task_id, tracker) in trackers.iter() {
+        by_activity.push((*tracker.last_activity.lock().await, task_id.clone()));
+    }
+    by_activity.sort();
+
+    let excess = trackers.len() - max_entries;
+    for (_, task_id) in by_activity.into_iter().take(excess) {
+        info!("Progress tracker map is full, evicting task {}.", task_id);
+        trackers.remove(&task_id);
+    }
 }
 
 // Implement Debug for ProgressTracker
@@ -96,3 +212,61 @@ impl fmt::Debug for ProgressTracker {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_service::tests::{attach_client, test_service};
+    use rumqttc::Request;
+
+    /// Percentages of the progress updates published to `requests`, once the spawned
+    /// publishes went out
+    async fn published(requests: &flume::Receiver<Request>) -> Vec<f64> {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        requests
+            .drain()
+            .filter_map(|request| match request {
+                Request::Publish(publish) => {
+                    let payload: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+                    payload["percentage"].as_f64()
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn tracker(vars: &[(&str, &str)]) -> (ProgressTracker, flume::Receiver<Request>) {
+        let service = test_service(&crate::config::tests::config(vars), None);
+        let requests = attach_client(&service).await;
+        (ProgressTracker::new(100, service, "upload".to_string()), requests)
+    }
+
+    #[tokio::test]
+    async fn intermediate_updates_are_throttled_but_the_final_one_goes_out() {
+        let (tracker, requests) = tracker(&[
+            ("PROGRESS_PUBLISH_INTERVAL_MS", "60000"),
+            ("PROGRESS_PUBLISH_STEP_PERCENT", "0"),
+        ])
+        .await;
+
+        for _ in 0..100 {
+            tracker.update_progress(1).await;
+        }
+        assert_eq!(published(&requests).await, [1.0, 100.0]);
+        assert!(tracker.is_finished().await);
+    }
+
+    #[tokio::test]
+    async fn updates_are_published_every_step() {
+        let (tracker, requests) = tracker(&[
+            ("PROGRESS_PUBLISH_INTERVAL_MS", "60000"),
+            ("PROGRESS_PUBLISH_STEP_PERCENT", "25"),
+        ])
+        .await;
+
+        for _ in 0..10 {
+            tracker.update_progress(10).await;
+        }
+        assert_eq!(published(&requests).await, [10.0, 40.0, 70.0, 100.0]);
+    }
+}
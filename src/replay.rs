@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use crate::db::DatabaseService;
+use crate::progress_tracker::ProgressTracker;
+use crate::tls;
+
+/// Number of stored values read from the database per batch
+const REPLAY_BATCH_SIZE: usize = 500;
+/// How long to wait for the target broker to accept or finish the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Broker the stored values are replayed into
+#[derive(Debug, Clone)]
+pub struct ReplayTarget {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// CA certificate enabling TLS to the target broker
+    pub ca_cert_path: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub target: ReplayTarget,
+    /// MQTT filter selecting the topics to replay
+    pub topic_filter: String,
+    /// Publish only the latest value of each topic instead of its full history
+    pub latest_only: bool,
+    pub retain: bool,
+}
+
+/// Publish the stored values selected by `options` to the target broker through a
+/// temporary client, reporting progress via `tracker`. Stops early when the tracker is
+/// cancelled. Binary values are published as the bytes they were received as. Returns
+/// the number of published values.
+pub async fn replay_to_broker(
+    db: Arc<DatabaseService>,
+    tracker: Arc<ProgressTracker>,
+    options: ReplayOptions,
+) -> Result<u64, String> {
+    let topics = db.find_topics(&options.topic_filter).map_err(|e| e.to_string())?;
+    let total = if options.latest_only {
+        topics.len() as u64
+    } else {
+        let mut total = 0;
+        for topic in &topics {
+            total += db.count_values(topic).map_err(|e| e.to_string())? as u64;
+        }
+        total
+    };
+    tracker.set_total_size(total).await;
+
+    let (client, connection) = connect(&options.target, &tracker.task_id).await?;
+    let mut published = 0;
+
+    'topics: for topic in &topics {
+        if options.latest_only {
+            if let Some((value, _)) = db.get_last_value_bytes(topic).map_err(|e| e.to_string())? {
+                publish(&client, topic, value, options.retain).await?;
+                published += 1;
+            }
+            tracker.update_progress(1).await;
+        } else {
+            let mut after_id = 0;
+            loop {
+                let rows = db
+                    .get_values_after(topic, after_id, REPLAY_BATCH_SIZE)
+                    .map_err(|e| e.to_string())?;
+                let Some(last) = rows.last() else {
+                    break;
+                };
+                after_id = last.id;
+
+                let batch_len = rows.len() as u64;
+                for row in rows {
+                    publish(&client, &row.topic, row.bytes(), options.retain).await?;
+                }
+                published += batch_len;
+                tracker.update_progress(batch_len).await;
+
+                if tracker.is_cancelled() {
+                    break 'topics;
+                }
+            }
+        }
+
+        if tracker.is_cancelled() {
+            break;
+        }
+    }
+
+    tracker.mark_finished().await;
+    if tracker.is_cancelled() {
+        warn!("Replay {} cancelled after {} values.", tracker.task_id, published);
+    } else {
+        info!("Replay {} published {} values.", tracker.task_id, published);
+    }
+
+    // Queued publishes are sent before the disconnect request, wait until they're out
+    if let Err(e) = client.disconnect().await {
+        warn!("Failed to disconnect replay client: {}", e);
+    }
+    let _ = tokio::time::timeout(CONNECT_TIMEOUT, connection).await;
+    Ok(published)
+}
+
+/// Connect the temporary client and drive its event loop in the background. The loop
+/// ends with the connection, after which publishing through the client fails.
+async fn connect(target: &ReplayTarget, task_id: &str) -> Result<(AsyncClient, JoinHandle<()>), String> {
+    let client_id = format!("monitorflux_replay_{}", Uuid::new_v4());
+    let mut mqtt_options = MqttOptions::new(client_id, target.host.clone(), target.port);
+    mqtt_options.set_keep_alive(Duration::from_secs(10));
+    mqtt_options.set_clean_session(true);
+
+    if let (Some(username), Some(password)) = (&target.username, &target.password) {
+        mqtt_options.set_credentials(username, password);
+    }
+    if let Some(ca_cert_path) = &target.ca_cert_path {
+        let tls_config = tls::mqtt_tls_config(ca_cert_path, &[]).map_err(|e| e.to_string())?;
+        mqtt_options.set_transport(Transport::tls_with_config(tls_config));
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+
+    // Publishes would only be queued until the connection fails, so wait for the ConnAck
+    let connected = tokio::time::timeout(CONNECT_TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) => return Err(format!("Failed to connect to target broker: {}", e)),
+            }
+        }
+    })
+    .await
+    .map_err(|_| "Timed out connecting to target broker".to_string())?;
+    connected?;
+
+    let task_id = task_id.to_string();
+    let connection = tokio::spawn(async move {
+        loop {
+            if let Err(e) = eventloop.poll().await {
+                // A clean disconnect also ends the loop with an error
+                info!("Replay {} connection closed: {:?}", task_id, e);
+                break;
+            }
+        }
+    });
+
+    Ok((client, connection))
+}
+
+async fn publish(client: &AsyncClient, topic: &str, value: Vec<u8>, retain: bool) -> Result<(), String> {
+    client
+        .publish(topic, QoS::AtLeastOnce, retain, value)
+        .await
+        .map_err(|e| {
+            error!("Failed to replay value for topic '{}': {}", topic, e);
+            format!("Target broker connection failed: {}", e)
+        })
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// Resource usage of this process. Read from `/proc`, so both fields are `None` on
+/// systems without it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    /// Resident set size
+    pub rss_bytes: Option<u64>,
+    /// CPU time spent by all threads since the process started
+    pub cpu_time: Option<Duration>,
+}
+
+impl ResourceUsage {
+    pub fn sample() -> Self {
+        Self {
+            rss_bytes: read_rss_bytes(),
+            cpu_time: read_cpu_time(),
+        }
+    }
+
+    /// CPU usage between `earlier` and this sample taken `elapsed` apart, in percent of
+    /// one core
+    pub fn cpu_percent_since(&self, earlier: &ResourceUsage, elapsed: Duration) -> Option<f64> {
+        let used = self.cpu_time?.checked_sub(earlier.cpu_time?)?;
+        (!elapsed.is_zero()).then(|| used.as_secs_f64() / elapsed.as_secs_f64() * 100.0)
+    }
+}
+
+/// `VmRSS:  123456 kB` from `/proc/self/status`
+fn read_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Clock ticks per second of the times in `/proc/self/stat`, `USER_HZ` is 100 on all
+/// common Linux architectures
+const CLOCK_TICKS_PER_SEC: u64 = 100;
+
+/// User and system time (fields 14 and 15) of `/proc/self/stat`, summed over all threads
+fn read_cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name in parentheses may contain spaces, fields are counted after it
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_millis((utime + stime) * 1000 / CLOCK_TICKS_PER_SEC))
+}
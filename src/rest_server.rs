@@ -1,19 +1,64 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::http::Status;
+use rocket::http::{ContentType, Method, Status};
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::{get, post, routes, State};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::stream::{Event, EventStream, TextStream};
+use rocket::response::status::{Accepted, Created};
+use rocket::{catch, catchers, delete, get, options, post, put, routes, Build, Either, Request, Rocket, Shutdown, State};
 use rocket::figment::Figment;
 use rusqlite::Result;
-use crate::config::Config;
+use time::{Duration, OffsetDateTime};
+use crate::archive::Archive;
+use crate::auth::{hash_password, AuthBackend, AuthError, Credentials, DatabaseUsers, StaticCredentials};
+use crate::config::{AuthBackendKind, Config, RootSummaryField};
 use crate::db::DatabaseService;
+use crate::log_stream::LogStream;
+use crate::materialize::{self, MaterializedColumn, MaterializedRow};
+use crate::metrics::{render_connection_gauge, render_granted_qos, render_service_counter, METRICS};
+use crate::models::{Aggregation, Broker, TopicDefaults, TopicRegistration, UnitRule, ValueRow, ValueType};
+use crate::mqtt_service::{ClientState, MqttService};
+use crate::progress_tracker::{register_tracker, ProgressTracker, SharedState};
+use crate::replay::{self, ReplayOptions, ReplayTarget};
+use crate::schema::{self, TopicSchema};
+use crate::time_range::{format_timestamp, parse_timestamp, rejection_message, OpenTimeRangeQuery, SinceQuery, TimeRange, TimeRangeQuery};
+use crate::topic_filter;
+use log::error;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+/// Upper bound for the number of buckets a downsample request may ask for
+const MAX_DOWNSAMPLE_POINTS: usize = 10_000;
+/// Upper bound for the topics a single `/query` may cover
+const MAX_QUERY_TOPICS: usize = 100;
+/// A topic is stale once its last value is older than this many expected intervals
+const DEFAULT_STALE_FACTOR: f64 = 3.0;
+/// Recent values per topic used to estimate its interval when none is configured
+const HEALTH_INTERVAL_SAMPLE: usize = 100;
+/// Values kept for a topic registered by watching it
+const WATCHED_TOPIC_MAX_VALUES: usize = 1_000;
+/// Rows read from the database per page when streaming a response
+const STREAM_PAGE_ROWS: usize = 500;
+/// Recent values per topic inspected to infer its schema, and how long the result is reused
+const SCHEMA_SAMPLE_VALUES: usize = 100;
+const SCHEMA_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Default and maximum number of entries returned by `/admin/audit`
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const MAX_AUDIT_LIMIT: usize = 1_000;
+
+/// Routes that use POST without changing any state and are therefore not audited
+const READ_ONLY_POSTS: &[&str] = &["/query"];
 
 /// API Request payload
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct ApiRequest {
     action: String,
-    data: Option<String>,
 }
 
 /// API Response
@@ -31,134 +76,4151 @@ struct LastValueResponse {
     topic: String,
     value: String,
     timestamp: String,
+    /// `value` is a payload that wasn't UTF-8, base64 encoded
+    is_binary: bool,
+    /// The payload as received, when a unit rule converted `value`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_value: Option<String>,
 }
 
-/// Struct for multiple values response
+/// Struct for the first value received for a watched topic
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
-struct LastValuesResponse {
+struct WatchResponse {
     topic: String,
-    values: Vec<(String, String)>, // Vec<(value, timestamp)>
+    value: String,
+    /// Whether the topic was registered by this request
+    registered: bool,
 }
 
-/// CORS Fairing with Config support
-pub struct Cors {
-    allowed_origins: Vec<String>,
+/// Struct for a single stored value addressed by id
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ValueResponse {
+    id: i64,
+    topic: String,
+    value: String,
+    timestamp: String,
+    /// `value` is a payload that wasn't UTF-8, base64 encoded
+    is_binary: bool,
+    /// The payload as received, when a unit rule converted `value`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_value: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
 }
 
-impl Cors {
-    pub fn new(config: &Config) -> Self {
+impl From<ValueRow> for ValueResponse {
+    fn from(row: ValueRow) -> Self {
         Self {
-            allowed_origins: config.cors_allowed_origins.clone(),
+            id: row.id,
+            topic: row.topic,
+            value: row.value,
+            timestamp: row.timestamp,
+            is_binary: row.is_binary,
+            raw_value: row.raw_value,
+            labels: BTreeMap::new(),
         }
     }
 }
 
-#[rocket::async_trait]
-impl Fairing for Cors {
-    fn info(&self) -> Info {
-        Info {
-            name: "CORS",
-            kind: Kind::Response,
+/// Payload for manually inserting a value
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct InsertValueRequest {
+    value: String,
+    /// RFC 3339 or `YYYY-MM-DD HH:MM:SS` (UTC), the current time when omitted
+    timestamp: Option<String>,
+    /// Key/value labels the value can be filtered by, see `last_values`
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// Payload for publishing a message through the internal broker
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PublishRequest {
+    topic: String,
+    payload: String,
+    /// 0, 1 or 2, 0 when omitted
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+}
+
+/// Payload for renaming a topic
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RenameTopicRequest {
+    new_topic: String,
+}
+
+/// Unit conversion of a topic, `value * scale + offset`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UnitRuleDto {
+    scale: f64,
+    #[serde(default)]
+    offset: f64,
+    /// Also store the received value
+    #[serde(default)]
+    keep_raw: bool,
+}
+
+/// JSON field holding a unique message id, values with an id stored before are ignored
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct MessageIdFieldDto {
+    field: String,
+}
+
+/// Minimum time between two stored values of a topic, 0 to store every value
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct StoreIntervalDto {
+    min_store_interval_ms: u64,
+}
+
+/// Every how many values a delta-stored topic stores one in full, 0 without delta storage
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeltaStorageDto {
+    snapshot_interval: u32,
+}
+
+/// Declared type of a topic's values: number, boolean, string or enum
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ValueTypeDto {
+    value_type: String,
+}
+
+/// QoS a topic is subscribed with, None for the configured default
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TopicQosDto {
+    qos: Option<u8>,
+}
+
+/// Whether values of a topic received over MQTT are stored or only passed to live
+/// consumers
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PersistenceDto {
+    persist: bool,
+}
+
+/// How long values of a topic are kept, 0 for as long as `max_values` allows
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RetentionDto {
+    retention_ms: u64,
+}
+
+/// Top-level JSON fields of a topic copied into typed columns, field to column name
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct MaterializationDto {
+    columns: BTreeMap<String, String>,
+}
+
+/// Typed rows of a topic's materialized table, keyed by column name next to `value_id`
+/// and `timestamp`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MaterializedResponse {
+    topic: String,
+    columns: Vec<MaterializedColumn>,
+    rows: Vec<MaterializedRow>,
+}
+
+/// Inferred schema of a topic next to the type it was declared with
+#[derive(Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+struct TopicSchemaResponse {
+    topic: String,
+    declared_type: Option<String>,
+    #[serde(flatten)]
+    schema: TopicSchema,
+}
+
+/// Pre-aggregation window of a topic, `0` when every value is stored
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PreAggregationDto {
+    window_secs: u64,
+}
+
+/// Struct for multiple values response
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LastValuesResponse {
+    topic: String,
+    values: Vec<TimestampedValue>,
+}
+
+/// A value with its timestamp, serialized as `[value, timestamp]`. Binary values, base64
+/// encoded, get `"base64"` as a third element.
+struct TimestampedValue {
+    value: String,
+    timestamp: String,
+    is_binary: bool,
+}
+
+impl From<ValueRow> for TimestampedValue {
+    fn from(row: ValueRow) -> Self {
+        Self {
+            value: row.value,
+            timestamp: row.timestamp,
+            is_binary: row.is_binary,
         }
     }
+}
 
-    async fn on_response<'r>(&self, req: &'r rocket::Request<'_>, res: &mut rocket::Response<'r>) {
-        if let Some(origin) = req.headers().get_one("Origin") {
-            if self.allowed_origins.contains(&origin.to_string())
-                || self.allowed_origins.contains(&"*".to_string())
-            {
-                res.set_header(rocket::http::Header::new("Access-Control-Allow-Origin", origin));
-            }
+impl Serialize for TimestampedValue {
+    fn serialize<S: rocket::serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.is_binary {
+            (&self.value, &self.timestamp, "base64").serialize(serializer)
+        } else {
+            (&self.value, &self.timestamp).serialize(serializer)
         }
-        res.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Methods",
-            "GET, POST",
-        ));
-        res.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Headers",
-            "Content-Type",
-        ));
     }
 }
 
-/// Get the last value of a topic
-#[get("/topics/<topic>/last")]
-fn last_value(
+/// A numeric value at a point in time
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct NumericPointDto {
+    value: f64,
+    timestamp: String,
+}
+
+/// Struct for the delta of a counter over a range
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct DeltaResponse {
     topic: String,
-    db: &State<DatabaseService>,
-) -> Result<Json<LastValueResponse>, Status> {
-    match db.get_last_value(&topic) {
-        Ok(Some((value, timestamp))) => Ok(Json(LastValueResponse {
-            topic,
-            value,
-            timestamp,
-        })),
-        Ok(None) => Err(Status::NotFound),
-        Err(_) => Err(Status::InternalServerError),
-    }
+    first: NumericPointDto,
+    last: NumericPointDto,
+    /// `last - first`, negative when the counter was reset in between
+    delta: f64,
+    reset_detected: bool,
+}
+
+/// Single bucket of a downsampled range
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct DownsampledValueDto {
+    timestamp: String,
+    value: String,
+    count: usize,
 }
 
-/// Get the last `n` values of a topic
-#[get("/topics/<topic>/values?<limit>")]
-fn last_values(
+/// Struct for downsampled values response
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct DownsampleResponse {
     topic: String,
-    limit: Option<usize>,
-    db: &State<DatabaseService>,
-) -> Result<Json<LastValuesResponse>, Status> {
-    let limit = limit.unwrap_or(10); // Default limit is 10
-    match db.get_last_values(&topic, limit) {
-        Ok(values) => Ok(Json(LastValuesResponse { topic, values })),
-        Err(_) => Err(Status::InternalServerError),
+    points: Vec<DownsampledValueDto>,
+}
+
+/// A value of topic `a` and the nearest value of topic `b`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct JoinedPairDto {
+    timestamp_a: String,
+    value_a: String,
+    timestamp_b: String,
+    value_b: String,
+}
+
+/// Struct for the time-aligned values of two topics
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct JoinResponse {
+    a: String,
+    b: String,
+    tolerance_ms: u64,
+    pairs: Vec<JoinedPairDto>,
+}
+
+/// Struct for the root summary, disabled details are left out
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ServiceSummary {
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connected_brokers: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topics: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uptime_secs: Option<u64>,
+}
+
+/// Connection state of one MQTT service
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MqttConnectionDto {
+    /// `disconnected`, `connecting`, `connected` or `error`
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Why the connection was last lost, see `DisconnectCause::name`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_disconnect_cause: Option<&'static str>,
+    /// `host:port` of the broker connected to or being tried
+    active_endpoint: String,
+    /// `host:port` of the failover broker in use, none while on the primary one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_over_to: Option<String>,
+}
+
+impl MqttConnectionDto {
+    async fn of(service: &MqttService) -> Self {
+        let state = service.client_state().await;
+        Self {
+            state: state.name(),
+            error: match state {
+                ClientState::Error(reason) => Some(reason),
+                _ => None,
+            },
+            last_disconnect_cause: service.last_disconnect_cause().await.map(|cause| cause.name()),
+            active_endpoint: service.active_endpoint().await.to_string(),
+            failed_over_to: service.failed_over_to().await.map(|endpoint| endpoint.to_string()),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state == ClientState::Connected.name()
     }
 }
 
-/// Root handler
-#[get("/")]
-fn root_handler(config: &State<Config>) -> Json<ApiResponse> {
-    Json(ApiResponse {
-        status: "success".to_string(),
-        message: format!(
-            "Welcome to the REST API running on {}:{}!",
-            config.rest_api_host, config.rest_api_port
-        ),
-    })
+/// Connection state of every MQTT service for `/health/mqtt`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MqttHealthDto {
+    internal: MqttConnectionDto,
+    monitored: MqttConnectionDto,
+    /// The services of MONITORED_BROKER_<n>_*, by broker
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    additional_monitored: Vec<BrokerConnectionDto>,
 }
 
-/// Action handler
-#[post("/action", data = "<payload>")]
-fn action_handler(payload: Json<ApiRequest>, config: &State<Config>) -> Result<Json<ApiResponse>, Status> {
-    if config.rest_api_auth_enabled {
-        return Ok(Json(ApiResponse {
-            status: "error".to_string(),
-            message: "Authentication required but not implemented.".to_string(),
-        }));
+/// Connection state of one of several storing MQTT services for `/health/mqtt`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BrokerConnectionDto {
+    broker: String,
+    #[serde(flatten)]
+    connection: MqttConnectionDto,
+}
+
+/// Readiness of the service for `/health`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct HealthDto {
+    /// `ok` or `error`
+    db: &'static str,
+    /// Connection state, see `MqttConnectionDto`
+    mqtt_internal: &'static str,
+    mqtt_monitored: &'static str,
+    /// Connection state of the services of MONITORED_BROKER_<n>_*, by broker
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mqtt_additional_monitored: Vec<BrokerStateDto>,
+    /// Components keeping the service from being ready
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failing: Vec<&'static str>,
+}
+
+/// Connection state of one of several storing MQTT services
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BrokerStateDto {
+    broker: String,
+    /// See `MqttConnectionDto`
+    state: &'static str,
+}
+
+/// Both MQTT services, `mqtt_service` alone is the internal one
+pub struct Brokers {
+    pub internal: Arc<MqttService>,
+    pub monitored: Arc<MqttService>,
+    /// Services of MONITORED_BROKER_<n>_*, storing like `monitored`
+    pub additional_monitored: Vec<Arc<MqttService>>,
+}
+
+impl Brokers {
+    /// Every service with the `service` label of its metrics: `internal`, `monitored`
+    /// and the broker name of each additional one
+    fn labeled(&self) -> impl Iterator<Item = (&str, &Arc<MqttService>)> {
+        [("internal", &self.internal), ("monitored", &self.monitored)]
+            .into_iter()
+            .chain(self.additional_monitored.iter().map(|service| (service.broker_name(), service)))
     }
-    match payload.action.as_str() {
-        "ping" => Ok(Json(ApiResponse {
-            status: "success".to_string(),
-            message: "pong".to_string(),
-        })),
-        _ => Ok(Json(ApiResponse {
-            status: "error".to_string(),
-            message: "Unknown action".to_string(),
-        })),
+
+    /// The storing service connected to the broker `name`
+    fn monitored_by_name(&self, name: &str) -> Option<&Arc<MqttService>> {
+        std::iter::once(&self.monitored)
+            .chain(&self.additional_monitored)
+            .find(|service| service.broker_name() == name)
     }
 }
 
-/// Run the Rocket server with the provided DatabaseService and Config
-pub async fn run_rest_server(db_service: Arc<DatabaseService>, config: Config) {
-    let figment = Figment::from(rocket::Config::default())
-        .merge(("address", config.rest_api_host.clone()))
-        .merge(("port", config.rest_api_port));
+/// When the REST API was started
+struct StartedAt(std::time::Instant);
 
-    rocket::custom(figment)
-        .manage(db_service.clone()) // DatabaseService korrekt registrieren
-        .manage(config.clone())    // Config korrekt registrieren
-        .mount("/", routes![root_handler, action_handler, last_value, last_values])
-        .attach(Cors::new(&config))
-        .launch()
-        .await
-        .unwrap_or_else(|e| panic!("Failed to launch Rocket server: {:?}", e));
+/// Open `GET /debug/tail` sessions, capped at DEBUG_TAIL_MAX_SESSIONS
+struct TailSessions {
+    open: AtomicUsize,
+    max: usize,
+}
+
+impl TailSessions {
+    /// Opens a session, `None` when the cap is reached. It closes when dropped.
+    fn open(self: &Arc<Self>) -> Option<TailSession> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| (open < self.max).then_some(open + 1))
+            .ok()
+            .map(|_| TailSession(self.clone()))
+    }
+}
+
+struct TailSession(Arc<TailSessions>);
+
+impl Drop for TailSession {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Recently inferred topic schemas and when they were inferred
+#[derive(Default)]
+struct SchemaCache(Mutex<HashMap<String, (std::time::Instant, TopicSchemaResponse)>>);
+
+/// Query payload for `/query`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct QueryRequest {
+    /// Topic name or MQTT filter (`+`/`#` wildcards)
+    topic: String,
+    from: String,
+    to: String,
+    aggregation: Aggregation,
+    /// Bucket width; the whole range forms one bucket when omitted
+    bucket_seconds: Option<u64>,
+}
+
+/// Single aggregated bucket of a query result
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AggregateBucketDto {
+    timestamp: String,
+    value: Option<f64>,
+    count: usize,
+    /// Values per category for `enum` topics
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    categories: BTreeMap<String, usize>,
+}
+
+/// Query result for one matched topic
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TopicQueryResult {
+    topic: String,
+    buckets: Vec<AggregateBucketDto>,
+}
+
+/// Struct for query response
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct QueryResponse {
+    results: Vec<TopicQueryResult>,
+}
+
+/// A registered topic
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TopicDto {
+    id: i64,
+    topic: String,
+    parent_topic: Option<String>,
+    max_values: usize,
+    query_frequency_ms: u64,
+}
+
+/// Count, minimum, maximum and average of the numeric values of a topic
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TopicStatsDto {
+    topic: String,
+    count: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+    last: Option<f64>,
+}
+
+/// Storage health of a single topic
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TopicHealthDto {
+    topic: String,
+    last_received_at: Option<String>,
+    seconds_since_last: Option<i64>,
+    /// `query_frequency_ms`, or the observed median interval when that is 0
+    expected_interval_ms: Option<u64>,
+    /// No value yet, or none within `factor` expected intervals
+    stale: bool,
+}
+
+/// Storage usage for `/admin/storage`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct StorageDto {
+    topics: usize,
+    /// None without a limit
+    max_topics: Option<usize>,
+    /// New topics refused or old ones evicted at the limit since the start
+    topics_rejected: u64,
+    topics_evicted: u64,
+    database_size_bytes: u64,
+}
+
+/// An archive file holding the values of one day
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ArchiveFileDto {
+    /// `YYYY-MM-DD`, or `undated` for values whose timestamp has no date
+    day: String,
+    file: String,
+    size_bytes: u64,
+}
+
+/// Archive files and the age at which values are archived
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ArchivesResponse {
+    /// None while archiving is disabled
+    archive_after_secs: Option<u64>,
+    files: Vec<ArchiveFileDto>,
+}
+
+/// Single bucket of the ingest rate response
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct IngestRateDto {
+    timestamp: String,
+    count: usize,
+}
+
+/// Struct for audit log entries
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AuditEntryDto {
+    id: i64,
+    actor: String,
+    action: String,
+    target: String,
+    outcome: String,
+    timestamp: String,
 }
 
+/// Replay request for `/admin/replay-to-broker`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ReplayRequest {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    /// CA certificate enabling TLS to the target broker
+    ca_cert_path: Option<String>,
+    /// Topic name or MQTT filter, all topics when omitted
+    topic: Option<String>,
+    /// Replay every stored value instead of only the latest per topic
+    full_history: Option<bool>,
+    /// Defaults to true so the target broker serves the values to new subscribers
+    retain: Option<bool>,
+}
+
+/// State of a replay task
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ReplayTaskDto {
+    task_id: String,
+    published: u64,
+    total: u64,
+    finished: bool,
+    cancelled: bool,
+}
+
+impl ReplayTaskDto {
+    async fn from_tracker(tracker: &ProgressTracker) -> Self {
+        Self {
+            task_id: tracker.task_id.clone(),
+            published: *tracker.uploaded_size.lock().await,
+            total: *tracker.total_size.lock().await,
+            finished: tracker.is_finished().await,
+            cancelled: tracker.is_cancelled(),
+        }
+    }
+}
+
+/// Broker payload for `POST /brokers`, creating or updating the broker of that name
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SaveBrokerRequest {
+    name: String,
+    host: String,
+    port: u16,
+    username: Option<String>,
+    /// Write-only, omitting it keeps the stored password of an existing broker
+    password: Option<String>,
+    #[serde(default)]
+    tls_enabled: bool,
+}
+
+/// Struct for brokers, never including the password
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BrokerDto {
+    id: i64,
+    name: String,
+    host: String,
+    port: u16,
+    username: Option<String>,
+    has_password: bool,
+    tls_enabled: bool,
+}
+
+impl From<Broker> for BrokerDto {
+    fn from(broker: Broker) -> Self {
+        BrokerDto {
+            id: broker.id,
+            name: broker.name,
+            host: broker.host,
+            port: broker.port,
+            username: broker.username,
+            has_password: broker.password.is_some(),
+            tls_enabled: broker.tls_enabled,
+        }
+    }
+}
+
+/// Subscription payload for `POST /brokers/<name>/subscriptions`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AddSubscriptionRequest {
+    topic: String,
+}
+
+/// Payload for `POST /mqtt/subscriptions`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RuntimeSubscriptionRequest {
+    filter: String,
+    /// 0, 1 or 2, 0 when omitted
+    #[serde(default)]
+    qos: u8,
+}
+
+/// Payload for `PUT /brokers/<name>/subscriptions/<topic>`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SubscriptionStateRequest {
+    active: bool,
+}
+
+/// Struct for a broker's subscription
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SubscriptionDto {
+    topic: String,
+    active: bool,
+}
+
+/// User creation payload for `/admin/users`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CreateUserRequest {
+    username: String,
+    password: String,
+}
+
+/// Struct for API users, never including the password hash
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct UserDto {
+    id: i64,
+    username: String,
+    created_at: String,
+}
+
+/// Result of verifying the Basic credentials of a request, cached for the request
+struct BasicIdentity(Option<String>);
+
+/// Username of valid Basic credentials, if the request carries any. Verified once per
+/// request, as both the `Authenticated` guard and the audit log ask for it, and never
+/// for requests `RateLimiter` rejected.
+async fn basic_auth_identity(req: &rocket::Request<'_>) -> Option<String> {
+    if req.local_cache(|| RateLimited(false)).0 {
+        return None;
+    }
+    req.local_cache_async(async { BasicIdentity(verify_basic_auth(req).await) })
+        .await
+        .0
+        .clone()
+}
+
+/// Verify the Basic credentials of a request on the blocking thread pool, as password
+/// hashes are slow to check on purpose.
+async fn verify_basic_auth(req: &rocket::Request<'_>) -> Option<String> {
+    let backend = req.rocket().state::<Arc<dyn AuthBackend>>()?.clone();
+
+    let encoded = req.headers().get_one("Authorization")?.strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(BASE64.decode(encoded).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    let (username, password) = (username.to_string(), password.to_string());
+
+    let verified = tokio::task::spawn_blocking(move || {
+        backend.verify(&Credentials {
+            username: &username,
+            password: &password,
+        })
+    })
+    .await;
+    match verified {
+        Ok(Ok(identity)) => Some(identity.username),
+        Ok(Err(AuthError::InvalidCredentials)) => None,
+        Ok(Err(e)) => {
+            error!("Failed to verify credentials: {}", e);
+            None
+        }
+        Err(e) => {
+            error!("Credential verification did not finish: {}", e);
+            None
+        }
+    }
+}
+
+/// Identity recorded for a request: the subject of a valid Bearer token with JWT
+/// authentication enabled, else the user of valid Basic credentials, `anonymous`
+/// otherwise.
+async fn request_identity(req: &rocket::Request<'_>) -> String {
+    if let Some(config) = req.rocket().state::<Config>().filter(|config| config.jwt_auth_enabled) {
+        if let Ok(Some(subject)) = bearer_subject(req, config) {
+            return subject.clone();
+        }
+    }
+    basic_auth_identity(req).await.unwrap_or_else(|| "anonymous".to_string())
+}
+
+/// Request guard for routes that require authentication when `REST_API_AUTH_ENABLED`
+/// is set. With authentication disabled every request passes.
+pub struct Authenticated;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(config) = req.rocket().state::<Config>() else {
+            return Outcome::Error((Status::InternalServerError, "Configuration is not available"));
+        };
+
+        match basic_auth_identity(req).await {
+            Some(_) => Outcome::Success(Authenticated),
+            None if !config.rest_api_auth_enabled => Outcome::Success(Authenticated),
+            None => Outcome::Error((Status::Unauthorized, "Valid credentials are required")),
+        }
+    }
+}
+
+/// Claims of the Bearer tokens issued by `/login` and accepted by `AuthToken`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TokenClaims {
+    sub: Option<String>,
+    /// Unix time the token expires at
+    exp: i64,
+}
+
+/// Login payload for `/login`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+/// A Bearer token for the routes taking `AuthToken`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LoginResponse {
+    token: String,
+    /// RFC 3339
+    expires_at: String,
+}
+
+/// Reason `AuthToken` rejected the request, kept for the 401 catcher to report
+struct TokenRejection(Option<&'static str>);
+
+/// Result of validating the Bearer token of a request, cached for the request: the
+/// token's subject, or why it is rejected
+struct BearerSubject(Result<Option<String>, &'static str>);
+
+/// Validate the Bearer token of a request against `JWT_SECRET_KEY`. Validated once per
+/// request, as both the `AuthToken` guard and the audit log ask for it.
+fn bearer_subject<'r>(req: &'r rocket::Request<'_>, config: &Config) -> &'r Result<Option<String>, &'static str> {
+    &req.local_cache(|| {
+        let Some(token) = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        else {
+            return BearerSubject(Err("A Bearer token is required"));
+        };
+        // Checked when loading the config
+        let secret = config.jwt_secret_key.as_deref().unwrap_or_default();
+        let key = jsonwebtoken::DecodingKey::from_secret(secret.as_bytes());
+        let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+
+        BearerSubject(match jsonwebtoken::decode::<TokenClaims>(token.trim(), &key, &validation) {
+            Ok(data) => Ok(data.claims.sub),
+            Err(e) => match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => Err("The token has expired"),
+                _ => Err("The token is invalid"),
+            },
+        })
+    })
+    .0
+}
+
+/// Request guard for routes that require a JWT when `JWT_AUTH_ENABLED` is set: an
+/// `Authorization: Bearer <token>` header with an HS256 token signed with
+/// `JWT_SECRET_KEY` and an `exp` claim in the future. With JWT authentication disabled
+/// every request passes.
+pub struct AuthToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthToken {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(config) = req.rocket().state::<Config>() else {
+            return Outcome::Error((Status::InternalServerError, "Configuration is not available"));
+        };
+        if !config.jwt_auth_enabled {
+            return Outcome::Success(AuthToken);
+        }
+
+        match bearer_subject(req, config) {
+            Ok(_) => Outcome::Success(AuthToken),
+            Err(reason) => {
+                req.local_cache(|| TokenRejection(Some(reason)));
+                Outcome::Error((Status::Unauthorized, *reason))
+            }
+        }
+    }
+}
+
+/// Audit Fairing recording every state-changing request in the `audit_log` table.
+/// Requests `RateLimiter` rejected are not recorded.
+pub struct AuditLog;
+
+#[rocket::async_trait]
+impl Fairing for AuditLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit Log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r rocket::Request<'_>, res: &mut rocket::Response<'r>) {
+        if matches!(req.method(), Method::Get | Method::Head | Method::Options) || req.local_cache(|| RateLimited(false)).0 {
+            return;
+        }
+        let target = req.uri().path().to_string();
+        let Some(db) = req.rocket().state::<Arc<DatabaseService>>() else {
+            return;
+        };
+        let route_path = req
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| strip_base_path(&config.rest_api_base_path, &target));
+        if route_path.is_some_and(|path| READ_ONLY_POSTS.contains(&path)) {
+            return;
+        }
+
+        let action = match req.route().and_then(|route| route.name.as_deref()) {
+            Some(name) => format!("{} {}", req.method(), name),
+            None => req.method().to_string(),
+        };
+        let outcome = if res.status().class().is_success() {
+            "success".to_string()
+        } else {
+            format!("failure ({})", res.status())
+        };
+
+        if let Err(e) = db.record_audit(&request_identity(req).await, &action, &target, &outcome) {
+            error!("Failed to record audit entry for '{}': {:?}", target, e);
+        }
+    }
+}
+
+/// Fairing advertising the response size limits on every response
+pub struct ResponseLimits {
+    max_rows: usize,
+    streaming_threshold: usize,
+}
+
+impl ResponseLimits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_rows: config.rest_api_max_response_rows,
+            streaming_threshold: config.rest_api_streaming_threshold_rows,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for ResponseLimits {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response Limits",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, _req: &'r rocket::Request<'_>, res: &mut rocket::Response<'r>) {
+        res.set_header(rocket::http::Header::new("X-Max-Response-Rows", self.max_rows.to_string()));
+        res.set_header(rocket::http::Header::new(
+            "X-Streaming-Threshold-Rows",
+            self.streaming_threshold.to_string(),
+        ));
+    }
+}
+
+const RATE_LIMIT_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Fairing enforcing MAX_API_REQUESTS_PER_MINUTE per client IP over a sliding minute,
+/// 0 disables the limit. Requests over the limit never reach their route: their method
+/// is switched to one no route is mounted for, and the resulting 404 is answered as 429.
+/// Clients are told apart by their peer address, not by headers they could set to
+/// anything. Requests without one (Unix socket) are not limited.
+pub struct RateLimiter {
+    max_per_minute: usize,
+    base_path: String,
+    requests: Mutex<RateLimitWindows>,
+}
+
+/// Times of the requests of the last minute per client IP
+struct RateLimitWindows {
+    per_client: HashMap<std::net::IpAddr, std::collections::VecDeque<std::time::Instant>>,
+    /// When clients without requests in the last minute were last dropped
+    last_sweep: std::time::Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_per_minute: config.max_api_requests_per_minute as usize,
+            base_path: config.rest_api_base_path.clone(),
+            requests: Mutex::new(RateLimitWindows {
+                per_client: HashMap::new(),
+                last_sweep: std::time::Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request of `client` if it is within the limit, returning whether it is.
+    /// Rejected requests are not counted, so a client holds at most `max_per_minute`
+    /// times.
+    fn allow(&self, client: std::net::IpAddr) -> bool {
+        let now = std::time::Instant::now();
+        let mut windows = self.requests.lock().unwrap();
+        if now.duration_since(windows.last_sweep) >= RATE_LIMIT_WINDOW {
+            windows
+                .per_client
+                .retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < RATE_LIMIT_WINDOW));
+            windows.last_sweep = now;
+        }
+
+        let times = windows.per_client.entry(client).or_default();
+        while times.front().is_some_and(|first| now.duration_since(*first) >= RATE_LIMIT_WINDOW) {
+            times.pop_front();
+        }
+        if times.len() >= self.max_per_minute {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limiter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut rocket::Request<'_>, _data: &mut rocket::Data<'_>) {
+        if self.max_per_minute == 0 || strip_base_path(&self.base_path, req.uri().path().as_str()).is_none() {
+            return;
+        }
+        let Some(client) = req.remote().map(|remote| remote.ip()) else {
+            return;
+        };
+        if self.allow(client) {
+            return;
+        }
+
+        req.local_cache(|| RateLimited(true));
+        req.set_method(Method::Trace);
+    }
+
+    async fn on_response<'r>(&self, req: &'r rocket::Request<'_>, res: &mut rocket::Response<'r>) {
+        if !req.local_cache(|| RateLimited(false)).0 {
+            return;
+        }
+        let body = serde_json::to_string(&ErrorDto {
+            error: format!("Too many requests, at most {} per minute are allowed", self.max_per_minute),
+        })
+        .unwrap_or_default();
+        res.set_status(Status::TooManyRequests);
+        res.set_header(ContentType::JSON);
+        res.set_sized_body(body.len(), std::io::Cursor::new(body));
+    }
+}
+
+/// Set on requests `RateLimiter` rejected
+struct RateLimited(bool);
+
+/// `path` relative to the REST API base path, None if it lies outside of it
+fn strip_base_path<'a>(base_path: &str, path: &'a str) -> Option<&'a str> {
+    if base_path == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(base_path) {
+        Some("") => Some("/"),
+        Some(rest) if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// `path` below the REST API base path, e.g. for `Location` headers
+fn with_base_path(base_path: &str, path: &str) -> String {
+    if base_path == "/" {
+        path.to_string()
+    } else {
+        format!("{}{}", base_path, path)
+    }
+}
+
+/// CORS Fairing with Config support. Only responses below the base path carry CORS
+/// headers, and none at all with CORS_ENABLED off.
+pub struct Cors {
+    enabled: bool,
+    allowed_origins: Vec<String>,
+    base_path: String,
+}
+
+impl Cors {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            enabled: config.cors_enabled,
+            allowed_origins: config.cors_allowed_origins.clone(),
+            base_path: config.rest_api_base_path.clone(),
+        }
+    }
+
+    /// Checks an `Origin` header against the configured origins. Besides exact matches
+    /// and the `*` catch-all, patterns like `https://*.example.com` match any subdomain
+    /// of `example.com` with the same scheme (but not `example.com` itself).
+    fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| {
+            if allowed == "*" || allowed == origin {
+                return true;
+            }
+            let Some((scheme, host_pattern)) = allowed.split_once("://") else {
+                return false;
+            };
+            let Some(suffix) = host_pattern.strip_prefix('*') else {
+                return false;
+            };
+            match origin.split_once("://") {
+                Some((origin_scheme, origin_host)) => {
+                    origin_scheme.eq_ignore_ascii_case(scheme)
+                        && suffix.starts_with('.')
+                        && origin_host.len() > suffix.len()
+                        && origin_host.to_ascii_lowercase().ends_with(&suffix.to_ascii_lowercase())
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r rocket::Request<'_>, res: &mut rocket::Response<'r>) {
+        if !self.enabled || strip_base_path(&self.base_path, req.uri().path().as_str()).is_none() {
+            return;
+        }
+        if let Some(origin) = req.headers().get_one("Origin") {
+            if self.is_origin_allowed(origin) {
+                res.set_header(rocket::http::Header::new("Access-Control-Allow-Origin", origin));
+            }
+        }
+        res.set_header(rocket::http::Header::new(
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, DELETE, OPTIONS",
+        ));
+        res.set_header(rocket::http::Header::new(
+            "Access-Control-Allow-Headers",
+            "Content-Type, Authorization",
+        ));
+    }
+}
+
+/// Answer CORS preflight requests, the headers are set by the `Cors` fairing
+#[options("/<_..>")]
+fn preflight() -> Status {
+    Status::NoContent
+}
+
+/// List the registered topics by name, optionally only those starting with `prefix`
+#[get("/topics?<prefix>")]
+fn list_topics(prefix: Option<&str>, db: &State<Arc<DatabaseService>>) -> Result<Json<Vec<TopicDto>>, Status> {
+    match db.list_topics(prefix) {
+        Ok(topics) => Ok(Json(
+            topics
+                .into_iter()
+                .map(|t| TopicDto {
+                    id: t.id,
+                    topic: t.topic,
+                    parent_topic: t.parent_topic,
+                    max_values: t.max_values,
+                    query_frequency_ms: t.query_frequency_ms,
+                })
+                .collect(),
+        )),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Get the time since the last value of every topic and whether it is overdue
+#[get("/topics/health?<factor>")]
+fn topic_health(
+    factor: Option<f64>,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<Vec<TopicHealthDto>>, Status> {
+    let factor = factor.unwrap_or(DEFAULT_STALE_FACTOR);
+    if !factor.is_finite() || factor <= 0.0 {
+        return Err(Status::BadRequest);
+    }
+
+    match db.topic_health(HEALTH_INTERVAL_SAMPLE) {
+        Ok(topics) => Ok(Json(
+            topics
+                .into_iter()
+                .map(|t| {
+                    let expected_interval_ms = if t.query_frequency_ms > 0 {
+                        Some(t.query_frequency_ms)
+                    } else {
+                        // Values arriving within the same second give no usable interval
+                        t.median_interval_secs.filter(|secs| *secs > 0).map(|secs| secs as u64 * 1000)
+                    };
+                    let stale = match (t.seconds_since_last, expected_interval_ms) {
+                        (None, _) => true,
+                        (Some(since), Some(expected)) => since as f64 * 1000.0 > expected as f64 * factor,
+                        (Some(_), None) => false,
+                    };
+                    TopicHealthDto {
+                        topic: t.topic,
+                        last_received_at: t.last_received_at,
+                        seconds_since_last: t.seconds_since_last,
+                        expected_interval_ms,
+                        stale,
+                    }
+                })
+                .collect(),
+        )),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Rename a topic, keeping its stored values
+#[post("/topics/<topic>/rename", data = "<request>")]
+fn rename_topic(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<RenameTopicRequest>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    if !topic_filter::is_valid_topic(&request.new_topic) {
+        return Status::BadRequest;
+    }
+
+    match db.rename_topic(topic, &request.new_topic) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+            Status::Conflict
+        }
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Register a topic, subscribe the monitored client to it while waiting unless a filter
+/// already covers it, and return the first value received within WATCH_TIMEOUT_MS, or
+/// 204 if none arrives. 507 when the topic is new and MAX_TOPICS is reached.
+#[post("/topics/<topic>/watch")]
+async fn watch_topic(
+    _auth: Authenticated,
+    topic: String,
+    db: &State<Arc<DatabaseService>>,
+    brokers: &State<Brokers>,
+    config: &State<Config>,
+) -> Result<Either<Json<WatchResponse>, Status>, Status> {
+    if !topic_filter::is_valid_topic(&topic) {
+        return Err(Status::BadRequest);
+    }
+    let registered = match db.register_topic(&topic, WATCHED_TOPIC_MAX_VALUES) {
+        Ok(TopicRegistration::Added) => true,
+        Ok(TopicRegistration::Existing) => false,
+        Ok(TopicRegistration::LimitReached) => return Err(Status::InsufficientStorage),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    let wait = std::time::Duration::from_millis(config.watch_timeout_ms);
+    match brokers.monitored.watch(&topic, wait).await {
+        Ok(Some(value)) => Ok(Either::Left(Json(WatchResponse {
+            topic,
+            value,
+            registered,
+        }))),
+        Ok(None) => Ok(Either::Right(Status::NoContent)),
+        Err(e) => {
+            error!("Failed to watch topic '{}': {}", topic, e);
+            Err(Status::ServiceUnavailable)
+        }
+    }
+}
+
+/// Get the unit conversion applied to a topic's numeric values on ingest
+#[get("/topics/<topic>/unit-rule")]
+fn get_unit_rule(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<UnitRuleDto>, Status> {
+    match db.get_unit_rule(topic) {
+        Ok(Some(rule)) => Ok(Json(UnitRuleDto {
+            scale: rule.scale,
+            offset: rule.offset,
+            keep_raw: rule.keep_raw,
+        })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Set the unit conversion of a topic, applied to values received from now on
+#[put("/topics/<topic>/unit-rule", data = "<request>")]
+fn set_unit_rule(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<UnitRuleDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    if !request.scale.is_finite() || !request.offset.is_finite() {
+        return Status::BadRequest;
+    }
+    let rule = UnitRule {
+        scale: request.scale,
+        offset: request.offset,
+        keep_raw: request.keep_raw,
+    };
+
+    match db.set_unit_rule(topic, Some(&rule)) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Remove the unit conversion of a topic
+#[delete("/topics/<topic>/unit-rule")]
+fn delete_unit_rule(_auth: Authenticated, topic: &str, db: &State<Arc<DatabaseService>>) -> Status {
+    match db.get_unit_rule(topic) {
+        Ok(Some(_)) => {}
+        Ok(None) => return Status::NotFound,
+        Err(_) => return Status::InternalServerError,
+    }
+    match db.set_unit_rule(topic, None) {
+        Ok(_) => Status::NoContent,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get the JSON field values of a topic are deduplicated by
+#[get("/topics/<topic>/message-id-field")]
+fn get_message_id_field(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<MessageIdFieldDto>, Status> {
+    match db.get_message_id_field(topic) {
+        Ok(Some(field)) => Ok(Json(MessageIdFieldDto { field })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Deduplicate values of a topic by a top-level field of their JSON object, a string or
+/// a number: values carrying an id that is already stored (e.g. QoS 1 redeliveries) are
+/// ignored. Values without the field are always stored.
+#[put("/topics/<topic>/message-id-field", data = "<request>")]
+fn set_message_id_field(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<MessageIdFieldDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    if request.field.is_empty() {
+        return Status::BadRequest;
+    }
+
+    match db.set_message_id_field(topic, Some(&request.field)) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Stop deduplicating values of a topic
+#[delete("/topics/<topic>/message-id-field")]
+fn delete_message_id_field(_auth: Authenticated, topic: &str, db: &State<Arc<DatabaseService>>) -> Status {
+    match db.get_message_id_field(topic) {
+        Ok(Some(_)) => {}
+        Ok(None) => return Status::NotFound,
+        Err(_) => return Status::InternalServerError,
+    }
+    match db.set_message_id_field(topic, None) {
+        Ok(_) => Status::NoContent,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get the minimum time between two stored values of a topic
+#[get("/topics/<topic>/store-interval")]
+fn get_store_interval(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<StoreIntervalDto>, Status> {
+    match db.get_min_store_interval(topic) {
+        Ok(Some(min_store_interval_ms)) => Ok(Json(StoreIntervalDto { min_store_interval_ms })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Throttle a chatty topic: values arriving within `min_store_interval_ms` of the last
+/// stored one are dropped, live consumers still get them. 0 stores every value.
+#[put("/topics/<topic>/store-interval", data = "<request>")]
+fn set_store_interval(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<StoreIntervalDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    match db.set_min_store_interval(topic, request.min_store_interval_ms) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get the delta storage snapshot interval of a topic
+#[get("/topics/<topic>/delta-storage")]
+fn get_delta_storage(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<DeltaStorageDto>, Status> {
+    match db.get_delta_snapshot_interval(topic) {
+        Ok(Some(snapshot_interval)) => Ok(Json(DeltaStorageDto { snapshot_interval })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Store JSON object values of a topic as the fields changed since the previous value,
+/// with every `snapshot_interval`-th value in full. Reads return the reconstructed
+/// objects. 0 stores values in full again, deltas stored before stay readable.
+#[put("/topics/<topic>/delta-storage", data = "<request>")]
+fn set_delta_storage(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<DeltaStorageDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    match db.set_delta_snapshot_interval(topic, request.snapshot_interval) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get the declared type of a topic's values
+#[get("/topics/<topic>/value-type")]
+fn get_value_type(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<ValueTypeDto>, Status> {
+    match db.get_value_type(topic) {
+        Ok(Some(value_type)) => Ok(Json(ValueTypeDto {
+            value_type: value_type.as_str().to_string(),
+        })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Declare the type of a topic's values, deciding how `/query` aggregates them: numbers
+/// and booleans by value, strings only by count and enums by the count of each value
+#[put("/topics/<topic>/value-type", data = "<request>")]
+fn set_value_type(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<ValueTypeDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    let Some(value_type) = ValueType::from_name(&request.value_type) else {
+        return Status::BadRequest;
+    };
+
+    match db.set_value_type(topic, value_type) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get the QoS a topic is subscribed with
+#[get("/topics/<topic>/qos")]
+fn get_topic_qos(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<TopicQosDto>, Status> {
+    match db.get_qos(topic) {
+        Ok(Some(qos)) => Ok(Json(TopicQosDto { qos })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Subscribe a topic with its own QoS from the next fresh session on, null for the
+/// default. A wildcard subscription covering the topic keeps its QoS, see
+/// `MqttService::subscription_filters`.
+#[put("/topics/<topic>/qos", data = "<request>")]
+fn set_topic_qos(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<TopicQosDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    if request.qos.is_some_and(|qos| qos > 2) {
+        return Status::BadRequest;
+    }
+
+    match db.set_topic_qos(topic, request.qos) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get whether values of a topic are stored
+#[get("/topics/<topic>/persistence")]
+fn get_persistence(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<PersistenceDto>, Status> {
+    match db.get_persist(topic) {
+        Ok(Some(persist)) => Ok(Json(PersistenceDto { persist })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Store the values a topic receives over MQTT, or with `persist: false` only pass them
+/// to watchers, hooks and traffic tails. Values inserted through the API are stored
+/// either way.
+#[put("/topics/<topic>/persistence", data = "<request>")]
+fn set_persistence(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<PersistenceDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    match db.set_persist(topic, request.persist) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get how long values of a topic are kept
+#[get("/topics/<topic>/retention")]
+fn get_retention(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<RetentionDto>, Status> {
+    match db.get_retention(topic) {
+        Ok(Some(retention_ms)) => Ok(Json(RetentionDto { retention_ms })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Delete values of a topic once they were received `retention_ms` ago, on top
+/// of its `max_values`. Expired values go with the next purge, every
+/// RETENTION_INTERVAL_SECS. 0 disables the retention.
+#[put("/topics/<topic>/retention", data = "<request>")]
+fn set_retention(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<RetentionDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    match db.set_retention(topic, request.retention_ms) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Get the fields of a topic materialized into typed columns
+#[get("/topics/<topic>/materialization")]
+fn get_materialization(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<MaterializationDto>, Status> {
+    match db.get_materialization(topic) {
+        Ok(Some(columns)) => Ok(Json(MaterializationDto { columns })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Materialize top-level fields of a topic's JSON values into typed columns of a table
+/// of its own, for values received from now on. Column names must be SQL identifiers
+/// other than `value_id` and `timestamp`, unique regardless of case. 409 when values are
+/// encrypted, as the columns would not be.
+#[put("/topics/<topic>/materialization", data = "<request>")]
+fn set_materialization(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<MaterializationDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    let mut seen = HashSet::new();
+    let valid = !request.columns.is_empty()
+        && request.columns.iter().all(|(field, column)| {
+            !field.is_empty() && materialize::is_valid_column(column) && seen.insert(column.to_ascii_lowercase())
+        });
+    if !valid {
+        return Status::BadRequest;
+    }
+    if db.encrypts_values() {
+        return Status::Conflict;
+    }
+
+    match db.set_materialization(topic, Some(&request.columns)) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Stop materializing a topic's values, its table and rows are kept
+#[delete("/topics/<topic>/materialization")]
+fn delete_materialization(_auth: Authenticated, topic: &str, db: &State<Arc<DatabaseService>>) -> Status {
+    match db.get_materialization(topic) {
+        Ok(Some(_)) => {}
+        Ok(None) => return Status::NotFound,
+        Err(_) => return Status::InternalServerError,
+    }
+    match db.set_materialization(topic, None) {
+        Ok(_) => Status::NoContent,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Materialized rows of a topic with a timestamp in the range, oldest first, with each
+/// column typed after the first value it was created with. 404 until the topic has
+/// materialized a value.
+#[get("/topics/<topic>/materialized")]
+fn materialized_rows(
+    topic: String,
+    range: TimeRangeQuery,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Json<MaterializedResponse>, Status> {
+    let (from, to) = range.range.formatted();
+    let limit = range
+        .limit
+        .unwrap_or(config.rest_api_max_response_rows)
+        .min(config.rest_api_max_response_rows);
+
+    match db.get_materialized_rows(&topic, &from, &to, limit) {
+        Ok(Some((columns, rows))) => Ok(Json(MaterializedResponse { topic, columns, rows })),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            error!("Failed to read materialized rows of topic '{}': {:?}", topic, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Get the values of a topic with a timestamp in `[from, to]`, oldest first, as
+/// `(value, timestamp)` pairs like `/topics/<topic>/values`
+#[get("/topics/<topic>/range")]
+async fn value_range(
+    topic: String,
+    range: TimeRangeQuery,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Json<LastValuesResponse>, Status> {
+    let (from, to) = range.range.formatted();
+    let limit = range
+        .limit
+        .unwrap_or(config.rest_api_max_response_rows)
+        .min(config.rest_api_max_response_rows);
+
+    let range_topic = topic.clone();
+    let rows = db
+        .inner()
+        .clone()
+        .blocking(move |db| db.get_values_in_range(&range_topic, &from, &to, limit))
+        .await;
+    match rows {
+        Ok(rows) => Ok(Json(LastValuesResponse {
+            topic,
+            values: rows.into_iter().map(TimestampedValue::from).collect(),
+        })),
+        Err(e) => {
+            error!("Failed to read values of topic '{}' in range: {:?}", topic, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Get the last value of a topic
+#[get("/topics/<topic>/last")]
+async fn last_value(
+    _token: AuthToken,
+    topic: String,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<LastValueResponse>, Status> {
+    match db.inner().clone().get_last_value_async(topic.clone()).await {
+        Ok(Some(row)) => Ok(Json(LastValueResponse {
+            topic,
+            value: row.value,
+            timestamp: row.timestamp,
+            is_binary: row.is_binary,
+            raw_value: row.raw_value,
+        })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Get count, minimum, maximum, average and last of the stored numeric values of a
+/// topic, ignoring values that aren't numbers. `since` (RFC 3339 or `YYYY-MM-DD
+/// HH:MM:SS`) only counts values with a later timestamp. 404 without numeric values.
+#[get("/topics/<topic>/stats")]
+async fn topic_stats(
+    topic: String,
+    since: SinceQuery,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<TopicStatsDto>, Status> {
+    let since = since.since.map(format_timestamp);
+
+    let stats_topic = topic.clone();
+    let stats = db
+        .inner()
+        .clone()
+        .blocking(move |db| db.get_stats(&stats_topic, since.as_deref()))
+        .await;
+    match stats {
+        Ok(Some(stats)) if stats.count > 0 => Ok(Json(TopicStatsDto {
+            topic,
+            count: stats.count,
+            min: stats.min,
+            max: stats.max,
+            avg: stats.avg,
+            last: stats.last,
+        })),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Infer the type, JSON fields and value range of a topic from its last stored values,
+/// so dashboards can pick a chart without fetching data. Cached for SCHEMA_CACHE_TTL.
+#[get("/topics/<topic>/schema")]
+async fn topic_schema(
+    topic: String,
+    db: &State<Arc<DatabaseService>>,
+    cache: &State<SchemaCache>,
+) -> Result<Json<TopicSchemaResponse>, Status> {
+    let now = std::time::Instant::now();
+    {
+        let mut cached = cache.0.lock().unwrap();
+        cached.retain(|_, (inferred_at, _)| now.duration_since(*inferred_at) < SCHEMA_CACHE_TTL);
+        if let Some((_, response)) = cached.get(&topic) {
+            return Ok(Json(response.clone()));
+        }
+    }
+
+    let sampled_topic = topic.clone();
+    let (values, declared_type) = db
+        .inner()
+        .clone()
+        .blocking(move |db| {
+            let values = db.get_last_values(&sampled_topic, SCHEMA_SAMPLE_VALUES, &[])?;
+            Ok::<_, rusqlite::Error>((values, db.get_value_type(&sampled_topic)?))
+        })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let samples = values.iter().filter(|row| !row.is_binary).map(|row| row.value.as_str());
+    let schema = schema::infer(samples).ok_or(Status::NotFound)?;
+    let declared_type = declared_type.map(|value_type| value_type.as_str().to_string());
+
+    let response = TopicSchemaResponse {
+        topic: topic.clone(),
+        declared_type,
+        schema,
+    };
+    cache.0.lock().unwrap().insert(topic, (now, response.clone()));
+    Ok(Json(response))
+}
+
+/// Get a single stored value by its row id
+#[get("/values/<id>")]
+async fn value_by_id(
+    id: i64,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<ValueResponse>, Status> {
+    match db.inner().clone().blocking(move |db| db.get_value_by_id(id)).await {
+        Ok(Some(row)) => Ok(Json(ValueResponse::from(row))),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Clear the value the internal broker (or the monitored one with `broker=monitored`, a
+/// further one with its name, e.g. `broker=monitored_1`) retains for a topic, e.g. status
+/// or progress messages of a removed subscription. This only affects the broker's
+/// retained store, stored values are kept.
+#[post("/topics/<topic>/clear-retained?<broker>")]
+async fn clear_retained(
+    _auth: Authenticated,
+    topic: &str,
+    broker: Option<&str>,
+    brokers: &State<Brokers>,
+) -> Status {
+    if !topic_filter::is_valid_topic(topic) {
+        return Status::BadRequest;
+    }
+    let mqtt_service = match broker.unwrap_or("internal") {
+        "internal" => &brokers.internal,
+        "monitored" => &brokers.monitored,
+        name => match brokers.monitored_by_name(name) {
+            Some(mqtt_service) => mqtt_service,
+            None => return Status::BadRequest,
+        },
+    };
+
+    mqtt_service.clear_retained(topic).await;
+    Status::Accepted
+}
+
+/// Publish a message through the internal broker. 503 while its client isn't connected,
+/// failures after that are only logged, like those of the service's own messages.
+#[post("/publish", data = "<request>")]
+async fn publish(
+    _auth: Authenticated,
+    request: Json<PublishRequest>,
+    mqtt_service: &State<Arc<MqttService>>,
+) -> Status {
+    if !topic_filter::is_valid_topic(&request.topic) {
+        return Status::BadRequest;
+    }
+    let Ok(qos) = rumqttc::qos(request.qos) else {
+        return Status::BadRequest;
+    };
+    if !matches!(mqtt_service.client_state().await, ClientState::Connected) {
+        return Status::ServiceUnavailable;
+    }
+
+    mqtt_service
+        .publish_message(&request.topic, request.payload.as_bytes(), qos, request.retain)
+        .await;
+    Status::Accepted
+}
+
+/// Subscribe the monitored service to a topic filter besides its configured ones, for
+/// this session and every following one until removed. Messages of registered topics
+/// under it are stored. 503 while its client isn't connected.
+#[post("/mqtt/subscriptions", data = "<request>")]
+async fn add_runtime_subscription(
+    _auth: Authenticated,
+    request: Json<RuntimeSubscriptionRequest>,
+    brokers: &State<Brokers>,
+) -> Status {
+    if !topic_filter::is_valid(&request.filter) {
+        return Status::BadRequest;
+    }
+    let Ok(qos) = rumqttc::qos(request.qos) else {
+        return Status::BadRequest;
+    };
+    if !matches!(brokers.monitored.client_state().await, ClientState::Connected) {
+        return Status::ServiceUnavailable;
+    }
+
+    match brokers.monitored.subscribe(&request.filter, qos).await {
+        Ok(()) => Status::Created,
+        Err(e) => {
+            error!("{}", e);
+            Status::InternalServerError
+        }
+    }
+}
+
+/// Remove a filter added through `POST /mqtt/subscriptions`, 404 for any other filter.
+/// 503 while the client of the monitored service isn't connected.
+#[delete("/mqtt/subscriptions/<filter>")]
+async fn remove_runtime_subscription(_auth: Authenticated, filter: &str, brokers: &State<Brokers>) -> Status {
+    if !matches!(brokers.monitored.client_state().await, ClientState::Connected) {
+        return Status::ServiceUnavailable;
+    }
+
+    match brokers.monitored.unsubscribe(filter).await {
+        Ok(()) => Status::NoContent,
+        // With the client connected, only a filter that wasn't subscribed at runtime fails
+        Err(_) => Status::NotFound,
+    }
+}
+
+/// Get the pre-aggregation window of a topic
+#[get("/topics/<topic>/pre-aggregation")]
+fn get_pre_aggregation(topic: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<PreAggregationDto>, Status> {
+    match db.get_aggregate_window(topic) {
+        Ok(Some(window_secs)) => Ok(Json(PreAggregationDto { window_secs })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Store one row of statistics per window instead of every numeric value of a topic,
+/// `0` stores every value again. The individual values of aggregated windows are lost.
+#[put("/topics/<topic>/pre-aggregation", data = "<request>")]
+fn set_pre_aggregation(
+    _auth: Authenticated,
+    topic: &str,
+    request: Json<PreAggregationDto>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    match db.set_aggregate_window(topic, request.window_secs) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Store a value for a registered topic without going through MQTT, e.g. to backfill
+/// history or feed dashboards in tests. Values skipped like MQTT ones (store interval,
+/// duplicate message id, pre-aggregation without a timestamp) are answered with 409.
+#[post("/topics/<topic>/values", data = "<request>")]
+fn insert_value(
+    _auth: Authenticated,
+    topic: String,
+    request: Json<InsertValueRequest>,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Created<Json<ValueResponse>>, Status> {
+    let request = request.into_inner();
+    let timestamp = match &request.timestamp {
+        Some(timestamp) => Some(format_timestamp(parse_timestamp(timestamp).ok_or(Status::BadRequest)?)),
+        None => None,
+    };
+    match db.get_value_type(&topic) {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+
+    let labels: Vec<(String, String)> = request.labels.clone().into_iter().collect();
+    match db.insert_value_at(&topic, &request.value, &labels, timestamp.as_deref()) {
+        Ok(Some((id, timestamp, value))) => {
+            let location = with_base_path(&config.rest_api_base_path, &format!("/values/{}", id));
+            Ok(Created::new(location).body(Json(ValueResponse {
+                id,
+                topic,
+                value,
+                timestamp,
+                is_binary: false,
+                raw_value: None,
+                labels: request.labels,
+            })))
+        }
+        Ok(None) => Err(Status::Conflict),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Get the last `n` values of a topic, optionally only those carrying all given labels.
+/// Labels are only attached by `insert_value`: MQTT 3.1.1 has no user properties, so
+/// values received from the broker carry none.
+#[get("/topics/<topic>/values?<limit>&<label>")]
+#[allow(clippy::type_complexity)]
+async fn last_values(
+    _token: AuthToken,
+    topic: String,
+    limit: Option<usize>,
+    label: HashMap<String, String>, // `label.<key>=<value>`, all must match
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Either<Json<LastValuesResponse>, (ContentType, TextStream![String])>, Status> {
+    let limit = limit.unwrap_or(10); // Default limit is 10
+    if limit > config.rest_api_max_response_rows {
+        return Err(Status::BadRequest);
+    }
+    let labels: Vec<(String, String)> = label.into_iter().collect();
+    if limit > config.rest_api_streaming_threshold_rows {
+        let stream = stream_last_values(db.inner().clone(), topic, limit, labels);
+        return Ok(Either::Right((ContentType::JSON, stream)));
+    }
+
+    let read_topic = topic.clone();
+    match db.inner().clone().blocking(move |db| db.get_last_values(&read_topic, limit, &labels)).await {
+        Ok(rows) => Ok(Either::Left(Json(LastValuesResponse {
+            topic,
+            values: rows.into_iter().map(TimestampedValue::from).collect(),
+        }))),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Streams the same JSON as `LastValuesResponse`, reading the values page by page so
+/// large responses are never held in memory at once. Should reading fail midway, the
+/// values read so far are followed by an `error` field, so the response isn't mistaken for
+/// the complete list.
+fn stream_last_values(
+    db: Arc<DatabaseService>,
+    topic: String,
+    limit: usize,
+    labels: Vec<(String, String)>,
+) -> TextStream![String] {
+    TextStream! {
+        yield format!("{{\"topic\":{},\"values\":[", serde_json::to_string(&topic).unwrap_or_default());
+
+        let mut cursor: Option<(String, i64)> = None;
+        let mut remaining = limit;
+        let mut first = true;
+        let mut failed = false;
+        while remaining > 0 {
+            let (page_topic, page_cursor, page_labels) = (topic.clone(), cursor.clone(), labels.clone());
+            let page_rows = remaining.min(STREAM_PAGE_ROWS);
+            let page = db.clone().blocking(move |db| {
+                let cursor = page_cursor.as_ref().map(|(timestamp, id)| (timestamp.as_str(), *id));
+                db.get_last_values_page(&page_topic, cursor, page_rows, &page_labels)
+            });
+            let page = match page.await {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Failed to stream values for topic '{}': {:?}", topic, e);
+                    failed = true;
+                    break;
+                }
+            };
+            if page.is_empty() {
+                break;
+            }
+            remaining -= page.len();
+
+            let mut chunk = String::new();
+            for row in page {
+                if !first {
+                    chunk.push(',');
+                }
+                first = false;
+                cursor = Some((row.timestamp.clone(), row.id));
+                chunk.push_str(&serde_json::to_string(&TimestampedValue::from(row)).unwrap_or_default());
+            }
+            yield chunk;
+        }
+
+        match failed {
+            true => yield "],\"error\":\"Failed to read values\"}".to_string(),
+            false => yield "]}".to_string(),
+        }
+    }
+}
+
+/// Get the difference between the first and last numeric value of a topic in a range
+#[get("/topics/<topic>/delta")]
+fn delta(
+    topic: String,
+    range: TimeRangeQuery,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<DeltaResponse>, Status> {
+    let (from, to) = range.range.formatted();
+    match db.get_value_type(&topic) {
+        Ok(Some(ValueType::Number)) => {}
+        Ok(Some(_)) => return Err(Status::BadRequest),
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+
+    match db.first_and_last_numeric(&topic, &from, &to) {
+        Ok(Some((first, last))) => Ok(Json(DeltaResponse {
+            topic,
+            delta: last.value - first.value,
+            reset_detected: last.value < first.value,
+            first: NumericPointDto {
+                value: first.value,
+                timestamp: first.timestamp,
+            },
+            last: NumericPointDto {
+                value: last.value,
+                timestamp: last.timestamp,
+            },
+        })),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Get a range of values reduced to at most `points` buckets
+#[get("/topics/<topic>/downsample?<points>")]
+async fn downsample(
+    topic: String,
+    range: TimeRangeQuery,
+    points: usize,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Json<DownsampleResponse>, Status> {
+    if points == 0 || points > MAX_DOWNSAMPLE_POINTS || points > config.rest_api_max_response_rows {
+        return Err(Status::BadRequest);
+    }
+    let (from, to) = range.range.formatted();
+
+    let sampled_topic = topic.clone();
+    let values = db
+        .inner()
+        .clone()
+        .blocking(move |db| db.downsample_values(&sampled_topic, &from, &to, points))
+        .await;
+    match values {
+        Ok(values) => Ok(Json(DownsampleResponse {
+            topic,
+            points: values
+                .into_iter()
+                .map(|v| DownsampledValueDto {
+                    timestamp: v.bucket_start,
+                    value: v.value,
+                    count: v.count,
+                })
+                .collect(),
+        })),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Align the values of two topics: each value of `a` is paired with the nearest value of
+/// `b` at most `tolerance_ms` away, values of `a` without such a match are left out
+#[get("/topics/join?<a>&<b>&<tolerance_ms>")]
+async fn join_topics(
+    a: String,
+    b: String,
+    range: TimeRangeQuery,
+    tolerance_ms: u64,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Json<JoinResponse>, Status> {
+    let (from, to) = range.range.formatted();
+    let max_rows = config.rest_api_max_response_rows;
+
+    // One row more than allowed tells a too large range apart from one that just fits
+    let (topic_a, topic_b) = (a.clone(), b.clone());
+    let (values_a, values_b) = db
+        .inner()
+        .clone()
+        .blocking(move |db| {
+            let values_a = db.get_values_in_range(&topic_a, &from, &to, max_rows + 1)?;
+            let values_b = db.get_values_in_range(&topic_b, &from, &to, max_rows + 1)?;
+            Ok::<_, rusqlite::Error>((values_a, values_b))
+        })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if values_a.len() > max_rows || values_b.len() > max_rows {
+        return Err(Status::BadRequest);
+    }
+
+    Ok(Json(JoinResponse {
+        pairs: merge_nearest(&values_a, &values_b, Duration::milliseconds(tolerance_ms as i64)),
+        a,
+        b,
+        tolerance_ms,
+    }))
+}
+
+/// Merge join of two value lists sorted by timestamp, pairing each value of `a` with the
+/// nearest value of `b` within `tolerance`.
+fn merge_nearest(a: &[ValueRow], b: &[ValueRow], tolerance: Duration) -> Vec<JoinedPairDto> {
+    fn with_time(rows: &[ValueRow]) -> Vec<(OffsetDateTime, &ValueRow)> {
+        rows.iter()
+            .filter_map(|row| parse_timestamp(&row.timestamp).map(|t| (t, row)))
+            .collect()
+    }
+    let (a, b) = (with_time(a), with_time(b));
+
+    let mut pairs = Vec::new();
+    let mut j = 0;
+    for (time_a, row_a) in &a {
+        // `a` is ascending, so the nearest value of `b` never moves backwards
+        while j + 1 < b.len() && (b[j + 1].0 - *time_a).abs() <= (b[j].0 - *time_a).abs() {
+            j += 1;
+        }
+        if let Some((time_b, row_b)) = b.get(j) {
+            if (*time_b - *time_a).abs() <= tolerance {
+                pairs.push(JoinedPairDto {
+                    timestamp_a: row_a.timestamp.clone(),
+                    value_a: row_a.value.clone(),
+                    timestamp_b: row_b.timestamp.clone(),
+                    value_b: row_b.value.clone(),
+                });
+            }
+        }
+    }
+    pairs
+}
+
+/// Aggregate all topics matching a filter over a time range in one call
+#[post("/query", data = "<query>")]
+async fn query(
+    query: Json<QueryRequest>,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Json<QueryResponse>, Status> {
+    if !topic_filter::is_valid(&query.topic) {
+        return Err(Status::BadRequest);
+    }
+    let range = TimeRange::parse(&query.from, &query.to).map_err(|_| Status::BadRequest)?;
+
+    let span_seconds = range.span_seconds();
+    let bucket_seconds = query.bucket_seconds.unwrap_or(span_seconds + 1);
+    range.check_bucket(bucket_seconds).map_err(|_| Status::BadRequest)?;
+
+    let filter = query.topic.clone();
+    let topics = db
+        .inner()
+        .clone()
+        .blocking(move |db| db.find_topics(&filter))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let max_rows = (topics.len() as u64).saturating_mul(span_seconds / bucket_seconds + 1);
+    if topics.len() > MAX_QUERY_TOPICS || max_rows > config.rest_api_max_response_rows as u64 {
+        return Err(Status::BadRequest);
+    }
+
+    let (from, to) = range.formatted();
+    let mut results = Vec::with_capacity(topics.len());
+    let aggregation = query.aggregation;
+    for topic in topics {
+        let (queried_topic, from, to) = (topic.clone(), from.clone(), to.clone());
+        let buckets = db
+            .inner()
+            .clone()
+            .blocking(move |db| {
+                let value_type = db.get_value_type(&queried_topic)?.unwrap_or(ValueType::Number);
+                // Free-form strings have no numeric meaning, only counting them is allowed
+                if value_type == ValueType::String && !matches!(aggregation, Aggregation::Count) {
+                    return Ok(None);
+                }
+                db.aggregate_values(&queried_topic, &from, &to, bucket_seconds, aggregation, value_type)
+                    .map(Some)
+            })
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .ok_or(Status::BadRequest)?;
+        results.push(TopicQueryResult {
+            topic,
+            buckets: buckets
+                .into_iter()
+                .map(|b| AggregateBucketDto {
+                    timestamp: b.bucket_start,
+                    value: b.value,
+                    count: b.count,
+                    categories: b.categories.into_iter().collect(),
+                })
+                .collect(),
+        });
+    }
+
+    Ok(Json(QueryResponse { results }))
+}
+
+/// Query the audit log of administrative actions
+#[get("/admin/audit")]
+fn audit_log(
+    _auth: Authenticated,
+    range: OpenTimeRangeQuery,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+) -> Result<Json<Vec<AuditEntryDto>>, Status> {
+    let (from, to) = (range.from.map(format_timestamp), range.to.map(format_timestamp));
+    let limit = range
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .min(MAX_AUDIT_LIMIT)
+        .min(config.rest_api_max_response_rows);
+
+    match db.get_audit_entries(from.as_deref(), to.as_deref(), limit) {
+        Ok(entries) => Ok(Json(
+            entries
+                .into_iter()
+                .map(|e| AuditEntryDto {
+                    id: e.id,
+                    actor: e.actor,
+                    action: e.action,
+                    target: e.target,
+                    outcome: e.outcome,
+                    timestamp: e.timestamp,
+                })
+                .collect(),
+        )),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Dump the values of all topics as newline-delimited JSON, one `ValueResponse` object
+/// per line in insertion order, labels included. Read page by page, so memory use stays
+/// flat however large the database is. Should reading fail midway, the last line is an
+/// `{"error": ..., "after_id": ...}` object instead of a value.
+#[get("/admin/export/ndjson")]
+fn export_ndjson(_auth: Authenticated, db: &State<Arc<DatabaseService>>) -> (ContentType, TextStream![String]) {
+    let db = db.inner().clone();
+    let stream = TextStream! {
+        let mut after_id = 0;
+        loop {
+            let page = db.clone().blocking(move |db| {
+                db.get_all_values_after(after_id, STREAM_PAGE_ROWS).and_then(|page| {
+                    let labels = match (page.first(), page.last()) {
+                        (Some(first), Some(last)) => db.get_value_labels(first.id, last.id)?,
+                        _ => HashMap::new(),
+                    };
+                    Ok((page, labels))
+                })
+            });
+            let (page, mut labels) = match page.await {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Failed to export values after id {}: {:?}", after_id, e);
+                    let line = serde_json::json!({ "error": "Failed to read values", "after_id": after_id });
+                    yield format!("{}\n", line);
+                    break;
+                }
+            };
+            let Some(last) = page.last() else {
+                break;
+            };
+            after_id = last.id;
+
+            let mut chunk = String::new();
+            for row in page {
+                let mut line = ValueResponse::from(row);
+                line.labels = labels.remove(&line.id).unwrap_or_default();
+                chunk.push_str(&serde_json::to_string(&line).unwrap_or_default());
+                chunk.push('\n');
+            }
+            yield chunk;
+        }
+    };
+    (ContentType::new("application", "x-ndjson"), stream)
+}
+
+/// Registered topics against the topic limit and the size of the database
+#[get("/admin/storage")]
+fn storage(_auth: Authenticated, db: &State<Arc<DatabaseService>>) -> Result<Json<StorageDto>, Status> {
+    let topics = db.count_topics().map_err(|_| Status::InternalServerError)?;
+    let database_size_bytes = db.database_size_bytes().map_err(|_| Status::InternalServerError)?;
+    Ok(Json(StorageDto {
+        topics,
+        max_topics: db.max_topics(),
+        topics_rejected: METRICS.topics_rejected.load(Ordering::Relaxed),
+        topics_evicted: METRICS.topics_evicted.load(Ordering::Relaxed),
+        database_size_bytes,
+    }))
+}
+
+/// List the archive files, oldest day first
+#[get("/admin/archives")]
+async fn list_archives(
+    _auth: Authenticated,
+    archive: &State<Arc<Archive>>,
+    config: &State<Config>,
+) -> Result<Json<ArchivesResponse>, Status> {
+    let files = archive.files().await.map_err(|e| {
+        error!("Failed to list archive files: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(Json(ArchivesResponse {
+        archive_after_secs: (config.archive_after_secs > 0).then_some(config.archive_after_secs),
+        files: files
+            .into_iter()
+            .map(|file| ArchiveFileDto {
+                day: file.day,
+                file: file.file,
+                size_bytes: file.size_bytes,
+            })
+            .collect(),
+    }))
+}
+
+/// Archived values of a topic with a timestamp in the range, oldest first. Reads the
+/// archive files of the days in range, so this is slower than the queries of the values
+/// still in the database.
+#[get("/topics/<topic>/archive")]
+async fn archived_values(
+    topic: &str,
+    range: TimeRangeQuery,
+    archive: &State<Arc<Archive>>,
+    config: &State<Config>,
+) -> Result<Json<Vec<ValueResponse>>, Status> {
+    let (from, to) = range.range.formatted();
+    let limit = range
+        .limit
+        .unwrap_or(config.rest_api_max_response_rows)
+        .min(config.rest_api_max_response_rows);
+
+    match archive.values_in_range(topic, &from, &to, limit).await {
+        Ok(values) => Ok(Json(
+            values
+                .into_iter()
+                .map(ValueResponse::from)
+                .collect(),
+        )),
+        Err(e) => {
+            error!("Failed to read archived values of topic '{}': {}", topic, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Total number of received values across all topics per `bucket` seconds, 400 without it
+#[get("/admin/ingest-rate")]
+fn ingest_rate(
+    _auth: Authenticated,
+    range: TimeRangeQuery,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<Vec<IngestRateDto>>, Status> {
+    // `TimeRangeQuery` already checked the bucket against the range
+    let Some(bucket) = range.bucket else {
+        return Err(Status::BadRequest);
+    };
+    let (from, to) = range.range.formatted();
+    match db.ingest_rate(&from, &to, bucket) {
+        Ok(buckets) => Ok(Json(
+            buckets
+                .into_iter()
+                .map(|b| IngestRateDto {
+                    timestamp: b.bucket_start,
+                    count: b.count,
+                })
+                .collect(),
+        )),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Replay stored values into another broker as a tracked background task
+#[post("/admin/replay-to-broker", data = "<request>")]
+async fn replay_to_broker(
+    _auth: Authenticated,
+    request: Json<ReplayRequest>,
+    db: &State<Arc<DatabaseService>>,
+    config: &State<Config>,
+    state: &State<SharedState>,
+    mqtt_service: &State<Arc<MqttService>>,
+) -> Result<Accepted<Json<ReplayTaskDto>>, Status> {
+    let request = request.into_inner();
+    let topic_filter = request.topic.unwrap_or_else(|| "#".to_string());
+    if !topic_filter::is_valid(&topic_filter) {
+        return Err(Status::BadRequest);
+    }
+
+    let options = ReplayOptions {
+        target: ReplayTarget {
+            host: request.host,
+            port: request.port,
+            username: request.username,
+            password: request.password,
+            ca_cert_path: request.ca_cert_path,
+        },
+        topic_filter,
+        latest_only: !request.full_history.unwrap_or(false),
+        retain: request.retain.unwrap_or(true),
+    };
+
+    let task_id = format!("replay_{}", Uuid::new_v4());
+    let tracker = Arc::new(ProgressTracker::new(0, mqtt_service.inner().clone(), task_id));
+    register_tracker(state, tracker.clone(), config.progress_tracker_max_entries).await;
+
+    let db = db.inner().clone();
+    let task_tracker = tracker.clone();
+    tokio::spawn(async move {
+        if let Err(e) = replay::replay_to_broker(db, task_tracker.clone(), options).await {
+            error!("Replay {} failed: {}", task_tracker.task_id, e);
+            task_tracker.stop().await;
+        }
+    });
+
+    Ok(Accepted(Json(ReplayTaskDto::from_tracker(&tracker).await)))
+}
+
+/// Progress of a replay task
+#[get("/admin/replay-to-broker/<task_id>")]
+async fn replay_status(
+    _auth: Authenticated,
+    task_id: &str,
+    state: &State<SharedState>,
+) -> Result<Json<ReplayTaskDto>, Status> {
+    let tracker = state.read().await.get(task_id).cloned().ok_or(Status::NotFound)?;
+    Ok(Json(ReplayTaskDto::from_tracker(&tracker).await))
+}
+
+/// Cancel a running replay task
+#[delete("/admin/replay-to-broker/<task_id>")]
+async fn cancel_replay(
+    _auth: Authenticated,
+    task_id: &str,
+    state: &State<SharedState>,
+) -> Result<Json<ReplayTaskDto>, Status> {
+    let tracker = state.read().await.get(task_id).cloned().ok_or(Status::NotFound)?;
+    if !tracker.is_finished().await {
+        tracker.stop().await;
+    }
+    Ok(Json(ReplayTaskDto::from_tracker(&tracker).await))
+}
+
+/// List the users of the database auth backend
+#[get("/admin/users")]
+fn list_users(
+    _auth: Authenticated,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Json<Vec<UserDto>>, Status> {
+    match db.list_users() {
+        Ok(users) => Ok(Json(
+            users
+                .into_iter()
+                .map(|u| UserDto {
+                    id: u.id,
+                    username: u.username,
+                    created_at: u.created_at,
+                })
+                .collect(),
+        )),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Create a user of the database auth backend
+#[post("/admin/users", data = "<request>")]
+fn create_user(
+    _auth: Authenticated,
+    request: Json<CreateUserRequest>,
+    db: &State<Arc<DatabaseService>>,
+) -> Result<Status, Status> {
+    if request.username.is_empty() || request.username.contains(':') || request.password.is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let password_hash = hash_password(&request.password).map_err(|_| Status::InternalServerError)?;
+
+    match db.create_user(&request.username, &password_hash) {
+        Ok(()) => Ok(Status::Created),
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+            Err(Status::Conflict)
+        }
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Delete a user of the database auth backend
+#[delete("/admin/users/<username>")]
+fn delete_user(
+    _auth: Authenticated,
+    username: &str,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    match db.delete_user(username) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// List the stored brokers
+#[get("/brokers")]
+fn list_brokers(db: &State<Arc<DatabaseService>>) -> Result<Json<Vec<BrokerDto>>, Status> {
+    match db.list_brokers() {
+        Ok(brokers) => Ok(Json(brokers.into_iter().map(BrokerDto::from).collect())),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// A stored broker by name
+#[get("/brokers/<name>")]
+fn get_broker(name: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<BrokerDto>, Status> {
+    match db.get_broker(name) {
+        Ok(Some(broker)) => Ok(Json(broker.into())),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Create a broker, or update the one with the same name. Running services keep their
+/// connection until restarted.
+#[post("/brokers", data = "<request>")]
+fn save_broker(
+    _auth: Authenticated,
+    request: Json<SaveBrokerRequest>,
+    db: &State<Arc<DatabaseService>>,
+) -> Status {
+    if request.name.is_empty() || request.host.is_empty() || request.port == 0 {
+        return Status::BadRequest;
+    }
+    match db.save_broker(
+        &request.name,
+        &request.host,
+        request.port,
+        request.username.as_deref(),
+        request.password.as_deref(),
+        request.tls_enabled,
+    ) {
+        Ok(true) => Status::Created,
+        Ok(false) => Status::Ok,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Delete a broker and its subscriptions
+#[delete("/brokers/<name>")]
+fn delete_broker(_auth: Authenticated, name: &str, db: &State<Arc<DatabaseService>>) -> Status {
+    match db.delete_broker(name) {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// List the subscriptions of a broker, active or not
+#[get("/brokers/<name>/subscriptions")]
+fn list_subscriptions(name: &str, db: &State<Arc<DatabaseService>>) -> Result<Json<Vec<SubscriptionDto>>, Status> {
+    match db.list_subscriptions(name) {
+        Ok(Some(subscriptions)) => Ok(Json(
+            subscriptions
+                .into_iter()
+                .map(|s| SubscriptionDto {
+                    topic: s.topic,
+                    active: s.is_active,
+                })
+                .collect(),
+        )),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Subscribe a broker to a topic filter, registering the topic with the defaults of
+/// DEFAULT_MAX_VALUES and DEFAULT_QUERY_FREQUENCY_MS if needed. A connected service
+/// taking its subscriptions from the database subscribes right away. 507 when the topic
+/// is new and MAX_TOPICS is reached.
+#[post("/brokers/<name>/subscriptions", data = "<request>")]
+async fn add_subscription(
+    _auth: Authenticated,
+    name: &str,
+    request: Json<AddSubscriptionRequest>,
+    db: &State<Arc<DatabaseService>>,
+    brokers: &State<Brokers>,
+    config: &State<Config>,
+) -> Status {
+    if !topic_filter::is_valid(&request.topic) {
+        return Status::BadRequest;
+    }
+    let defaults = TopicDefaults {
+        max_values: config.default_max_values,
+        query_frequency_ms: config.default_query_frequency_ms,
+    };
+    match db.add_subscription(name, &request.topic, defaults) {
+        Ok(Some(TopicRegistration::LimitReached)) => return Status::InsufficientStorage,
+        Ok(Some(_)) => {}
+        Ok(None) => return Status::NotFound,
+        Err(_) => return Status::InternalServerError,
+    }
+    if let Some(service) = brokers.monitored_by_name(name) {
+        service.sync_subscription(&request.topic, true).await;
+    }
+    Status::Created
+}
+
+/// Activate or deactivate a subscription of a broker, subscribing or unsubscribing a
+/// connected service taking its subscriptions from the database right away
+#[put("/brokers/<name>/subscriptions/<topic>", data = "<request>")]
+async fn set_subscription_active(
+    _auth: Authenticated,
+    name: &str,
+    topic: &str,
+    request: Json<SubscriptionStateRequest>,
+    db: &State<Arc<DatabaseService>>,
+    brokers: &State<Brokers>,
+) -> Status {
+    match db.set_subscription_active(name, topic, request.active) {
+        Ok(true) => {
+            if let Some(service) = brokers.monitored_by_name(name) {
+                service.sync_subscription(topic, request.active).await;
+            }
+            Status::NoContent
+        }
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Remove a subscription of a broker, unsubscribing a connected service taking its
+/// subscriptions from the database right away
+#[delete("/brokers/<name>/subscriptions/<topic>")]
+async fn remove_subscription(
+    _auth: Authenticated,
+    name: &str,
+    topic: &str,
+    db: &State<Arc<DatabaseService>>,
+    brokers: &State<Brokers>,
+) -> Status {
+    match db.remove_subscription(name, topic) {
+        Ok(true) => {
+            if let Some(service) = brokers.monitored_by_name(name) {
+                service.sync_subscription(topic, false).await;
+            }
+            Status::NoContent
+        }
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// Stream application logs as server-sent events, optionally filtered by minimum level
+#[get("/admin/logs/stream?<level>")]
+fn log_stream(
+    _auth: Authenticated,
+    level: Option<&str>,
+    logs: &State<LogStream>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Status> {
+    let min_level = match level {
+        Some(level) => level.parse::<tracing::Level>().map_err(|_| Status::BadRequest)?,
+        None => tracing::Level::TRACE,
+    };
+    let mut records = logs.subscribe();
+
+    Ok(EventStream! {
+        loop {
+            let record = tokio::select! {
+                record = records.recv() => match record {
+                    Ok(record) => record,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            // More verbose levels compare greater in `tracing`
+            if record.severity <= min_level {
+                yield Event::json(&record);
+            }
+        }
+    })
+}
+
+/// Stream the raw traffic of the monitored broker on topics matching the MQTT `filter`
+/// as server-sent events, for debugging. Unlike the stored values this includes messages
+/// that are excluded, rejected or dropped, each with its disposition. Only topics the
+/// client is subscribed to show up. 429 while DEBUG_TAIL_MAX_SESSIONS tails are open.
+#[get("/debug/tail?<filter>")]
+fn debug_tail(
+    _auth: Authenticated,
+    filter: String,
+    brokers: &State<Brokers>,
+    sessions: &State<Arc<TailSessions>>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Status> {
+    if !topic_filter::is_valid(&filter) {
+        return Err(Status::BadRequest);
+    }
+    let session = sessions.open().ok_or(Status::TooManyRequests)?;
+    let mut messages = brokers.monitored.tail_traffic();
+
+    Ok(EventStream! {
+        let _session = session;
+        loop {
+            let message = tokio::select! {
+                message = messages.recv() => match message {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            if topic_filter::matches(&filter, &message.topic) {
+                yield Event::json(&message);
+            }
+        }
+    })
+}
+
+/// Metrics in the Prometheus text exposition format
+#[get("/metrics")]
+async fn metrics(brokers: &State<Brokers>) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    let mut out = METRICS.render();
+    let mut services = Vec::new();
+    let mut granted = Vec::new();
+    let mut rejected = Vec::new();
+    let mut excluded = Vec::new();
+    let mut sessions_resumed = Vec::new();
+    let mut sessions_fresh = Vec::new();
+    let mut hook_events_dropped = Vec::new();
+    for (name, broker) in brokers.labeled() {
+        services.push((name, matches!(broker.client_state().await, ClientState::Connected)));
+        granted.push((name, broker.granted_qos().await));
+        rejected.push((name, broker.rejected_payload_count()));
+        excluded.push((name, broker.excluded_message_count()));
+        let (resumed, fresh) = broker.session_counts();
+        sessions_resumed.push((name, resumed));
+        sessions_fresh.push((name, fresh));
+        hook_events_dropped.push((name, broker.dropped_hook_event_count()));
+    }
+    render_connection_gauge(&mut out, &services);
+    render_granted_qos(&mut out, &granted);
+    render_service_counter(
+        &mut out,
+        "mqtt_rejected_payloads_total",
+        "Received messages skipped for a malformed or oversized payload",
+        &rejected,
+    );
+    render_service_counter(
+        &mut out,
+        "mqtt_excluded_messages_total",
+        "Received messages not stored because their topic is excluded",
+        &excluded,
+    );
+    render_service_counter(
+        &mut out,
+        "mqtt_sessions_resumed_total",
+        "Connections on which the broker resumed the previous session",
+        &sessions_resumed,
+    );
+    render_service_counter(
+        &mut out,
+        "mqtt_sessions_fresh_total",
+        "Connections on which the broker started a fresh session",
+        &sessions_fresh,
+    );
+    render_service_counter(
+        &mut out,
+        "mqtt_hook_events_dropped_total",
+        "Events the event hooks missed because they fell behind",
+        &hook_events_dropped,
+    );
+    (content_type, out)
+}
+
+/// Root handler: an unauthenticated at-a-glance status with the details enabled in
+/// ROOT_SUMMARY_FIELDS
+#[get("/")]
+async fn root_handler(
+    db: &State<Arc<DatabaseService>>,
+    brokers: &State<Brokers>,
+    started_at: &State<StartedAt>,
+    config: &State<Config>,
+) -> Json<ServiceSummary> {
+    let mut summary = ServiceSummary {
+        status: "ok".to_string(),
+        version: None,
+        connected_brokers: None,
+        topics: None,
+        uptime_secs: None,
+    };
+    for field in &config.root_summary_fields {
+        match field {
+            RootSummaryField::Version => summary.version = Some(env!("CARGO_PKG_VERSION").to_string()),
+            RootSummaryField::Brokers => {
+                let mut connected = 0;
+                for broker in [&brokers.internal, &brokers.monitored].into_iter().chain(&brokers.additional_monitored) {
+                    if matches!(broker.client_state().await, ClientState::Connected) {
+                        connected += 1;
+                    }
+                }
+                summary.connected_brokers = Some(connected);
+            }
+            RootSummaryField::Topics => summary.topics = db.count_topics().ok(),
+            RootSummaryField::Uptime => summary.uptime_secs = Some(started_at.0.elapsed().as_secs()),
+        }
+    }
+    Json(summary)
+}
+
+/// Readiness probe: 200 when the database answers and every monitored MQTT service is
+/// connected, 503 naming the failing components otherwise. The internal service is
+/// reported, but not required.
+#[get("/health")]
+async fn health(db: &State<Arc<DatabaseService>>, brokers: &State<Brokers>) -> (Status, Json<HealthDto>) {
+    let mut failing = Vec::new();
+    let db_state = match db.inner().clone().blocking(|db| db.ping()).await {
+        Ok(()) => "ok",
+        Err(e) => {
+            error!("Health check failed to query the database: {:?}", e);
+            failing.push("db");
+            "error"
+        }
+    };
+    let monitored = brokers.monitored.client_state().await;
+    if !matches!(monitored, ClientState::Connected) {
+        failing.push("mqtt_monitored");
+    }
+    let mut additional_monitored = Vec::new();
+    for service in &brokers.additional_monitored {
+        let state = service.client_state().await;
+        if !matches!(state, ClientState::Connected) && !failing.contains(&"mqtt_additional_monitored") {
+            failing.push("mqtt_additional_monitored");
+        }
+        additional_monitored.push(BrokerStateDto {
+            broker: service.broker_name().to_string(),
+            state: state.name(),
+        });
+    }
+
+    let status = if failing.is_empty() { Status::Ok } else { Status::ServiceUnavailable };
+    (
+        status,
+        Json(HealthDto {
+            db: db_state,
+            mqtt_internal: brokers.internal.connection_state().await,
+            mqtt_monitored: monitored.name(),
+            mqtt_additional_monitored: additional_monitored,
+            failing,
+        }),
+    )
+}
+
+/// Connection state of every MQTT service, the broker each uses and why each last lost
+/// its connection, for liveness and readiness probes. 200 while all are connected, 503 with the same body
+/// otherwise.
+#[get("/health/mqtt")]
+async fn mqtt_health(brokers: &State<Brokers>) -> (Status, Json<MqttHealthDto>) {
+    let internal = MqttConnectionDto::of(&brokers.internal).await;
+    let monitored = MqttConnectionDto::of(&brokers.monitored).await;
+    let mut additional_monitored = Vec::new();
+    for service in &brokers.additional_monitored {
+        additional_monitored.push(BrokerConnectionDto {
+            broker: service.broker_name().to_string(),
+            connection: MqttConnectionDto::of(service).await,
+        });
+    }
+    let connected = internal.is_connected()
+        && monitored.is_connected()
+        && additional_monitored.iter().all(|broker| broker.connection.is_connected());
+    let status = match connected {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+    (status, Json(MqttHealthDto { internal, monitored, additional_monitored }))
+}
+
+/// Action handler
+#[post("/action", data = "<payload>")]
+fn action_handler(_token: AuthToken, payload: Json<ApiRequest>) -> Result<Json<ApiResponse>, Status> {
+    match payload.action.as_str() {
+        "ping" => Ok(Json(ApiResponse {
+            status: "success".to_string(),
+            message: "pong".to_string(),
+        })),
+        _ => Ok(Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Unknown action".to_string(),
+        })),
+    }
+}
+
+/// Error body of rejected requests
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ErrorDto {
+    error: String,
+}
+
+/// 400 responses, telling what's wrong when a guard like `TimeRangeQuery` rejected the
+/// request
+#[catch(400)]
+fn bad_request(req: &Request<'_>) -> Json<ErrorDto> {
+    Json(ErrorDto {
+        error: rejection_message(req).unwrap_or("Bad Request").to_string(),
+    })
+}
+
+/// Exchange API credentials for a JWT valid for JWT_EXPIRATION_MINUTES, to be sent as
+/// `Authorization: Bearer <token>`. Credentials are verified by the REST API auth
+/// backend. 500 when no JWT_SECRET_KEY is configured.
+#[post("/login", data = "<creds>")]
+async fn login(
+    creds: Json<LoginRequest>,
+    auth_backend: &State<Arc<dyn AuthBackend>>,
+    config: &State<Config>,
+) -> Result<Json<LoginResponse>, (Status, Json<ErrorDto>)> {
+    let fail = |status: Status, error: &str| (status, Json(ErrorDto { error: error.to_string() }));
+    let Some(secret) = config.jwt_secret_key.as_deref().filter(|secret| !secret.is_empty()) else {
+        error!("Cannot issue a token without JWT_SECRET_KEY.");
+        return Err(fail(Status::InternalServerError, "Token signing is not configured"));
+    };
+
+    // Password hashes are slow to check on purpose, see `verify_basic_auth`
+    let backend = auth_backend.inner().clone();
+    let creds = creds.into_inner();
+    let verified = tokio::task::spawn_blocking(move || {
+        backend.verify(&Credentials {
+            username: &creds.username,
+            password: &creds.password,
+        })
+    })
+    .await;
+    let identity = match verified {
+        Ok(Ok(identity)) => identity,
+        Ok(Err(AuthError::InvalidCredentials)) => return Err(fail(Status::Unauthorized, "Invalid username or password")),
+        Ok(Err(e)) => {
+            error!("Failed to verify credentials: {}", e);
+            return Err(fail(Status::InternalServerError, "Credentials could not be verified"));
+        }
+        Err(e) => {
+            error!("Credential verification did not finish: {}", e);
+            return Err(fail(Status::InternalServerError, "Credentials could not be verified"));
+        }
+    };
+
+    let expires_at = OffsetDateTime::now_utc() + Duration::minutes(config.jwt_expiration_minutes as i64);
+    let claims = TokenClaims {
+        sub: Some(identity.username),
+        exp: expires_at.unix_timestamp(),
+    };
+    let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+    let token = jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256), &claims, &key)
+        .map_err(|e| {
+            error!("Failed to sign token: {}", e);
+            fail(Status::InternalServerError, "Token could not be signed")
+        })?;
+    Ok(Json(LoginResponse {
+        token,
+        expires_at: expires_at.format(&time::format_description::well_known::Rfc3339).unwrap_or_default(),
+    }))
+}
+
+/// 401 responses, telling why `AuthToken` rejected the request
+#[catch(401)]
+fn unauthorized(req: &Request<'_>) -> Json<ErrorDto> {
+    Json(ErrorDto {
+        error: req.local_cache(|| TokenRejection(None)).0.unwrap_or("Unauthorized").to_string(),
+    })
+}
+
+/// Run the Rocket server with the provided DatabaseService and Config
+pub async fn run_rest_server(
+    db_service: Arc<DatabaseService>,
+    config: Config,
+    log_stream: LogStream,
+    state: SharedState,
+    mqtt_service: Arc<MqttService>,
+    brokers: Brokers,
+    archive: Arc<Archive>,
+) {
+    let figment = Figment::from(rocket::Config::default())
+        .merge(("address", config.rest_api_host.clone()))
+        .merge(("port", config.rest_api_port));
+    let figment = match (&config.rest_api_tls_cert_path, &config.rest_api_tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls_config = crate::tls::rocket_tls_config(cert_path, key_path)
+                .unwrap_or_else(|e| panic!("Failed to load REST API TLS configuration: {}", e));
+            figment.merge(("tls", tls_config))
+        }
+        _ => figment,
+    };
+
+    let rocket = build_rocket(figment, db_service, config.clone(), log_stream, state, mqtt_service, brokers, archive);
+
+    // Unix socket only replaces the TCP listener, all routes and fairings stay the same
+    if let Some(socket_path) = &config.rest_api_uds_path {
+        crate::unix_socket::serve(rocket, socket_path)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to serve REST API on unix socket: {:?}", e));
+        return;
+    }
+
+    rocket
+        .launch()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to launch Rocket server: {:?}", e));
+}
+
+/// The REST API with its state, routes, catchers and fairings, ready to launch
+#[allow(clippy::too_many_arguments)]
+fn build_rocket(
+    figment: Figment,
+    db_service: Arc<DatabaseService>,
+    config: Config,
+    log_stream: LogStream,
+    state: SharedState,
+    mqtt_service: Arc<MqttService>,
+    brokers: Brokers,
+    archive: Arc<Archive>,
+) -> Rocket<Build> {
+    let started_at = StartedAt(std::time::Instant::now());
+    let auth_backend: Arc<dyn AuthBackend> = match config.rest_api_auth_backend {
+        AuthBackendKind::Static => Arc::new(StaticCredentials::new(
+            config.rest_api_username.clone(),
+            config.rest_api_password.clone(),
+        )),
+        AuthBackendKind::Database => {
+            let users = DatabaseUsers::new(db_service.clone());
+            if let Err(e) = users.bootstrap(config.rest_api_username.as_deref(), config.rest_api_password.as_deref()) {
+                error!("Failed to create initial API user: {}", e);
+            }
+            Arc::new(users)
+        }
+    };
+
+    rocket::custom(figment)
+        .manage(db_service.clone()) // DatabaseService korrekt registrieren
+        .manage(config.clone())    // Config korrekt registrieren
+        .manage(log_stream)
+        .manage(auth_backend)
+        .manage(state)
+        .manage(mqtt_service)
+        .manage(brokers)
+        .manage(started_at)
+        .manage(SchemaCache::default())
+        .manage(archive)
+        .manage(Arc::new(TailSessions {
+            open: AtomicUsize::new(0),
+            max: config.debug_tail_max_sessions,
+        }))
+        .mount(config.rest_api_base_path.as_str(), routes![root_handler, preflight, health, mqtt_health, action_handler, login, list_topics, topic_health, join_topics, last_value, last_values, topic_stats, topic_schema, insert_value, rename_topic, watch_topic, get_unit_rule, set_unit_rule, delete_unit_rule, get_message_id_field, set_message_id_field, delete_message_id_field, get_store_interval, set_store_interval, get_delta_storage, set_delta_storage, get_value_type, set_value_type, get_topic_qos, set_topic_qos, get_persistence, set_persistence, get_retention, set_retention, get_materialization, set_materialization, delete_materialization, materialized_rows, value_range, get_pre_aggregation, set_pre_aggregation, clear_retained, publish, add_runtime_subscription, remove_runtime_subscription, value_by_id, delta, downsample, query, audit_log, storage, list_archives, archived_values, ingest_rate, export_ndjson, replay_to_broker, replay_status, cancel_replay, list_users, create_user, delete_user, list_brokers, get_broker, save_broker, delete_broker, list_subscriptions, add_subscription, set_subscription_active, remove_subscription, log_stream, debug_tail, metrics])
+        .register(config.rest_api_base_path.as_str(), catchers![bad_request, unauthorized])
+        .attach(RateLimiter::new(&config))
+        .attach(Cors::new(&config))
+        .attach(AuditLog)
+        .attach(ResponseLimits::new(&config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+    use crate::mqtt_service::tests::{
+        attach_client, mark_connected, mark_disconnected, receive, start_session, test_broker_mqtt_config, test_service, test_service_for,
+    };
+    use crate::mqtt_service::DisconnectCause;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    /// Credentials of the static API user in the test configuration
+    const USERNAME: &str = "tester";
+    const PASSWORD: &str = "secret";
+
+    /// The test configuration: `.env` with the API user and `vars` on top
+    fn test_config(vars: &[(&str, &str)]) -> Config {
+        let mut all_vars = vec![
+            ("REST_API_USERNAME", USERNAME),
+            ("REST_API_PASSWORD", PASSWORD),
+            ("REST_API_AUTH_ENABLED", "true"),
+            ("REST_API_AUTH_BACKEND", "static"),
+            ("REST_API_BASE_PATH", "/"),
+            ("JWT_AUTH_ENABLED", "false"),
+            ("MAX_API_REQUESTS_PER_MINUTE", "0"),
+        ];
+        all_vars.extend_from_slice(vars);
+        config(&all_vars)
+    }
+
+    /// A client of the API on a fresh in-memory database, configured from `.env` with
+    /// `vars` on top. The MQTT services are never started.
+    fn client_with(vars: &[(&str, &str)]) -> Client {
+        let config = test_config(vars);
+        let internal = test_service(&config, None);
+        client_for(config, internal)
+    }
+
+    /// Like `client_with`, with `internal` as the internal MQTT service
+    fn client_for(config: Config, internal: Arc<MqttService>) -> Client {
+        let db = Arc::new(DatabaseService::in_memory().with_topic_limit(config.max_topics, config.max_topics_evict));
+        let monitored = test_service(&config, Some(db.clone()));
+        let brokers = Brokers {
+            internal: internal.clone(),
+            monitored,
+            additional_monitored: config
+                .additional_monitored_brokers
+                .iter()
+                .map(|broker| test_service_for(&config, broker, Some(db.clone())))
+                .collect(),
+        };
+        let state: SharedState = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        let archive = Arc::new(Archive::new("archive", config.archive_format, None));
+        let figment = Figment::from(rocket::Config::debug_default()).merge(("log_level", rocket::config::LogLevel::Off));
+
+        let rocket = build_rocket(figment, db, config, LogStream::new(16), state, internal, brokers, archive);
+        Client::tracked(rocket).expect("valid rocket instance")
+    }
+
+    fn client() -> Client {
+        client_with(&[])
+    }
+
+    fn db(client: &Client) -> &Arc<DatabaseService> {
+        client.rocket().state::<Arc<DatabaseService>>().expect("database is managed")
+    }
+
+    fn basic_auth() -> Header<'static> {
+        Header::new("Authorization", format!("Basic {}", BASE64.encode(format!("{}:{}", USERNAME, PASSWORD))))
+    }
+
+    #[test]
+    fn message_id_field_round_trip() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+
+        let response = client.get("/topics/sensors%2Fa/message-id-field").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .put("/topics/sensors%2Fa/message-id-field")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"field": "id"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+
+        let response = client.get("/topics/sensors%2Fa/message-id-field").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), r#"{"field":"id"}"#);
+
+        let response = client
+            .delete("/topics/sensors%2Fa/message-id-field")
+            .header(basic_auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(db(&client).get_message_id_field("sensors/a").unwrap(), None);
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_answered_429_without_reaching_their_route() {
+        let client = client_with(&[("MAX_API_REQUESTS_PER_MINUTE", "1")]);
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        let peer: std::net::SocketAddr = "192.0.2.1:40000".parse().unwrap();
+
+        let response = client.get("/topics").remote(peer).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .put("/topics/sensors%2Fa/store-interval")
+            .remote(peer)
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"min_store_interval_ms": 60000}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(body["error"], "Too many requests, at most 1 per minute are allowed");
+        assert_eq!(db(&client).get_min_store_interval("sensors/a").unwrap(), Some(0));
+        assert!(db(&client).get_audit_entries(None, None, 10).unwrap().is_empty());
+
+        // A client header doesn't make the same peer another client
+        let response = client.get("/topics").remote(peer).header(Header::new("X-Real-IP", "192.0.2.2")).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = client.get("/topics").remote("192.0.2.2:40000".parse().unwrap()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    /// Mark every MQTT service of `client` connected
+    fn connect_all(client: &Client) {
+        let brokers = client.rocket().state::<Brokers>().expect("brokers are managed");
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        for service in [&brokers.internal, &brokers.monitored].into_iter().chain(&brokers.additional_monitored) {
+            runtime.block_on(mark_connected(service));
+        }
+    }
+
+    #[test]
+    fn health_is_ok_with_a_responding_database_and_connected_brokers() {
+        let client = client();
+        let health = || {
+            let response = client.get("/health").dispatch();
+            let status = response.status();
+            (status, serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap())
+        };
+
+        connect_all(&client);
+        let (status, body) = health();
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["db"], "ok");
+        assert_eq!(body["mqtt_internal"], "connected");
+        assert_eq!(body["mqtt_monitored"], "connected");
+        assert!(body.get("failing").is_none());
+
+        db(&client).execute_batch("DROP TABLE topics").unwrap();
+        let (status, body) = health();
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(body["db"], "error");
+        assert_eq!(body["failing"], serde_json::json!(["db"]));
+    }
+
+    #[test]
+    fn health_and_root_include_additional_monitored_brokers() {
+        let client = client_with(&[("MONITORED_BROKER_1_HOST", "broker-b"), ("ROOT_SUMMARY_FIELDS", "brokers")]);
+
+        let response = client.get("/health").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let health: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(health["mqtt_additional_monitored"][0]["broker"], "monitored_1");
+        assert_eq!(health["mqtt_additional_monitored"][0]["state"], "disconnected");
+        assert!(health["failing"].as_array().unwrap().contains(&"mqtt_additional_monitored".into()));
+
+        connect_all(&client);
+        let response = client.get("/health").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let health: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(health["mqtt_additional_monitored"][0]["state"], "connected");
+
+        let response = client.get("/").dispatch();
+        let summary: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(summary["connected_brokers"], 3);
+    }
+
+    #[test]
+    fn monitored_brokers_on_one_host_are_told_apart_by_name() {
+        let host = config(&[]).monitored_mqtt_host;
+        let client = client_with(&[("MONITORED_BROKER_1_HOST", host.as_str()), ("MONITORED_BROKER_1_PORT", "1884")]);
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let additional = &brokers.additional_monitored[0];
+        assert_eq!(brokers.monitored.broker_name(), host);
+        assert_eq!(additional.broker_name(), "monitored_1");
+        assert!(Arc::ptr_eq(brokers.monitored_by_name("monitored_1").unwrap(), additional));
+
+        let defaults = TopicDefaults { max_values: 100, query_frequency_ms: 0 };
+        db(&client).save_broker("monitored_1", &host, 1884, None, None, false).unwrap();
+        db(&client).ensure_topic("sensors/a", "monitored_1", defaults).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let publish = |payload: &str| rumqttc::Publish::new("sensors/a", rumqttc::QoS::AtLeastOnce, payload.to_string());
+            receive(&brokers.monitored, publish("1")).await;
+            receive(additional, publish("2")).await;
+        });
+        // Only the broker the topic is registered for stores it
+        assert_eq!(db(&client).count_values("sensors/a").unwrap(), 1);
+        assert_eq!(db(&client).get_last_value("sensors/a").unwrap().unwrap().value, "2");
+    }
+
+    #[test]
+    fn mqtt_health_metrics_and_clear_retained_cover_additional_monitored_brokers() {
+        let client = client_with(&[("MONITORED_BROKER_1_HOST", "broker-b")]);
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            mark_connected(&brokers.internal).await;
+            mark_connected(&brokers.monitored).await;
+        });
+
+        let response = client.get("/health/mqtt").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let health: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(health["additional_monitored"][0]["broker"], "monitored_1");
+        assert_eq!(health["additional_monitored"][0]["state"], "disconnected");
+        assert_eq!(health["additional_monitored"][0]["active_endpoint"], "broker-b:1883");
+        connect_all(&client);
+        assert_eq!(client.get("/health/mqtt").dispatch().status(), Status::Ok);
+
+        let body = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(body.contains("mqtt_connected{service=\"monitored_1\"} 1\n"));
+        assert!(body.contains("mqtt_rejected_payloads_total{service=\"monitored_1\"} 0\n"));
+
+        let additional = runtime.block_on(attach_client(&brokers.additional_monitored[0]));
+        let response = client.post("/topics/plant%2Fstatus/clear-retained?broker=monitored_1").header(basic_auth()).dispatch();
+        assert_eq!(response.status(), Status::Accepted);
+        assert!(matches!(additional.try_recv(), Ok(rumqttc::Request::Publish(publish)) if publish.topic == "plant/status"));
+    }
+
+    #[test]
+    fn mqtt_health_reports_the_last_disconnect_cause() {
+        let client = client();
+        let response = client.get("/health/mqtt").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let health: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(health["monitored"]["state"], "disconnected");
+        assert!(health["monitored"].get("last_disconnect_cause").is_none());
+        let config = client.rocket().state::<Config>().unwrap();
+        let primary = format!("{}:{}", config.monitored_mqtt_host, config.monitored_mqtt_port);
+        assert_eq!(health["monitored"]["active_endpoint"], primary.as_str());
+        assert!(health["monitored"].get("failed_over_to").is_none());
+
+        connect_all(&client);
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(mark_disconnected(&brokers.monitored, DisconnectCause::AuthFailure));
+        let response = client.get("/health/mqtt").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let health: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(health["internal"]["state"], "connected");
+        assert_eq!(health["monitored"]["state"], "disconnected");
+        assert_eq!(health["monitored"]["last_disconnect_cause"], "auth failure");
+    }
+
+    #[test]
+    fn mqtt_health_is_ok_only_while_both_services_are_connected() {
+        let client = client();
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let health = || {
+            let response = client.get("/health/mqtt").dispatch();
+            let status = response.status();
+            let health: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            (status, health["internal"]["state"].clone(), health["monitored"]["state"].clone())
+        };
+
+        runtime.block_on(mark_connected(&brokers.internal));
+        assert_eq!(health(), (Status::ServiceUnavailable, "connected".into(), "disconnected".into()));
+        runtime.block_on(mark_connected(&brokers.monitored));
+        assert_eq!(health(), (Status::Ok, "connected".into(), "connected".into()));
+        runtime.block_on(mark_disconnected(&brokers.internal, DisconnectCause::Network));
+        assert_eq!(health(), (Status::ServiceUnavailable, "disconnected".into(), "connected".into()));
+    }
+
+    #[test]
+    fn store_interval_round_trip() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+
+        let response = client
+            .put("/topics/sensors%2Fa/store-interval")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"min_store_interval_ms": 60000}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+
+        let response = client.get("/topics/sensors%2Fa/store-interval").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"min_store_interval_ms":60000}"#);
+
+        db(&client).insert_value("sensors/a", "1").unwrap();
+        db(&client).insert_value("sensors/a", "2").unwrap();
+        assert_eq!(db(&client).count_values("sensors/a").unwrap(), 1);
+
+        let response = client.get("/topics/missing/store-interval").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .put("/topics/missing/store-interval")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"min_store_interval_ms": 0}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn delta_storage_round_trip() {
+        let client = client();
+        db(&client).register_topic("devices/state", 100).unwrap();
+
+        let response = client
+            .put("/topics/devices%2Fstate/delta-storage")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"snapshot_interval": 5}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let response = client.get("/topics/devices%2Fstate/delta-storage").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"snapshot_interval":5}"#);
+
+        db(&client).insert_value("devices/state", r#"{"a":1,"b":2}"#).unwrap();
+        db(&client).insert_value("devices/state", r#"{"a":1,"b":3}"#).unwrap();
+        let last = db(&client).get_last_value("devices/state").unwrap().unwrap().value;
+        assert_eq!(last, r#"{"a":1,"b":3}"#);
+
+        let response = client.get("/topics/missing/delta-storage").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn value_type_round_trip() {
+        let client = client();
+        db(&client).register_topic("door/open", 100).unwrap();
+        let put = |topic: &str, body: &'static str| {
+            client
+                .put(format!("/topics/{}/value-type", topic))
+                .header(basic_auth())
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(put("door%2Fopen", r#"{"value_type": "boolean"}"#), Status::NoContent);
+        let response = client.get("/topics/door%2Fopen/value-type").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"value_type":"boolean"}"#);
+
+        assert_eq!(put("door%2Fopen", r#"{"value_type": "decimal"}"#), Status::BadRequest);
+        assert_eq!(put("missing", r#"{"value_type": "enum"}"#), Status::NotFound);
+        let response = client.get("/topics/missing/value-type").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn topic_qos_round_trip() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        let put = |topic: &str, body: &'static str| {
+            client
+                .put(format!("/topics/{}/qos", topic))
+                .header(basic_auth())
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status()
+        };
+
+        let response = client.get("/topics/sensors%2Fa/qos").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"qos":null}"#);
+        assert_eq!(put("sensors%2Fa", r#"{"qos": 2}"#), Status::NoContent);
+        let response = client.get("/topics/sensors%2Fa/qos").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"qos":2}"#);
+        assert_eq!(put("sensors%2Fa", r#"{"qos": null}"#), Status::NoContent);
+        assert_eq!(db(&client).get_qos("sensors/a").unwrap(), Some(None));
+
+        assert_eq!(put("sensors%2Fa", r#"{"qos": 3}"#), Status::BadRequest);
+        assert_eq!(put("missing", r#"{"qos": 1}"#), Status::NotFound);
+        let response = client.get("/topics/missing/qos").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn binary_values_are_marked_base64() {
+        let client = client();
+        db(&client).register_topic("cam/frame", 100).unwrap();
+        db(&client).insert_value_bytes("cam/frame", &[0xff, 0x00]).unwrap();
+        let encoded = BASE64.encode([0xff, 0x00]);
+
+        let response = client.get("/topics/cam%2Fframe/last").header(basic_auth()).dispatch();
+        let last: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(last["value"], encoded.as_str());
+        assert_eq!(last["is_binary"], true);
+
+        db(&client).insert_value("cam/frame", "text").unwrap();
+        let response = client.get("/topics/cam%2Fframe/values").header(basic_auth()).dispatch();
+        let values: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let values = values["values"].as_array().unwrap();
+        assert_eq!(values[0].as_array().unwrap().len(), 2);
+        assert_eq!(values[1][0], encoded.as_str());
+        assert_eq!(values[1][2], "base64");
+
+        let response = client.get("/admin/export/ndjson").header(basic_auth()).dispatch();
+        let first = response.into_string().unwrap().lines().next().unwrap().to_string();
+        let record: serde_json::Value = serde_json::from_str(&first).unwrap();
+        assert_eq!(record["is_binary"], true);
+    }
+
+    #[test]
+    fn export_includes_labels_and_raw_values() {
+        let client = client();
+        db(&client).register_topic("sensors/temp", 100).unwrap();
+        let rule = UnitRule { scale: 0.1, offset: 0.0, keep_raw: true };
+        db(&client).set_unit_rule("sensors/temp", Some(&rule)).unwrap();
+        let labels = vec![("site".to_string(), "north".to_string())];
+        db(&client).insert_value_with_labels("sensors/temp", "215", &labels).unwrap();
+
+        let response = client.get("/admin/export/ndjson").header(basic_auth()).dispatch();
+        let body = response.into_string().unwrap();
+        let record: serde_json::Value = serde_json::from_str(body.lines().next().unwrap()).unwrap();
+        assert_eq!(record["raw_value"], "215");
+        assert_eq!(record["labels"]["site"], "north");
+    }
+
+    #[test]
+    fn raw_values_are_returned_next_to_converted_ones() {
+        let client = client();
+        db(&client).register_topic("sensors/temp", 100).unwrap();
+        let rule = UnitRule { scale: 10.0, offset: 0.0, keep_raw: true };
+        db(&client).set_unit_rule("sensors/temp", Some(&rule)).unwrap();
+        db(&client).insert_value("sensors/temp", "2.5").unwrap();
+
+        let response = client.get("/topics/sensors%2Ftemp/last").header(basic_auth()).dispatch();
+        let last: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(last["value"], "25");
+        assert_eq!(last["raw_value"], "2.5");
+
+        let id = db(&client).get_last_value("sensors/temp").unwrap().unwrap().id;
+        let response = client.get(format!("/values/{}", id)).header(basic_auth()).dispatch();
+        let value: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(value["raw_value"], "2.5");
+
+        // Overflowing the conversion stores the value as received
+        db(&client).insert_value("sensors/temp", "1e308").unwrap();
+        let last = db(&client).get_last_value("sensors/temp").unwrap().unwrap();
+        assert_eq!(last.value, "1e308");
+        assert_eq!(last.raw_value, None);
+    }
+
+    #[test]
+    fn preflight_allows_authorized_writes() {
+        let client = client_with(&[("CORS_ALLOWED_ORIGINS", "https://app.example.com")]);
+        let response = client
+            .options("/topics/sensors%2Ftemp/unit")
+            .header(Header::new("Origin", "https://app.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "PUT"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let headers = response.headers();
+        assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some("https://app.example.com"));
+        assert!(headers.get_one("Access-Control-Allow-Methods").unwrap().contains("PUT"));
+        assert!(headers.get_one("Access-Control-Allow-Methods").unwrap().contains("DELETE"));
+        assert!(headers.get_one("Access-Control-Allow-Headers").unwrap().contains("Authorization"));
+    }
+
+    #[test]
+    fn cors_origins_match_exactly_or_by_subdomain_pattern() {
+        let cors = Cors::new(&crate::config::tests::config(&[(
+            "CORS_ALLOWED_ORIGINS",
+            "https://*.example.com,http://localhost:3000",
+        )]));
+
+        assert!(cors.is_origin_allowed("https://app.example.com"));
+        assert!(cors.is_origin_allowed("https://eu.app.EXAMPLE.com"));
+        assert!(cors.is_origin_allowed("http://localhost:3000"));
+        // The pattern only covers subdomains with the same scheme
+        assert!(!cors.is_origin_allowed("https://example.com"));
+        assert!(!cors.is_origin_allowed("http://app.example.com"));
+        assert!(!cors.is_origin_allowed("https://app.example.com.evil.org"));
+        assert!(!cors.is_origin_allowed("https://evilexample.com"));
+        assert!(!cors.is_origin_allowed("http://localhost:8080"));
+
+        let any = Cors::new(&crate::config::tests::config(&[("CORS_ALLOWED_ORIGINS", "*")]));
+        assert!(any.is_origin_allowed("http://anything.test"));
+    }
+
+    #[test]
+    fn cors_headers_are_only_set_for_allowed_origins() {
+        let client = client_with(&[("CORS_ALLOWED_ORIGINS", "https://*.example.com")]);
+        let origin = |origin: &'static str| {
+            let response = client.get("/health").header(Header::new("Origin", origin)).dispatch();
+            response.headers().get_one("Access-Control-Allow-Origin").map(str::to_string)
+        };
+
+        assert_eq!(origin("https://app.example.com").as_deref(), Some("https://app.example.com"));
+        assert_eq!(origin("http://app.example.com"), None);
+
+        let client = client_with(&[("CORS_ALLOWED_ORIGINS", "*"), ("CORS_ENABLED", "false")]);
+        let response = client.get("/health").header(Header::new("Origin", "https://app.example.com")).dispatch();
+        assert!(response.headers().get_one("Access-Control-Allow-Origin").is_none());
+        assert!(response.headers().get_one("Access-Control-Allow-Methods").is_none());
+    }
+
+    #[test]
+    fn clearing_retained_values_publishes_an_empty_retained_message() {
+        let client = client();
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let internal = runtime.block_on(attach_client(&brokers.internal));
+        let monitored = runtime.block_on(attach_client(&brokers.monitored));
+        let clear = |uri: &str, auth: bool| {
+            let request = client.post(uri.to_string());
+            let request = if auth { request.header(basic_auth()) } else { request };
+            request.dispatch().status()
+        };
+
+        assert_eq!(clear("/topics/plant%2Fstatus/clear-retained?broker=monitored", true), Status::Accepted);
+        let Ok(rumqttc::Request::Publish(publish)) = monitored.try_recv() else {
+            panic!("an empty retained message is published");
+        };
+        assert_eq!(publish.topic, "plant/status");
+        assert!(publish.payload.is_empty());
+        assert!(publish.retain);
+        assert!(internal.is_empty());
+
+        assert_eq!(clear("/topics/plant%2Fstatus/clear-retained", false), Status::Unauthorized);
+        assert_eq!(clear("/topics/plant%2F%23/clear-retained", true), Status::BadRequest);
+        assert_eq!(clear("/topics/plant%2Fstatus/clear-retained?broker=other", true), Status::BadRequest);
+        assert!(internal.is_empty() && monitored.is_empty());
+    }
+
+    #[test]
+    fn messages_are_published_through_the_internal_broker_once_connected() {
+        let client = client();
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let internal = runtime.block_on(attach_client(&brokers.internal));
+        let publish = |body: &str, auth: bool| {
+            let request = client.post("/publish").header(ContentType::JSON).body(body);
+            let request = if auth { request.header(basic_auth()) } else { request };
+            request.dispatch().status()
+        };
+        let message = r#"{"topic": "plant/command", "payload": "start", "qos": 2, "retain": true}"#;
+
+        assert_eq!(publish(message, true), Status::ServiceUnavailable);
+        connect_all(&client);
+        assert_eq!(publish(message, false), Status::Unauthorized);
+        assert_eq!(publish(r#"{"topic": "plant/command", "payload": "start", "qos": 3}"#, true), Status::BadRequest);
+        assert_eq!(publish(r#"{"topic": "plant/+", "payload": "start"}"#, true), Status::BadRequest);
+        assert!(internal.is_empty());
+
+        assert_eq!(publish(message, true), Status::Accepted);
+        let Ok(rumqttc::Request::Publish(sent)) = internal.try_recv() else {
+            panic!("the message is published");
+        };
+        assert_eq!(sent.topic, "plant/command");
+        assert_eq!(&sent.payload[..], b"start");
+        assert_eq!(sent.qos, rumqttc::QoS::ExactlyOnce);
+        assert!(sent.retain);
+    }
+
+    #[test]
+    fn runtime_subscriptions_are_added_and_removed_on_the_monitored_broker() {
+        let client = client();
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let monitored = runtime.block_on(attach_client(&brokers.monitored));
+        let add = |body: &str| {
+            client
+                .post("/mqtt/subscriptions")
+                .header(basic_auth())
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status()
+        };
+        let remove = |uri: &str| client.delete(uri.to_string()).header(basic_auth()).dispatch().status();
+
+        assert_eq!(add(r#"{"filter": "alarms/+", "qos": 1}"#), Status::ServiceUnavailable);
+        connect_all(&client);
+        assert_eq!(add(r#"{"filter": "alarms/#/x"}"#), Status::BadRequest);
+        assert_eq!(add(r#"{"filter": "alarms/+", "qos": 3}"#), Status::BadRequest);
+        assert!(monitored.is_empty());
+
+        assert_eq!(add(r#"{"filter": "alarms/+", "qos": 1}"#), Status::Created);
+        let Ok(rumqttc::Request::Subscribe(subscribe)) = monitored.try_recv() else {
+            panic!("the filter is subscribed to");
+        };
+        assert_eq!(subscribe.filters[0].path, "alarms/+");
+        assert_eq!(subscribe.filters[0].qos, rumqttc::QoS::AtLeastOnce);
+
+        assert_eq!(remove("/mqtt/subscriptions/sensors%2F%23"), Status::NotFound);
+        assert_eq!(remove("/mqtt/subscriptions/alarms%2F%2B"), Status::NoContent);
+        assert!(matches!(monitored.try_recv(), Ok(rumqttc::Request::Unsubscribe(unsubscribe)) if unsubscribe.topics == ["alarms/+"]));
+        assert_eq!(remove("/mqtt/subscriptions/alarms%2F%2B"), Status::NotFound);
+        assert_eq!(client.delete("/mqtt/subscriptions/alarms%2F%2B").dispatch().status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn ingest_rate_requires_a_bucket() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "1").unwrap();
+        let ingest_rate = |query: &str| client.get(format!("/admin/ingest-rate?{}", query)).header(basic_auth()).dispatch();
+
+        let response = ingest_rate("from=2000-01-01T00:00:00Z&to=2100-01-01T00:00:00Z&bucket=31536000000");
+        assert_eq!(response.status(), Status::Ok);
+        let buckets: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(buckets[0]["count"], 1);
+        assert_eq!(ingest_rate("from=2000-01-01T00:00:00Z&to=2100-01-01T00:00:00Z").status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn storage_reports_topics_against_the_limit() {
+        let client = client_with(&[("MAX_TOPICS", "2")]);
+        db(&client).register_topic("sensors/a", 100).unwrap();
+
+        let response = client.get("/admin/storage").header(basic_auth()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let storage: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(storage["topics"], 1);
+        assert_eq!(storage["max_topics"], 2);
+        assert!(storage["database_size_bytes"].as_u64().unwrap() > 0);
+
+        assert_eq!(client.get("/admin/storage").dispatch().status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn persistence_round_trip() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        let put = |topic: &str, auth: bool| {
+            let request = client
+                .put(format!("/topics/{}/persistence", topic))
+                .header(ContentType::JSON)
+                .body(r#"{"persist": false}"#);
+            let request = if auth { request.header(basic_auth()) } else { request };
+            request.dispatch().status()
+        };
+
+        let response = client.get("/topics/sensors%2Fa/persistence").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"persist":true}"#);
+        assert_eq!(put("sensors%2Fa", false), Status::Unauthorized);
+        assert_eq!(put("sensors%2Fa", true), Status::NoContent);
+        let response = client.get("/topics/sensors%2Fa/persistence").dispatch();
+        assert_eq!(response.into_string().unwrap(), r#"{"persist":false}"#);
+
+        assert_eq!(put("missing", true), Status::NotFound);
+        assert_eq!(client.get("/topics/missing/persistence").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn topics_are_listed_optionally_by_prefix() {
+        let client = client();
+        for topic in ["sensors/a", "sensors/b", "plant_x/status"] {
+            db(&client).register_topic(topic, 100).unwrap();
+        }
+        let names = |uri: &'static str| -> Vec<String> {
+            let body = client.get(uri).dispatch().into_string().unwrap();
+            let topics: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+            topics.iter().map(|topic| topic["topic"].as_str().unwrap().to_string()).collect()
+        };
+
+        assert_eq!(names("/topics"), ["plant_x/status", "sensors/a", "sensors/b"]);
+        assert_eq!(names("/topics?prefix=sensors%2F"), ["sensors/a", "sensors/b"]);
+        // Matched exactly, not as a LIKE pattern
+        assert_eq!(names("/topics?prefix=plant_x"), ["plant_x/status"]);
+        assert!(names("/topics?prefix=plant%25").is_empty());
+        assert!(names("/topics?prefix=Sensors").is_empty());
+    }
+
+    /// A Bearer header with a token signed with `secret` that expires `expires_in` from now
+    fn bearer(secret: &str, expires_in: Duration) -> Header<'static> {
+        let claims = TokenClaims {
+            sub: Some(USERNAME.to_string()),
+            exp: (OffsetDateTime::now_utc() + expires_in).unix_timestamp(),
+        };
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap();
+        Header::new("Authorization", format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn value_reads_require_a_valid_token_with_jwt_enabled() {
+        let client = client_with(&[("JWT_AUTH_ENABLED", "true"), ("JWT_SECRET_KEY", "test-secret")]);
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "21.5").unwrap();
+        let read = |header: Option<Header<'static>>| {
+            let request = client.get("/topics/sensors%2Fa/last");
+            let response = match header {
+                Some(header) => request.header(header).dispatch(),
+                None => request.dispatch(),
+            };
+            (response.status(), response.into_string().unwrap())
+        };
+
+        assert_eq!(read(Some(bearer("test-secret", Duration::minutes(5)))).0, Status::Ok);
+        assert_eq!(
+            read(Some(bearer("test-secret", Duration::hours(-1)))),
+            (Status::Unauthorized, r#"{"error":"The token has expired"}"#.to_string())
+        );
+        assert_eq!(
+            read(Some(bearer("other-secret", Duration::minutes(5)))),
+            (Status::Unauthorized, r#"{"error":"The token is invalid"}"#.to_string())
+        );
+        assert_eq!(
+            read(None),
+            (Status::Unauthorized, r#"{"error":"A Bearer token is required"}"#.to_string())
+        );
+
+        let client = client_with(&[("JWT_AUTH_ENABLED", "false")]);
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "21.5").unwrap();
+        assert_eq!(client.get("/topics/sensors%2Fa/last").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn login_issues_tokens_for_valid_credentials() {
+        let client = client_with(&[
+            ("JWT_AUTH_ENABLED", "true"),
+            ("JWT_SECRET_KEY", "test-secret"),
+            ("JWT_EXPIRATION_MINUTES", "60"),
+        ]);
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "21.5").unwrap();
+        let login = |password: &str| {
+            client
+                .post("/login")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({"username": USERNAME, "password": password}).to_string())
+                .dispatch()
+        };
+
+        let response = login(PASSWORD);
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let token = body["token"].as_str().unwrap();
+        let key = jsonwebtoken::DecodingKey::from_secret(b"test-secret");
+        let claims = jsonwebtoken::decode::<TokenClaims>(token, &key, &jsonwebtoken::Validation::default())
+            .unwrap()
+            .claims;
+        assert_eq!(claims.sub.as_deref(), Some(USERNAME));
+        let lifetime = claims.exp - OffsetDateTime::now_utc().unix_timestamp();
+        assert!((3500..=3600).contains(&lifetime), "token expires in {} s", lifetime);
+        let response = client
+            .get("/topics/sensors%2Fa/last")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = login("wrong");
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.into_string().unwrap(), r#"{"error":"Invalid username or password"}"#);
+
+        let client = client_with(&[("JWT_AUTH_ENABLED", "false"), ("JWT_SECRET_KEY", "")]);
+        let response = client
+            .post("/login")
+            .header(ContentType::JSON)
+            .body(serde_json::json!({"username": USERNAME, "password": PASSWORD}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn brokers_are_saved_listed_without_passwords_and_deleted_with_their_subscriptions() {
+        let client = client();
+        let save = |body: &str| {
+            client.post("/brokers").header(basic_auth()).header(ContentType::JSON).body(body).dispatch().status()
+        };
+
+        let broker = r#"{"name": "plant", "host": "plant.local", "port": 8883, "username": "mf", "password": "hunter2", "tls_enabled": true}"#;
+        assert_eq!(save(broker), Status::Created);
+        assert_eq!(save(r#"{"name": "plant", "host": "plant.local", "port": 1883, "username": "mf"}"#), Status::Ok);
+        assert_eq!(save(r#"{"name": "", "host": "plant.local", "port": 1883}"#), Status::BadRequest);
+        assert_eq!(client.post("/brokers").header(ContentType::JSON).body(broker).dispatch().status(), Status::Unauthorized);
+        // Omitting the password on update keeps the stored one
+        assert_eq!(db(&client).get_broker("plant").unwrap().unwrap().password.as_deref(), Some("hunter2"));
+
+        let response = client.get("/brokers").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(!body.contains("hunter2"));
+        let brokers: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let plant = brokers.as_array().unwrap().iter().find(|broker| broker["name"] == "plant").unwrap();
+        assert_eq!(plant["port"], 1883);
+        assert_eq!(plant["has_password"], true);
+        assert!(plant.get("password").is_none());
+        let body = client.get("/brokers/plant").dispatch().into_string().unwrap();
+        assert!(!body.contains("hunter2") && body.contains(r#""has_password":true"#));
+
+        let defaults = TopicDefaults { max_values: 100, query_frequency_ms: 0 };
+        db(&client).add_subscription("plant", "sensors/a", defaults).unwrap().unwrap();
+        assert_eq!(client.delete("/brokers/plant").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.delete("/brokers/plant").header(basic_auth()).dispatch().status(), Status::NoContent);
+        assert_eq!(client.get("/brokers/plant").dispatch().status(), Status::NotFound);
+        assert_eq!(client.get("/brokers/plant/subscriptions").dispatch().status(), Status::NotFound);
+        assert_eq!(client.delete("/brokers/plant").header(basic_auth()).dispatch().status(), Status::NotFound);
+        // The topic stays, no longer bound to a broker
+        assert_eq!(db(&client).validate_topic_persist("sensors/a", "other").unwrap(), Some(true));
+    }
+
+    #[test]
+    fn subscriptions_are_added_toggled_and_removed_on_the_connected_broker() {
+        let client = client_with(&[("MQTT_SUBSCRIPTIONS_FROM_DB", "true")]);
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+        let broker = brokers.monitored.broker_name().to_string();
+        db(&client).save_broker(&broker, &broker, 1883, None, None, false).unwrap();
+        connect_all(&client);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let requests = runtime.block_on(attach_client(&brokers.monitored));
+        let subscriptions = format!("/brokers/{}/subscriptions", broker);
+        let list = || -> serde_json::Value {
+            serde_json::from_str(&client.get(subscriptions.as_str()).dispatch().into_string().unwrap()).unwrap()
+        };
+        let add = |topic: &str| {
+            let body = format!(r#"{{"topic": "{}"}}"#, topic);
+            client.post(subscriptions.as_str()).header(basic_auth()).header(ContentType::JSON).body(body).dispatch().status()
+        };
+
+        assert_eq!(add("sensors/#"), Status::Created);
+        assert_eq!(add("alarms/+"), Status::Created);
+        assert_eq!(add("sensors/#/a"), Status::BadRequest);
+        let response = client
+            .post("/brokers/missing/subscriptions")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"topic": "a"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        for topic in ["sensors/#", "alarms/+"] {
+            let Ok(rumqttc::Request::Subscribe(subscribe)) = requests.try_recv() else {
+                panic!("'{}' is subscribed to right away", topic);
+            };
+            assert_eq!(subscribe.filters[0].path, topic);
+        }
+
+        let response = client
+            .put(format!("{}/alarms%2F%2B", subscriptions))
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"active": false}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(matches!(requests.try_recv(), Ok(rumqttc::Request::Unsubscribe(unsubscribe)) if unsubscribe.topics == ["alarms/+"]));
+        assert_eq!(list(), serde_json::json!([{"topic": "alarms/+", "active": false}, {"topic": "sensors/#", "active": true}]));
+        assert_eq!(db(&client).get_active_subscriptions(&broker).unwrap(), ["sensors/#"]);
+
+        let remove = |auth: bool| {
+            let request = client.delete(format!("{}/sensors%2F%23", subscriptions));
+            let request = if auth { request.header(basic_auth()) } else { request };
+            request.dispatch().status()
+        };
+        assert_eq!(remove(false), Status::Unauthorized);
+        assert_eq!(remove(true), Status::NoContent);
+        assert!(matches!(requests.try_recv(), Ok(rumqttc::Request::Unsubscribe(unsubscribe)) if unsubscribe.topics == ["sensors/#"]));
+        assert_eq!(remove(true), Status::NotFound);
+        assert_eq!(list(), serde_json::json!([{"topic": "alarms/+", "active": false}]));
+    }
+
+    #[test]
+    fn inserted_labels_filter_value_listings() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "1").unwrap();
+
+        let response = client
+            .post("/topics/sensors%2Fa/values")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"value": "2", "labels": {"site": "north"}}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let created: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(created["labels"]["site"], "north");
+
+        let response = client.get("/topics/sensors%2Fa/values?label.site=north").dispatch();
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let values = body["values"].as_array().unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0][0], "2");
+    }
+
+    #[test]
+    fn streams_end_with_an_error_when_reading_fails() {
+        let client = client_with(&[("REST_API_STREAMING_THRESHOLD_ROWS", "1")]);
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "1").unwrap();
+        db(&client).execute_batch("DROP TABLE value_labels").unwrap();
+
+        let response = client.get("/admin/export/ndjson").header(basic_auth()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        let last: serde_json::Value = serde_json::from_str(body.lines().last().unwrap()).unwrap();
+        assert_eq!(last["error"], "Failed to read values");
+        assert_eq!(last["after_id"], 0);
+
+        let response = client
+            .get("/topics/sensors%2Fa/values?limit=10&label.site=north")
+            .header(basic_auth())
+            .dispatch();
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(body["error"], "Failed to read values");
+    }
+
+    /// Accepts `USERNAME`/`PASSWORD`, counting the verifications
+    struct CountingBackend(Arc<AtomicUsize>);
+
+    impl AuthBackend for CountingBackend {
+        fn verify(&self, credentials: &Credentials<'_>) -> std::result::Result<crate::auth::Identity, AuthError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            match (credentials.username, credentials.password) {
+                (USERNAME, PASSWORD) => Ok(crate::auth::Identity {
+                    username: USERNAME.to_string(),
+                }),
+                _ => Err(AuthError::InvalidCredentials),
+            }
+        }
+    }
+
+    #[test]
+    fn audit_entries_name_the_subject_of_the_bearer_token() {
+        let client = client_with(&[("JWT_AUTH_ENABLED", "true"), ("JWT_SECRET_KEY", "test-secret")]);
+        let response = client
+            .post("/action")
+            .header(bearer("test-secret", Duration::minutes(5)))
+            .header(ContentType::JSON)
+            .body(r#"{"action": "ping"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(db(&client).get_audit_entries(None, None, 10).unwrap()[0].actor, USERNAME);
+    }
+
+    #[test]
+    fn credentials_are_verified_once_per_request() {
+        let verified = Arc::new(AtomicUsize::new(0));
+        let backend: Arc<dyn AuthBackend> = Arc::new(CountingBackend(verified.clone()));
+        let db = Arc::new(DatabaseService::in_memory());
+        db.register_topic("sensors/a", 100).unwrap();
+        let figment = Figment::from(rocket::Config::debug_default()).merge(("log_level", rocket::config::LogLevel::Off));
+        let rocket = rocket::custom(figment)
+            .manage(db.clone())
+            .manage(config(&[("REST_API_AUTH_ENABLED", "true")]))
+            .manage(backend)
+            .mount("/", routes![set_store_interval])
+            .attach(AuditLog);
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client
+            .put("/topics/sensors%2Fa/store-interval")
+            .header(basic_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"min_store_interval_ms": 100}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(verified.load(Ordering::Relaxed), 1);
+        assert_eq!(db.get_audit_entries(None, None, 10).unwrap()[0].actor, USERNAME);
+    }
+
+    /// Hook whose events are never handled, see `metrics_export_message_counters_per_service`
+    struct IdleHook;
+
+    impl crate::hooks::EventHook for IdleHook {}
+
+    #[test]
+    fn metrics_export_message_counters_per_service() {
+        let config = test_config(&[("MQTT_MAX_JSON_DEPTH", "2"), ("MQTT_EXCLUDE_TOPICS", "ignored/#")]);
+        // The hook task is spawned on a runtime that never runs again, so its queue fills up
+        let hook_runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let internal = hook_runtime.block_on(async {
+            let mqtt_config = test_broker_mqtt_config(&config, &config.internal_broker());
+            MqttService::with_hooks(mqtt_config, None, vec![Arc::new(IdleHook)])
+        });
+        let client = client_for(config, internal);
+        let brokers = client.rocket().state::<Brokers>().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let publish = |topic: &str, payload: &str| rumqttc::Publish::new(topic, rumqttc::QoS::AtLeastOnce, payload.to_string());
+            receive(&brokers.monitored, publish("sensors/a", "[[[1]]]")).await;
+            receive(&brokers.monitored, publish("ignored/a", "1")).await;
+            for _ in 0..1_025 {
+                receive(&brokers.internal, publish("commands/a", "1")).await;
+            }
+            let _requests = attach_client(&brokers.monitored).await;
+            start_session(&brokers.monitored, true).await;
+        });
+
+        let body = client.get("/metrics").dispatch().into_string().unwrap();
+        let counter = |name: &str, service: &str| -> u64 {
+            let prefix = format!("{}{{service=\"{}\"}} ", name, service);
+            let line = body.lines().find_map(|line| line.strip_prefix(prefix.as_str()));
+            line.unwrap_or_else(|| panic!("{} is exported for {}", name, service)).parse().unwrap()
+        };
+        for (name, service) in [
+            ("mqtt_rejected_payloads_total", "monitored"),
+            ("mqtt_excluded_messages_total", "monitored"),
+            ("mqtt_hook_events_dropped_total", "internal"),
+            ("mqtt_sessions_resumed_total", "monitored"),
+        ] {
+            assert!(body.contains(&format!("# TYPE {} counter\n", name)));
+            assert!(counter(name, service) > 0, "{} counts for {}", name, service);
+        }
+        assert_eq!(counter("mqtt_rejected_payloads_total", "internal"), 0);
+        assert_eq!(counter("mqtt_hook_events_dropped_total", "monitored"), 0);
+    }
+
+    #[test]
+    fn message_id_field_rejects_bad_requests() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        let put = |topic: &str, body: &'static str, auth: bool| {
+            let request = client
+                .put(format!("/topics/{}/message-id-field", topic))
+                .header(ContentType::JSON)
+                .body(body);
+            let request = if auth { request.header(basic_auth()) } else { request };
+            request.dispatch().status()
+        };
+
+        assert_eq!(put("sensors%2Fa", r#"{"field": "id"}"#, false), Status::Unauthorized);
+        assert_eq!(put("sensors%2Fa", r#"{"field": ""}"#, true), Status::BadRequest);
+        assert_eq!(put("missing", r#"{"field": "id"}"#, true), Status::NotFound);
+    }
+
+    #[test]
+    fn stats_summarize_numeric_values_optionally_since_a_timestamp() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).register_topic("sensors/text", 100).unwrap();
+        db(&client).insert_value("sensors/text", "on").unwrap();
+        for (value, timestamp) in [("10", "2024-05-01 10:00:00"), ("20", "2024-05-01 11:00:00"), ("30", "2024-05-01 12:00:00")] {
+            db(&client).insert_value_at("sensors/a", value, &[], Some(timestamp)).unwrap();
+        }
+        let stats = |uri: &str| {
+            let response = client.get(uri.to_string()).dispatch();
+            (response.status(), response.into_string().and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok()))
+        };
+
+        let (status, body) = stats("/topics/sensors%2Fa/stats");
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            body.unwrap(),
+            serde_json::json!({"topic": "sensors/a", "count": 3, "min": 10.0, "max": 30.0, "avg": 20.0, "last": 30.0})
+        );
+
+        let (_, body) = stats("/topics/sensors%2Fa/stats?since=2024-05-01T10:30:00Z");
+        let body = body.unwrap();
+        assert_eq!((body["count"].as_u64(), body["min"].as_f64()), (Some(2), Some(20.0)));
+
+        assert_eq!(stats("/topics/sensors%2Fa/stats?since=2024-05-01T13:00:00Z").0, Status::NotFound);
+        assert_eq!(stats("/topics/sensors%2Ftext/stats").0, Status::NotFound);
+        assert_eq!(stats("/topics/missing/stats").0, Status::NotFound);
+
+        db(&client).execute_batch("DROP TABLE topic_values").unwrap();
+        assert_eq!(stats("/topics/sensors%2Fa/stats").0, Status::InternalServerError);
+    }
+
+    #[test]
+    fn range_returns_the_values_between_valid_bounds() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        for (value, timestamp) in [("10", "2024-05-01 10:00:00"), ("20", "2024-05-01 11:00:00"), ("30", "2024-05-01 12:00:00")] {
+            db(&client).insert_value_at("sensors/a", value, &[], Some(timestamp)).unwrap();
+        }
+        let range = |query: &str| {
+            let response = client.get(format!("/topics/sensors%2Fa/range?{}", query)).dispatch();
+            (response.status(), response.into_string().unwrap())
+        };
+        let values = |body: &str| -> Vec<String> {
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            body["values"].as_array().unwrap().iter().map(|value| value[0].as_str().unwrap().to_string()).collect()
+        };
+
+        let (status, body) = range("from=2024-05-01T10:30:00Z&to=2024-05-01%2012:00:00");
+        assert_eq!(status, Status::Ok);
+        assert_eq!(values(&body), ["20", "30"]);
+        assert_eq!(values(&range("from=2024-05-01T09:00:00Z&to=2024-05-01T13:00:00Z&limit=2").1), ["10", "20"]);
+
+        let (status, body) = range("from=2024-05-01T12:00:00Z&to=2024-05-01T10:00:00Z");
+        assert_eq!(status, Status::BadRequest);
+        assert!(body.contains("'from' must not be after 'to'"));
+        let (status, body) = range("from=2024-05-01T10:00:00Z&to=2024-05-01T10:00:00Z");
+        assert_eq!(status, Status::Ok);
+        assert_eq!(values(&body), ["10"]);
+        assert_eq!(range("from=2024-05-01T10:00:00Z").0, Status::BadRequest);
+        assert_eq!(range("from=yesterday&to=2024-05-01T10:00:00Z").0, Status::BadRequest);
+        assert_eq!(range("from=2024-05-01T09:00:00Z&to=2024-05-01T13:00:00Z&limit=0").0, Status::BadRequest);
+    }
+
+    #[test]
+    fn stats_reject_an_invalid_since() {
+        let client = client();
+        db(&client).register_topic("sensors/a", 100).unwrap();
+        db(&client).insert_value("sensors/a", "21.5").unwrap();
+
+        let response = client.get("/topics/sensors%2Fa/stats?since=yesterday").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response.into_string().unwrap().contains("'since' is not an RFC 3339"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Kind of value inferred from stored payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InferredType {
+    Number,
+    Boolean,
+    /// JSON object or array
+    Json,
+    String,
+    /// Values of more than one kind
+    Mixed,
+}
+
+impl InferredType {
+    /// The common kind of `self` and `other`
+    fn merge(self, other: InferredType) -> InferredType {
+        if self == other {
+            self
+        } else {
+            InferredType::Mixed
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NumericRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl NumericRange {
+    fn include(range: &mut Option<NumericRange>, value: f64) {
+        match range {
+            Some(range) => {
+                range.min = range.min.min(value);
+                range.max = range.max.max(value);
+            }
+            None => *range = Some(NumericRange { min: value, max: value }),
+        }
+    }
+}
+
+/// A top-level field of JSON object values
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldSchema {
+    pub name: String,
+    /// `None` when the field was `null` in every sampled value
+    pub value_type: Option<InferredType>,
+    /// Number of sampled values carrying the field
+    pub present_in: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<NumericRange>,
+}
+
+/// What a sample of a topic's values looks like. Values are classified one by one:
+/// numbers, boolean words (`true`/`on`/`yes` and their opposites), JSON objects or
+/// arrays, and anything else as strings.
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicSchema {
+    pub value_type: InferredType,
+    pub sampled_values: usize,
+    /// Range of the numeric values, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<NumericRange>,
+    /// Fields of the JSON object values, in name order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldSchema>,
+}
+
+/// Infers the schema of `values`, `None` for an empty sample
+pub fn infer<'a>(values: impl IntoIterator<Item = &'a str>) -> Option<TopicSchema> {
+    let mut value_type = None;
+    let mut sampled_values = 0;
+    let mut range = None;
+    let mut fields: BTreeMap<String, FieldSchema> = BTreeMap::new();
+
+    for value in values {
+        sampled_values += 1;
+        let kind = classify(value);
+        value_type = Some(value_type.map_or(kind, |known: InferredType| known.merge(kind)));
+
+        match kind {
+            InferredType::Number => {
+                if let Ok(number) = value.trim().parse() {
+                    NumericRange::include(&mut range, number);
+                }
+            }
+            InferredType::Json => {
+                if let Ok(Value::Object(object)) = serde_json::from_str::<Value>(value) {
+                    for (name, field_value) in &object {
+                        let field = fields.entry(name.clone()).or_insert_with(|| FieldSchema {
+                            name: name.clone(),
+                            value_type: None,
+                            present_in: 0,
+                            range: None,
+                        });
+                        field.present_in += 1;
+                        let Some(kind) = json_kind(field_value) else {
+                            continue;
+                        };
+                        field.value_type = Some(field.value_type.map_or(kind, |known| known.merge(kind)));
+                        if let Some(number) = field_value.as_f64() {
+                            NumericRange::include(&mut field.range, number);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(TopicSchema {
+        value_type: value_type?,
+        sampled_values,
+        range,
+        fields: fields.into_values().collect(),
+    })
+}
+
+fn classify(value: &str) -> InferredType {
+    let trimmed = value.trim();
+    if trimmed.parse::<f64>().is_ok_and(|number| number.is_finite()) {
+        return InferredType::Number;
+    }
+    if matches!(
+        trimmed.to_lowercase().as_str(),
+        "true" | "false" | "on" | "off" | "yes" | "no"
+    ) {
+        return InferredType::Boolean;
+    }
+    if trimmed.starts_with(['{', '[']) && serde_json::from_str::<Value>(trimmed).is_ok() {
+        return InferredType::Json;
+    }
+    InferredType::String
+}
+
+/// Kind of a JSON field value, `None` for `null`
+fn json_kind(value: &Value) -> Option<InferredType> {
+    match value {
+        Value::Null => None,
+        Value::Bool(_) => Some(InferredType::Boolean),
+        Value::Number(_) => Some(InferredType::Number),
+        Value::String(_) => Some(InferredType::String),
+        Value::Array(_) | Value::Object(_) => Some(InferredType::Json),
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::config::ConfigError;
+
+/// Wire format of the status, progress, analytics and log messages we publish.
+///
+/// JSON is published to the configured topics unchanged. The binary formats are
+/// published to the same topics with a `/msgpack` or `/cbor` suffix so consumers can
+/// tell the encoding from the topic alone.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishFormat {
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl PublishFormat {
+    /// Serialize `payload` in this format
+    pub fn encode<T: Serialize>(&self, payload: &T) -> Result<Vec<u8>, String> {
+        match self {
+            PublishFormat::Json => serde_json::to_vec(payload).map_err(|e| e.to_string()),
+            PublishFormat::Msgpack => rmp_serde::to_vec_named(payload).map_err(|e| e.to_string()),
+            PublishFormat::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(payload, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    /// MIME type for the MQTT v5 content type, not sent by the MQTT 3.1.1 client
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PublishFormat::Json => "application/json",
+            PublishFormat::Msgpack => "application/msgpack",
+            PublishFormat::Cbor => "application/cbor",
+        }
+    }
+
+    /// Topic a payload of this format is published to
+    pub fn topic(&self, base_topic: &str) -> String {
+        match self {
+            PublishFormat::Json => base_topic.to_string(),
+            PublishFormat::Msgpack => format!("{}/msgpack", base_topic),
+            PublishFormat::Cbor => format!("{}/cbor", base_topic),
+        }
+    }
+}
+
+impl FromStr for PublishFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(PublishFormat::Json),
+            "msgpack" => Ok(PublishFormat::Msgpack),
+            "cbor" => Ok(PublishFormat::Cbor),
+            other => Err(ConfigError::ParsingError(format!(
+                "Unknown serialization format '{}', expected json, msgpack or cbor",
+                other
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Status {
+        status: String,
+        progress: u64,
+        percentage: f64,
+        details: Option<String>,
+    }
+
+    fn status() -> Status {
+        Status {
+            status: "running".to_string(),
+            progress: 3,
+            percentage: 37.5,
+            details: Some("step \"3\" of 8".to_string()),
+        }
+    }
+
+    #[test]
+    fn payloads_round_trip_in_every_format() {
+        let json = PublishFormat::Json.encode(&status()).unwrap();
+        assert_eq!(serde_json::from_slice::<Status>(&json).unwrap(), status());
+
+        let msgpack = PublishFormat::Msgpack.encode(&status()).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Status>(&msgpack).unwrap(), status());
+
+        let cbor = PublishFormat::Cbor.encode(&status()).unwrap();
+        assert_eq!(ciborium::from_reader::<Status, _>(cbor.as_slice()).unwrap(), status());
+    }
+
+    #[test]
+    fn binary_formats_are_published_to_suffixed_topics() {
+        assert_eq!(PublishFormat::Json.topic("mf/status"), "mf/status");
+        assert_eq!(PublishFormat::Msgpack.topic("mf/status"), "mf/status/msgpack");
+        assert_eq!(PublishFormat::Cbor.topic("mf/status"), "mf/status/cbor");
+    }
+
+    #[test]
+    fn formats_parse_case_insensitively() {
+        assert_eq!("JSON".parse::<PublishFormat>().unwrap(), PublishFormat::Json);
+        assert_eq!("msgpack".parse::<PublishFormat>().unwrap(), PublishFormat::Msgpack);
+        assert_eq!("Cbor".parse::<PublishFormat>().unwrap(), PublishFormat::Cbor);
+        assert!("xml".parse::<PublishFormat>().is_err());
+    }
+}
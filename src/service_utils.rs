@@ -1,13 +1,21 @@
 use uuid::Uuid;
+use serde::Serialize;
 use std::sync::Arc;
-use tracing::{error, info};
-use crate::mqtt_service::MqttService;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+use crate::archive::Archive;
+use crate::db::DatabaseService;
+use crate::mqtt_service::{ClientState, MqttService, PublishProperties};
+use crate::progress_tracker::{evict_trackers, SharedState};
+use crate::resource_usage::ResourceUsage;
+use crate::sinks::SinkKind;
 
-/// Start an MQTT service with a specific client ID prefix
+/// Start an MQTT service with its client ID, see `mqtt_client_id`.
 pub fn start_mqtt_service(mqtt_service: Arc<MqttService>, client_id_prefix: &str) {
     let mqtt_host = mqtt_service.config.mqtt_host.clone();
     let mqtt_port = mqtt_service.config.mqtt_port;
-    let mqtt_client_id = format!("{}_{}", client_id_prefix, Uuid::new_v4());
+    let mqtt_client_id = mqtt_client_id(&mqtt_service, client_id_prefix);
 
     let mqtt_service_clone = mqtt_service.clone();
     tokio::spawn(async move {
@@ -17,16 +25,98 @@ pub fn start_mqtt_service(mqtt_service: Arc<MqttService>, client_id_prefix: &str
     });
 }
 
+/// The configured client ID of an MQTT service, used verbatim, or a random one with a
+/// specific prefix. Only a stable client ID lets the broker resume the session after a
+/// restart.
+fn mqtt_client_id(mqtt_service: &MqttService, client_id_prefix: &str) -> String {
+    match &mqtt_service.config.client_id {
+        Some(client_id) => client_id.clone(),
+        None => {
+            if !mqtt_service.config.clean_session {
+                warn!("MQTT service '{}' has no stable client ID, its session can't be resumed after a restart.", client_id_prefix);
+            }
+            format!("{}_{}", client_id_prefix, Uuid::new_v4())
+        }
+    }
+}
+
+/// Payload published to the log topic
+#[derive(Debug, Serialize)]
+pub struct LogPayload {
+    pub level: String,
+    pub message: String,
+}
+
+/// Payload published to the analytics topic
+#[derive(Debug, Serialize)]
+pub struct AnalyticsEvent {
+    pub event: String,
+    pub details: String,
+}
+
+/// Payload published to the progress topic
+#[derive(Debug, Serialize)]
+pub struct ProgressPayload {
+    pub progress: u64,
+    pub total: u64,
+    pub percentage: f64,
+}
+
+/// Payload published to the status topic
+#[derive(Debug, Serialize)]
+pub struct StatusPayload {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Payload published to the heartbeat topic
+#[derive(Debug, Serialize)]
+pub struct HeartbeatPayload {
+    pub timestamp: String,
+    pub uptime_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rss_bytes: Option<u64>,
+    /// Since the previous heartbeat, in percent of one core
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db_size_bytes: Option<u64>,
+    /// Messages received from the monitored broker since startup
+    pub messages_received: u64,
+    /// Since the previous heartbeat
+    pub messages_per_sec: f64,
+}
+
+/// Payload published to the connection topic when the client state changes
+#[derive(Debug, Serialize)]
+pub struct ConnectionStatePayload {
+    pub state: String,
+    /// `host:port` of the broker
+    pub broker: String,
+    pub timestamp: String,
+    /// Reconnect attempts so far
+    pub retries: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 /// Start logging for a specific MQTT service
 pub fn start_logging(mqtt_service: Arc<MqttService>, message: String) {
     let mqtt_service_clone = mqtt_service.clone();
     tokio::spawn(async move {
         mqtt_service_clone
-            .publish_message(
+            .publish_payload(
                 &mqtt_service_clone.config.log_topic,
-                &format!("{{\"level\": \"INFO\", \"message\": \"{}\"}}", message),
+                &LogPayload {
+                    level: "INFO".to_string(),
+                    message,
+                },
                 rumqttc::QoS::AtLeastOnce,
                 true,
+                PublishProperties::default(),
             )
             .await;
     });
@@ -40,14 +130,16 @@ pub fn publish_analytics(
 ) {
     let mqtt_service_clone = mqtt_service.clone();
     tokio::spawn(async move {
-        mqtt_service_clone
-            .publish_message(
-                &mqtt_service_clone.config.analytics_topic,
-                &format!("{{\"event\": \"{}\", \"details\": \"{}\"}}", event, details),
-                rumqttc::QoS::AtLeastOnce,
-                true,
-            )
-            .await;
+        publish_to_sinks(
+            &mqtt_service_clone,
+            SinkKind::Analytics,
+            &mqtt_service_clone.config.analytics_topic,
+            &AnalyticsEvent { event, details },
+            rumqttc::QoS::AtLeastOnce,
+            true,
+            PublishProperties::default(),
+        )
+        .await;
     });
 }
 
@@ -59,20 +151,26 @@ pub fn publish_progress(
 ) {
     let mqtt_service_clone = mqtt_service.clone();
     let topic = mqtt_service_clone.config.progress_topic.clone();
+    let percentage = if total > 0 {
+        ((progress as f64 / total as f64) * 10_000.0).round() / 100.0
+    } else {
+        0.0
+    };
     tokio::spawn(async move {
-        mqtt_service_clone
-            .publish_message(
-                &topic,
-                &format!(
-                    "{{\"progress\": {}, \"total\": {}, \"percentage\": {:.2}}}",
-                    progress,
-                    total,
-                    (progress as f64 / total as f64) * 100.0
-                ),
-                rumqttc::QoS::AtLeastOnce,
-                true,
-            )
-            .await;
+        publish_to_sinks(
+            &mqtt_service_clone,
+            SinkKind::Progress,
+            &topic,
+            &ProgressPayload {
+                progress,
+                total,
+                percentage,
+            },
+            rumqttc::QoS::AtLeastOnce,
+            true,
+            PublishProperties::expiring_after(mqtt_service_clone.config.progress_expiry_secs),
+        )
+        .await;
     });
 }
 
@@ -86,79 +184,250 @@ pub fn publish_status(
     let topic = mqtt_service_clone.config.status_topic.clone();
     let details_message = details.unwrap_or_default();
     tokio::spawn(async move {
-        mqtt_service_clone
-            .publish_message(
-                &topic,
-                &format!(
-                    "{{\"status\": \"{}\", \"details\": \"{}\"}}",
-                    status, details_message
-                ),
-                rumqttc::QoS::AtLeastOnce,
-                true,
-            )
-            .await;
+        publish_to_sinks(
+            &mqtt_service_clone,
+            SinkKind::Status,
+            &topic,
+            &StatusPayload {
+                status,
+                details: Some(details_message),
+                message: None,
+            },
+            rumqttc::QoS::AtLeastOnce,
+            true,
+            PublishProperties::expiring_after(mqtt_service_clone.config.status_expiry_secs),
+        )
+        .await;
     });
 }
 
-/// Graceful shutdown for a specific MQTT service
-pub async fn handle_shutdown(mqtt_service: Arc<MqttService>, client_name: &str) {
-    let status_topic = mqtt_service.config.status_topic.clone();
+/// Wait for Ctrl+C or, on Unix, SIGTERM as sent by service managers and container
+/// runtimes. Fails if Ctrl+C can't be listened for, without SIGTERM only Ctrl+C counts.
+pub async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to handle SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate => Ok(()),
+    }
+}
 
-    if let Err(e) = tokio::signal::ctrl_c().await {
-        error!("[{}] Failed to handle termination signal: {:?}", client_name, e);
+/// Start periodic status updates for a specific MQTT service. The status follows the
+/// connection state, so the retained status never claims `running` while the client is
+/// reconnecting or has given up.
+pub fn periodic_status_update(mqtt_service: Arc<MqttService>, client_name: &str) {
+    let topic = mqtt_service.config.status_topic.clone();
+    let client_name = client_name.to_string(); // Kopiere `client_name` in einen String
 
-        mqtt_service
-            .publish_message(
-                &status_topic,
-                &format!(
-                    "{{\"status\": \"error\", \"message\": \"Termination signal failed for {}\"}}",
-                    client_name
+    tokio::spawn(async move {
+        loop {
+            let retries = mqtt_service.reconnect_attempts();
+            let last_cause = match mqtt_service.last_disconnect_cause().await {
+                Some(cause) => format!(", last disconnect: {}", cause.name()),
+                None => String::new(),
+            };
+            let (status, details, message) = match mqtt_service.client_state().await {
+                ClientState::Connected => (
+                    "running",
+                    mqtt_service
+                        .failed_over_to()
+                        .await
+                        .map(|broker| format!("failed over to {}", broker)),
+                    format!("{} is operational", client_name),
                 ),
-                rumqttc::QoS::AtLeastOnce,
-                true,
-            )
-            .await;
-    } else {
-        mqtt_service
-            .publish_message(
-                &status_topic,
-                &format!(
-                    "{{\"status\": \"shutdown\", \"message\": \"{} is shutting down...\"}}",
-                    client_name
+                ClientState::Connecting | ClientState::Disconnected => (
+                    "reconnecting",
+                    Some(format!("{} reconnect attempts{}", retries, last_cause)),
+                    format!("{} is reconnecting", client_name),
                 ),
+                ClientState::Error(reason) => (
+                    "degraded",
+                    Some(format!("{} after {} reconnect attempts{}", reason, retries, last_cause)),
+                    format!("{} stopped connecting", client_name),
+                ),
+            };
+            publish_to_sinks(
+                &mqtt_service,
+                SinkKind::Status,
+                &topic,
+                &StatusPayload {
+                    status: status.to_string(),
+                    details,
+                    message: Some(message),
+                },
                 rumqttc::QoS::AtLeastOnce,
                 true,
+                PublishProperties::expiring_after(mqtt_service.config.status_expiry_secs),
             )
             .await;
 
-        info!("[{}] is shutting down...", client_name);
+            tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
+        }
+    });
+}
+
+/// Periodically evict finished progress trackers and enforce the map size cap
+pub fn start_progress_eviction(state: SharedState, ttl_secs: u64, max_entries: usize) {
+    let ttl = tokio::time::Duration::from_secs(ttl_secs);
+    // Check at least once a minute, more often for short TTLs
+    let interval = ttl.clamp(tokio::time::Duration::from_secs(1), tokio::time::Duration::from_secs(60));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            evict_trackers(&state, ttl, max_entries).await;
+        }
+    });
+}
+
+/// Store the pre-aggregation windows of all topics once they close. Windows are checked
+/// every second, so a quiet topic's window is stored shortly after its end rather than
+/// with its next value.
+pub fn start_aggregate_flush(db: Arc<DatabaseService>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            if let Err(e) = db.clone().blocking(|db| db.flush_aggregate_windows(false)).await {
+                error!("Failed to store pre-aggregated windows: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Delete values past the retention of their topic every `interval_secs`, see
+/// `DatabaseService::purge_expired_values`
+pub fn start_retention(db: Arc<DatabaseService>, interval_secs: u64) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(interval_secs)).await;
+            match db.clone().blocking(|db| db.purge_expired_values()).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} values past their topic's retention.", purged),
+                Err(e) => error!("Failed to purge expired values: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Move values received more than `after_secs` ago to `archive` every `interval_secs`,
+/// disabled when `after_secs` is 0
+pub fn start_archiver(db: Arc<DatabaseService>, archive: Arc<Archive>, after_secs: u64, interval_secs: u64) {
+    if after_secs == 0 {
+        return;
     }
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = archive.archive_values(&db, after_secs).await {
+                error!("Failed to archive old values: {}", e);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(interval_secs)).await;
+        }
+    });
 }
 
-/// Start periodic status updates for a specific MQTT service
-pub fn periodic_status_update(mqtt_service: Arc<MqttService>, client_name: &str) {
-    let topic = mqtt_service.config.status_topic.clone();
-    let client_name = client_name.to_string(); // Kopiere `client_name` in einen String
+/// Publish the resource usage and message throughput through `publisher` every
+/// `interval_secs`, disabled when 0. Throughput is counted on `monitored`.
+pub fn start_heartbeat(
+    publisher: Arc<MqttService>,
+    monitored: Arc<MqttService>,
+    db: Arc<DatabaseService>,
+    topic: String,
+    interval_secs: u64,
+) {
+    if interval_secs == 0 {
+        return;
+    }
+    let interval = tokio::time::Duration::from_secs(interval_secs);
+    // A heartbeat missing its successor is stale, don't let the broker hand it out later
+    let expiry_secs = u32::try_from(interval_secs.saturating_mul(2)).unwrap_or(u32::MAX);
 
     tokio::spawn(async move {
+        let started = tokio::time::Instant::now();
+        let mut previous = (started, ResourceUsage::sample(), monitored.received_message_count());
+
         loop {
-            mqtt_service
-                .publish_message(
+            tokio::time::sleep(interval).await;
+
+            let now = tokio::time::Instant::now();
+            let usage = ResourceUsage::sample();
+            let received = monitored.received_message_count();
+            let (previous_at, previous_usage, previous_received) = previous;
+            let elapsed = now - previous_at;
+
+            let db_size_bytes = match db.clone().blocking(|db| db.database_size_bytes()).await {
+                Ok(size) => Some(size),
+                Err(e) => {
+                    warn!("Failed to read the database size for the heartbeat: {:?}", e);
+                    None
+                }
+            };
+            let payload = HeartbeatPayload {
+                timestamp: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+                uptime_secs: started.elapsed().as_secs(),
+                rss_bytes: usage.rss_bytes,
+                cpu_percent: usage.cpu_percent_since(&previous_usage, elapsed),
+                db_size_bytes,
+                messages_received: received,
+                messages_per_sec: (received - previous_received) as f64 / elapsed.as_secs_f64(),
+            };
+            publisher
+                .publish_payload(
                     &topic,
-                    &format!(
-                        "{{\"status\": \"running\", \"message\": \"{} is operational\"}}",
-                        client_name
-                    ),
-                    rumqttc::QoS::AtLeastOnce,
-                    true,
+                    &payload,
+                    rumqttc::QoS::AtMostOnce,
+                    false,
+                    PublishProperties::expiring_after(expiry_secs),
                 )
                 .await;
 
-            tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
+            previous = (now, usage, received);
         }
     });
 }
 
+/// Publish `payload` to `topic` and hand it to the HTTP sink for `kind`, if one is
+/// configured. The sink request runs on its own task, so a slow endpoint never holds up
+/// the MQTT publish.
+async fn publish_to_sinks<T: Serialize>(
+    mqtt_service: &MqttService,
+    kind: SinkKind,
+    topic: &str,
+    payload: &T,
+    qos: rumqttc::QoS,
+    retain: bool,
+    properties: PublishProperties,
+) {
+    let sinks = mqtt_service.config.http_sinks.clone();
+    if sinks.is_enabled(kind) {
+        match serde_json::to_vec(payload) {
+            Ok(body) => {
+                let topic = topic.to_string();
+                tokio::spawn(async move {
+                    sinks.send(kind, &topic, body).await;
+                });
+            }
+            Err(e) => error!("Failed to serialize {:?} message for its HTTP sink: {:?}", kind, e),
+        }
+    }
+    mqtt_service
+        .publish_payload(topic, payload, qos, retain, properties)
+        .await;
+}
+
 /// Start multiple MQTT services
 pub fn start_multiple_mqtt_services(services: Vec<(Arc<MqttService>, &str)>) {
     for (mqtt_service, client_name) in services {
@@ -166,3 +435,51 @@ pub fn start_multiple_mqtt_services(services: Vec<(Arc<MqttService>, &str)>) {
         periodic_status_update(mqtt_service, client_name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::PublishFormat;
+
+    #[test]
+    fn quotes_and_newlines_survive_serialization() {
+        let message = "said \"stop\"\\\nthen left";
+
+        let status = PublishFormat::Json
+            .encode(&StatusPayload {
+                status: "offline".to_string(),
+                details: Some(message.to_string()),
+                message: None,
+            })
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&status).unwrap();
+        assert_eq!(status["details"], message);
+        assert!(status.get("message").is_none());
+
+        let log = PublishFormat::Json
+            .encode(&LogPayload { level: "info".to_string(), message: message.to_string() })
+            .unwrap();
+        let log: serde_json::Value = serde_json::from_slice(&log).unwrap();
+        assert_eq!(log["message"], message);
+
+        let event = PublishFormat::Json
+            .encode(&AnalyticsEvent { event: message.to_string(), details: message.to_string() })
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&event).unwrap();
+        assert_eq!(event["event"], message);
+        assert_eq!(event["details"], message);
+    }
+
+    #[test]
+    fn configured_client_ids_are_used_verbatim() {
+        let config = crate::config::tests::config(&[("MONITORED_MQTT_CLIENT_ID", "MonitorFlux-Store_1")]);
+        let service = crate::mqtt_service::tests::test_service(&config, None);
+        assert_eq!(mqtt_client_id(&service, "monitored"), "MonitorFlux-Store_1");
+
+        let config = crate::config::tests::config_with(&[("MONITORED_MQTT_CLIENT_ID", None)]).unwrap();
+        let service = crate::mqtt_service::tests::test_service(&config, None);
+        let random = mqtt_client_id(&service, "monitored");
+        assert!(random.starts_with("monitored_"));
+        assert_ne!(random, mqtt_client_id(&service, "monitored"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+/// Kinds of messages an HTTP sink can receive, each with its own URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    Status,
+    Progress,
+    Analytics,
+}
+
+/// Settings shared by all HTTP sinks
+#[derive(Debug, Clone, Copy)]
+pub struct HttpSinkSettings {
+    pub timeout: Duration,
+    /// Failed requests in a row after which a sink is skipped for `cooldown`
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+/// Stops calling an endpoint that keeps failing. After `failure_threshold` failures in a
+/// row the breaker opens and requests are skipped until `cooldown` has passed; the next
+/// requests then probe the endpoint, closing the breaker on success and opening it for
+/// another cooldown on failure.
+#[derive(Debug)]
+struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Whether a request may go out now
+    fn allows(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.open_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn succeeded(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    /// Records a failure, returning whether it opened the breaker
+    fn failed(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        // A failed probe after the cooldown opens it again right away
+        if state.failures >= self.failure_threshold || state.open_until.is_some() {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            return true;
+        }
+        false
+    }
+}
+
+#[derive(Debug)]
+struct HttpSink {
+    url: String,
+    breaker: CircuitBreaker,
+}
+
+/// HTTP endpoints receiving status, progress and analytics messages as JSON POSTs next to
+/// the MQTT publish, for consumers that can't reach the broker. Every sink is optional
+/// and has its own circuit breaker, so one failing endpoint neither delays nor blocks
+/// the others or the MQTT publish.
+#[derive(Debug)]
+pub struct HttpSinks {
+    client: reqwest::Client,
+    status: Option<HttpSink>,
+    progress: Option<HttpSink>,
+    analytics: Option<HttpSink>,
+}
+
+impl HttpSinks {
+    pub fn new(
+        status_url: Option<String>,
+        progress_url: Option<String>,
+        analytics_url: Option<String>,
+        settings: HttpSinkSettings,
+    ) -> Self {
+        let sink = |url: Option<String>| {
+            url.map(|url| HttpSink {
+                url,
+                breaker: CircuitBreaker::new(settings.failure_threshold, settings.cooldown),
+            })
+        };
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(settings.timeout)
+                .build()
+                .unwrap_or_default(),
+            status: sink(status_url),
+            progress: sink(progress_url),
+            analytics: sink(analytics_url),
+        }
+    }
+
+    fn sink(&self, kind: SinkKind) -> Option<&HttpSink> {
+        match kind {
+            SinkKind::Status => self.status.as_ref(),
+            SinkKind::Progress => self.progress.as_ref(),
+            SinkKind::Analytics => self.analytics.as_ref(),
+        }
+    }
+
+    /// Whether messages of `kind` go anywhere besides MQTT
+    pub fn is_enabled(&self, kind: SinkKind) -> bool {
+        self.sink(kind).is_some()
+    }
+
+    /// POST the JSON `body` to the sink for `kind`, with the MQTT topic it was published
+    /// to in the `X-MQTT-Topic` header. Failures are logged and counted by the breaker.
+    pub async fn send(&self, kind: SinkKind, topic: &str, body: Vec<u8>) {
+        let Some(sink) = self.sink(kind) else {
+            return;
+        };
+        if !sink.breaker.allows() {
+            debug!("Skipping {:?} sink {}, its circuit breaker is open.", kind, sink.url);
+            return;
+        }
+
+        let result = self
+            .client
+            .post(&sink.url)
+            .header("X-MQTT-Topic", topic)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => sink.breaker.succeeded(),
+            Err(e) => {
+                if sink.breaker.failed() {
+                    warn!(
+                        "{:?} sink {} keeps failing ({}), pausing it for {:?}.",
+                        kind, sink.url, e, sink.breaker.cooldown
+                    );
+                } else {
+                    warn!("Failed to send {:?} message to {}: {}", kind, sink.url, e);
+                }
+            }
+        }
+    }
+}
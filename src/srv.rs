@@ -0,0 +1,272 @@
+use std::io;
+use std::net::SocketAddr;
+
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+use crate::config::BrokerEndpoint;
+
+/// How long a nameserver gets to answer
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+const DNS_PORT: u16 = 53;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Error)]
+pub enum SrvError {
+    #[error("DNS query failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("DNS query timed out")]
+    Timeout,
+    #[error("No nameserver configured and none found in /etc/resolv.conf")]
+    NoNameserver,
+    #[error("Malformed DNS response")]
+    Malformed,
+    #[error("DNS query failed with response code {0}")]
+    ResponseCode(u8),
+    #[error("No SRV records found")]
+    NotFound,
+}
+
+/// An SRV record pointing at a broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Whether `name` is an SRV name `_service._proto.domain`, e.g. `_mqtt._tcp.example.com`
+pub fn is_valid_srv_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let labels: Vec<&str> = name.split('.').collect();
+    name.len() <= 253
+        && labels.len() >= 3
+        && labels[0].len() > 1
+        && labels[0].starts_with('_')
+        && matches!(labels[1], "_tcp" | "_udp")
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        })
+}
+
+/// Looks up the brokers behind an SRV name, asking `nameserver` or the first one of
+/// `/etc/resolv.conf`. Only plain DNS over UDP is spoken, falling back to TCP for
+/// truncated answers.
+#[derive(Debug, Clone)]
+pub struct SrvResolver {
+    pub name: String,
+    nameserver: Option<SocketAddr>,
+}
+
+impl SrvResolver {
+    pub fn new(name: String, nameserver: Option<SocketAddr>) -> Self {
+        Self { name, nameserver }
+    }
+
+    /// The brokers of the record in the order they should be tried (RFC 2782): lowest
+    /// priority first, records of the same priority shuffled by weight.
+    pub async fn lookup(&self) -> Result<Vec<BrokerEndpoint>, SrvError> {
+        let nameserver = self.nameserver.or_else(system_nameserver).ok_or(SrvError::NoNameserver)?;
+        let id = Uuid::new_v4().as_u128() as u16;
+        let query = build_query(id, &self.name);
+
+        let response = timeout(DNS_TIMEOUT, query_udp(nameserver, &query))
+            .await
+            .map_err(|_| SrvError::Timeout)??;
+        let (records, truncated) = parse_response(id, &response)?;
+        let records = if truncated {
+            let response = timeout(DNS_TIMEOUT, query_tcp(nameserver, &query))
+                .await
+                .map_err(|_| SrvError::Timeout)??;
+            parse_response(id, &response)?.0
+        } else {
+            records
+        };
+
+        // A single record with target "." means the service is deliberately unavailable
+        let endpoints: Vec<BrokerEndpoint> = order(records)
+            .into_iter()
+            .filter(|record| !record.target.is_empty())
+            .map(|record| BrokerEndpoint {
+                host: record.target,
+                port: record.port,
+            })
+            .collect();
+        if endpoints.is_empty() {
+            return Err(SrvError::NotFound);
+        }
+        Ok(endpoints)
+    }
+}
+
+/// First nameserver of `/etc/resolv.conf`
+fn system_nameserver() -> Option<SocketAddr> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("nameserver"), Some(address)) => address
+                .parse()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT)),
+            _ => None,
+        }
+    })
+}
+
+async fn query_udp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, SrvError> {
+    let bind: SocketAddr = if nameserver.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 4096];
+    let len = socket.recv(&mut buf).await?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+async fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, SrvError> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    // Messages over TCP are prefixed with their length
+    stream.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    stream.write_all(query).await?;
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// A recursive query for the SRV records of `name`
+fn build_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+/// The SRV records of a response to the query `id`, and whether it was truncated
+fn parse_response(id: u16, response: &[u8]) -> Result<(Vec<SrvRecord>, bool), SrvError> {
+    let header = response.get(..12).ok_or(SrvError::Malformed)?;
+    if u16::from_be_bytes([header[0], header[1]]) != id || header[2] & 0x80 == 0 {
+        return Err(SrvError::Malformed);
+    }
+    let truncated = header[2] & 0x02 != 0;
+    match header[3] & 0x0f {
+        0 => {}
+        // NXDOMAIN
+        3 => return Err(SrvError::NotFound),
+        code => return Err(SrvError::ResponseCode(code)),
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(response, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(response, pos)?.1;
+        let fixed = response.get(pos..pos + 10).ok_or(SrvError::Malformed)?;
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let data_len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let data_start = pos + 10;
+        let data = response.get(data_start..data_start + data_len).ok_or(SrvError::Malformed)?;
+        // CNAMEs of the name and other records are skipped
+        if record_type == TYPE_SRV {
+            if data.len() < 7 {
+                return Err(SrvError::Malformed);
+            }
+            records.push(SrvRecord {
+                priority: u16::from_be_bytes([data[0], data[1]]),
+                weight: u16::from_be_bytes([data[2], data[3]]),
+                port: u16::from_be_bytes([data[4], data[5]]),
+                target: read_name(response, data_start + 6)?.0,
+            });
+        }
+        pos = data_start + data_len;
+    }
+    Ok((records, truncated))
+}
+
+/// Reads the possibly compressed name at `pos`, returning it without the root dot and
+/// the position after it
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize), SrvError> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer must point backwards, so a loop can't go on forever
+    let mut limit = pos;
+    loop {
+        let len = *message.get(pos).ok_or(SrvError::Malformed)? as usize;
+        match len {
+            0 => {
+                let end = end.unwrap_or(pos + 1);
+                return Ok((labels.join("."), end));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let low = *message.get(pos + 1).ok_or(SrvError::Malformed)? as usize;
+                let target = ((len & 0x3f) << 8) | low;
+                if target >= limit {
+                    return Err(SrvError::Malformed);
+                }
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            len if len <= 63 => {
+                let label = message.get(pos + 1..pos + 1 + len).ok_or(SrvError::Malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+            _ => return Err(SrvError::Malformed),
+        }
+    }
+}
+
+/// Orders records by priority and, within a priority, by the weighted random selection
+/// of RFC 2782
+fn order(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    records.sort_by_key(|record| record.priority);
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let same_priority = records.iter().take_while(|record| record.priority == priority).count();
+        let mut group: Vec<SrvRecord> = records.drain(..same_priority).collect();
+        // Weight 0 records go first so they keep a small chance of being picked
+        group.sort_by_key(|record| record.weight);
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| record.weight as u32).sum();
+            let pick = (Uuid::new_v4().as_u128() % (total as u128 + 1)) as u32;
+            let mut running = 0;
+            let index = group
+                .iter()
+                .position(|record| {
+                    running += record.weight as u32;
+                    running >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
@@ -0,0 +1,257 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+/// Upper bound for the number of buckets a bucketed range may be split into
+pub const MAX_BUCKETS: u64 = 10_000;
+
+const SQLITE_TIMESTAMP_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+
+/// Parses an RFC 3339 or SQLite (`YYYY-MM-DD HH:MM:SS`, UTC) timestamp.
+pub fn parse_timestamp(input: &str) -> Option<OffsetDateTime> {
+    match OffsetDateTime::parse(input, &Rfc3339) {
+        Ok(dt) => Some(dt.to_offset(time::UtcOffset::UTC)),
+        Err(_) => PrimitiveDateTime::parse(input, SQLITE_TIMESTAMP_FORMAT)
+            .ok()
+            .map(|dt| dt.assume_utc()),
+    }
+}
+
+/// Formats a timestamp the way SQLite stores `topic_values.timestamp`.
+pub fn format_timestamp(dt: OffsetDateTime) -> String {
+    dt.format(SQLITE_TIMESTAMP_FORMAT).unwrap_or_default()
+}
+
+/// Why the time range parameters of a request were rejected.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TimeRangeError {
+    #[error("missing query parameter '{0}'")]
+    Missing(&'static str),
+    #[error("'{field}' is not an RFC 3339 or 'YYYY-MM-DD HH:MM:SS' timestamp: '{value}'")]
+    InvalidTimestamp { field: &'static str, value: String },
+    #[error("'from' must not be after 'to'")]
+    Reversed,
+    #[error("'{0}' must be a positive integer")]
+    NotPositive(&'static str),
+    #[error("{bucket_seconds} second buckets split the range into more than {MAX_BUCKETS} buckets")]
+    TooManyBuckets { bucket_seconds: u64 },
+}
+
+/// A validated time range, `from` not after `to`
+#[derive(Debug, Clone, Copy)]
+pub struct TimeRange {
+    pub from: OffsetDateTime,
+    pub to: OffsetDateTime,
+}
+
+impl TimeRange {
+    pub fn parse(from: &str, to: &str) -> Result<Self, TimeRangeError> {
+        let (from, to) = (parse_bound("from", from)?, parse_bound("to", to)?);
+        if from > to {
+            return Err(TimeRangeError::Reversed);
+        }
+        Ok(Self { from, to })
+    }
+
+    /// Length of the range in whole seconds, at least one
+    pub fn span_seconds(&self) -> u64 {
+        (self.to - self.from).whole_seconds().max(1) as u64
+    }
+
+    /// Checks that buckets of `bucket_seconds` split the range into at most `MAX_BUCKETS`
+    pub fn check_bucket(&self, bucket_seconds: u64) -> Result<(), TimeRangeError> {
+        if bucket_seconds == 0 {
+            return Err(TimeRangeError::NotPositive("bucket"));
+        }
+        if self.span_seconds() / bucket_seconds > MAX_BUCKETS {
+            return Err(TimeRangeError::TooManyBuckets { bucket_seconds });
+        }
+        Ok(())
+    }
+
+    /// Both ends formatted for comparison with stored timestamps
+    pub fn formatted(&self) -> (String, String) {
+        (format_timestamp(self.from), format_timestamp(self.to))
+    }
+}
+
+fn parse_bound(field: &'static str, input: &str) -> Result<OffsetDateTime, TimeRangeError> {
+    parse_timestamp(input).ok_or_else(|| TimeRangeError::InvalidTimestamp {
+        field,
+        value: input.to_string(),
+    })
+}
+
+/// Reason a guard rejected the request, kept for the 400 catcher to report
+struct Rejection(Option<String>);
+
+/// Message of the `TimeRangeError` a guard rejected this request with, if any
+pub fn rejection_message<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.local_cache(|| Rejection(None)).0.as_deref()
+}
+
+fn reject<T>(req: &Request<'_>, error: TimeRangeError) -> Outcome<T, TimeRangeError> {
+    req.local_cache(|| Rejection(Some(error.to_string())));
+    Outcome::Error((Status::BadRequest, error))
+}
+
+/// Raw query parameter, `None` when absent
+fn query_param<'r>(req: &'r Request<'_>, name: &str) -> Option<&'r str> {
+    req.query_value::<&str>(name).and_then(|value| value.ok())
+}
+
+fn positive_param(req: &Request<'_>, name: &'static str) -> Result<Option<u64>, TimeRangeError> {
+    match query_param(req, name) {
+        Some(input) => match input.parse() {
+            Ok(0) | Err(_) => Err(TimeRangeError::NotPositive(name)),
+            Ok(value) => Ok(Some(value)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Request guard for the `from` and `to` query parameters of the time-based endpoints,
+/// together with the optional `limit` and `bucket` (seconds) some of them take. Invalid
+/// input is rejected with 400 and a message naming the offending parameter.
+#[derive(Debug)]
+pub struct TimeRangeQuery {
+    pub range: TimeRange,
+    pub limit: Option<usize>,
+    pub bucket: Option<u64>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TimeRangeQuery {
+    type Error = TimeRangeError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let parsed = (|| {
+            let from = query_param(req, "from").ok_or(TimeRangeError::Missing("from"))?;
+            let to = query_param(req, "to").ok_or(TimeRangeError::Missing("to"))?;
+            let range = TimeRange::parse(from, to)?;
+            let limit = positive_param(req, "limit")?;
+            let bucket = positive_param(req, "bucket")?;
+            if let Some(bucket) = bucket {
+                range.check_bucket(bucket)?;
+            }
+            Ok(TimeRangeQuery {
+                range,
+                limit: limit.map(|limit| limit as usize),
+                bucket,
+            })
+        })();
+
+        match parsed {
+            Ok(query) => Outcome::Success(query),
+            Err(e) => reject(req, e),
+        }
+    }
+}
+
+/// Like `TimeRangeQuery`, but either end may be left open
+#[derive(Debug)]
+pub struct OpenTimeRangeQuery {
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+    pub limit: Option<usize>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OpenTimeRangeQuery {
+    type Error = TimeRangeError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let parsed = (|| {
+            let bound = |field| query_param(req, field).map(|input| parse_bound(field, input)).transpose();
+            let (from, to) = (bound("from")?, bound("to")?);
+            if matches!((from, to), (Some(from), Some(to)) if from > to) {
+                return Err(TimeRangeError::Reversed);
+            }
+            Ok(OpenTimeRangeQuery {
+                from,
+                to,
+                limit: positive_param(req, "limit")?.map(|limit| limit as usize),
+            })
+        })();
+
+        match parsed {
+            Ok(query) => Outcome::Success(query),
+            Err(e) => reject(req, e),
+        }
+    }
+}
+
+/// Request guard for the optional `since` query parameter, rejected with 400 when it
+/// isn't a timestamp
+#[derive(Debug)]
+pub struct SinceQuery {
+    pub since: Option<OffsetDateTime>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SinceQuery {
+    type Error = TimeRangeError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match query_param(req, "since").map(|input| parse_bound("since", input)).transpose() {
+            Ok(since) => Outcome::Success(SinceQuery { since }),
+            Err(e) => reject(req, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn timestamps_parse_from_rfc3339_and_sqlite_format() {
+        assert_eq!(parse_timestamp("2024-05-01T12:00:00Z"), Some(datetime!(2024-05-01 12:00:00 UTC)));
+        assert_eq!(parse_timestamp("2024-05-01T14:00:00+02:00"), Some(datetime!(2024-05-01 12:00:00 UTC)));
+        assert_eq!(parse_timestamp("2024-05-01 12:00:00"), Some(datetime!(2024-05-01 12:00:00 UTC)));
+        assert_eq!(parse_timestamp("2024-05-01"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn timestamps_are_formatted_like_sqlite_stores_them() {
+        let parsed = parse_timestamp("2024-05-01T14:30:15+02:00").unwrap();
+        assert_eq!(format_timestamp(parsed), "2024-05-01 12:30:15");
+    }
+
+    #[test]
+    fn ranges_must_be_valid_and_in_order() {
+        let range = TimeRange::parse("2024-05-01 00:00:00", "2024-05-01T01:00:00Z").unwrap();
+        assert_eq!(range.span_seconds(), 3600);
+        assert_eq!(
+            range.formatted(),
+            ("2024-05-01 00:00:00".to_string(), "2024-05-01 01:00:00".to_string())
+        );
+
+        // A single instant is a valid range
+        assert_eq!(TimeRange::parse("2024-05-01 01:00:00", "2024-05-01 01:00:00").unwrap().span_seconds(), 1);
+        assert_eq!(
+            TimeRange::parse("2024-05-01 01:00:01", "2024-05-01 01:00:00").unwrap_err(),
+            TimeRangeError::Reversed
+        );
+        assert_eq!(
+            TimeRange::parse("2024-05-01 00:00:00", "later").unwrap_err(),
+            TimeRangeError::InvalidTimestamp { field: "to", value: "later".to_string() }
+        );
+    }
+
+    #[test]
+    fn buckets_must_be_positive_and_bounded() {
+        let range = TimeRange::parse("2024-05-01 00:00:00", "2024-05-02 00:00:00").unwrap();
+        assert_eq!(range.check_bucket(60), Ok(()));
+        assert_eq!(range.check_bucket(0), Err(TimeRangeError::NotPositive("bucket")));
+        assert_eq!(range.check_bucket(8), Err(TimeRangeError::TooManyBuckets { bucket_seconds: 8 }));
+        assert_eq!(range.check_bucket(9), Ok(()));
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use rumqttc::TlsConfiguration;
+use thiserror::Error;
+
+/// Errors while loading PEM encoded certificates and keys.
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("TLS file '{0}' does not exist")]
+    NotFound(String),
+    #[error("Failed to read TLS file '{path}': {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("'{0}' does not contain a valid PEM certificate")]
+    InvalidCertificate(String),
+    #[error("'{0}' does not contain a valid PEM private key")]
+    InvalidKey(String),
+    #[error("Private key '{key_path}' does not match certificate '{cert_path}'")]
+    KeyMismatch { cert_path: String, key_path: String },
+}
+
+/// A validated certificate chain and the private key of its leaf certificate, both PEM.
+pub struct Identity {
+    pub cert_chain: Vec<u8>,
+    pub private_key: Vec<u8>,
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, TlsError> {
+    if !Path::new(path).exists() {
+        return Err(TlsError::NotFound(path.to_string()));
+    }
+    std::fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Load one or more PEM CA certificates.
+pub fn load_ca(path: &str) -> Result<Vec<u8>, TlsError> {
+    let pem = read_pem(path)?;
+    match X509::stack_from_pem(&pem) {
+        Ok(certs) if !certs.is_empty() => Ok(pem),
+        _ => Err(TlsError::InvalidCertificate(path.to_string())),
+    }
+}
+
+/// Load a PEM certificate chain and private key, checking that the key belongs to the
+/// first (leaf) certificate.
+pub fn load_identity(cert_path: &str, key_path: &str) -> Result<Identity, TlsError> {
+    let cert_chain = read_pem(cert_path)?;
+    let private_key = read_pem(key_path)?;
+
+    let leaf = X509::stack_from_pem(&cert_chain)
+        .ok()
+        .and_then(|certs| certs.into_iter().next())
+        .ok_or_else(|| TlsError::InvalidCertificate(cert_path.to_string()))?;
+    let key = PKey::private_key_from_pem(&private_key)
+        .map_err(|_| TlsError::InvalidKey(key_path.to_string()))?;
+
+    let matches = leaf
+        .public_key()
+        .map(|public_key| public_key.public_eq(&key))
+        .unwrap_or(false);
+    if !matches {
+        return Err(TlsError::KeyMismatch {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+        });
+    }
+
+    Ok(Identity {
+        cert_chain,
+        private_key,
+    })
+}
+
+/// rumqttc TLS configuration trusting the CA certificates at `ca_path` and offering the
+/// `alpn` protocols, if any.
+pub fn mqtt_tls_config(ca_path: &str, alpn: &[String]) -> Result<TlsConfiguration, TlsError> {
+    let alpn = (!alpn.is_empty()).then(|| alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect());
+    Ok(TlsConfiguration::Simple {
+        ca: load_ca(ca_path)?,
+        alpn,
+        client_auth: None,
+    })
+}
+
+/// Rocket TLS configuration serving the certificate chain at `cert_path`.
+#[cfg(feature = "rest-api")]
+pub fn rocket_tls_config(cert_path: &str, key_path: &str) -> Result<rocket::config::TlsConfig, TlsError> {
+    let identity = load_identity(cert_path, key_path)?;
+    Ok(rocket::config::TlsConfig::from_bytes(
+        &identity.cert_chain,
+        &identity.private_key,
+    ))
+}
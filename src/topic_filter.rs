@@ -0,0 +1,43 @@
+/// Checks whether `topic` matches the MQTT subscription `filter`, honoring the
+/// single-level (`+`) and multi-level (`#`) wildcards.
+///
+/// As in the MQTT spec, wildcards at the start of a filter never match topics
+/// beginning with `$` (e.g. `$SYS/...`).
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Validates an MQTT subscription filter: `#` may only appear as the last level and
+/// wildcards must occupy a whole level.
+pub fn is_valid(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains('#') && !level.contains('+'),
+    })
+}
+
+/// Validates an MQTT topic name, which unlike a filter must not contain wildcards.
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#', '\0'])
+}
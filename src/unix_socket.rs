@@ -0,0 +1,153 @@
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use rocket::http::{Header, Method};
+use rocket::local::asynchronous::Client;
+use rocket::{Build, Rocket};
+use tokio::net::UnixListener;
+use tokio::sync::oneshot;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info, warn};
+
+type ProxyBody = BoxBody<Bytes, std::io::Error>;
+
+/// Serve the Rocket application on a Unix domain socket instead of a TCP port.
+///
+/// Rocket 0.5 can only bind TCP listeners, so each HTTP/1.1 request accepted on the
+/// socket is dispatched through Rocket's in-process client. Response bodies are
+/// streamed, so long-lived responses keep working over the socket.
+pub async fn serve(rocket: Rocket<Build>, socket_path: &str) -> std::io::Result<()> {
+    remove_stale_socket(socket_path)?;
+
+    let client = Client::untracked(rocket)
+        .await
+        .map_err(|e| std::io::Error::other(format!("Failed to ignite Rocket: {:?}", e)))?;
+    let client = Arc::new(client);
+
+    let listener = UnixListener::bind(socket_path)?;
+    info!("REST API listening on unix socket '{}'", socket_path);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Failed to accept unix socket connection: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| forward(client.clone(), req));
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        debug!("Unix socket connection closed with error: {:?}", e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down unix socket listener...");
+                break;
+            }
+        }
+    }
+
+    remove_stale_socket(socket_path)
+}
+
+/// Remove a socket file left behind by a previous run.
+fn remove_stale_socket(socket_path: &str) -> std::io::Result<()> {
+    if Path::new(socket_path).exists() {
+        warn!("Removing socket file '{}'", socket_path);
+        std::fs::remove_file(socket_path)?;
+    }
+    Ok(())
+}
+
+/// Dispatch a single hyper request through the Rocket client.
+async fn forward(client: Arc<Client>, req: Request<Incoming>) -> Result<Response<ProxyBody>, Infallible> {
+    let (parts, body) = req.into_parts();
+
+    let Ok(method) = parts.method.as_str().parse::<Method>() else {
+        return Ok(empty_response(StatusCode::METHOD_NOT_ALLOWED));
+    };
+    let body = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            warn!("Failed to read request body from unix socket: {:?}", e);
+            return Ok(empty_response(StatusCode::BAD_REQUEST));
+        }
+    };
+    let uri = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let headers: Vec<Header<'static>> = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            Some(Header::new(name.as_str().to_string(), value.to_string()))
+        })
+        .collect();
+
+    // The local response borrows the client, so it is driven by its own task and
+    // its body is piped back through an in-memory duplex stream.
+    let (head_tx, head_rx) = oneshot::channel();
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let mut request = client.req(method, uri).body(&body);
+        for header in headers {
+            request.add_header(header);
+        }
+
+        let mut response = request.dispatch().await;
+        let head: (u16, Vec<(String, String)>) = (
+            response.status().code,
+            response
+                .headers()
+                .iter()
+                .map(|h| (h.name().to_string(), h.value().to_string()))
+                .collect(),
+        );
+        if head_tx.send(head).is_err() {
+            return;
+        }
+        if let Err(e) = tokio::io::copy(&mut response, &mut writer).await {
+            debug!("Unix socket client went away while streaming response: {:?}", e);
+        }
+    });
+
+    let Ok((status, headers)) = head_rx.await else {
+        return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+
+    let body = StreamBody::new(ReaderStream::new(reader).map_ok(Frame::data)).boxed();
+    let mut builder = Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    Ok(builder
+        .body(body)
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+fn empty_response(status: StatusCode) -> Response<ProxyBody> {
+    let mut response = Response::new(Empty::new().map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}